@@ -129,6 +129,21 @@ impl<'a> BencodeParser<'a> {
         }
     }
 
+    /// Helper to start a list
+    pub fn expect_list_start(&mut self) -> Result<()> {
+        if self.peek() == Some(b'l') {
+            self.input = &self.input[1..];
+            Ok(())
+        } else {
+            Err(Error::ExpectedList)
+        }
+    }
+
+    /// Returns true if the next element is the end of a list and consumes it.
+    pub fn match_list_end(&mut self) -> bool {
+        self.match_dict_end()
+    }
+
     /// Returns true if the next element is the end of a dict and consumes it.
     pub fn match_dict_end(&mut self) -> bool {
         if self.peek() == Some(b'e') {
@@ -311,6 +326,26 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_list_parsing_workflow() {
+        let mut parser = BencodeParser::new(b"l3:fooi42eei7e");
+        parser.expect_list_start().unwrap();
+        assert_eq!(parser.parse_str().unwrap(), "foo");
+        assert!(!parser.match_list_end());
+        assert_eq!(parser.parse_int().unwrap(), 42);
+        assert!(parser.match_list_end());
+        assert_eq!(parser.parse_int().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_expect_list_start_on_dict() {
+        let mut parser = BencodeParser::new(b"de");
+        assert!(matches!(
+            parser.expect_list_start(),
+            Err(Error::ExpectedList)
+        ));
+    }
+
     #[test]
     fn test_match_dict_end() {
         let mut parser = BencodeParser::new(b"e");
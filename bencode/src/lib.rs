@@ -1,10 +1,14 @@
 #![cfg_attr(not(test), no_std)]
 
+extern crate alloc;
+
 use core::str::Utf8Error;
 
 pub use crate::deserialize::BencodeParser;
+pub use crate::serialize::BencodeWriter;
 
 mod deserialize;
+mod serialize;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Error {
@@ -13,6 +17,7 @@ pub enum Error {
     InvalidUtf8(Utf8Error),
     ExpectedInteger,
     ExpectedString,
+    ExpectedList,
     ExpectedDict,
     UnknownField,
 }
@@ -0,0 +1,126 @@
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Appends bencoded values to a buffer.
+///
+/// Lists and dictionaries are opened with [`BencodeWriter::list_start`] or
+/// [`BencodeWriter::dict_start`] and closed with [`BencodeWriter::end`].
+/// Dictionary keys have to be written in sorted order, as the spec requires.
+pub struct BencodeWriter<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl<'a> BencodeWriter<'a> {
+    pub fn new(out: &'a mut Vec<u8>) -> Self {
+        Self { out }
+    }
+
+    /// Write an integer: 42 -> "i42e"
+    pub fn int(&mut self, value: i64) -> &mut Self {
+        self.out.push(b'i');
+        self.write_decimal(value);
+        self.out.push(b'e');
+        self
+    }
+
+    /// Write a length-prefixed byte string: "spam" -> "4:spam"
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.write_decimal(value.len() as i64);
+        self.out.push(b':');
+        self.out.extend_from_slice(value);
+        self
+    }
+
+    pub fn str(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    pub fn list_start(&mut self) -> &mut Self {
+        self.out.push(b'l');
+        self
+    }
+
+    pub fn dict_start(&mut self) -> &mut Self {
+        self.out.push(b'd');
+        self
+    }
+
+    /// Closes the innermost list or dictionary.
+    pub fn end(&mut self) -> &mut Self {
+        self.out.push(b'e');
+        self
+    }
+
+    fn write_decimal(&mut self, value: i64) {
+        struct Adapter<'v>(&'v mut Vec<u8>);
+        impl Write for Adapter<'_> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                self.0.extend_from_slice(s.as_bytes());
+                Ok(())
+            }
+        }
+        write!(Adapter(self.out), "{}", value).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BencodeParser;
+
+    #[test]
+    fn test_write_int() {
+        let mut out = Vec::new();
+        BencodeWriter::new(&mut out).int(42).int(-7).int(0);
+        assert_eq!(out, b"i42ei-7ei0e");
+    }
+
+    #[test]
+    fn test_write_str() {
+        let mut out = Vec::new();
+        BencodeWriter::new(&mut out)
+            .str("spam")
+            .bytes(&[0, 255])
+            .str("");
+        assert_eq!(out, b"4:spam2:\x00\xff0:");
+    }
+
+    #[test]
+    fn test_write_nested() {
+        let mut out = Vec::new();
+        BencodeWriter::new(&mut out)
+            .dict_start()
+            .str("list")
+            .list_start()
+            .int(1)
+            .str("a")
+            .end()
+            .str("m")
+            .dict_start()
+            .str("ut_pex")
+            .int(1)
+            .end()
+            .end();
+        assert_eq!(out, b"d4:listli1e1:ae1:md6:ut_pexi1eee");
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut out = Vec::new();
+        BencodeWriter::new(&mut out)
+            .dict_start()
+            .str("n")
+            .int(-12345)
+            .str("s")
+            .str("value")
+            .end();
+
+        let mut parser = BencodeParser::new(&out);
+        parser.expect_dict_start().unwrap();
+        assert_eq!(parser.parse_str().unwrap(), "n");
+        assert_eq!(parser.parse_int().unwrap(), -12345);
+        assert_eq!(parser.parse_str().unwrap(), "s");
+        assert_eq!(parser.parse_str().unwrap(), "value");
+        assert!(parser.match_dict_end());
+    }
+}
@@ -1,6 +1,16 @@
+pub mod bitfield;
+pub mod choker;
+pub mod dht;
+pub mod lsd;
 pub mod metainfo;
 pub mod net;
+pub mod peer;
+pub mod peer_id;
+pub mod piece_picker;
+pub mod storage;
 pub mod tracker;
+pub mod utp;
+pub mod verify;
 
 pub type InfoHash = [u8; 20];
 pub type PeerId = [u8; 20];
@@ -0,0 +1,239 @@
+//! Which pieces of a torrent are present, for us and for our peers.
+
+use alloc::{vec, vec::Vec};
+use defmt::Format;
+
+use crate::core::metainfo::Info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BitfieldError {
+    /// The number of bytes doesn't match the number of pieces.
+    InvalidLength,
+    /// One of the bits after the last piece is set.
+    SpareBitsSet,
+}
+
+/// One bit per piece, highest bit of the first byte first, exactly as sent in a `bitfield` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    num_pieces: u32,
+}
+
+impl Bitfield {
+    /// An empty bitfield.
+    pub fn new(num_pieces: u32) -> Self {
+        Self {
+            bytes: vec![0; num_pieces.div_ceil(8) as usize],
+            num_pieces,
+        }
+    }
+
+    /// An empty bitfield for the pieces of `info`.
+    pub fn for_info(info: &Info) -> Self {
+        Self::new(info.pieces.len() as u32)
+    }
+
+    /// A bitfield with every piece set, e.g. for a seed.
+    pub fn full(num_pieces: u32) -> Self {
+        let mut bitfield = Self {
+            bytes: vec![0xff; num_pieces.div_ceil(8) as usize],
+            num_pieces,
+        };
+        if let Some(last) = bitfield.bytes.last_mut() {
+            *last &= last_byte_mask(num_pieces);
+        }
+        bitfield
+    }
+
+    /// Takes over the payload of a `bitfield` message.
+    pub fn from_bytes(bytes: &[u8], num_pieces: u32) -> Result<Self, BitfieldError> {
+        if bytes.len() != num_pieces.div_ceil(8) as usize {
+            return Err(BitfieldError::InvalidLength);
+        }
+        if bytes
+            .last()
+            .is_some_and(|last| last & !last_byte_mask(num_pieces) != 0)
+        {
+            return Err(BitfieldError::SpareBitsSet);
+        }
+        Ok(Self {
+            bytes: bytes.to_vec(),
+            num_pieces,
+        })
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.num_pieces
+    }
+
+    /// Returns `false` for indices outside of the torrent.
+    pub fn has(&self, index: u32) -> bool {
+        index < self.num_pieces && self.bytes[index as usize / 8] & mask(index) != 0
+    }
+
+    /// Sets a piece, returns `false` if it was already set.
+    ///
+    /// # Panics
+    /// If `index` is outside of the torrent.
+    pub fn set(&mut self, index: u32) -> bool {
+        assert!(index < self.num_pieces);
+        let was_set = self.has(index);
+        self.bytes[index as usize / 8] |= mask(index);
+        !was_set
+    }
+
+    /// Clears a piece, e.g. after it failed verification.
+    pub fn clear(&mut self, index: u32) {
+        if index < self.num_pieces {
+            self.bytes[index as usize / 8] &= !mask(index);
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.bytes.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.iter().all(|byte| *byte == 0)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.num_pieces
+    }
+
+    /// The indices of all pieces that are set.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.num_pieces).filter(|index| self.has(*index))
+    }
+
+    /// The wire representation, ready to be sent in a `bitfield` message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+fn mask(index: u32) -> u8 {
+    0x80 >> (index % 8)
+}
+
+/// The bits of the last byte that belong to actual pieces.
+fn last_byte_mask(num_pieces: u32) -> u8 {
+    match num_pieces % 8 {
+        0 => 0xff,
+        used => !(0xff >> used),
+    }
+}
+
+/// How many connected peers have each piece.
+pub struct Availability {
+    counts: Vec<u16>,
+}
+
+impl Availability {
+    pub fn new(num_pieces: u32) -> Self {
+        Self {
+            counts: vec![0; num_pieces as usize],
+        }
+    }
+
+    /// Returns 0 for indices outside of the torrent.
+    pub fn get(&self, index: u32) -> u16 {
+        self.counts.get(index as usize).copied().unwrap_or(0)
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.counts.len() as u32
+    }
+
+    /// Counts the pieces of a peer that sent its bitfield.
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter() {
+            self.counts[index as usize] = self.counts[index as usize].saturating_add(1);
+        }
+    }
+
+    /// Forgets the pieces of a peer that disconnected.
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter() {
+            self.counts[index as usize] = self.counts[index as usize].saturating_sub(1);
+        }
+    }
+
+    /// Counts a piece a peer announced with `have`.
+    pub fn add_have(&mut self, index: u32) {
+        if let Some(count) = self.counts.get_mut(index as usize) {
+            *count = count.saturating_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_clear() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), [0, 0]);
+        assert!(bitfield.is_empty());
+
+        assert!(bitfield.set(0));
+        assert!(!bitfield.set(0));
+        assert!(bitfield.set(9));
+        assert_eq!(bitfield.as_bytes(), [0x80, 0x40]);
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield.count(), 2);
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), [0, 9]);
+
+        bitfield.clear(0);
+        assert_eq!(bitfield.as_bytes(), [0, 0x40]);
+    }
+
+    #[test]
+    fn test_full() {
+        assert_eq!(Bitfield::full(10).as_bytes(), [0xff, 0xc0]);
+        assert_eq!(Bitfield::full(16).as_bytes(), [0xff, 0xff]);
+        assert!(Bitfield::full(10).is_complete());
+        assert!(!Bitfield::new(10).is_complete());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0xc0], 10).unwrap(),
+            Bitfield::full(10)
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff], 10),
+            Err(BitfieldError::InvalidLength)
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0xe0], 10),
+            Err(BitfieldError::SpareBitsSet)
+        );
+        assert!(Bitfield::from_bytes(&[0xff], 8).is_ok());
+    }
+
+    #[test]
+    fn test_availability() {
+        let mut availability = Availability::new(10);
+        let first = Bitfield::from_bytes(&[0xc0, 0x00], 10).unwrap();
+        let second = Bitfield::from_bytes(&[0x80, 0x40], 10).unwrap();
+
+        availability.add_bitfield(&first);
+        availability.add_bitfield(&second);
+        availability.add_have(5);
+        availability.add_have(10);
+        assert_eq!(availability.get(0), 2);
+        assert_eq!(availability.get(1), 1);
+        assert_eq!(availability.get(5), 1);
+        assert_eq!(availability.get(9), 1);
+        assert_eq!(availability.get(10), 0);
+
+        availability.remove_bitfield(&first);
+        assert_eq!(availability.get(0), 1);
+        assert_eq!(availability.get(1), 0);
+    }
+}
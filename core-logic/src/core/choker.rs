@@ -0,0 +1,214 @@
+//! Deciding which peers we upload to (BEP 3 tit-for-tat).
+//!
+//! Every [`UNCHOKE_INTERVAL_MS`] the interested peers are ranked and the best ones get
+//! the regular unchoke slots. While downloading, peers are ranked by how fast they
+//! upload to us, so we reciprocate. While seeding nobody uploads to us, so peers are
+//! ranked by how fast we can upload to them instead. One more slot rotates through the
+//! remaining peers to discover better partners and give new peers a start.
+
+use alloc::vec::Vec;
+
+use crate::rng::Rng;
+
+/// How often the unchoked peers are recalculated.
+pub const UNCHOKE_INTERVAL_MS: u64 = 10_000;
+/// The optimistic unchoke moves on every this many rounds.
+pub const OPTIMISTIC_UNCHOKE_ROUNDS: u32 = 3;
+
+/// Time constant of [`Rate`], rates follow changes within a few seconds.
+const RATE_WINDOW_MS: u64 = 5_000;
+
+/// Transfer speed in bytes per second, averaged over the last few seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    bytes_per_second: u32,
+    /// bytes since the last update
+    pending: u32,
+    last_update: u64,
+}
+
+impl Rate {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            bytes_per_second: 0,
+            pending: 0,
+            last_update: now_ms,
+        }
+    }
+
+    pub fn record(&mut self, bytes: u32, now_ms: u64) {
+        self.pending = self.pending.saturating_add(bytes);
+        self.update(now_ms);
+    }
+
+    pub fn get(&mut self, now_ms: u64) -> u32 {
+        self.update(now_ms);
+        self.bytes_per_second
+    }
+
+    /// Folds the pending bytes into an exponential moving average.
+    fn update(&mut self, now_ms: u64) {
+        let elapsed = now_ms.saturating_sub(self.last_update);
+        if elapsed < 1000 {
+            return;
+        }
+        let sample = self.pending as u64 * 1000 / elapsed;
+        let weight = elapsed.min(RATE_WINDOW_MS);
+        self.bytes_per_second = ((self.bytes_per_second as u64 * (RATE_WINDOW_MS - weight)
+            + sample * weight)
+            / RATE_WINDOW_MS) as u32;
+        self.pending = 0;
+        self.last_update = now_ms;
+    }
+}
+
+/// What the choker needs to know about a connected peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    /// chosen by the caller to identify the peer
+    pub id: usize,
+    pub interested: bool,
+    /// how fast the peer uploads to us, bytes per second
+    pub download_rate: u32,
+    /// how fast we upload to the peer, bytes per second
+    pub upload_rate: u32,
+}
+
+pub struct Choker {
+    regular_slots: usize,
+    optimistic: Option<usize>,
+    round: u32,
+}
+
+impl Choker {
+    /// `regular_slots` peers are unchoked by rank, plus one optimistic unchoke.
+    pub fn new(regular_slots: usize) -> Self {
+        Self {
+            regular_slots,
+            optimistic: None,
+            round: 0,
+        }
+    }
+
+    /// The peer that currently holds the optimistic unchoke.
+    pub fn optimistic(&self) -> Option<usize> {
+        self.optimistic
+    }
+
+    /// Recalculates the unchoked peers, should be called every [`UNCHOKE_INTERVAL_MS`].
+    /// Returns the ids of the peers to unchoke, all others should be choked.
+    pub fn rechoke<R: Rng>(
+        &mut self,
+        peers: &[Candidate],
+        seeding: bool,
+        rng: &mut R,
+    ) -> Vec<usize> {
+        let mut ranked: Vec<&Candidate> = peers.iter().filter(|peer| peer.interested).collect();
+        if seeding {
+            ranked.sort_unstable_by_key(|peer| core::cmp::Reverse(peer.upload_rate));
+        } else {
+            ranked.sort_unstable_by_key(|peer| core::cmp::Reverse(peer.download_rate));
+        }
+        let mut unchoked: Vec<usize> = ranked
+            .iter()
+            .take(self.regular_slots)
+            .map(|peer| peer.id)
+            .collect();
+
+        let others: Vec<usize> = ranked
+            .iter()
+            .skip(self.regular_slots)
+            .map(|peer| peer.id)
+            .collect();
+        let keep = self.optimistic.filter(|id| {
+            others.contains(id) && !self.round.is_multiple_of(OPTIMISTIC_UNCHOKE_ROUNDS)
+        });
+        self.optimistic = match keep {
+            Some(id) => Some(id),
+            None if others.is_empty() => None,
+            None => Some(others[rng.next_below(others.len() as u32) as usize]),
+        };
+        self.round = self.round.wrapping_add(1);
+
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift32;
+
+    fn candidate(id: usize, download_rate: u32, upload_rate: u32) -> Candidate {
+        Candidate {
+            id,
+            interested: true,
+            download_rate,
+            upload_rate,
+        }
+    }
+
+    #[test]
+    fn test_rate() {
+        let mut rate = Rate::new(0);
+        rate.record(10_000, 500);
+        assert_eq!(rate.get(500), 0);
+        rate.record(0, 1000);
+        assert_eq!(rate.get(1000), 2000);
+
+        for second in 2..=20 {
+            rate.record(10_000, second * 1000);
+        }
+        assert!(rate.get(20_000) > 9_500);
+    }
+
+    #[test]
+    fn test_tit_for_tat() {
+        let mut choker = Choker::new(2);
+        let mut rng = XorShift32::new(1);
+        let mut peers = [
+            candidate(0, 100, 0),
+            candidate(1, 300, 0),
+            candidate(2, 200, 0),
+            candidate(3, 500, 0),
+        ];
+        peers[3].interested = false;
+
+        let unchoked = choker.rechoke(&peers, false, &mut rng);
+        assert_eq!(unchoked[..2], [1, 2]);
+        // the only remaining interested peer gets the optimistic unchoke
+        assert_eq!(unchoked[2..], [0]);
+        assert_eq!(choker.optimistic(), Some(0));
+    }
+
+    #[test]
+    fn test_seeding_ranks_by_upload_rate() {
+        let mut choker = Choker::new(1);
+        let mut rng = XorShift32::new(1);
+        let peers = [candidate(0, 500, 10), candidate(1, 0, 20)];
+
+        assert_eq!(choker.rechoke(&peers, true, &mut rng)[0], 1);
+        assert_eq!(choker.rechoke(&peers, false, &mut rng)[0], 0);
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_rotates() {
+        let mut choker = Choker::new(0);
+        let mut rng = XorShift32::new(3);
+        let peers: Vec<Candidate> = (0..8).map(|id| candidate(id, 0, 0)).collect();
+
+        let mut optimistic = Vec::new();
+        for _ in 0..30 {
+            let unchoked = choker.rechoke(&peers, false, &mut rng);
+            assert_eq!(unchoked.len(), 1);
+            optimistic.push(unchoked[0]);
+        }
+        // kept for a few rounds at a time
+        for rounds in optimistic.chunks(OPTIMISTIC_UNCHOKE_ROUNDS as usize) {
+            assert!(rounds.iter().all(|id| *id == rounds[0]));
+        }
+        // but not forever
+        assert!(optimistic.iter().any(|id| *id != optimistic[0]));
+    }
+}
@@ -0,0 +1,1180 @@
+//! The mainline DHT (BEP 5), to find peers without a tracker, e.g. for magnet links.
+//!
+//! [`Dht`] is a state machine that turns incoming datagrams and timer ticks into
+//! datagrams to send. Lookups walk towards their target by repeatedly asking the
+//! closest nodes known so far, at most [`ALPHA`] at a time.
+//!
+//! On small heaps the node can run client-only with [`DhtConfig::CLIENT_ONLY`]:
+//! it looks up peers but neither answers queries nor stores announces.
+
+use alloc::vec::Vec;
+use core::{net::SocketAddr, time::Duration};
+use defmt::Format;
+
+use crate::{
+    core::{
+        InfoHash,
+        dht::{
+            krpc::{
+                Body, ERROR_METHOD_UNKNOWN, ERROR_PROTOCOL, KrpcMessage, NodeInfo, Query, Response,
+            },
+            peer_store::PeerStore,
+            routing::{Insert, K, MAX_BUCKETS, RoutingTable, distance},
+            token::TokenSecret,
+        },
+        metainfo::Info,
+        net::{Datagram, send_all},
+    },
+    fs::{FileSystem, FsError, VolumeMgr},
+    rng::Rng,
+    wifi::{UdpError, UdpSocket, WifiStack},
+};
+
+pub mod krpc;
+pub mod peer_store;
+pub mod routing;
+pub mod token;
+
+pub type NodeId = [u8; 20];
+
+/// Queries a lookup has in flight at most.
+pub const ALPHA: usize = 3;
+
+/// How long to wait for a node to answer.
+pub const QUERY_TIMEOUT_MS: u64 = 5_000;
+
+/// Nodes a lookup remembers, the closest ones are kept.
+pub const MAX_LOOKUP_NODES: usize = 3 * K;
+
+/// Queries in flight at most, over all lookups.
+pub const MAX_PENDING: usize = 24;
+
+/// Peers we return for a `get_peers` query at most, so the response fits into a datagram.
+pub const MAX_VALUES: usize = 50;
+
+/// The largest datagram we expect.
+pub const MAX_DATAGRAM_LEN: usize = 1500;
+
+/// How long [`Dht::poll`] waits for a datagram before it checks the timers.
+pub const POLL_INTERVAL_MS: u64 = 1_000;
+
+/// Well known nodes to join the DHT through.
+pub const DEFAULT_BOOTSTRAP_NODES: &[(&str, u16)] = &[
+    ("router.bittorrent.com", 6881),
+    ("dht.transmissionbt.com", 6881),
+    ("router.utorrent.com", 6881),
+];
+
+/// Where the routing table is kept between restarts.
+pub const ROUTING_TABLE_FILE: &str = "DHT.DAT";
+
+/// How a node takes part in the DHT and how much memory it may use.
+///
+/// Pick one of the constants or build your own one at compile time; every limit
+/// bounds a buffer that grows with the size of the DHT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct DhtConfig {
+    /// Only send queries and never answer them, flagged with `ro` (BEP 43) so that
+    /// other nodes keep us out of their routing tables.
+    pub read_only: bool,
+    /// Buckets of the routing table at most, each holds [`K`] nodes and as many replacements.
+    pub max_buckets: usize,
+    /// Announces of other peers we store for `get_peers`, unused when read only.
+    pub max_stored_peers: usize,
+    /// Lookups that run at the same time at most.
+    pub max_lookups: usize,
+}
+
+impl DhtConfig {
+    /// A regular node that answers queries and stores announces.
+    pub const FULL: Self = Self {
+        read_only: false,
+        max_buckets: MAX_BUCKETS,
+        max_stored_peers: 64,
+        max_lookups: 4,
+    };
+
+    /// A client-only node for devices with little heap, like the ESP32-C3. The routing
+    /// table holds at most 64 nodes, enough to start lookups close to any target.
+    pub const CLIENT_ONLY: Self = Self {
+        read_only: true,
+        max_buckets: 4,
+        max_stored_peers: 0,
+        max_lookups: 1,
+    };
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self::FULL
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum DhtError {
+    /// A datagram isn't a valid KRPC message.
+    InvalidMessage,
+    /// A response doesn't match any query we sent.
+    UnexpectedResponse,
+    Udp(UdpError),
+}
+
+impl From<bencode::Error> for DhtError {
+    fn from(_: bencode::Error) -> Self {
+        DhtError::InvalidMessage
+    }
+}
+
+impl From<UdpError> for DhtError {
+    fn from(err: UdpError) -> Self {
+        DhtError::Udp(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Format)]
+pub enum DhtEvent {
+    /// A node returned peers of a torrent we look up.
+    Peers {
+        info_hash: InfoHash,
+        peers: Vec<SocketAddr>,
+    },
+    /// A lookup finished. `responded` is the number of nodes that answered it.
+    LookupDone { target: NodeId, responded: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingKind {
+    Ping,
+    Lookup(u16),
+    Announce,
+}
+
+/// A query waiting for its response.
+struct Pending {
+    transaction: u16,
+    addr: SocketAddr,
+    /// the id of the queried node, unknown for bootstrap nodes
+    id: Option<NodeId>,
+    kind: PendingKind,
+    sent_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LookupKind {
+    FindNode,
+    /// announces us with the port once the closest nodes are found
+    GetPeers {
+        announce: Option<u16>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeState {
+    Fresh,
+    Queried,
+    Responded,
+    Failed,
+}
+
+struct LookupNode {
+    info: NodeInfo,
+    state: NodeState,
+    /// the token for `announce_peer`, from the `get_peers` response
+    token: Option<Vec<u8>>,
+}
+
+/// An iterative lookup of the nodes closest to `target`.
+struct Lookup {
+    id: u16,
+    target: NodeId,
+    kind: LookupKind,
+    /// sorted by distance to the target
+    nodes: Vec<LookupNode>,
+}
+
+impl Lookup {
+    fn add(&mut self, info: NodeInfo, own_id: &NodeId) {
+        if info.id == *own_id || self.nodes.iter().any(|node| node.info.id == info.id) {
+            return;
+        }
+        let node_distance = distance(&info.id, &self.target);
+        let position = self
+            .nodes
+            .partition_point(|node| distance(&node.info.id, &self.target) < node_distance);
+        if position >= MAX_LOOKUP_NODES {
+            return;
+        }
+        self.nodes.insert(
+            position,
+            LookupNode {
+                info,
+                state: NodeState::Fresh,
+                token: None,
+            },
+        );
+        self.nodes.truncate(MAX_LOOKUP_NODES);
+    }
+
+    /// The [`K`] closest nodes that haven't failed, the ones that decide when we're done.
+    fn closest(&mut self) -> impl Iterator<Item = &mut LookupNode> {
+        self.nodes
+            .iter_mut()
+            .filter(|node| node.state != NodeState::Failed)
+            .take(K)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.state == NodeState::Queried)
+            .count()
+    }
+
+    fn node(&mut self, id: &NodeId) -> Option<&mut LookupNode> {
+        self.nodes.iter_mut().find(|node| node.info.id == *id)
+    }
+}
+
+pub struct Dht {
+    config: DhtConfig,
+    table: RoutingTable,
+    tokens: TokenSecret,
+    peers: PeerStore,
+    pending: Vec<Pending>,
+    lookups: Vec<Lookup>,
+    next_transaction: u16,
+    next_lookup: u16,
+    events: Vec<DhtEvent>,
+}
+
+impl Dht {
+    /// A node with the given routing table, either a new one with a random id or one
+    /// restored with [`load_routing_table`]. A table with more buckets than
+    /// `config` allows is shrunk to fit.
+    pub fn new<R: Rng>(
+        config: DhtConfig,
+        mut table: RoutingTable,
+        rng: &mut R,
+        now_ms: u64,
+    ) -> Self {
+        table.limit_buckets(config.max_buckets, now_ms);
+        let max_stored_peers = if config.read_only {
+            0
+        } else {
+            config.max_stored_peers
+        };
+        Self {
+            config,
+            table,
+            tokens: TokenSecret::new(rng, now_ms),
+            peers: PeerStore::new(max_stored_peers),
+            pending: Vec::new(),
+            lookups: Vec::new(),
+            next_transaction: rng.next_u32() as u16,
+            next_lookup: 0,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &DhtConfig {
+        &self.config
+    }
+
+    pub fn table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    /// Takes the events since the last call.
+    pub fn take_events(&mut self) -> Vec<DhtEvent> {
+        core::mem::take(&mut self.events)
+    }
+
+    /// Pings a node whose id we don't know yet, e.g. one a peer told us about with `Port`.
+    pub fn ping(&mut self, addr: SocketAddr, now_ms: u64) -> Option<Datagram> {
+        self.query(addr, None, Query::Ping, PendingKind::Ping, now_ms)
+    }
+
+    /// Starts looking for the nodes closest to `target`.
+    pub fn find_node(&mut self, target: NodeId, now_ms: u64) -> Vec<Datagram> {
+        self.start_lookup(target, LookupKind::FindNode, now_ms)
+    }
+
+    /// Starts looking for peers of a torrent, reported as [`DhtEvent::Peers`].
+    /// With `announce` set, we announce ourselves with that port to the closest nodes.
+    /// Private torrents are never looked up, nothing is sent for them.
+    pub fn get_peers(
+        &mut self,
+        info_hash: InfoHash,
+        info: &Info,
+        announce: Option<u16>,
+        now_ms: u64,
+    ) -> Vec<Datagram> {
+        if info.private {
+            return Vec::new();
+        }
+        self.start_lookup(info_hash, LookupKind::GetPeers { announce }, now_ms)
+    }
+
+    /// The payload of a `find_node` query for our own id, to send to a bootstrap node.
+    pub fn bootstrap_query(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        KrpcMessage {
+            transaction: b"bs",
+            read_only: self.config.read_only,
+            body: Body::Query {
+                id: *self.table.own_id(),
+                query: Query::FindNode {
+                    target: *self.table.own_id(),
+                },
+            },
+        }
+        .encode(&mut payload);
+        payload
+    }
+
+    /// Adds the nodes a bootstrap node answered [`Dht::bootstrap_query`] with.
+    /// Bootstrap nodes themselves don't go into the routing table.
+    pub fn on_bootstrap_response(
+        &mut self,
+        datagram: &[u8],
+        now_ms: u64,
+    ) -> Result<usize, DhtError> {
+        let message = KrpcMessage::parse(datagram)?;
+        let Body::Response(response) = message.body else {
+            return Err(DhtError::UnexpectedResponse);
+        };
+        for node in &response.nodes {
+            self.table.add_unverified(*node, now_ms);
+        }
+        Ok(response.nodes.len())
+    }
+
+    /// Handles a datagram that arrived on the DHT socket.
+    pub fn on_datagram(
+        &mut self,
+        from: SocketAddr,
+        datagram: &[u8],
+        now_ms: u64,
+    ) -> Result<Vec<Datagram>, DhtError> {
+        let message = KrpcMessage::parse(datagram)?;
+        match message.body {
+            // read-only nodes don't answer, they don't even tell the sender about it
+            Body::Query { .. } if self.config.read_only => Ok(Vec::new()),
+            Body::Query { id, query } => Ok(self.on_query(
+                from,
+                message.transaction,
+                id,
+                query,
+                message.read_only,
+                now_ms,
+            )),
+            Body::Response(response) => {
+                self.on_response(from, message.transaction, response, now_ms)
+            }
+            Body::Error {
+                code,
+                message: text,
+            } => {
+                defmt::debug!("DHT node {} answered with error {}: {}", from, code, text);
+                let pending = self.take_pending(from, message.transaction)?;
+                // the node is alive, but the lookup can't use it
+                Ok(self.on_failed(pending, now_ms))
+            }
+        }
+    }
+
+    /// Expires unanswered queries, refreshes stale buckets and pings questionable nodes.
+    pub fn poll_timers<R: Rng>(&mut self, rng: &mut R, now_ms: u64) -> Vec<Datagram> {
+        let mut out = Vec::new();
+        while let Some(position) = self
+            .pending
+            .iter()
+            .position(|p| now_ms.saturating_sub(p.sent_ms) >= QUERY_TIMEOUT_MS)
+        {
+            let pending = self.pending.swap_remove(position);
+            if let Some(id) = pending.id {
+                self.table.failed(&id);
+            }
+            out.extend(self.on_failed(pending, now_ms));
+        }
+
+        // lookups that couldn't send because too many queries were in flight
+        for index in (0..self.lookups.len()).rev() {
+            if self.lookups[index].in_flight() == 0 {
+                out.extend(self.step_lookup(index, now_ms));
+            }
+        }
+
+        self.tokens.rotate(rng, now_ms);
+        self.peers.expire(now_ms);
+
+        if self.lookups.is_empty()
+            && !self.table.is_empty()
+            && let Some(target) = self.table.refresh_target(rng, now_ms)
+        {
+            out.extend(self.find_node(target, now_ms));
+        }
+        if let Some(node) = self.table.questionable(now_ms)
+            && !self.pending.iter().any(|p| p.id == Some(node.id))
+        {
+            out.extend(self.query(
+                node.addr,
+                Some(node.id),
+                Query::Ping,
+                PendingKind::Ping,
+                now_ms,
+            ));
+        }
+        out
+    }
+
+    /// Waits up to [`POLL_INTERVAL_MS`] for a datagram, handles it and the timers and
+    /// sends what they produce. Call it in a loop, starting lookups in between.
+    pub async fn poll<S: UdpSocket, R: Rng>(
+        &mut self,
+        socket: &mut S,
+        rng: &mut R,
+        buf: &mut [u8],
+        now_ms: impl Fn() -> u64,
+    ) -> Result<(), UdpError> {
+        socket.set_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)));
+        let out = match socket.recv_from(buf).await {
+            Ok((len, from)) => self
+                .on_datagram(from, &buf[..len], now_ms())
+                .unwrap_or_else(|err| {
+                    defmt::debug!("dropped DHT datagram from {}: {}", from, err);
+                    Vec::new()
+                }),
+            Err(UdpError::TimedOut | UdpError::Truncated) => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        send_all(socket, out).await?;
+        let out = self.poll_timers(rng, now_ms());
+        send_all(socket, out).await
+    }
+
+    /// Asks the bootstrap nodes for nodes close to us and starts looking up our own id.
+    /// Returns the queries of that lookup, nothing if no bootstrap node answered.
+    pub async fn bootstrap<W: WifiStack>(
+        &mut self,
+        wifi: &W,
+        nodes: &[(&str, u16)],
+        buf: &mut [u8],
+        now_ms: u64,
+    ) -> Vec<Datagram> {
+        let query = self.bootstrap_query();
+        for (host, port) in nodes {
+            match wifi.make_udp_request(host, *port, &query, buf).await {
+                Ok(response) => match self.on_bootstrap_response(response, now_ms) {
+                    Ok(count) => defmt::info!("bootstrap node {} knows {} nodes", host, count),
+                    Err(err) => defmt::warn!("invalid answer of bootstrap node {}: {}", host, err),
+                },
+                Err(err) => defmt::warn!("bootstrap node {} unreachable: {}", host, err),
+            }
+        }
+        if self.table.is_empty() {
+            return Vec::new();
+        }
+        self.find_node(*self.table.own_id(), now_ms)
+    }
+
+    fn on_query(
+        &mut self,
+        from: SocketAddr,
+        transaction: &[u8],
+        id: NodeId,
+        query: Query,
+        read_only: bool,
+        now_ms: u64,
+    ) -> Vec<Datagram> {
+        let mut out = Vec::new();
+        // read-only nodes won't answer our queries, so they don't belong in the table
+        if !read_only
+            && let Insert::Ping(node) = self.table.heard_from(NodeInfo { id, addr: from }, now_ms)
+            && !self.pending.iter().any(|p| p.id == Some(node.id))
+        {
+            out.extend(self.query(
+                node.addr,
+                Some(node.id),
+                Query::Ping,
+                PendingKind::Ping,
+                now_ms,
+            ));
+        }
+
+        let own_id = *self.table.own_id();
+        let token;
+        let body = match query {
+            Query::Ping => Body::Response(Response {
+                id: own_id,
+                ..Response::default()
+            }),
+            Query::FindNode { target } => Body::Response(Response {
+                id: own_id,
+                nodes: self.table.closest(&target, K),
+                ..Response::default()
+            }),
+            Query::GetPeers { info_hash } => {
+                token = self.tokens.token(from.ip());
+                let values = self.peers.peers(&info_hash, MAX_VALUES);
+                Body::Response(Response {
+                    id: own_id,
+                    nodes: if values.is_empty() {
+                        self.table.closest(&info_hash, K)
+                    } else {
+                        Vec::new()
+                    },
+                    values,
+                    token: Some(&token),
+                })
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if self.tokens.is_valid(token, from.ip()) {
+                    let port = if implied_port { from.port() } else { port };
+                    self.peers
+                        .announce(info_hash, SocketAddr::new(from.ip(), port), now_ms);
+                    Body::Response(Response {
+                        id: own_id,
+                        ..Response::default()
+                    })
+                } else {
+                    Body::Error {
+                        code: ERROR_PROTOCOL,
+                        message: "invalid token",
+                    }
+                }
+            }
+            Query::Unknown { method } => {
+                defmt::debug!("unknown DHT method {=[u8]} from {}", method, from);
+                Body::Error {
+                    code: ERROR_METHOD_UNKNOWN,
+                    message: "Method Unknown",
+                }
+            }
+        };
+        out.push(encode(from, transaction, body, false));
+        out
+    }
+
+    fn on_response(
+        &mut self,
+        from: SocketAddr,
+        transaction: &[u8],
+        response: Response,
+        now_ms: u64,
+    ) -> Result<Vec<Datagram>, DhtError> {
+        let pending = self.take_pending(from, transaction)?;
+        if pending.id.is_some_and(|id| id != response.id) {
+            // someone else answers at that address now
+            self.table.remove(&pending.id.unwrap());
+            return Ok(self.on_failed(pending, now_ms));
+        }
+
+        let mut out = Vec::new();
+        let node = NodeInfo {
+            id: response.id,
+            addr: from,
+        };
+        if let Insert::Ping(stale) = self.table.heard_from(node, now_ms)
+            && !self.pending.iter().any(|p| p.id == Some(stale.id))
+        {
+            out.extend(self.query(
+                stale.addr,
+                Some(stale.id),
+                Query::Ping,
+                PendingKind::Ping,
+                now_ms,
+            ));
+        }
+
+        let PendingKind::Lookup(lookup_id) = pending.kind else {
+            return Ok(out);
+        };
+        let Some(index) = self.lookups.iter().position(|l| l.id == lookup_id) else {
+            return Ok(out);
+        };
+        let own_id = *self.table.own_id();
+        let lookup = &mut self.lookups[index];
+        if let Some(node) = lookup.node(&response.id) {
+            node.state = NodeState::Responded;
+            node.token = response.token.map(|token| token.to_vec());
+        }
+        for node in response.nodes {
+            lookup.add(node, &own_id);
+        }
+        if let LookupKind::GetPeers { .. } = lookup.kind
+            && !response.values.is_empty()
+        {
+            self.events.push(DhtEvent::Peers {
+                info_hash: lookup.target,
+                peers: response.values,
+            });
+        }
+        out.extend(self.step_lookup(index, now_ms));
+        Ok(out)
+    }
+
+    /// Handles a query that timed out or was answered with an error.
+    fn on_failed(&mut self, pending: Pending, now_ms: u64) -> Vec<Datagram> {
+        let PendingKind::Lookup(lookup_id) = pending.kind else {
+            return Vec::new();
+        };
+        let Some(index) = self.lookups.iter().position(|l| l.id == lookup_id) else {
+            return Vec::new();
+        };
+        if let Some(id) = pending.id
+            && let Some(node) = self.lookups[index].node(&id)
+        {
+            node.state = NodeState::Failed;
+        }
+        self.step_lookup(index, now_ms)
+    }
+
+    fn take_pending(&mut self, from: SocketAddr, transaction: &[u8]) -> Result<Pending, DhtError> {
+        let transaction = u16::from_be_bytes(
+            transaction
+                .try_into()
+                .map_err(|_| DhtError::UnexpectedResponse)?,
+        );
+        let position = self
+            .pending
+            .iter()
+            .position(|p| p.transaction == transaction && p.addr == from)
+            .ok_or(DhtError::UnexpectedResponse)?;
+        Ok(self.pending.swap_remove(position))
+    }
+
+    fn start_lookup(&mut self, target: NodeId, kind: LookupKind, now_ms: u64) -> Vec<Datagram> {
+        if self
+            .lookups
+            .iter()
+            .any(|l| l.target == target && l.kind == kind)
+        {
+            return Vec::new();
+        }
+        if self.lookups.len() >= self.config.max_lookups {
+            defmt::warn!("too many DHT lookups, dropping one");
+            return Vec::new();
+        }
+        let mut lookup = Lookup {
+            id: self.next_lookup,
+            target,
+            kind,
+            nodes: Vec::new(),
+        };
+        self.next_lookup = self.next_lookup.wrapping_add(1);
+        for node in self.table.closest(&target, MAX_LOOKUP_NODES) {
+            lookup.add(node, self.table.own_id());
+        }
+        self.lookups.push(lookup);
+        self.step_lookup(self.lookups.len() - 1, now_ms)
+    }
+
+    /// Queries the closest fresh nodes of a lookup, or finishes it once the
+    /// closest nodes all answered or failed.
+    fn step_lookup(&mut self, index: usize, now_ms: u64) -> Vec<Datagram> {
+        let mut lookup = self.lookups.swap_remove(index);
+        let mut out = Vec::new();
+
+        let query = match lookup.kind {
+            LookupKind::FindNode => Query::FindNode {
+                target: lookup.target,
+            },
+            LookupKind::GetPeers { .. } => Query::GetPeers {
+                info_hash: lookup.target,
+            },
+        };
+        let mut in_flight = lookup.in_flight();
+        let fresh: Vec<NodeInfo> = lookup
+            .closest()
+            .filter(|node| node.state == NodeState::Fresh)
+            .map(|node| node.info)
+            .take(ALPHA.saturating_sub(in_flight))
+            .collect();
+        for node in fresh {
+            if let Some(datagram) = self.query(
+                node.addr,
+                Some(node.id),
+                query,
+                PendingKind::Lookup(lookup.id),
+                now_ms,
+            ) {
+                out.push(datagram);
+                lookup.node(&node.id).unwrap().state = NodeState::Queried;
+                in_flight += 1;
+            }
+        }
+
+        let done = in_flight == 0
+            && lookup
+                .closest()
+                .all(|node| node.state == NodeState::Responded);
+        if !done {
+            self.lookups.push(lookup);
+            return out;
+        }
+
+        let responded: Vec<(NodeInfo, Option<Vec<u8>>)> = lookup
+            .closest()
+            .map(|node| (node.info, node.token.take()))
+            .collect();
+        if let LookupKind::GetPeers {
+            announce: Some(port),
+        } = lookup.kind
+        {
+            for (node, token) in &responded {
+                let Some(token) = token else { continue };
+                let announce = Query::AnnouncePeer {
+                    info_hash: lookup.target,
+                    port,
+                    implied_port: false,
+                    token,
+                };
+                out.extend(self.query(
+                    node.addr,
+                    Some(node.id),
+                    announce,
+                    PendingKind::Announce,
+                    now_ms,
+                ));
+            }
+        }
+        self.events.push(DhtEvent::LookupDone {
+            target: lookup.target,
+            responded: responded.len(),
+        });
+        out
+    }
+
+    /// Builds a query and remembers it, `None` if too many queries are in flight.
+    fn query(
+        &mut self,
+        addr: SocketAddr,
+        id: Option<NodeId>,
+        query: Query,
+        kind: PendingKind,
+        now_ms: u64,
+    ) -> Option<Datagram> {
+        if self.pending.len() >= MAX_PENDING {
+            return None;
+        }
+        let transaction = self.next_transaction;
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        self.pending.push(Pending {
+            transaction,
+            addr,
+            id,
+            kind,
+            sent_ms: now_ms,
+        });
+        let body = Body::Query {
+            id: *self.table.own_id(),
+            query,
+        };
+        Some(encode(
+            addr,
+            &transaction.to_be_bytes(),
+            body,
+            self.config.read_only,
+        ))
+    }
+}
+
+fn encode(addr: SocketAddr, transaction: &[u8], body: Body, read_only: bool) -> Datagram {
+    let mut payload = Vec::new();
+    KrpcMessage {
+        transaction,
+        body,
+        read_only,
+    }
+    .encode(&mut payload);
+    Datagram { addr, payload }
+}
+
+/// A random node id, for a node without a stored routing table.
+pub fn random_id<R: Rng>(rng: &mut R) -> NodeId {
+    let mut id = [0u8; 20];
+    rng.fill_bytes(&mut id);
+    id
+}
+
+/// Writes the routing table to [`ROUTING_TABLE_FILE`] in the current directory.
+pub fn save_routing_table<V: VolumeMgr>(
+    fs: &mut FileSystem<V>,
+    table: &RoutingTable,
+) -> Result<(), FsError<V>> {
+    let mut bytes = Vec::new();
+    table.encode(&mut bytes);
+    fs.write_file(ROUTING_TABLE_FILE, &bytes)
+}
+
+/// Restores the routing table saved with [`save_routing_table`], limited to the
+/// buckets of `config`. `None` if there is none or it is corrupt.
+pub fn load_routing_table<V: VolumeMgr>(
+    fs: &mut FileSystem<V>,
+    config: &DhtConfig,
+    now_ms: u64,
+) -> Option<RoutingTable> {
+    let bytes = fs.read_file(ROUTING_TABLE_FILE).ok()?;
+    RoutingTable::decode(&bytes, config.max_buckets, now_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift32;
+
+    const ME: SocketAddr = SocketAddr::new(
+        core::net::IpAddr::V4(core::net::Ipv4Addr::new(10, 0, 0, 1)),
+        6881,
+    );
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::new(core::net::Ipv4Addr::new(10, 0, 1, n).into(), 6881)
+    }
+
+    fn node(n: u8) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[0] = n;
+        NodeInfo { id, addr: addr(n) }
+    }
+
+    fn dht(own_id: NodeId) -> Dht {
+        Dht::new(
+            DhtConfig::FULL,
+            RoutingTable::new(own_id),
+            &mut XorShift32::new(1),
+            0,
+        )
+    }
+
+    fn info(private: bool) -> Info<'static> {
+        Info {
+            piece_length: 16384,
+            name: "test",
+            pieces: &[],
+            length: 0,
+            private,
+        }
+    }
+
+    fn parse(datagram: &Datagram) -> KrpcMessage<'_> {
+        KrpcMessage::parse(&datagram.payload).unwrap()
+    }
+
+    /// Answers a query of `datagram` as `node`.
+    fn respond(datagram: &Datagram, node: NodeInfo, response: Response) -> Vec<u8> {
+        assert_eq!(datagram.addr, node.addr);
+        let query = parse(datagram);
+        let mut payload = Vec::new();
+        KrpcMessage {
+            transaction: query.transaction,
+            read_only: false,
+            body: Body::Response(Response {
+                id: node.id,
+                ..response
+            }),
+        }
+        .encode(&mut payload);
+        payload
+    }
+
+    #[test]
+    fn test_table_is_shrunk_to_the_config() {
+        let mut table = RoutingTable::new([0xff; 20]);
+        for shared_bits in 0..8 {
+            for n in 1..=K as u8 {
+                let mut id = [0xff; 20];
+                id[0] ^= 0x80 >> shared_bits;
+                id[19] = n;
+                table.heard_from(NodeInfo { id, addr: addr(n) }, 1);
+            }
+        }
+        assert_eq!(table.len(), 8 * K);
+
+        let client = Dht::new(DhtConfig::CLIENT_ONLY, table, &mut XorShift32::new(1), 2);
+        assert_eq!(client.table().len(), DhtConfig::CLIENT_ONLY.max_buckets * K);
+    }
+
+    #[test]
+    fn test_answers_queries() {
+        let mut dht = dht([0xff; 20]);
+        let query = |query| {
+            let mut payload = Vec::new();
+            KrpcMessage {
+                transaction: b"xy",
+                read_only: false,
+                body: Body::Query { id: [1; 20], query },
+            }
+            .encode(&mut payload);
+            payload
+        };
+
+        let out = dht.on_datagram(ME, &query(Query::Ping), 1).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].addr, ME);
+        let pong = parse(&out[0]);
+        assert_eq!(pong.transaction, b"xy");
+        assert_eq!(
+            pong.body,
+            Body::Response(Response {
+                id: [0xff; 20],
+                ..Response::default()
+            })
+        );
+        // the querying node is in our table now
+        assert_eq!(dht.table().len(), 1);
+
+        let out = dht
+            .on_datagram(ME, &query(Query::GetPeers { info_hash: [7; 20] }), 2)
+            .unwrap();
+        let Body::Response(response) = parse(&out[0]).body else {
+            panic!("no response");
+        };
+        let token = response.token.unwrap().to_vec();
+        assert!(response.values.is_empty());
+
+        let announce = |token| {
+            query(Query::AnnouncePeer {
+                info_hash: [7; 20],
+                port: 51413,
+                implied_port: false,
+                token,
+            })
+        };
+        let out = dht.on_datagram(ME, &announce(b"bad"), 3).unwrap();
+        assert!(matches!(
+            parse(&out[0]).body,
+            Body::Error {
+                code: ERROR_PROTOCOL,
+                ..
+            }
+        ));
+        dht.on_datagram(ME, &announce(&token), 3).unwrap();
+
+        let out = dht
+            .on_datagram(addr(9), &query(Query::GetPeers { info_hash: [7; 20] }), 4)
+            .unwrap();
+        let Body::Response(response) = parse(&out[0]).body else {
+            panic!("no response");
+        };
+        assert_eq!(response.values, [SocketAddr::new(ME.ip(), 51413)]);
+
+        let out = dht
+            .on_datagram(ME, &query(Query::Unknown { method: b"vote" }), 5)
+            .unwrap();
+        let error = parse(&out[0]);
+        assert_eq!(error.transaction, b"xy");
+        assert!(matches!(
+            error.body,
+            Body::Error {
+                code: ERROR_METHOD_UNKNOWN,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_read_only_nodes() {
+        let ping = |read_only| {
+            let mut payload = Vec::new();
+            KrpcMessage {
+                transaction: b"xy",
+                read_only,
+                body: Body::Query {
+                    id: [1; 20],
+                    query: Query::Ping,
+                },
+            }
+            .encode(&mut payload);
+            payload
+        };
+
+        // a full node answers read-only nodes, but keeps them out of its table
+        let mut full = dht([0xff; 20]);
+        assert_eq!(full.on_datagram(ME, &ping(true), 1).unwrap().len(), 1);
+        assert!(full.table().is_empty());
+
+        // a read-only node doesn't answer at all and flags its own queries
+        let mut client = Dht::new(
+            DhtConfig::CLIENT_ONLY,
+            RoutingTable::new([0xff; 20]),
+            &mut XorShift32::new(1),
+            0,
+        );
+        assert!(client.on_datagram(ME, &ping(false), 1).unwrap().is_empty());
+        assert!(client.table().is_empty());
+
+        client.table.heard_from(node(1), 1);
+        let queries = client.get_peers([0; 20], &info(false), Some(6881), 2);
+        assert!(parse(&queries[0]).read_only);
+        assert!(client.get_peers([1; 20], &info(false), None, 2).is_empty());
+    }
+
+    #[test]
+    fn test_get_peers_lookup_and_announce() {
+        let mut dht = dht([0xff; 20]);
+        let mut rng = XorShift32::new(3);
+        for n in 1..=4 {
+            dht.table.heard_from(node(n), 0);
+        }
+        let info_hash = [0; 20];
+
+        let queries = dht.get_peers(info_hash, &info(false), Some(6881), 1);
+        assert_eq!(queries.len(), ALPHA);
+        // the closest nodes are asked first
+        assert_eq!(queries[0].addr, node(1).addr);
+
+        // node 1 knows an even closer node and a peer
+        let mut id = [0; 20];
+        id[19] = 1;
+        let closer = NodeInfo {
+            id,
+            addr: addr(100),
+        };
+        let peer: SocketAddr = "10.9.9.9:51413".parse().unwrap();
+        let answer = respond(
+            &queries[0],
+            node(1),
+            Response {
+                nodes: vec![closer],
+                values: vec![peer],
+                token: Some(b"t1"),
+                ..Response::default()
+            },
+        );
+        let out = dht.on_datagram(node(1).addr, &answer, 2).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].addr, closer.addr);
+        assert_eq!(
+            dht.take_events(),
+            [DhtEvent::Peers {
+                info_hash,
+                peers: vec![peer]
+            }]
+        );
+
+        // node 2 never answers, the others do and node 4 is asked in the meantime
+        let mut sent = Vec::new();
+        for (datagram, responder) in [(&queries[2], node(3)), (&out[0], closer)] {
+            let answer = respond(
+                datagram,
+                responder,
+                Response {
+                    token: Some(b"t"),
+                    ..Response::default()
+                },
+            );
+            sent.extend(dht.on_datagram(responder.addr, &answer, 3).unwrap());
+        }
+        let fourth = sent.iter().find(|d| d.addr == node(4).addr).unwrap();
+        let answer = respond(
+            fourth,
+            node(4),
+            Response {
+                token: Some(b"t"),
+                ..Response::default()
+            },
+        );
+        assert!(
+            dht.on_datagram(node(4).addr, &answer, 4)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(dht.take_events().is_empty());
+
+        let out = dht.poll_timers(&mut rng, 1 + QUERY_TIMEOUT_MS);
+        // all closest nodes answered, so we announce to them
+        let announced: Vec<SocketAddr> = out
+            .iter()
+            .filter(|d| {
+                matches!(
+                    parse(d).body,
+                    Body::Query {
+                        query: Query::AnnouncePeer { port: 6881, .. },
+                        ..
+                    }
+                )
+            })
+            .map(|d| d.addr)
+            .collect();
+        assert_eq!(
+            announced,
+            [closer.addr, node(1).addr, node(3).addr, node(4).addr]
+        );
+        assert_eq!(
+            dht.take_events(),
+            [DhtEvent::LookupDone {
+                target: info_hash,
+                responded: 4
+            }]
+        );
+    }
+
+    #[test]
+    fn test_private_torrents_are_not_looked_up() {
+        let mut dht = dht([0xff; 20]);
+        dht.table.heard_from(node(1), 0);
+        assert!(
+            dht.get_peers([0; 20], &info(true), Some(6881), 1)
+                .is_empty()
+        );
+        assert!(dht.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_unexpected_responses_are_rejected() {
+        let mut dht = dht([0xff; 20]);
+        dht.table.heard_from(node(1), 0);
+        let queries = dht.find_node([0; 20], 1);
+        let answer = respond(&queries[0], node(1), Response::default());
+
+        assert_eq!(
+            dht.on_datagram(addr(2), &answer, 2),
+            Err(DhtError::UnexpectedResponse)
+        );
+        assert!(dht.on_datagram(node(1).addr, &answer, 2).is_ok());
+        assert_eq!(
+            dht.on_datagram(node(1).addr, &answer, 2),
+            Err(DhtError::UnexpectedResponse)
+        );
+        assert_eq!(
+            dht.on_datagram(ME, b"garbage", 2),
+            Err(DhtError::InvalidMessage)
+        );
+    }
+
+    #[test]
+    fn test_bootstrap_response() {
+        let mut dht = dht([0xff; 20]);
+        let payload = dht.bootstrap_query();
+        let query = KrpcMessage::parse(&payload).unwrap();
+        assert_eq!(
+            query.body,
+            Body::Query {
+                id: [0xff; 20],
+                query: Query::FindNode { target: [0xff; 20] },
+            }
+        );
+
+        let mut payload = Vec::new();
+        KrpcMessage {
+            transaction: b"bs",
+            read_only: false,
+            body: Body::Response(Response {
+                id: [9; 20],
+                nodes: vec![node(1), node(2)],
+                ..Response::default()
+            }),
+        }
+        .encode(&mut payload);
+        assert_eq!(dht.on_bootstrap_response(&payload, 1), Ok(2));
+        assert_eq!(dht.table().len(), 2);
+        assert_eq!(dht.find_node([0xff; 20], 1).len(), 2);
+    }
+}
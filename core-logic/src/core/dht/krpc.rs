@@ -0,0 +1,512 @@
+//! KRPC, the bencoded query/response protocol of the DHT.
+//!
+//! Every message is a dictionary with a transaction id `t` and a type `y`:
+//! `q` for queries, `r` for responses and `e` for errors. Queries of read-only
+//! nodes (BEP 43) carry `ro`, so they aren't added to routing tables.
+
+use alloc::vec::Vec;
+use bencode::{BencodeParser, BencodeWriter};
+use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::core::{
+    InfoHash,
+    dht::{DhtError, NodeId},
+    net::{decode_compact_peers_v4, decode_compact_peers_v6, encode_compact_peer},
+};
+
+/// Size of a compact IPv4 node: 20 byte id followed by a compact peer.
+pub const COMPACT_NODE_V4_LEN: usize = 26;
+/// Size of a compact IPv6 node: 20 byte id followed by a compact peer.
+pub const COMPACT_NODE_V6_LEN: usize = 38;
+
+/// Error code for malformed queries and invalid tokens.
+pub const ERROR_PROTOCOL: i64 = 203;
+/// Error code for query methods we don't know.
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A DHT node and where to reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query<'a> {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: InfoHash,
+    },
+    AnnouncePeer {
+        info_hash: InfoHash,
+        port: u16,
+        /// use the source port of the datagram instead of `port`
+        implied_port: bool,
+        token: &'a [u8],
+    },
+    /// a method we don't know, answered with [`ERROR_METHOD_UNKNOWN`]
+    Unknown {
+        method: &'a [u8],
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response<'a> {
+    /// id of the responding node
+    pub id: NodeId,
+    /// closest nodes from `nodes` followed by those from `nodes6`
+    pub nodes: Vec<NodeInfo>,
+    /// peers of the torrent, for `get_peers`
+    pub values: Vec<SocketAddr>,
+    /// needed to announce to the responding node, for `get_peers`
+    pub token: Option<&'a [u8]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body<'a> {
+    Query { id: NodeId, query: Query<'a> },
+    Response(Response<'a>),
+    Error { code: i64, message: &'a str },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcMessage<'a> {
+    pub transaction: &'a [u8],
+    pub body: Body<'a>,
+    /// the sender doesn't answer queries
+    pub read_only: bool,
+}
+
+/// The arguments of a query, collected before we know which query it is.
+#[derive(Default)]
+struct Args<'a> {
+    id: Option<NodeId>,
+    target: Option<NodeId>,
+    info_hash: Option<InfoHash>,
+    port: Option<u16>,
+    implied_port: bool,
+    token: Option<&'a [u8]>,
+}
+
+impl<'a> KrpcMessage<'a> {
+    pub fn parse(datagram: &'a [u8]) -> Result<Self, DhtError> {
+        let mut p = BencodeParser::new(datagram);
+        let mut transaction = None;
+        let mut kind = None;
+        let mut method = None;
+        let mut args = None;
+        let mut response = None;
+        let mut error = None;
+        let mut read_only = false;
+
+        p.expect_dict_start()?;
+        while !p.match_dict_end() {
+            match p.parse_str_bytes()? {
+                b"t" => transaction = Some(p.parse_str_bytes()?),
+                b"y" => kind = Some(p.parse_str_bytes()?),
+                b"q" => method = Some(p.parse_str_bytes()?),
+                b"ro" => read_only = p.parse_int()? == 1,
+                b"a" => args = Some(parse_args(&mut p)?),
+                b"r" => response = Some(parse_response(&mut p)?),
+                b"e" => {
+                    p.expect_list_start()?;
+                    let code = p.parse_int()?;
+                    let message = p.parse_str()?;
+                    while !p.match_list_end() {
+                        p.skip_any()?;
+                    }
+                    error = Some((code, message));
+                }
+                _ => p.skip_any()?,
+            }
+        }
+
+        let transaction = transaction.ok_or(DhtError::InvalidMessage)?;
+        let body = match (kind, method, args, response, error) {
+            (Some(b"q"), Some(method), Some(args), _, _) => {
+                let id = args.id.ok_or(DhtError::InvalidMessage)?;
+                let query = match method {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode {
+                        target: args.target.ok_or(DhtError::InvalidMessage)?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        info_hash: args.info_hash.ok_or(DhtError::InvalidMessage)?,
+                    },
+                    b"announce_peer" => Query::AnnouncePeer {
+                        info_hash: args.info_hash.ok_or(DhtError::InvalidMessage)?,
+                        port: args.port.ok_or(DhtError::InvalidMessage)?,
+                        implied_port: args.implied_port,
+                        token: args.token.ok_or(DhtError::InvalidMessage)?,
+                    },
+                    method => Query::Unknown { method },
+                };
+                Body::Query { id, query }
+            }
+            (Some(b"r"), _, _, Some(response), _) => Body::Response(response),
+            (Some(b"e"), _, _, _, Some((code, message))) => Body::Error { code, message },
+            _ => return Err(DhtError::InvalidMessage),
+        };
+        Ok(Self {
+            transaction,
+            body,
+            read_only,
+        })
+    }
+
+    /// Bencodes the message, keys sorted as bencode requires.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mut w = BencodeWriter::new(out);
+        w.dict_start();
+        match &self.body {
+            Body::Query { id, query } => {
+                w.str("a").dict_start();
+                w.str("id").bytes(id);
+                let method: &[u8] = match query {
+                    Query::Ping => b"ping",
+                    Query::FindNode { target } => {
+                        w.str("target").bytes(target);
+                        b"find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        w.str("info_hash").bytes(info_hash);
+                        b"get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        w.str("implied_port").int(*implied_port as i64);
+                        w.str("info_hash").bytes(info_hash);
+                        w.str("port").int(*port as i64);
+                        w.str("token").bytes(token);
+                        b"announce_peer"
+                    }
+                    Query::Unknown { method } => method,
+                };
+                w.end();
+                w.str("q").bytes(method);
+                if self.read_only {
+                    w.str("ro").int(1);
+                }
+                w.str("t").bytes(self.transaction);
+                w.str("y").str("q");
+            }
+            Body::Response(response) => {
+                w.str("r").dict_start();
+                w.str("id").bytes(&response.id);
+                let (nodes, nodes6) = encode_nodes(&response.nodes);
+                if !nodes.is_empty() {
+                    w.str("nodes").bytes(&nodes);
+                }
+                if !nodes6.is_empty() {
+                    w.str("nodes6").bytes(&nodes6);
+                }
+                if let Some(token) = response.token {
+                    w.str("token").bytes(token);
+                }
+                if !response.values.is_empty() {
+                    w.str("values").list_start();
+                    let mut compact = Vec::new();
+                    for peer in &response.values {
+                        compact.clear();
+                        encode_compact_peer(peer, &mut compact);
+                        w.bytes(&compact);
+                    }
+                    w.end();
+                }
+                w.end();
+                w.str("t").bytes(self.transaction);
+                w.str("y").str("r");
+            }
+            Body::Error { code, message } => {
+                w.str("e").list_start().int(*code).str(message).end();
+                w.str("t").bytes(self.transaction);
+                w.str("y").str("e");
+            }
+        }
+        w.end();
+    }
+}
+
+fn parse_args<'a>(p: &mut BencodeParser<'a>) -> Result<Args<'a>, DhtError> {
+    let mut args = Args::default();
+    p.expect_dict_start()?;
+    while !p.match_dict_end() {
+        match p.parse_str_bytes()? {
+            b"id" => args.id = Some(id(p.parse_str_bytes()?)?),
+            b"target" => args.target = Some(id(p.parse_str_bytes()?)?),
+            b"info_hash" => args.info_hash = Some(id(p.parse_str_bytes()?)?),
+            b"port" => args.port = u16::try_from(p.parse_int()?).ok(),
+            b"implied_port" => args.implied_port = p.parse_int()? == 1,
+            b"token" => args.token = Some(p.parse_str_bytes()?),
+            _ => p.skip_any()?,
+        }
+    }
+    Ok(args)
+}
+
+fn parse_response<'a>(p: &mut BencodeParser<'a>) -> Result<Response<'a>, DhtError> {
+    let mut response = Response::default();
+    let mut id_seen = false;
+    p.expect_dict_start()?;
+    while !p.match_dict_end() {
+        match p.parse_str_bytes()? {
+            b"id" => {
+                response.id = id(p.parse_str_bytes()?)?;
+                id_seen = true;
+            }
+            b"nodes" => {
+                let nodes = decode_compact_nodes_v4(p.parse_str_bytes()?);
+                response
+                    .nodes
+                    .extend(nodes.ok_or(DhtError::InvalidMessage)?);
+            }
+            b"nodes6" => {
+                let nodes = decode_compact_nodes_v6(p.parse_str_bytes()?);
+                response
+                    .nodes
+                    .extend(nodes.ok_or(DhtError::InvalidMessage)?);
+            }
+            b"values" => {
+                p.expect_list_start()?;
+                while !p.match_list_end() {
+                    let peer = p.parse_str_bytes()?;
+                    // a single peer per string, of either family
+                    let peers = decode_compact_peers_v4(peer)
+                        .filter(|peers| peers.len() == 1)
+                        .or_else(|| decode_compact_peers_v6(peer));
+                    response.values.extend(peers.unwrap_or_default());
+                }
+            }
+            b"token" => response.token = Some(p.parse_str_bytes()?),
+            _ => p.skip_any()?,
+        }
+    }
+    if !id_seen {
+        return Err(DhtError::InvalidMessage);
+    }
+    Ok(response)
+}
+
+fn id(bytes: &[u8]) -> Result<[u8; 20], DhtError> {
+    bytes.try_into().map_err(|_| DhtError::InvalidMessage)
+}
+
+/// Decodes compact IPv4 node infos, `None` if the length isn't a multiple of
+/// [`COMPACT_NODE_V4_LEN`].
+pub fn decode_compact_nodes_v4(bytes: &[u8]) -> Option<Vec<NodeInfo>> {
+    let (nodes, rest) = bytes.as_chunks::<COMPACT_NODE_V4_LEN>();
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(
+        nodes
+            .iter()
+            .map(|node| {
+                let ip = Ipv4Addr::new(node[20], node[21], node[22], node[23]);
+                NodeInfo {
+                    id: node[..20].try_into().unwrap(),
+                    addr: SocketAddr::new(ip.into(), u16::from_be_bytes([node[24], node[25]])),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Decodes compact IPv6 node infos, `None` if the length isn't a multiple of
+/// [`COMPACT_NODE_V6_LEN`].
+pub fn decode_compact_nodes_v6(bytes: &[u8]) -> Option<Vec<NodeInfo>> {
+    let (nodes, rest) = bytes.as_chunks::<COMPACT_NODE_V6_LEN>();
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(
+        nodes
+            .iter()
+            .map(|node| {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&node[20..36]).unwrap());
+                NodeInfo {
+                    id: node[..20].try_into().unwrap(),
+                    addr: SocketAddr::new(ip.into(), u16::from_be_bytes([node[36], node[37]])),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Appends the compact node info, 26 bytes for IPv4 and 38 bytes for IPv6.
+pub fn encode_compact_node(node: &NodeInfo, out: &mut Vec<u8>) {
+    out.extend_from_slice(&node.id);
+    encode_compact_peer(&node.addr, out);
+}
+
+/// Splits nodes into the compact `nodes` and `nodes6` strings.
+fn encode_nodes(nodes: &[NodeInfo]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for node in nodes {
+        match node.addr {
+            SocketAddr::V4(_) => encode_compact_node(node, &mut v4),
+            SocketAddr::V6(_) => encode_compact_node(node, &mut v6),
+        }
+    }
+    (v4, v6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(message: &KrpcMessage) -> Vec<u8> {
+        let mut out = Vec::new();
+        message.encode(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_ping_matches_bep_5() {
+        let ping = KrpcMessage {
+            transaction: b"aa",
+            read_only: false,
+            body: Body::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::Ping,
+            },
+        };
+        assert_eq!(
+            encoded(&ping),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+
+        let pong = KrpcMessage::parse(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re").unwrap();
+        assert_eq!(pong.transaction, b"aa");
+        assert_eq!(
+            pong.body,
+            Body::Response(Response {
+                id: *b"mnopqrstuvwxyz123456",
+                ..Response::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_read_only_flag_matches_bep_43() {
+        let ping = KrpcMessage {
+            transaction: b"aa",
+            read_only: true,
+            body: Body::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::Ping,
+            },
+        };
+        let bytes = encoded(&ping);
+        assert_eq!(
+            bytes,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:aa1:y1:qe"
+        );
+        assert_eq!(KrpcMessage::parse(&bytes).unwrap(), ping);
+    }
+
+    #[test]
+    fn test_announce_peer_matches_bep_5() {
+        let announce = KrpcMessage {
+            transaction: b"aa",
+            read_only: false,
+            body: Body::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::AnnouncePeer {
+                    info_hash: *b"mnopqrstuvwxyz123456",
+                    port: 6881,
+                    implied_port: true,
+                    token: b"aoeusnth",
+                },
+            },
+        };
+        let bytes = encoded(&announce);
+        assert_eq!(
+            bytes,
+            b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe"
+                .as_slice()
+        );
+        assert_eq!(KrpcMessage::parse(&bytes).unwrap(), announce);
+    }
+
+    #[test]
+    fn test_get_peers_response_roundtrip() {
+        let response = KrpcMessage {
+            transaction: b"\x00\x01",
+            read_only: false,
+            body: Body::Response(Response {
+                id: [1; 20],
+                nodes: alloc::vec![
+                    NodeInfo {
+                        id: [2; 20],
+                        addr: "10.0.0.2:6881".parse().unwrap(),
+                    },
+                    NodeInfo {
+                        id: [3; 20],
+                        addr: "[2001:db8::3]:6881".parse().unwrap(),
+                    },
+                ],
+                values: alloc::vec![
+                    "10.0.0.4:51413".parse().unwrap(),
+                    "[2001:db8::5]:6881".parse().unwrap(),
+                ],
+                token: Some(b"token"),
+            }),
+        };
+        assert_eq!(KrpcMessage::parse(&encoded(&response)).unwrap(), response);
+    }
+
+    #[test]
+    fn test_error_message() {
+        let error =
+            KrpcMessage::parse(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            error.body,
+            Body::Error {
+                code: 201,
+                message: "A Generic Error Ocurred"
+            }
+        );
+        assert_eq!(
+            encoded(&error),
+            b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee"
+        );
+    }
+
+    #[test]
+    fn test_unknown_method_keeps_transaction() {
+        let bytes = b"d1:ad2:id20:abcdefghij0123456789e1:q4:pong1:t2:aa1:y1:qe";
+        let query = KrpcMessage::parse(bytes).unwrap();
+        assert_eq!(query.transaction, b"aa");
+        assert_eq!(
+            query.body,
+            Body::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::Unknown { method: b"pong" },
+            }
+        );
+        assert_eq!(encoded(&query), bytes);
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        // short node id
+        assert_eq!(
+            KrpcMessage::parse(b"d1:rd2:id3:abce1:t2:aa1:y1:re"),
+            Err(DhtError::InvalidMessage)
+        );
+        // missing transaction id
+        assert_eq!(
+            KrpcMessage::parse(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:y1:re"),
+            Err(DhtError::InvalidMessage)
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Peers that announced themselves to us, to answer `get_peers` for them.
+
+use alloc::vec::Vec;
+use core::net::SocketAddr;
+
+use crate::core::InfoHash;
+
+/// Announced peers are forgotten after this long unless they announce again.
+pub const PEER_TTL_MS: u64 = 30 * 60 * 1000;
+
+struct Announce {
+    info_hash: InfoHash,
+    addr: SocketAddr,
+    announced_ms: u64,
+}
+
+/// A fixed-capacity store of announced peers. When it is full, the oldest announce is dropped.
+///
+/// The memory is allocated up front, so a busy DHT can't fragment the heap.
+pub struct PeerStore {
+    announces: Vec<Announce>,
+    max: usize,
+}
+
+impl PeerStore {
+    pub fn new(max: usize) -> Self {
+        Self {
+            announces: Vec::with_capacity(max),
+            max,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.announces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.announces.is_empty()
+    }
+
+    pub fn announce(&mut self, info_hash: InfoHash, addr: SocketAddr, now_ms: u64) {
+        if let Some(announce) = self
+            .announces
+            .iter_mut()
+            .find(|a| a.info_hash == info_hash && a.addr == addr)
+        {
+            announce.announced_ms = now_ms;
+            return;
+        }
+        if self.max == 0 {
+            // read-only nodes don't store announces
+            return;
+        }
+        if self.announces.len() >= self.max
+            && let Some(oldest) = self
+                .announces
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, a)| a.announced_ms)
+                .map(|(i, _)| i)
+        {
+            self.announces.swap_remove(oldest);
+        }
+        self.announces.push(Announce {
+            info_hash,
+            addr,
+            announced_ms: now_ms,
+        });
+    }
+
+    /// Up to `max` peers of a torrent, the most recent announces first.
+    pub fn peers(&self, info_hash: &InfoHash, max: usize) -> Vec<SocketAddr> {
+        let mut announces: Vec<&Announce> = self
+            .announces
+            .iter()
+            .filter(|a| a.info_hash == *info_hash)
+            .collect();
+        announces.sort_unstable_by_key(|a| core::cmp::Reverse(a.announced_ms));
+        announces.iter().take(max).map(|a| a.addr).collect()
+    }
+
+    /// Forgets peers that didn't announce for [`PEER_TTL_MS`].
+    pub fn expire(&mut self, now_ms: u64) {
+        self.announces
+            .retain(|a| now_ms.saturating_sub(a.announced_ms) < PEER_TTL_MS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_store() {
+        let mut store = PeerStore::new(2);
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+
+        store.announce([1; 20], a, 0);
+        store.announce([1; 20], b, 10);
+        store.announce([1; 20], a, 20);
+        assert_eq!(store.peers(&[1; 20], 10), [a, b]);
+        assert!(store.peers(&[2; 20], 10).is_empty());
+
+        // full, b is the oldest announce
+        store.announce([2; 20], c, 30);
+        assert_eq!(store.peers(&[1; 20], 10), [a]);
+        assert_eq!(store.len(), 2);
+
+        store.expire(20 + PEER_TTL_MS);
+        assert_eq!(store.peers(&[2; 20], 10), [c]);
+        assert_eq!(store.len(), 1);
+    }
+}
@@ -0,0 +1,552 @@
+//! The Kademlia routing table: the nodes we know, in buckets by distance to our id.
+//!
+//! The table starts with a single bucket covering the whole id space. When the bucket
+//! that contains our own id overflows, it is split in two, so the table only holds many
+//! nodes close to us and a few far away ones.
+
+use alloc::vec::Vec;
+use core::{
+    cmp::Reverse,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::{
+    core::dht::{NodeId, krpc::NodeInfo},
+    rng::Rng,
+};
+
+/// Nodes per bucket.
+pub const K: usize = 8;
+
+/// Nodes that haven't been heard from for this long have to be pinged before they
+/// are trusted again, and buckets that didn't change for this long are refreshed.
+pub const QUESTIONABLE_AFTER_MS: u64 = 15 * 60 * 1000;
+
+/// Unanswered queries after which a node is replaced.
+pub const MAX_FAILURES: u8 = 2;
+
+/// The most buckets a table can have, one per bit of the id.
+pub const MAX_BUCKETS: usize = 160;
+
+const TAG_V4: u8 = 4;
+const TAG_V6: u8 = 6;
+
+/// The XOR distance between two ids, compares like a 160 bit number.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    core::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// The number of leading bits two ids have in common.
+pub fn common_prefix_len(a: &NodeId, b: &NodeId) -> usize {
+    let distance = distance(a, b);
+    distance
+        .iter()
+        .position(|byte| *byte != 0)
+        .map_or(MAX_BUCKETS, |i| {
+            i * 8 + distance[i].leading_zeros() as usize
+        })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    info: NodeInfo,
+    /// when the node last answered or queried us, 0 if never
+    last_seen_ms: u64,
+    /// queries it didn't answer since it was last seen
+    failures: u8,
+}
+
+impl Node {
+    fn is_good(&self, now_ms: u64) -> bool {
+        self.last_seen_ms != 0
+            && self.failures == 0
+            && now_ms.saturating_sub(self.last_seen_ms) < QUESTIONABLE_AFTER_MS
+    }
+
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Bucket {
+    nodes: Vec<Node>,
+    /// nodes that didn't fit, waiting for a bad node to replace
+    replacements: Vec<NodeInfo>,
+    last_changed_ms: u64,
+}
+
+/// What [`RoutingTable::heard_from`] did with a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Insert {
+    /// The node is in the table.
+    Added,
+    /// The bucket is full of good nodes, the node was dropped.
+    Full,
+    /// The bucket is full, but this node hasn't been heard from in a while and
+    /// should be pinged. If it doesn't answer, the new node takes its place.
+    Ping(NodeInfo),
+}
+
+pub struct RoutingTable {
+    own_id: NodeId,
+    /// the last bucket stops splitting once there are this many
+    max_buckets: usize,
+    /// bucket `i` holds the nodes sharing `i` leading bits with our id,
+    /// the last one also those sharing more
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self::with_max_buckets(own_id, MAX_BUCKETS)
+    }
+
+    /// A table of at most `max_buckets` buckets, to bound its memory.
+    ///
+    /// Nodes close to us then share the last bucket, so lookups near our own id
+    /// take a few more steps.
+    pub fn with_max_buckets(own_id: NodeId, max_buckets: usize) -> Self {
+        Self {
+            own_id,
+            max_buckets: max_buckets.clamp(1, MAX_BUCKETS),
+            buckets: alloc::vec![Bucket::default()],
+        }
+    }
+
+    /// Shrinks the table to at most `max_buckets` buckets. The nodes that no longer
+    /// fit are dropped, the most recently seen ones keep their places.
+    pub fn limit_buckets(&mut self, max_buckets: usize, now_ms: u64) {
+        let max_buckets = max_buckets.clamp(1, MAX_BUCKETS);
+        if max_buckets >= self.max_buckets {
+            return;
+        }
+        let old = core::mem::replace(self, Self::with_max_buckets(self.own_id, max_buckets));
+        let mut nodes: Vec<Node> = old
+            .buckets
+            .into_iter()
+            .flat_map(|bucket| bucket.nodes)
+            .filter(|node| !node.is_bad())
+            .collect();
+        nodes.sort_unstable_by_key(|node| Reverse(node.last_seen_ms));
+        for node in nodes {
+            self.insert(node.info, node.last_seen_ms, now_ms);
+        }
+    }
+
+    pub fn own_id(&self) -> &NodeId {
+        &self.own_id
+    }
+
+    /// The number of nodes in the table, not counting replacements.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of nodes that answered recently.
+    pub fn good_nodes(&self, now_ms: u64) -> usize {
+        self.nodes().filter(|node| node.is_good(now_ms)).count()
+    }
+
+    /// Records that a node answered a query or queried us.
+    pub fn heard_from(&mut self, info: NodeInfo, now_ms: u64) -> Insert {
+        self.insert(info, now_ms, now_ms)
+    }
+
+    /// Adds a node we only heard about from another node, e.g. in a `find_node`
+    /// response. It has to answer a query before it counts as good.
+    pub fn add_unverified(&mut self, info: NodeInfo, now_ms: u64) -> Insert {
+        self.insert(info, 0, now_ms)
+    }
+
+    fn insert(&mut self, info: NodeInfo, last_seen_ms: u64, now_ms: u64) -> Insert {
+        if info.id == self.own_id {
+            return Insert::Full;
+        }
+        let index = self.bucket_index(&info.id);
+        let bucket = &mut self.buckets[index];
+
+        if let Some(node) = bucket.nodes.iter_mut().find(|node| node.info.id == info.id) {
+            // a node that changed its address could be someone else claiming its id
+            if node.info.addr == info.addr && last_seen_ms != 0 {
+                node.last_seen_ms = last_seen_ms;
+                node.failures = 0;
+                bucket.last_changed_ms = now_ms;
+            }
+            return Insert::Added;
+        }
+
+        let node = Node {
+            info,
+            last_seen_ms,
+            failures: 0,
+        };
+        if bucket.nodes.len() < K {
+            bucket.nodes.push(node);
+            bucket.last_changed_ms = now_ms;
+            return Insert::Added;
+        }
+        if let Some(bad) = bucket.nodes.iter_mut().find(|node| node.is_bad()) {
+            *bad = node;
+            bucket.last_changed_ms = now_ms;
+            return Insert::Added;
+        }
+        if index == self.buckets.len() - 1 && self.buckets.len() < self.max_buckets {
+            self.split_last();
+            return self.insert(info, last_seen_ms, now_ms);
+        }
+
+        let bucket = &mut self.buckets[index];
+        if !bucket.replacements.iter().any(|r| r.id == info.id) {
+            if bucket.replacements.len() >= K {
+                bucket.replacements.remove(0);
+            }
+            bucket.replacements.push(info);
+        }
+        bucket
+            .nodes
+            .iter()
+            .filter(|node| !node.is_good(now_ms))
+            .min_by_key(|node| node.last_seen_ms)
+            .map_or(Insert::Full, |node| Insert::Ping(node.info))
+    }
+
+    /// Records that a node didn't answer a query.
+    /// Bad nodes are replaced by the newest replacement of their bucket.
+    pub fn failed(&mut self, id: &NodeId) {
+        let index = self.bucket_index(id);
+        let bucket = &mut self.buckets[index];
+        let Some(position) = bucket.nodes.iter().position(|node| node.info.id == *id) else {
+            return;
+        };
+        let node = &mut bucket.nodes[position];
+        node.failures = node.failures.saturating_add(1);
+        if node.is_bad()
+            && let Some(replacement) = bucket.replacements.pop()
+        {
+            bucket.nodes[position] = Node {
+                info: replacement,
+                last_seen_ms: 0,
+                failures: 0,
+            };
+        }
+    }
+
+    /// Forgets a node, e.g. one that answered with a different id than expected.
+    pub fn remove(&mut self, id: &NodeId) {
+        let index = self.bucket_index(id);
+        self.buckets[index].nodes.retain(|node| node.info.id != *id);
+    }
+
+    /// Up to `count` nodes closest to `target`, closest first. Bad nodes are skipped.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .nodes()
+            .filter(|node| !node.is_bad())
+            .map(|node| node.info)
+            .collect();
+        nodes.sort_unstable_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// A node that hasn't been heard from in a while and should be pinged, the stalest first.
+    pub fn questionable(&self, now_ms: u64) -> Option<NodeInfo> {
+        self.nodes()
+            .filter(|node| !node.is_good(now_ms) && !node.is_bad())
+            .min_by_key(|node| (node.failures, node.last_seen_ms))
+            .map(|node| node.info)
+    }
+
+    /// A random id within a bucket that didn't change for [`QUESTIONABLE_AFTER_MS`],
+    /// to look up with `find_node`. The bucket counts as refreshed right away.
+    pub fn refresh_target<R: Rng>(&mut self, rng: &mut R, now_ms: u64) -> Option<NodeId> {
+        let last = self.buckets.len() - 1;
+        let (index, bucket) = self
+            .buckets
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, bucket)| {
+                now_ms.saturating_sub(bucket.last_changed_ms) >= QUESTIONABLE_AFTER_MS
+            })
+            .min_by_key(|(_, bucket)| bucket.last_changed_ms)?;
+        bucket.last_changed_ms = now_ms;
+
+        let mut target = [0u8; 20];
+        rng.fill_bytes(&mut target);
+        // keep our first `index` bits, flip the next one unless it's the last bucket
+        for bit in 0..index {
+            set_bit(&mut target, bit, bit_of(&self.own_id, bit));
+        }
+        if index < last {
+            set_bit(&mut target, index, !bit_of(&self.own_id, index));
+        }
+        Some(target)
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flat_map(|bucket| bucket.nodes.iter())
+    }
+
+    /// Appends our id and the nodes of the table, to restore it after a restart.
+    /// Good nodes come first, so they survive if the table shrinks.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.own_id);
+        let mut nodes: Vec<&Node> = self.nodes().filter(|node| !node.is_bad()).collect();
+        nodes.sort_unstable_by_key(|node| Reverse(node.last_seen_ms));
+        for node in nodes {
+            match node.info.addr {
+                SocketAddr::V4(addr) => {
+                    out.push(TAG_V4);
+                    out.extend_from_slice(&addr.ip().octets());
+                }
+                SocketAddr::V6(addr) => {
+                    out.push(TAG_V6);
+                    out.extend_from_slice(&addr.ip().octets());
+                }
+            }
+            out.extend_from_slice(&node.info.addr.port().to_be_bytes());
+            out.extend_from_slice(&node.info.id);
+        }
+    }
+
+    /// Restores a table written by [`RoutingTable::encode`], with at most `max_buckets`.
+    ///
+    /// The nodes may have left the DHT since, so they are unverified until they answer.
+    pub fn decode(bytes: &[u8], max_buckets: usize, now_ms: u64) -> Option<Self> {
+        let own_id: NodeId = bytes.get(..20)?.try_into().ok()?;
+        let mut table = Self::with_max_buckets(own_id, max_buckets);
+        let mut bytes = &bytes[20..];
+        while let [tag, rest @ ..] = bytes {
+            let (ip, rest) = match *tag {
+                TAG_V4 => {
+                    let octets: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+                    (Ipv4Addr::from(octets).into(), &rest[4..])
+                }
+                TAG_V6 => {
+                    let octets: [u8; 16] = rest.get(..16)?.try_into().ok()?;
+                    (Ipv6Addr::from(octets).into(), &rest[16..])
+                }
+                _ => return None,
+            };
+            let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+            let id: NodeId = rest.get(2..22)?.try_into().ok()?;
+            bytes = &rest[22..];
+            table.add_unverified(
+                NodeInfo {
+                    id,
+                    addr: SocketAddr::new(ip, port),
+                },
+                now_ms,
+            );
+        }
+        Some(table)
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        common_prefix_len(&self.own_id, id).min(self.buckets.len() - 1)
+    }
+
+    /// Splits the last bucket, moving the nodes closer to us into a new one.
+    fn split_last(&mut self) {
+        let depth = self.buckets.len() - 1;
+        let own_id = self.own_id;
+        let last = self.buckets.last_mut().unwrap();
+
+        let (closer, rest): (Vec<Node>, Vec<Node>) = last
+            .nodes
+            .drain(..)
+            .partition(|node| common_prefix_len(&own_id, &node.info.id) > depth);
+        last.nodes = rest;
+        let (closer_replacements, rest): (Vec<NodeInfo>, Vec<NodeInfo>) = last
+            .replacements
+            .drain(..)
+            .partition(|info| common_prefix_len(&own_id, &info.id) > depth);
+        last.replacements = rest;
+
+        let last_changed_ms = last.last_changed_ms;
+        self.buckets.push(Bucket {
+            nodes: closer,
+            replacements: closer_replacements,
+            last_changed_ms,
+        });
+    }
+}
+
+fn bit_of(id: &NodeId, bit: usize) -> bool {
+    id[bit / 8] & (0x80 >> (bit % 8)) != 0
+}
+
+fn set_bit(id: &mut NodeId, bit: usize, value: bool) {
+    if value {
+        id[bit / 8] |= 0x80 >> (bit % 8);
+    } else {
+        id[bit / 8] &= !(0x80 >> (bit % 8));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift32;
+
+    const OWN_ID: NodeId = [0; 20];
+
+    /// A node whose id has `prefix` leading zero bits, i.e. lands in bucket `prefix`.
+    fn node(prefix: usize, n: u8) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[19] = n;
+        set_bit(&mut id, prefix, true);
+        NodeInfo {
+            id,
+            addr: SocketAddr::new(Ipv4Addr::new(10, 0, prefix as u8, n).into(), 6881),
+        }
+    }
+
+    #[test]
+    fn test_common_prefix_len() {
+        assert_eq!(common_prefix_len(&OWN_ID, &OWN_ID), 160);
+        assert_eq!(common_prefix_len(&OWN_ID, &node(0, 1).id), 0);
+        assert_eq!(common_prefix_len(&OWN_ID, &node(13, 1).id), 13);
+    }
+
+    #[test]
+    fn test_buckets_split_around_own_id() {
+        let mut table = RoutingTable::new(OWN_ID);
+        for n in 1..=K as u8 {
+            assert_eq!(table.heard_from(node(0, n), 1), Insert::Added);
+        }
+        // the first bucket covers our own id, so it splits instead of rejecting
+        assert_eq!(table.heard_from(node(3, 1), 1), Insert::Added);
+        assert_eq!(table.len(), K + 1);
+
+        // far away buckets don't split
+        assert_eq!(table.heard_from(node(0, 100), 1), Insert::Full);
+        assert_eq!(table.len(), K + 1);
+    }
+
+    #[test]
+    fn test_bucket_count_is_bounded() {
+        let mut table = RoutingTable::with_max_buckets(OWN_ID, 2);
+        for prefix in 0..8 {
+            for n in 1..=K as u8 {
+                table.heard_from(node(prefix, n), 1);
+            }
+        }
+        assert_eq!(table.buckets.len(), 2);
+        assert_eq!(table.len(), 2 * K);
+        assert!(
+            table
+                .buckets
+                .iter()
+                .all(|bucket| bucket.replacements.len() <= K)
+        );
+    }
+
+    #[test]
+    fn test_limit_buckets_keeps_recent_nodes() {
+        let mut table = RoutingTable::new(OWN_ID);
+        for prefix in 0..4 {
+            for n in 1..=K as u8 {
+                table.heard_from(node(prefix, n), prefix as u64 + 1);
+            }
+        }
+        assert_eq!(table.buckets.len(), 4);
+
+        table.limit_buckets(2, 5);
+        assert_eq!(table.buckets.len(), 2);
+        assert_eq!(table.len(), 2 * K);
+        // the last bucket holds the most recently seen of the close nodes
+        assert!(
+            table.buckets[1]
+                .nodes
+                .iter()
+                .all(|node| node.last_seen_ms == 4)
+        );
+
+        // a table never grows
+        table.limit_buckets(MAX_BUCKETS, 5);
+        assert_eq!(table.max_buckets, 2);
+    }
+
+    #[test]
+    fn test_stale_nodes_are_pinged_and_replaced() {
+        let mut table = RoutingTable::new(OWN_ID);
+        for n in 1..=K as u8 {
+            table.heard_from(node(0, n), 1);
+        }
+        table.heard_from(node(1, 1), 1);
+
+        let now = 1 + QUESTIONABLE_AFTER_MS;
+        let Insert::Ping(stale) = table.heard_from(node(0, 100), now) else {
+            panic!("no node to ping");
+        };
+        for _ in 0..MAX_FAILURES {
+            table.failed(&stale.id);
+        }
+        let far = table.closest(&node(0, 0).id, 2 * K);
+        assert!(far.contains(&node(0, 100)));
+        assert!(!far.contains(&stale));
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new(OWN_ID);
+        table.heard_from(node(0, 1), 1);
+        table.heard_from(node(5, 1), 1);
+        table.heard_from(node(2, 1), 1);
+
+        let closest: Vec<NodeId> = table.closest(&OWN_ID, 2).iter().map(|n| n.id).collect();
+        assert_eq!(closest, [node(5, 1).id, node(2, 1).id]);
+    }
+
+    #[test]
+    fn test_refresh_target_lands_in_stale_bucket() {
+        let mut table = RoutingTable::new(OWN_ID);
+        for n in 1..=K as u8 {
+            table.heard_from(node(0, n), 1);
+        }
+        table.heard_from(node(1, 1), 1);
+        let mut rng = XorShift32::new(7);
+
+        assert_eq!(table.refresh_target(&mut rng, 2), None);
+        let target = table
+            .refresh_target(&mut rng, 1 + QUESTIONABLE_AFTER_MS)
+            .unwrap();
+        assert_eq!(common_prefix_len(&OWN_ID, &target), 0);
+        let target = table
+            .refresh_target(&mut rng, 1 + QUESTIONABLE_AFTER_MS)
+            .unwrap();
+        assert!(common_prefix_len(&OWN_ID, &target) >= 1);
+        assert_eq!(
+            table.refresh_target(&mut rng, 1 + QUESTIONABLE_AFTER_MS),
+            None
+        );
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let mut table = RoutingTable::new([7; 20]);
+        table.heard_from(node(0, 1), 1);
+        table.heard_from(
+            NodeInfo {
+                id: [9; 20],
+                addr: "[2001:db8::1]:6881".parse().unwrap(),
+            },
+            2,
+        );
+
+        let mut bytes = Vec::new();
+        table.encode(&mut bytes);
+        let restored = RoutingTable::decode(&bytes, MAX_BUCKETS, 3).unwrap();
+        assert_eq!(restored.own_id(), &[7; 20]);
+        assert_eq!(restored.closest(&[0; 20], K), table.closest(&[0; 20], K));
+        assert_eq!(restored.good_nodes(3), 0);
+
+        assert!(RoutingTable::decode(&bytes[..bytes.len() - 1], MAX_BUCKETS, 3).is_none());
+    }
+}
@@ -0,0 +1,92 @@
+//! Tokens for `announce_peer`.
+//!
+//! A node may only announce to us after it asked us for peers from the same address.
+//! The token we hand out with `get_peers` is a hash of its IP and a secret that changes
+//! every few minutes. Tokens of the previous secret stay valid, so a token is accepted
+//! for at least one and at most two rotation intervals.
+
+use core::net::IpAddr;
+use sha1_smol::Sha1;
+
+use crate::rng::Rng;
+
+/// How often the secret changes.
+pub const TOKEN_ROTATION_MS: u64 = 5 * 60 * 1000;
+
+/// Length of our tokens, short enough to keep responses small.
+pub const TOKEN_LEN: usize = 8;
+
+pub type Token = [u8; TOKEN_LEN];
+
+pub struct TokenSecret {
+    current: [u8; 8],
+    previous: [u8; 8],
+    rotated_ms: u64,
+}
+
+impl TokenSecret {
+    pub fn new<R: Rng>(rng: &mut R, now_ms: u64) -> Self {
+        let mut current = [0u8; 8];
+        rng.fill_bytes(&mut current);
+        Self {
+            current,
+            previous: current,
+            rotated_ms: now_ms,
+        }
+    }
+
+    /// The token for a node at `ip`.
+    pub fn token(&self, ip: IpAddr) -> Token {
+        hash_token(&self.current, ip)
+    }
+
+    pub fn is_valid(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == self.token(ip) || token == hash_token(&self.previous, ip)
+    }
+
+    /// Replaces the secret once [`TOKEN_ROTATION_MS`] have passed.
+    pub fn rotate<R: Rng>(&mut self, rng: &mut R, now_ms: u64) {
+        if now_ms.saturating_sub(self.rotated_ms) < TOKEN_ROTATION_MS {
+            return;
+        }
+        self.previous = self.current;
+        rng.fill_bytes(&mut self.current);
+        self.rotated_ms = now_ms;
+    }
+}
+
+fn hash_token(secret: &[u8; 8], ip: IpAddr) -> Token {
+    let mut sha1 = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => sha1.update(&ip.octets()),
+        IpAddr::V6(ip) => sha1.update(&ip.octets()),
+    }
+    sha1.update(secret);
+    sha1.digest().bytes()[..TOKEN_LEN].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift32;
+
+    #[test]
+    fn test_tokens_expire_after_two_rotations() {
+        let mut rng = XorShift32::new(1);
+        let mut secret = TokenSecret::new(&mut rng, 0);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let token = secret.token(ip);
+
+        assert!(secret.is_valid(&token, ip));
+        assert!(!secret.is_valid(&token, "10.0.0.2".parse().unwrap()));
+        assert!(!secret.is_valid(&token[..4], ip));
+
+        secret.rotate(&mut rng, TOKEN_ROTATION_MS - 1);
+        assert_eq!(secret.token(ip), token);
+        secret.rotate(&mut rng, TOKEN_ROTATION_MS);
+        assert_ne!(secret.token(ip), token);
+        assert!(secret.is_valid(&token, ip));
+        secret.rotate(&mut rng, 2 * TOKEN_ROTATION_MS);
+        assert!(!secret.is_valid(&token, ip));
+    }
+}
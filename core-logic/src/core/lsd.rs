@@ -0,0 +1,567 @@
+//! Local Service Discovery (BEP 14), to find peers on the same LAN without a tracker.
+//!
+//! Every client sends an HTTP-like `BT-SEARCH` datagram to a multicast group, listing
+//! the info hashes it is interested in and the port it accepts peers on. Whoever shares
+//! one of the torrents connects to the sender's address at that port.
+//!
+//! Private torrents (BEP 27) must only get peers from their tracker, so they are
+//! never announced and announces for them are ignored.
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use defmt::Format;
+
+use crate::{
+    core::{
+        InfoHash,
+        metainfo::Info,
+        net::{Datagram, send_all},
+        peer::candidates::{Candidates, Source},
+    },
+    rng::Rng,
+    wifi::{UdpError, UdpSocket},
+};
+
+/// The port both multicast groups listen on.
+pub const LSD_PORT: u16 = 6771;
+
+pub const LSD_GROUP_V4: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 192, 152, 143)), LSD_PORT);
+
+pub const LSD_GROUP_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)),
+    LSD_PORT,
+);
+
+/// How often we announce all our torrents.
+pub const ANNOUNCE_INTERVAL_MS: u64 = 5 * 60_000;
+
+/// BEP 14 allows a torrent to be announced at most once a minute. Announces from the
+/// same host for the same torrent that come faster than this are ignored.
+pub const MIN_ANNOUNCE_INTERVAL_MS: u64 = 60_000;
+
+/// The most info hashes we put into one datagram, and the most we look at in one.
+/// Keeps a datagram well below the MTU.
+pub const MAX_INFO_HASHES: usize = 20;
+
+/// How many recent announces we remember to rate limit senders.
+pub const MAX_RECENT_ANNOUNCES: usize = 64;
+
+/// How long [`Lsd::poll`] waits for a datagram before it returns.
+pub const POLL_INTERVAL_MS: u64 = 1_000;
+
+/// Large enough for an announce with [`MAX_INFO_HASHES`] info hashes.
+pub const MAX_DATAGRAM_LEN: usize = 1400;
+
+const REQUEST_LINE: &str = "BT-SEARCH * HTTP/1.1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum LsdError {
+    /// The datagram isn't a `BT-SEARCH` request or misses a header.
+    InvalidMessage,
+    /// The `Port` header isn't a valid, non-zero port.
+    InvalidPort,
+    /// An `Infohash` header isn't 40 hex digits.
+    InvalidInfoHash,
+}
+
+/// A parsed `BT-SEARCH` datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce<'a> {
+    /// The port the sender accepts peers on.
+    pub port: u16,
+    pub info_hashes: Vec<InfoHash>,
+    /// Lets a client recognize its own announces when the group loops them back.
+    pub cookie: Option<&'a str>,
+}
+
+impl<'a> Announce<'a> {
+    /// Parses a datagram. Header names are case insensitive, unknown headers are ignored
+    /// and only the first [`MAX_INFO_HASHES`] info hashes are kept.
+    pub fn parse(datagram: &'a [u8]) -> Result<Self, LsdError> {
+        let text = core::str::from_utf8(datagram).map_err(|_| LsdError::InvalidMessage)?;
+        let mut lines = text.lines();
+        if lines.next().map(str::trim_end) != Some(REQUEST_LINE) {
+            return Err(LsdError::InvalidMessage);
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("port") {
+                port = match value.parse() {
+                    Ok(0) | Err(_) => return Err(LsdError::InvalidPort),
+                    Ok(port) => Some(port),
+                };
+            } else if name.eq_ignore_ascii_case("infohash") {
+                if info_hashes.len() < MAX_INFO_HASHES {
+                    info_hashes.push(decode_info_hash(value)?);
+                }
+            } else if name.eq_ignore_ascii_case("cookie") {
+                cookie = Some(value);
+            }
+        }
+
+        if info_hashes.is_empty() {
+            return Err(LsdError::InvalidMessage);
+        }
+        Ok(Self {
+            port: port.ok_or(LsdError::InvalidMessage)?,
+            info_hashes,
+            cookie,
+        })
+    }
+
+    /// Encodes the announce for the multicast group `group`.
+    pub fn encode(&self, group: SocketAddr) -> Vec<u8> {
+        let mut out = String::with_capacity(100 + self.info_hashes.len() * 52);
+        write!(
+            out,
+            "{REQUEST_LINE}\r\nHost: {group}\r\nPort: {}\r\n",
+            self.port
+        )
+        .unwrap();
+        for info_hash in &self.info_hashes {
+            out.push_str("Infohash: ");
+            for b in info_hash {
+                write!(out, "{:02x}", b).unwrap();
+            }
+            out.push_str("\r\n");
+        }
+        if let Some(cookie) = self.cookie {
+            write!(out, "cookie: {cookie}\r\n").unwrap();
+        }
+        out.push_str("\r\n\r\n");
+        out.into_bytes()
+    }
+}
+
+/// A peer on the LAN that announced one of our torrents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct LsdPeer {
+    pub info_hash: InfoHash,
+    pub addr: SocketAddr,
+}
+
+/// Announces our torrents to the LAN and collects the peers that announce them too.
+///
+/// Like [`crate::core::dht::Dht`] it is a state machine driven by datagrams and the
+/// current time, [`Lsd::poll`] runs it on a socket.
+pub struct Lsd {
+    port: u16,
+    cookie: [u8; 8],
+    ipv6: bool,
+    torrents: Vec<InfoHash>,
+    last_announce_ms: Option<u64>,
+    /// A torrent was added since the last announce.
+    changed: bool,
+    /// Who announced which torrent when, to drop announces that come too fast.
+    recent: VecDeque<(IpAddr, InfoHash, u64)>,
+}
+
+impl Lsd {
+    /// Announces that we accept peers on `port`.
+    pub fn new<R: Rng>(port: u16, rng: &mut R) -> Self {
+        let mut cookie = [0; 8];
+        let random = rng.next_u32();
+        for (i, digit) in cookie.iter_mut().enumerate() {
+            *digit = b"0123456789abcdef"[(random >> (i * 4)) as usize & 0xf];
+        }
+
+        Self {
+            port,
+            cookie,
+            ipv6: false,
+            torrents: Vec::new(),
+            last_announce_ms: None,
+            changed: false,
+            recent: VecDeque::with_capacity(MAX_RECENT_ANNOUNCES),
+        }
+    }
+
+    /// Also announces to and listens on the IPv6 group.
+    pub fn with_ipv6(mut self) -> Self {
+        self.ipv6 = true;
+        self
+    }
+
+    /// Starts announcing a torrent. Returns `false` for private torrents, which are
+    /// never announced.
+    pub fn add_torrent(&mut self, info_hash: InfoHash, info: &Info) -> bool {
+        if info.private {
+            return false;
+        }
+        if !self.torrents.contains(&info_hash) {
+            self.torrents.push(info_hash);
+            self.changed = true;
+        }
+        true
+    }
+
+    pub fn remove_torrent(&mut self, info_hash: &InfoHash) {
+        self.torrents.retain(|t| t != info_hash);
+    }
+
+    pub fn torrents(&self) -> &[InfoHash] {
+        &self.torrents
+    }
+
+    /// Joins the multicast groups on `socket`, which has to be bound to [`LSD_PORT`].
+    pub fn join<S: UdpSocket>(&self, socket: &mut S) -> Result<(), UdpError> {
+        socket.join_multicast(LSD_GROUP_V4.ip())?;
+        if self.ipv6 {
+            socket.join_multicast(LSD_GROUP_V6.ip())?;
+        }
+        Ok(())
+    }
+
+    /// The announces that are due, one datagram per group and [`MAX_INFO_HASHES`] torrents.
+    ///
+    /// All torrents are announced every [`ANNOUNCE_INTERVAL_MS`], and sooner once a
+    /// torrent was added, but never more than once every [`MIN_ANNOUNCE_INTERVAL_MS`].
+    pub fn poll_announce(&mut self, now_ms: u64) -> Vec<Datagram> {
+        if self.torrents.is_empty() {
+            return Vec::new();
+        }
+        if let Some(last) = self.last_announce_ms {
+            let elapsed = now_ms.saturating_sub(last);
+            let due = elapsed >= ANNOUNCE_INTERVAL_MS
+                || (self.changed && elapsed >= MIN_ANNOUNCE_INTERVAL_MS);
+            if !due {
+                return Vec::new();
+            }
+        }
+        self.last_announce_ms = Some(now_ms);
+        self.changed = false;
+
+        let cookie = core::str::from_utf8(&self.cookie).ok();
+        let groups: &[SocketAddr] = if self.ipv6 {
+            &[LSD_GROUP_V4, LSD_GROUP_V6]
+        } else {
+            &[LSD_GROUP_V4]
+        };
+        let mut out = Vec::new();
+        for chunk in self.torrents.chunks(MAX_INFO_HASHES) {
+            let announce = Announce {
+                port: self.port,
+                info_hashes: chunk.to_vec(),
+                cookie,
+            };
+            for &group in groups {
+                out.push(Datagram {
+                    addr: group,
+                    payload: announce.encode(group),
+                });
+            }
+        }
+        out
+    }
+
+    /// Handles a datagram `from` the LAN and returns the peers for our torrents it announced.
+    ///
+    /// Our own announces and announces that repeat too fast are dropped.
+    pub fn on_datagram(
+        &mut self,
+        from: SocketAddr,
+        datagram: &[u8],
+        now_ms: u64,
+    ) -> Result<Vec<LsdPeer>, LsdError> {
+        let announce = Announce::parse(datagram)?;
+        if announce.cookie.map(str::as_bytes) == Some(&self.cookie[..]) {
+            return Ok(Vec::new());
+        }
+
+        self.recent
+            .retain(|&(_, _, at)| now_ms.saturating_sub(at) < MIN_ANNOUNCE_INTERVAL_MS);
+        let ip = from.ip();
+        let mut peers = Vec::new();
+        for info_hash in announce.info_hashes {
+            if !self.torrents.contains(&info_hash)
+                || self
+                    .recent
+                    .iter()
+                    .any(|&(seen, hash, _)| seen == ip && hash == info_hash)
+            {
+                continue;
+            }
+            if self.recent.len() == MAX_RECENT_ANNOUNCES {
+                self.recent.pop_front();
+            }
+            self.recent.push_back((ip, info_hash, now_ms));
+            peers.push(LsdPeer {
+                info_hash,
+                addr: SocketAddr::new(ip, announce.port),
+            });
+        }
+        Ok(peers)
+    }
+
+    /// Sends the due announces, then waits up to [`POLL_INTERVAL_MS`] for a datagram.
+    /// Call it in a loop.
+    pub async fn poll<S: UdpSocket>(
+        &mut self,
+        socket: &mut S,
+        buf: &mut [u8],
+        now_ms: impl Fn() -> u64,
+    ) -> Result<Vec<LsdPeer>, UdpError> {
+        let out = self.poll_announce(now_ms());
+        send_all(socket, out).await?;
+
+        socket.set_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)));
+        match socket.recv_from(buf).await {
+            Ok((len, from)) => Ok(self
+                .on_datagram(from, &buf[..len], now_ms())
+                .unwrap_or_else(|err| {
+                    defmt::debug!("dropped LSD datagram from {}: {}", from, err);
+                    Vec::new()
+                })),
+            Err(UdpError::TimedOut | UdpError::Truncated) => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Adds the peers that were announced for `info_hash` to its candidates.
+/// Returns how many were new.
+pub fn update_candidates(
+    peers: &[LsdPeer],
+    info_hash: &InfoHash,
+    candidates: &mut Candidates,
+) -> usize {
+    candidates.extend(
+        peers
+            .iter()
+            .filter(|peer| &peer.info_hash == info_hash)
+            .map(|peer| peer.addr),
+        Source::Lsd,
+    )
+}
+
+fn decode_info_hash(hex: &str) -> Result<InfoHash, LsdError> {
+    let hex = hex.as_bytes();
+    if hex.len() != 40 {
+        return Err(LsdError::InvalidInfoHash);
+    }
+    let mut info_hash = [0; 20];
+    let digit = |c: u8| (c as char).to_digit(16).ok_or(LsdError::InvalidInfoHash);
+    for (b, &[high, low]) in info_hash.iter_mut().zip(hex.as_chunks::<2>().0) {
+        *b = (digit(high)? << 4 | digit(low)?) as u8;
+    }
+    Ok(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift32;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn info(private: bool) -> Info<'static> {
+        Info {
+            piece_length: 16384,
+            name: "test",
+            pieces: &[],
+            length: 0,
+            private,
+        }
+    }
+
+    #[test]
+    fn test_parse_announce() {
+        let datagram = b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: 0123456789ABCDEF0123456789abcdef01234567\r\ncookie: abc\r\n\r\n\r\n";
+        let announce = Announce::parse(datagram).unwrap();
+
+        assert_eq!(announce.port, 6881);
+        assert_eq!(
+            announce.info_hashes,
+            [[
+                0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+                0xcd, 0xef, 0x01, 0x23, 0x45, 0x67
+            ]]
+        );
+        assert_eq!(announce.cookie, Some("abc"));
+    }
+
+    #[test]
+    fn test_parse_invalid_announce() {
+        assert_eq!(
+            Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            Err(LsdError::InvalidMessage)
+        );
+        assert_eq!(
+            Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n"),
+            Err(LsdError::InvalidMessage)
+        );
+        assert_eq!(
+            Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 0\r\n\r\n"),
+            Err(LsdError::InvalidPort)
+        );
+        assert_eq!(
+            Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: 0123\r\n\r\n"),
+            Err(LsdError::InvalidInfoHash)
+        );
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: [[0xab; 20], [1; 20]].to_vec(),
+            cookie: Some("c00c1e"),
+        };
+        let encoded = announce.encode(LSD_GROUP_V6);
+
+        assert!(encoded.starts_with(
+            b"BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:988f]:6771\r\nPort: 6881\r\nInfohash: abab"
+        ));
+        assert!(encoded.ends_with(b"cookie: c00c1e\r\n\r\n\r\n"));
+        assert_eq!(Announce::parse(&encoded), Ok(announce));
+    }
+
+    #[test]
+    fn test_private_torrents_are_not_announced() {
+        let mut lsd = Lsd::new(6881, &mut XorShift32::new(1));
+
+        assert!(!lsd.add_torrent([1; 20], &info(true)));
+        assert!(lsd.poll_announce(0).is_empty());
+        assert!(lsd.add_torrent([2; 20], &info(false)));
+        assert_eq!(lsd.torrents(), [[2; 20]]);
+    }
+
+    #[test]
+    fn test_announce_interval() {
+        let mut lsd = Lsd::new(6881, &mut XorShift32::new(1)).with_ipv6();
+        lsd.add_torrent([1; 20], &info(false));
+
+        let out = lsd.poll_announce(1_000);
+        assert_eq!(
+            out.iter().map(|d| d.addr).collect::<Vec<_>>(),
+            [LSD_GROUP_V4, LSD_GROUP_V6]
+        );
+        assert!(lsd.poll_announce(2_000).is_empty());
+
+        // a new torrent is announced early, but not within a minute of the last announce
+        lsd.add_torrent([2; 20], &info(false));
+        assert!(lsd.poll_announce(30_000).is_empty());
+        let out = lsd.poll_announce(1_000 + MIN_ANNOUNCE_INTERVAL_MS);
+        assert_eq!(
+            Announce::parse(&out[0].payload).unwrap().info_hashes,
+            [[1; 20], [2; 20]]
+        );
+
+        assert!(lsd.poll_announce(ANNOUNCE_INTERVAL_MS).is_empty());
+        assert_eq!(
+            lsd.poll_announce(1_000 + MIN_ANNOUNCE_INTERVAL_MS + ANNOUNCE_INTERVAL_MS)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_many_torrents_are_split() {
+        let mut lsd = Lsd::new(6881, &mut XorShift32::new(1));
+        for n in 0..(MAX_INFO_HASHES + 1) as u8 {
+            lsd.add_torrent([n; 20], &info(false));
+        }
+
+        let out = lsd.poll_announce(0);
+        assert_eq!(out.len(), 2);
+        assert!(out.iter().all(|d| d.payload.len() <= MAX_DATAGRAM_LEN));
+        assert_eq!(
+            Announce::parse(&out[1].payload).unwrap().info_hashes,
+            [[20; 20]]
+        );
+    }
+
+    #[test]
+    fn test_on_datagram() {
+        let mut lsd = Lsd::new(6881, &mut XorShift32::new(1));
+        lsd.add_torrent([1; 20], &info(false));
+        let announce = Announce {
+            port: 7000,
+            info_hashes: [[1; 20], [2; 20]].to_vec(),
+            cookie: None,
+        }
+        .encode(LSD_GROUP_V4);
+
+        let peers = lsd
+            .on_datagram(addr("192.168.1.7:6771"), &announce, 0)
+            .unwrap();
+        assert_eq!(
+            peers,
+            [LsdPeer {
+                info_hash: [1; 20],
+                addr: addr("192.168.1.7:7000"),
+            }]
+        );
+
+        // repeated too fast
+        assert!(
+            lsd.on_datagram(addr("192.168.1.7:6771"), &announce, 10_000)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            lsd.on_datagram(addr("192.168.1.8:6771"), &announce, 10_000)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            lsd.on_datagram(
+                addr("192.168.1.7:6771"),
+                &announce,
+                MIN_ANNOUNCE_INTERVAL_MS
+            )
+            .unwrap()
+            .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_own_announces_are_ignored() {
+        let mut lsd = Lsd::new(6881, &mut XorShift32::new(1));
+        lsd.add_torrent([1; 20], &info(false));
+        let out = lsd.poll_announce(0);
+
+        assert!(
+            lsd.on_datagram(addr("192.168.1.42:6771"), &out[0].payload, 0)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_update_candidates() {
+        let peers = [
+            LsdPeer {
+                info_hash: [1; 20],
+                addr: addr("192.168.1.7:7000"),
+            },
+            LsdPeer {
+                info_hash: [2; 20],
+                addr: addr("192.168.1.8:7000"),
+            },
+        ];
+        let mut candidates = Candidates::new(10);
+
+        assert_eq!(update_candidates(&peers, &[1; 20], &mut candidates), 1);
+        assert_eq!(
+            candidates.pop().map(|c| (c.addr, c.source)),
+            Some((addr("192.168.1.7:7000"), Source::Lsd))
+        );
+    }
+}
@@ -16,6 +16,8 @@ pub struct Info<'a> {
     pub name: &'a str,
     pub pieces: &'a [InfoHash],
     pub length: u32,
+    /// peers may only be found through the tracker, not through DHT or PEX (BEP 27)
+    pub private: bool,
 }
 
 impl<'a> MetaInfoFile<'a> {
@@ -69,6 +71,7 @@ impl<'a> Info<'a> {
         let mut name = None;
         let mut pieces = None;
         let mut length = None;
+        let mut private = false;
 
         p.expect_dict_start()?;
 
@@ -93,6 +96,9 @@ impl<'a> Info<'a> {
                 "name" => {
                     name = Some(p.parse_str()?);
                 }
+                "private" => {
+                    private = p.parse_int()? == 1;
+                }
                 _ => {
                     // Unknown field: skip the value
                     p.skip_any()?;
@@ -106,6 +112,7 @@ impl<'a> Info<'a> {
             name: name.ok_or(Error::UnknownField)?,
             pieces: pieces.ok_or(Error::UnknownField)?,
             length: length.ok_or(Error::UnknownField)?,
+            private,
         })
     }
 }
@@ -196,6 +203,14 @@ mod tests {
 
         assert_eq!(meta.name, "log");
         assert_eq!(meta.length, 100);
+        assert!(!meta.private);
+    }
+
+    #[test]
+    fn test_private_torrent() {
+        let input = b"d6:lengthi100e4:name3:log12:piece lengthi16e6:pieces20:123456789012345678907:privatei1ee";
+
+        assert!(Info::parse(input).unwrap().private);
     }
 
     #[test]
@@ -1,5 +1,15 @@
-use alloc::string::String;
-use core::fmt::Write;
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::wifi::{UdpError, UdpSocket};
+
+/// Size of a compact IPv4 peer: 4 byte address followed by a 2 byte port.
+pub const COMPACT_PEER_V4_LEN: usize = 6;
+/// Size of a compact IPv6 peer: 16 byte address followed by a 2 byte port.
+pub const COMPACT_PEER_V6_LEN: usize = 18;
 
 pub fn percent_encode(bytes: &[u8]) -> String {
     let mut encoded = String::with_capacity(60);
@@ -9,6 +19,91 @@ pub fn percent_encode(bytes: &[u8]) -> String {
     encoded
 }
 
+/// Splits an url like `udp://tracker.example.com:6969/announce` into its host and port.
+/// IPv6 literals have to be put in brackets, e.g. `udp://[::1]:6969`.
+pub fn host_and_port(url: &str) -> Option<(&str, u16)> {
+    let (_scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?']).next()?;
+
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, port) = rest.split_once(']')?;
+        (host, port.strip_prefix(':')?)
+    } else {
+        authority.rsplit_once(':')?
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
+
+/// Decodes a compact IPv4 peer list (BEP 23).
+/// Returns `None` if the length isn't a multiple of [`COMPACT_PEER_V4_LEN`].
+pub fn decode_compact_peers_v4(bytes: &[u8]) -> Option<Vec<SocketAddr>> {
+    let (peers, rest) = bytes.as_chunks::<COMPACT_PEER_V4_LEN>();
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(
+        peers
+            .iter()
+            .map(|peer| {
+                let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+                SocketAddr::new(ip.into(), u16::from_be_bytes([peer[4], peer[5]]))
+            })
+            .collect(),
+    )
+}
+
+/// Decodes a compact IPv6 peer list (BEP 7).
+/// Returns `None` if the length isn't a multiple of [`COMPACT_PEER_V6_LEN`].
+pub fn decode_compact_peers_v6(bytes: &[u8]) -> Option<Vec<SocketAddr>> {
+    let (peers, rest) = bytes.as_chunks::<COMPACT_PEER_V6_LEN>();
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(
+        peers
+            .iter()
+            .map(|peer| {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&peer[..16]).unwrap());
+                SocketAddr::new(ip.into(), u16::from_be_bytes([peer[16], peer[17]]))
+            })
+            .collect(),
+    )
+}
+
+/// Appends the compact representation of a peer, 6 bytes for IPv4 and 18 bytes for IPv6.
+pub fn encode_compact_peer(peer: &SocketAddr, out: &mut Vec<u8>) {
+    match peer {
+        SocketAddr::V4(peer) => out.extend_from_slice(&peer.ip().octets()),
+        SocketAddr::V6(peer) => out.extend_from_slice(&peer.ip().octets()),
+    }
+    out.extend_from_slice(&peer.port().to_be_bytes());
+}
+
+/// A datagram to send, e.g. by the DHT or LSD.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub addr: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// Sends the datagrams, skipping those to unreachable addresses.
+pub async fn send_all<S: UdpSocket>(
+    socket: &mut S,
+    datagrams: Vec<Datagram>,
+) -> Result<(), UdpError> {
+    for datagram in datagrams {
+        match socket.send_to(&datagram.payload, datagram.addr).await {
+            Ok(()) | Err(UdpError::NoRoute) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::core::{InfoHash, PeerId};
@@ -28,4 +123,34 @@ mod tests {
             "%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01"
         );
     }
+
+    #[test]
+    fn test_host_and_port() {
+        assert_eq!(
+            host_and_port("udp://tracker.opentrackr.org:1337/announce"),
+            Some(("tracker.opentrackr.org", 1337))
+        );
+        assert_eq!(host_and_port("udp://[::1]:6969"), Some(("::1", 6969)));
+        assert_eq!(host_and_port("udp://tracker.example.com/announce"), None);
+        assert_eq!(host_and_port("tracker.example.com:80"), None);
+    }
+
+    #[test]
+    fn test_compact_peers_roundtrip() {
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+
+        let mut compact = Vec::new();
+        encode_compact_peer(&v4, &mut compact);
+        assert_eq!(compact, [10, 0, 0, 1, 0x1a, 0xe1]);
+        assert_eq!(decode_compact_peers_v4(&compact).unwrap(), [v4]);
+
+        let mut compact = Vec::new();
+        encode_compact_peer(&v6, &mut compact);
+        assert_eq!(compact.len(), COMPACT_PEER_V6_LEN);
+        assert_eq!(decode_compact_peers_v6(&compact).unwrap(), [v6]);
+
+        assert!(decode_compact_peers_v4(&compact[..7]).is_none());
+        assert!(decode_compact_peers_v6(&compact[..17]).is_none());
+    }
 }
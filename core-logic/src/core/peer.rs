@@ -0,0 +1,136 @@
+//! The peer wire protocol (BEP 3) that is spoken between two peers over TCP.
+
+use core::net::SocketAddr;
+use defmt::Format;
+
+use crate::{
+    core::peer::{
+        handshake::{HANDSHAKE_LEN, Handshake, HandshakeError},
+        listener::HANDSHAKE_TIMEOUT,
+        mse::{EncryptionPolicy, MseError, MseStream},
+    },
+    rng::Rng,
+    wifi::{TcpConnection, TcpError, WifiStack},
+};
+
+pub mod ban;
+pub mod candidates;
+pub mod connection;
+pub mod extension;
+pub mod fast;
+pub mod handshake;
+pub mod listener;
+pub mod message;
+pub mod mse;
+pub mod pex;
+pub mod pipeline;
+pub mod upload;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PeerError {
+    Tcp(TcpError),
+    Handshake(HandshakeError),
+    Mse(MseError),
+    /// The peer asked for a torrent we don't have.
+    UnknownTorrent,
+    /// All connection slots are taken.
+    TooManyConnections,
+}
+
+impl From<TcpError> for PeerError {
+    fn from(err: TcpError) -> Self {
+        PeerError::Tcp(err)
+    }
+}
+
+impl From<HandshakeError> for PeerError {
+    fn from(err: HandshakeError) -> Self {
+        PeerError::Handshake(err)
+    }
+}
+
+impl From<MseError> for PeerError {
+    fn from(err: MseError) -> Self {
+        match err {
+            MseError::Tcp(err) => PeerError::Tcp(err),
+            MseError::UnknownTorrent => PeerError::UnknownTorrent,
+            err => PeerError::Mse(err),
+        }
+    }
+}
+
+/// Sends our handshake on an outgoing connection and waits for the peer's answer,
+/// which has to be about the same torrent.
+pub async fn exchange_handshakes<C: TcpConnection>(
+    connection: &mut C,
+    ours: &Handshake,
+) -> Result<Handshake, PeerError> {
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    connection.write_all(ours.encode(&mut buf)?).await?;
+
+    connection.read_exact(&mut buf).await?;
+    Ok(Handshake::decode_for(&buf, &ours.info_hash)?)
+}
+
+/// A peer to connect to with [`connect_peer`].
+pub struct OutgoingPeer<'h> {
+    pub remote: SocketAddr,
+    /// the key exchange to try first
+    pub policy: EncryptionPolicy,
+    /// ours, for the torrent we want from the peer
+    pub handshake: &'h Handshake,
+}
+
+/// Connects to a peer, runs the key exchange and exchanges handshakes, then hands
+/// the connection and the peer's handshake to `handle`.
+///
+/// If the key exchange fails, e.g. because the peer doesn't know MSE and drops the
+/// connection, we reconnect with [`EncryptionPolicy::fallback`]. The retry needs
+/// the buffers again, which is why the connection goes to `handle` instead of
+/// being returned.
+pub async fn connect_peer<W, R, F, T>(
+    wifi: &W,
+    peer: &OutgoingPeer<'_>,
+    rng: &mut R,
+    rx_buf: &mut [u8],
+    tx_buf: &mut [u8],
+    handle: F,
+) -> Result<T, PeerError>
+where
+    W: WifiStack,
+    R: Rng,
+    F: AsyncFnOnce(MseStream<W::Connection<'_>>, Handshake) -> T,
+{
+    let mut policy = peer.policy;
+    loop {
+        let connection = wifi
+            .connect(peer.remote, HANDSHAKE_TIMEOUT, rx_buf, tx_buf)
+            .await?;
+        let mut connection = MseStream::new(connection);
+        connection.set_timeout(Some(HANDSHAKE_TIMEOUT));
+        if let Err(err) = connection
+            .initiate(policy, &peer.handshake.info_hash, rng)
+            .await
+        {
+            connection.close().await;
+            match policy.fallback() {
+                Some(fallback) => {
+                    defmt::debug!("key exchange with {} failed: {}", peer.remote, err);
+                    policy = fallback;
+                    continue;
+                }
+                None => return Err(err.into()),
+            }
+        }
+        return match exchange_handshakes(&mut connection, peer.handshake).await {
+            Ok(theirs) => {
+                connection.set_timeout(None);
+                Ok(handle(connection, theirs).await)
+            }
+            Err(err) => {
+                connection.close().await;
+                Err(err)
+            }
+        };
+    }
+}
@@ -0,0 +1,402 @@
+//! Banning peers that send corrupt data.
+//!
+//! With only a few connection slots, a single peer sending bad blocks could keep a
+//! piece from ever completing. Once a failed piece has been downloaded again, its
+//! blocks are compared to the good ones, which exposes the peer that sent the bad
+//! ones. Until then nobody is blamed: with two connections, banning the honest peer
+//! that shared a piece with a poisoner would stall the download. Only failed pieces
+//! that are never compared hand out strikes to all of their contributors.
+
+use alloc::vec::Vec;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::{
+    core::verify::{BlockDigest, Contribution, Sha1Digest},
+    fs::{FileSystem, FsError, VolumeMgr},
+};
+
+/// Where [`save_ban_list`] keeps the banned addresses, in the current directory.
+pub const BAN_LIST_FILE: &str = "BANS.DAT";
+
+/// How many failed pieces we keep the block hashes of, waiting for a good download.
+/// The contributors of the oldest one get a strike when it has to make room.
+const MAX_SUSPECT_PIECES: usize = 8;
+
+const TAG_V4: u8 = 4;
+const TAG_V6: u8 = 6;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BanPolicy {
+    /// Failed pieces that were never downloaded again a peer may contribute to
+    /// before it is banned.
+    pub max_strikes: u8,
+    /// Banned addresses that are remembered, the oldest ban is lifted beyond that.
+    pub max_banned: usize,
+    /// Ban a peer that sent every block of a failed piece right away, without
+    /// waiting for the piece to be downloaded again: nobody else can have
+    /// corrupted it.
+    pub ban_sole_contributor: bool,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        Self {
+            max_strikes: 3,
+            max_banned: 64,
+            ban_sole_contributor: true,
+        }
+    }
+}
+
+/// A failed piece, who sent which block of it and what the block hashed to.
+struct SuspectPiece {
+    index: u32,
+    blocks: Vec<(Contribution, Sha1Digest)>,
+}
+
+/// Banned addresses and the strikes of peers that aren't banned (yet).
+///
+/// Bans are keyed by IP address rather than by connection, so a banned peer can't
+/// come back by reconnecting. The list can be stored with [`save_ban_list`] to
+/// survive a restart.
+pub struct BanList {
+    policy: BanPolicy,
+    /// oldest ban first
+    banned: Vec<IpAddr>,
+    strikes: Vec<(IpAddr, u8)>,
+    suspects: Vec<SuspectPiece>,
+}
+
+impl BanList {
+    pub fn new(policy: BanPolicy) -> Self {
+        Self {
+            policy,
+            banned: Vec::new(),
+            strikes: Vec::new(),
+            suspects: Vec::new(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    pub fn banned(&self) -> &[IpAddr] {
+        &self.banned
+    }
+
+    pub fn strikes(&self, ip: &IpAddr) -> u8 {
+        self.strikes
+            .iter()
+            .find(|(peer, _)| peer == ip)
+            .map_or(0, |(_, strikes)| *strikes)
+    }
+
+    /// Bans an address, returns `false` if it already was.
+    pub fn ban(&mut self, ip: IpAddr) -> bool {
+        if self.is_banned(&ip) {
+            return false;
+        }
+        defmt::info!("banning {}", ip);
+        if self.banned.len() >= self.policy.max_banned {
+            self.banned.remove(0);
+        }
+        self.banned.push(ip);
+        self.strikes.retain(|(peer, _)| *peer != ip);
+        true
+    }
+
+    /// Keeps the blocks of a piece that failed verification, to compare them once
+    /// the piece passes. `blocks` are the hashes of the failed download from
+    /// [`hash_blocks`](crate::core::verify::hash_blocks), taken before the piece is
+    /// downloaded again. Returns the peers that got banned, which should be
+    /// disconnected.
+    pub fn on_piece_failed(
+        &mut self,
+        index: u32,
+        contributions: &[Contribution],
+        blocks: &[BlockDigest],
+    ) -> Vec<IpAddr> {
+        let mut banned = Vec::new();
+        if self.policy.ban_sole_contributor
+            && let [peer] = distinct_peers(contributions)[..]
+        {
+            if self.ban(peer) {
+                banned.push(peer);
+            }
+            return banned;
+        }
+
+        let hashed = contributions.iter().filter_map(|contribution| {
+            let block = blocks.iter().find(|b| b.begin == contribution.begin)?;
+            Some((*contribution, block.digest))
+        });
+        match self.suspects.iter_mut().find(|s| s.index == index) {
+            Some(suspect) => suspect.blocks.extend(hashed),
+            None => {
+                if self.suspects.len() >= MAX_SUSPECT_PIECES {
+                    // it won't be compared anymore, so everyone involved is suspicious
+                    let oldest = self.suspects.remove(0);
+                    let contributions: Vec<Contribution> =
+                        oldest.blocks.iter().map(|(c, _)| *c).collect();
+                    for peer in distinct_peers(&contributions) {
+                        if self.strike(peer) >= self.policy.max_strikes && self.ban(peer) {
+                            banned.push(peer);
+                        }
+                    }
+                }
+                self.suspects.push(SuspectPiece {
+                    index,
+                    blocks: hashed.collect(),
+                });
+            }
+        }
+        banned
+    }
+
+    /// Whether a piece failed before, so its next download has to be hashed block by
+    /// block for [`BanList::on_piece_passed`].
+    pub fn is_suspect(&self, index: u32) -> bool {
+        self.suspects.iter().any(|s| s.index == index)
+    }
+
+    /// Compares a piece that passed verification to its failed downloads, `blocks`
+    /// are the hashes of the good download.
+    ///
+    /// Peers that sent a block that differs from the good one are banned right away.
+    /// Returns the peers that got banned.
+    pub fn on_piece_passed(&mut self, index: u32, blocks: &[BlockDigest]) -> Vec<IpAddr> {
+        let Some(position) = self.suspects.iter().position(|s| s.index == index) else {
+            return Vec::new();
+        };
+        let suspect = self.suspects.swap_remove(position);
+
+        let mut guilty = Vec::new();
+        for (old, digest) in &suspect.blocks {
+            let Some(good) = blocks.iter().find(|b| b.begin == old.begin) else {
+                continue;
+            };
+            if good.digest != *digest && !guilty.contains(&old.peer) {
+                guilty.push(old.peer);
+            }
+        }
+        guilty.retain(|peer| self.ban(*peer));
+        guilty
+    }
+
+    /// Serializes the banned addresses, the strikes are not kept.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        for ip in &self.banned {
+            match ip {
+                IpAddr::V4(ip) => {
+                    out.push(TAG_V4);
+                    out.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    out.push(TAG_V6);
+                    out.extend_from_slice(&ip.octets());
+                }
+            }
+        }
+    }
+
+    /// Restores a list written by [`BanList::encode`].
+    pub fn decode(mut bytes: &[u8], policy: BanPolicy) -> Option<Self> {
+        let mut list = Self::new(policy);
+        while let [tag, rest @ ..] = bytes {
+            let ip = match *tag {
+                TAG_V4 => {
+                    let octets: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+                    bytes = &rest[4..];
+                    IpAddr::V4(Ipv4Addr::from(octets))
+                }
+                TAG_V6 => {
+                    let octets: [u8; 16] = rest.get(..16)?.try_into().ok()?;
+                    bytes = &rest[16..];
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                _ => return None,
+            };
+            list.ban(ip);
+        }
+        Some(list)
+    }
+
+    fn strike(&mut self, ip: IpAddr) -> u8 {
+        match self.strikes.iter_mut().find(|(peer, _)| *peer == ip) {
+            Some((_, strikes)) => {
+                *strikes += 1;
+                *strikes
+            }
+            None => {
+                self.strikes.push((ip, 1));
+                1
+            }
+        }
+    }
+}
+
+/// Writes the banned addresses to [`BAN_LIST_FILE`] in the current directory.
+pub fn save_ban_list<V: VolumeMgr>(
+    fs: &mut FileSystem<V>,
+    bans: &BanList,
+) -> Result<(), FsError<V>> {
+    let mut bytes = Vec::new();
+    bans.encode(&mut bytes);
+    fs.write_file(BAN_LIST_FILE, &bytes)
+}
+
+/// Restores the bans saved with [`save_ban_list`], limited by `policy`.
+/// `None` if there are none or the file is corrupt.
+pub fn load_ban_list<V: VolumeMgr>(fs: &mut FileSystem<V>, policy: BanPolicy) -> Option<BanList> {
+    let bytes = fs.read_file(BAN_LIST_FILE).ok()?;
+    BanList::decode(&bytes, policy)
+}
+
+fn distinct_peers(contributions: &[Contribution]) -> Vec<IpAddr> {
+    let mut peers = Vec::new();
+    for contribution in contributions {
+        if !peers.contains(&contribution.peer) {
+            peers.push(contribution.peer);
+        }
+    }
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HONEST: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const POISONER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    const OTHER: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    fn contribution(begin: u32, peer: IpAddr) -> Contribution {
+        Contribution { begin, peer }
+    }
+
+    fn block(begin: u32, digest: u8) -> BlockDigest {
+        BlockDigest {
+            begin,
+            digest: [digest; 20],
+        }
+    }
+
+    #[test]
+    fn test_single_contributor_is_banned() {
+        let mut bans = BanList::new(BanPolicy::default());
+        let failed = [contribution(0, POISONER), contribution(1, POISONER)];
+        let blocks = [block(0, 1), block(1, 1)];
+
+        assert_eq!(bans.on_piece_failed(0, &failed, &blocks), [POISONER]);
+        assert!(bans.is_banned(&POISONER));
+        assert!(!bans.is_suspect(0));
+    }
+
+    #[test]
+    fn test_single_contributor_waits_for_comparison_if_configured() {
+        let mut bans = BanList::new(BanPolicy {
+            ban_sole_contributor: false,
+            ..BanPolicy::default()
+        });
+        let failed = [contribution(0, POISONER), contribution(1, POISONER)];
+
+        assert!(
+            bans.on_piece_failed(0, &failed, &[block(0, 1), block(1, 1)])
+                .is_empty()
+        );
+        assert!(bans.is_suspect(0));
+        assert_eq!(
+            bans.on_piece_passed(0, &[block(0, 1), block(1, 2)]),
+            [POISONER]
+        );
+    }
+
+    #[test]
+    fn test_strikes() {
+        let mut bans = BanList::new(BanPolicy {
+            max_strikes: 2,
+            ..BanPolicy::default()
+        });
+        let failed = [contribution(0, HONEST), contribution(1, POISONER)];
+        let blocks = [block(0, 1), block(1, 1)];
+
+        // the honest peer keeps its place while the pieces fail
+        assert!(bans.on_piece_failed(0, &failed, &blocks).is_empty());
+        assert!(bans.on_piece_failed(1, &failed, &blocks).is_empty());
+        assert_eq!(bans.strikes(&HONEST), 0);
+
+        // and is cleared once a piece is downloaded again
+        assert_eq!(
+            bans.on_piece_passed(0, &[block(0, 1), block(1, 2)]),
+            [POISONER]
+        );
+        assert!(!bans.is_banned(&HONEST));
+        assert_eq!(bans.strikes(&HONEST), 0);
+    }
+
+    #[test]
+    fn test_pieces_never_compared_give_strikes() {
+        let mut bans = BanList::new(BanPolicy {
+            max_strikes: 2,
+            ..BanPolicy::default()
+        });
+        let failed = [contribution(0, HONEST), contribution(1, POISONER)];
+        let blocks = [block(0, 1), block(1, 1)];
+
+        for index in 0..MAX_SUSPECT_PIECES as u32 {
+            assert!(bans.on_piece_failed(index, &failed, &blocks).is_empty());
+        }
+        let index = MAX_SUSPECT_PIECES as u32;
+        assert!(bans.on_piece_failed(index, &failed, &blocks).is_empty());
+        assert_eq!(bans.strikes(&POISONER), 1);
+        assert_eq!(
+            bans.on_piece_failed(index + 1, &failed, &blocks),
+            [HONEST, POISONER]
+        );
+    }
+
+    #[test]
+    fn test_redownload_exposes_poisoner() {
+        let mut bans = BanList::new(BanPolicy::default());
+        let failed = [contribution(0, HONEST), contribution(1, POISONER)];
+        bans.on_piece_failed(7, &failed, &[block(0, 1), block(1, 2)]);
+
+        let passed = [block(0, 1), block(1, 1)];
+        assert_eq!(bans.on_piece_passed(7, &passed), [POISONER]);
+        assert!(!bans.is_banned(&HONEST));
+
+        // nothing is left to compare
+        assert!(!bans.is_suspect(7));
+        assert!(bans.on_piece_passed(7, &passed).is_empty());
+    }
+
+    #[test]
+    fn test_oldest_ban_is_lifted() {
+        let mut bans = BanList::new(BanPolicy {
+            max_banned: 2,
+            ..BanPolicy::default()
+        });
+        bans.ban(HONEST);
+        bans.ban(POISONER);
+        assert!(!bans.ban(POISONER));
+        bans.ban(OTHER);
+        assert_eq!(bans.banned(), [POISONER, OTHER]);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut bans = BanList::new(BanPolicy::default());
+        bans.ban(POISONER);
+        bans.ban(OTHER);
+
+        let mut bytes = Vec::new();
+        bans.encode(&mut bytes);
+        assert_eq!(bytes.len(), 5 + 17);
+
+        let decoded = BanList::decode(&bytes, BanPolicy::default()).unwrap();
+        assert_eq!(decoded.banned(), [POISONER, OTHER]);
+        assert!(BanList::decode(&bytes[..bytes.len() - 1], BanPolicy::default()).is_none());
+        assert!(BanList::decode(&[5], BanPolicy::default()).is_none());
+    }
+}
@@ -0,0 +1,120 @@
+//! Addresses of peers we could connect to.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::net::SocketAddr;
+use defmt::Format;
+
+/// Where we learned about a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Source {
+    Tracker,
+    /// Peer Exchange (BEP 11)
+    Pex,
+    Dht,
+    /// Local Service Discovery (BEP 14)
+    Lsd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+    pub source: Source,
+}
+
+/// A bounded queue of peers to connect to, without duplicates.
+///
+/// Swarms can be much larger than what fits into the heap, so once the queue
+/// is full new addresses are dropped until connection attempts drain it.
+pub struct Candidates {
+    queue: VecDeque<Candidate>,
+    max: usize,
+}
+
+impl Candidates {
+    pub fn new(max: usize) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            max,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.queue.iter().any(|candidate| candidate.addr == *addr)
+    }
+
+    /// Queues a peer, returns `false` if it is known already or the queue is full.
+    pub fn add(&mut self, addr: SocketAddr, source: Source) -> bool {
+        if self.queue.len() >= self.max || self.contains(&addr) {
+            return false;
+        }
+        self.queue.push_back(Candidate { addr, source });
+        true
+    }
+
+    /// Queues several peers, returns how many were new.
+    pub fn extend(&mut self, addrs: impl IntoIterator<Item = SocketAddr>, source: Source) -> usize {
+        addrs
+            .into_iter()
+            .filter(|addr| self.add(*addr, source))
+            .count()
+    }
+
+    /// Forgets a peer, e.g. because another peer told us it left the swarm.
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.queue.retain(|candidate| candidate.addr != *addr);
+    }
+
+    /// The peer to connect to next, oldest first.
+    pub fn pop(&mut self) -> Option<Candidate> {
+        self.queue.pop_front()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Candidate> {
+        self.queue.iter()
+    }
+
+    /// Removes all candidates matching `f`, e.g. banned ones.
+    pub fn retain(&mut self, mut f: impl FnMut(&Candidate) -> bool) {
+        self.queue.retain(|candidate| f(candidate));
+    }
+
+    pub fn to_vec(&self) -> Vec<Candidate> {
+        self.queue.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let mut candidates = Candidates::new(2);
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+
+        assert!(candidates.add(a, Source::Tracker));
+        assert!(!candidates.add(a, Source::Pex));
+        assert_eq!(candidates.extend([b, c], Source::Pex), 1);
+        assert_eq!(candidates.len(), 2);
+
+        assert_eq!(
+            candidates.pop(),
+            Some(Candidate {
+                addr: a,
+                source: Source::Tracker
+            })
+        );
+        candidates.remove(&b);
+        assert!(candidates.is_empty());
+    }
+}
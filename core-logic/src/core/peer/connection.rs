@@ -0,0 +1,945 @@
+//! State of a single peer connection, independent of the socket it runs on.
+
+use alloc::{borrow::Cow, vec::Vec};
+use defmt::Format;
+
+use crate::core::{
+    bitfield::{Bitfield, BitfieldError},
+    metainfo::Info,
+    peer::{
+        extension::REQUEST_QUEUE_LEN,
+        message::{BLOCK_LEN, BlockInfo, Message},
+    },
+};
+
+/// Send a keep-alive if we haven't sent anything for this long.
+pub const KEEP_ALIVE_INTERVAL_MS: u64 = 120_000;
+/// Drop the peer if it hasn't sent anything for this long.
+pub const IDLE_TIMEOUT_MS: u64 = 180_000;
+/// Consider the peer snubbing us if it doesn't answer any of our requests for this long.
+pub const SNUB_TIMEOUT_MS: u64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ProtocolError {
+    /// A bitfield was sent after other messages.
+    UnexpectedBitfield,
+    /// The bitfield has the wrong length or spare bits set.
+    InvalidBitfield(BitfieldError),
+    /// The piece index is outside of the torrent.
+    InvalidPiece(u32),
+    /// The peer requested a block larger than [`BLOCK_LEN`] or outside of its piece.
+    InvalidRequest(BlockInfo),
+    /// A Fast Extension message, although the extension wasn't negotiated.
+    FastExtensionDisabled,
+    /// An extended message, although the extension protocol wasn't negotiated.
+    ExtensionProtocolDisabled,
+}
+
+/// Something the owner of the connection has to act on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// The peer choked us, all our outstanding requests are void.
+    /// With the Fast Extension requests stay valid until they are rejected.
+    Choked {
+        dropped: Vec<BlockInfo>,
+    },
+    Unchoked,
+    Interested,
+    NotInterested,
+    /// The peer completed a piece it didn't have before.
+    Have(u32),
+    /// The peer told us which pieces it has, with a bitfield, `have all` or `have none`.
+    Bitfield,
+    /// A block we requested arrived.
+    Block {
+        block: BlockInfo,
+        data: &'a [u8],
+        /// time since the block was requested
+        latency_ms: u64,
+    },
+    /// The peer wants a block from us.
+    Request(BlockInfo),
+    /// The peer's DHT node listens on this port.
+    Port(u16),
+    /// The peer recommends a piece.
+    Suggest(u32),
+    /// The peer won't answer one of our requests.
+    Rejected(BlockInfo),
+    /// The piece may be requested even while the peer is choking us.
+    AllowedFast(u32),
+    /// The peer asked for a block while choked or with [`REQUEST_QUEUE_LEN`]
+    /// requests pending, it should get a [`PeerConnection::reject`] for it.
+    MustReject(BlockInfo),
+    /// A message of the extension protocol, see [`ExtensionProtocol`].
+    ///
+    /// [`ExtensionProtocol`]: crate::core::peer::extension::ExtensionProtocol
+    Extended {
+        id: u8,
+        payload: &'a [u8],
+    },
+}
+
+/// What [`PeerConnection::poll_timers`] asks the owner to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum TimerAction {
+    SendKeepAlive,
+    /// The peer has been silent for [`IDLE_TIMEOUT_MS`] and should be dropped.
+    Disconnect,
+}
+
+/// The four choke/interest flags, the peer's pieces and the requests in flight.
+///
+/// Incoming messages are fed to [`PeerConnection::on_message`]; outgoing messages are
+/// produced by methods like [`PeerConnection::request`], which return `None` if the
+/// message would be pointless, e.g. unchoking a peer that isn't choked.
+/// All times are milliseconds since an arbitrary, monotonic epoch.
+pub struct PeerConnection {
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    peer_pieces: Bitfield,
+    /// whether any message has been received, a bitfield is only allowed as the first one
+    received_any: bool,
+    /// requests we sent and the time we sent them
+    our_requests: Vec<(BlockInfo, u64)>,
+    /// requests the peer sent us that we haven't served yet, at most [`REQUEST_QUEUE_LEN`]
+    peer_requests: Vec<BlockInfo>,
+    /// piece length and total length of the torrent, to check the peer's requests
+    layout: Option<(u32, u64)>,
+    last_received: u64,
+    last_sent: u64,
+    /// the last time one of our requests was answered, or we started waiting
+    last_block: u64,
+    /// whether both sides support the Fast Extension (BEP 6)
+    fast: bool,
+    /// pieces we may request while choked
+    allowed_fast: Vec<u32>,
+    /// pieces the peer may request while we choke it
+    allowed_for_peer: Vec<u32>,
+    /// whether both sides support the extension protocol (BEP 10)
+    extension_protocol: bool,
+}
+
+impl PeerConnection {
+    /// A connection for a torrent whose pieces are known.
+    pub fn for_info(info: &Info, now_ms: u64) -> Self {
+        Self {
+            layout: Some((info.piece_length, info.length as u64)),
+            ..Self::new(info.pieces.len() as u32, now_ms)
+        }
+    }
+
+    /// Both sides start out choked and not interested.
+    ///
+    /// Without the lengths of the pieces, requests of the peer are only checked
+    /// against [`BLOCK_LEN`], see [`PeerConnection::for_info`].
+    pub fn new(num_pieces: u32, now_ms: u64) -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer_pieces: Bitfield::new(num_pieces),
+            received_any: false,
+            our_requests: Vec::new(),
+            peer_requests: Vec::new(),
+            layout: None,
+            last_received: now_ms,
+            last_sent: now_ms,
+            last_block: now_ms,
+            fast: false,
+            allowed_fast: Vec::new(),
+            allowed_for_peer: Vec::new(),
+            extension_protocol: false,
+        }
+    }
+
+    /// Enables the Fast Extension, if both handshakes had its reserved bit set.
+    pub fn with_fast_extension(mut self) -> Self {
+        self.fast = true;
+        self
+    }
+
+    pub fn fast_extension(&self) -> bool {
+        self.fast
+    }
+
+    /// Enables the extension protocol, if both handshakes had its reserved bit set.
+    pub fn with_extension_protocol(mut self) -> Self {
+        self.extension_protocol = true;
+        self
+    }
+
+    pub fn extension_protocol(&self) -> bool {
+        self.extension_protocol
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    /// The pieces the peer announced so far.
+    pub fn peer_pieces(&self) -> &Bitfield {
+        &self.peer_pieces
+    }
+
+    /// The pieces we can request right now: all of the peer's pieces while it
+    /// unchokes us, only the allowed fast ones while it chokes us.
+    pub fn requestable_pieces(&self) -> Cow<'_, Bitfield> {
+        if !self.peer_choking {
+            return Cow::Borrowed(&self.peer_pieces);
+        }
+        let mut pieces = Bitfield::new(self.peer_pieces.num_pieces());
+        for index in &self.allowed_fast {
+            if self.peer_pieces.has(*index) {
+                pieces.set(*index);
+            }
+        }
+        Cow::Owned(pieces)
+    }
+
+    /// Requests we sent that haven't been answered yet.
+    pub fn our_requests(&self) -> impl Iterator<Item = &BlockInfo> {
+        self.our_requests.iter().map(|(block, _)| block)
+    }
+
+    /// Requests of the peer we haven't served yet.
+    pub fn peer_requests(&self) -> &[BlockInfo] {
+        &self.peer_requests
+    }
+
+    /// Whether the peer has left our requests unanswered for [`SNUB_TIMEOUT_MS`].
+    pub fn is_snubbing(&self, now_ms: u64) -> bool {
+        !self.our_requests.is_empty() && now_ms.saturating_sub(self.last_block) >= SNUB_TIMEOUT_MS
+    }
+
+    /// Updates the state with a message received from the peer.
+    pub fn on_message<'a>(
+        &mut self,
+        message: &Message<'a>,
+        now_ms: u64,
+    ) -> Result<Option<Event<'a>>, ProtocolError> {
+        let first_message = !core::mem::replace(&mut self.received_any, true);
+        self.last_received = now_ms;
+
+        let event = match *message {
+            Message::KeepAlive => None,
+            Message::Choke => {
+                self.peer_choking = true;
+                let dropped = if self.fast {
+                    Vec::new()
+                } else {
+                    self.our_requests
+                        .drain(..)
+                        .map(|(block, _)| block)
+                        .collect()
+                };
+                Some(Event::Choked { dropped })
+            }
+            Message::Unchoke => {
+                self.peer_choking = false;
+                self.last_block = now_ms;
+                Some(Event::Unchoked)
+            }
+            Message::Interested => {
+                self.peer_interested = true;
+                Some(Event::Interested)
+            }
+            Message::NotInterested => {
+                self.peer_interested = false;
+                Some(Event::NotInterested)
+            }
+            Message::Have { index } => {
+                if index >= self.peer_pieces.num_pieces() {
+                    return Err(ProtocolError::InvalidPiece(index));
+                }
+                self.peer_pieces.set(index).then_some(Event::Have(index))
+            }
+            Message::Bitfield(bitfield) => {
+                if !first_message {
+                    return Err(ProtocolError::UnexpectedBitfield);
+                }
+                self.peer_pieces = Bitfield::from_bytes(bitfield, self.peer_pieces.num_pieces())
+                    .map_err(ProtocolError::InvalidBitfield)?;
+                Some(Event::Bitfield)
+            }
+            Message::HaveAll | Message::HaveNone => {
+                self.require_fast()?;
+                if !first_message {
+                    return Err(ProtocolError::UnexpectedBitfield);
+                }
+                let num_pieces = self.peer_pieces.num_pieces();
+                self.peer_pieces = match message {
+                    Message::HaveAll => Bitfield::full(num_pieces),
+                    _ => Bitfield::new(num_pieces),
+                };
+                Some(Event::Bitfield)
+            }
+            Message::Request(block) => {
+                if block.index >= self.peer_pieces.num_pieces() {
+                    return Err(ProtocolError::InvalidPiece(block.index));
+                }
+                if !self.is_valid_request(&block) {
+                    return Err(ProtocolError::InvalidRequest(block));
+                }
+                let choked = self.am_choking && !self.allowed_for_peer.contains(&block.index);
+                let queue_full = self.peer_requests.len() >= REQUEST_QUEUE_LEN as usize;
+                if self.peer_requests.contains(&block) {
+                    // already queued, it is served once
+                    None
+                } else if choked || queue_full {
+                    // without the Fast Extension the request is dropped silently, e.g.
+                    // one that was on its way when we choked the peer
+                    self.fast.then_some(Event::MustReject(block))
+                } else {
+                    self.peer_requests.push(block);
+                    Some(Event::Request(block))
+                }
+            }
+            Message::Piece { index, begin, data } => {
+                let block = BlockInfo {
+                    index,
+                    begin,
+                    length: data.len() as u32,
+                };
+                match self.our_requests.iter().position(|(b, _)| *b == block) {
+                    Some(position) => {
+                        let (_, sent) = self.our_requests.swap_remove(position);
+                        self.last_block = now_ms;
+                        Some(Event::Block {
+                            block,
+                            data,
+                            latency_ms: now_ms.saturating_sub(sent),
+                        })
+                    }
+                    None => {
+                        // e.g. a block we cancelled that was already on its way
+                        defmt::debug!("ignoring unrequested block {}", block);
+                        None
+                    }
+                }
+            }
+            Message::Cancel(block) => {
+                self.peer_requests.retain(|b| *b != block);
+                None
+            }
+            Message::Port(port) => Some(Event::Port(port)),
+            Message::SuggestPiece { index } => {
+                self.require_fast()?;
+                (index < self.peer_pieces.num_pieces()).then_some(Event::Suggest(index))
+            }
+            Message::RejectRequest(block) => {
+                self.require_fast()?;
+                let position = self.our_requests.iter().position(|(b, _)| *b == block);
+                position.map(|position| {
+                    self.our_requests.swap_remove(position);
+                    Event::Rejected(block)
+                })
+            }
+            Message::AllowedFast { index } => {
+                self.require_fast()?;
+                if index >= self.peer_pieces.num_pieces() {
+                    return Err(ProtocolError::InvalidPiece(index));
+                }
+                if !self.allowed_fast.contains(&index) {
+                    self.allowed_fast.push(index);
+                }
+                Some(Event::AllowedFast(index))
+            }
+            Message::Extended { id, payload } => {
+                self.require_extension_protocol()?;
+                Some(Event::Extended { id, payload })
+            }
+            Message::Unknown { .. } => None,
+        };
+
+        Ok(event)
+    }
+
+    /// Chokes the peer. Returns the `choke` followed by the rejects the Fast Extension
+    /// requires for pending requests, or nothing if the peer is choked already.
+    pub fn choke(&mut self, now_ms: u64) -> Vec<Message<'static>> {
+        if self.am_choking {
+            return Vec::new();
+        }
+        self.am_choking = true;
+        self.last_sent = now_ms;
+
+        let mut messages = alloc::vec![Message::Choke];
+        if self.fast {
+            // requests for allowed fast pieces are still served
+            let allowed = &self.allowed_for_peer;
+            self.peer_requests.retain(|block| {
+                let keep = allowed.contains(&block.index);
+                if !keep {
+                    messages.push(Message::RejectRequest(*block));
+                }
+                keep
+            });
+        } else {
+            // choking discards all requests of the peer
+            self.peer_requests.clear();
+        }
+        messages
+    }
+
+    pub fn unchoke(&mut self, now_ms: u64) -> Option<Message<'static>> {
+        if !self.am_choking {
+            return None;
+        }
+        self.am_choking = false;
+        self.sent(Message::Unchoke, now_ms)
+    }
+
+    pub fn interested(&mut self, now_ms: u64) -> Option<Message<'static>> {
+        if self.am_interested {
+            return None;
+        }
+        self.am_interested = true;
+        self.sent(Message::Interested, now_ms)
+    }
+
+    pub fn not_interested(&mut self, now_ms: u64) -> Option<Message<'static>> {
+        if !self.am_interested {
+            return None;
+        }
+        self.am_interested = false;
+        self.sent(Message::NotInterested, now_ms)
+    }
+
+    /// Requests a block, unless the peer is choking us, doesn't have the piece
+    /// or the block has already been requested.
+    pub fn request(&mut self, block: BlockInfo, now_ms: u64) -> Option<Message<'static>> {
+        if !self.requestable_pieces().has(block.index)
+            || self.our_requests.iter().any(|(b, _)| *b == block)
+        {
+            return None;
+        }
+        if self.our_requests.is_empty() {
+            self.last_block = now_ms;
+        }
+        self.our_requests.push((block, now_ms));
+        self.sent(Message::Request(block), now_ms)
+    }
+
+    pub fn cancel(&mut self, block: BlockInfo, now_ms: u64) -> Option<Message<'static>> {
+        let position = self.our_requests.iter().position(|(b, _)| *b == block)?;
+        self.our_requests.swap_remove(position);
+        self.sent(Message::Cancel(block), now_ms)
+    }
+
+    /// Marks a request of the peer as served, before the `piece` message is sent.
+    /// Returns `false` if the peer cancelled it in the meantime.
+    pub fn serve(&mut self, block: &BlockInfo, now_ms: u64) -> bool {
+        let Some(position) = self.peer_requests.iter().position(|b| b == block) else {
+            return false;
+        };
+        self.peer_requests.remove(position);
+        self.last_sent = now_ms;
+        true
+    }
+
+    /// Refuses a request of the peer, e.g. because the SD card can't keep up.
+    /// Only possible with the Fast Extension.
+    pub fn reject(&mut self, block: &BlockInfo, now_ms: u64) -> Option<Message<'static>> {
+        if !self.fast {
+            return None;
+        }
+        self.peer_requests.retain(|b| b != block);
+        self.sent(Message::RejectRequest(*block), now_ms)
+    }
+
+    /// Lets the peer request a piece while we choke it.
+    pub fn allow_fast(&mut self, index: u32, now_ms: u64) -> Option<Message<'static>> {
+        if !self.fast || self.allowed_for_peer.contains(&index) {
+            return None;
+        }
+        self.allowed_for_peer.push(index);
+        self.sent(Message::AllowedFast { index }, now_ms)
+    }
+
+    /// The first message to send: which pieces we have, as compact as possible.
+    pub fn announce_pieces<'b>(&mut self, have: &'b Bitfield, now_ms: u64) -> Message<'b> {
+        self.last_sent = now_ms;
+        match (self.fast, have.is_complete(), have.is_empty()) {
+            (true, true, _) => Message::HaveAll,
+            (true, _, true) => Message::HaveNone,
+            _ => Message::Bitfield(have.as_bytes()),
+        }
+    }
+
+    /// Tells the peer that we completed a piece.
+    pub fn have(&mut self, index: u32, now_ms: u64) -> Message<'static> {
+        self.last_sent = now_ms;
+        Message::Have { index }
+    }
+
+    /// Requests that have been in flight for longer than `timeout_ms`.
+    pub fn timed_out_requests(
+        &self,
+        timeout_ms: u64,
+        now_ms: u64,
+    ) -> impl Iterator<Item = &BlockInfo> {
+        self.our_requests
+            .iter()
+            .filter(move |(_, sent)| now_ms.saturating_sub(*sent) >= timeout_ms)
+            .map(|(block, _)| block)
+    }
+
+    /// Checks the keep-alive and idle timers, should be called regularly.
+    pub fn poll_timers(&mut self, now_ms: u64) -> Option<TimerAction> {
+        if now_ms.saturating_sub(self.last_received) >= IDLE_TIMEOUT_MS {
+            return Some(TimerAction::Disconnect);
+        }
+        if now_ms.saturating_sub(self.last_sent) >= KEEP_ALIVE_INTERVAL_MS {
+            self.last_sent = now_ms;
+            return Some(TimerAction::SendKeepAlive);
+        }
+        None
+    }
+
+    /// Whether the block is at most [`BLOCK_LEN`] long and lies inside its piece.
+    fn is_valid_request(&self, block: &BlockInfo) -> bool {
+        if block.length == 0 || block.length > BLOCK_LEN {
+            return false;
+        }
+        let Some((piece_length, length)) = self.layout else {
+            return true;
+        };
+        let piece_start = block.index as u64 * piece_length as u64;
+        let piece_len = length.saturating_sub(piece_start).min(piece_length as u64);
+        block.begin as u64 + block.length as u64 <= piece_len
+    }
+
+    fn require_fast(&self) -> Result<(), ProtocolError> {
+        if self.fast {
+            Ok(())
+        } else {
+            Err(ProtocolError::FastExtensionDisabled)
+        }
+    }
+
+    fn require_extension_protocol(&self) -> Result<(), ProtocolError> {
+        if self.extension_protocol {
+            Ok(())
+        } else {
+            Err(ProtocolError::ExtensionProtocolDisabled)
+        }
+    }
+
+    fn sent(&mut self, message: Message<'static>, now_ms: u64) -> Option<Message<'static>> {
+        self.last_sent = now_ms;
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const BLOCK: BlockInfo = BlockInfo {
+        index: 1,
+        begin: 0,
+        length: 4,
+    };
+
+    fn unchoked_peer_with_all_pieces() -> PeerConnection {
+        let mut peer = PeerConnection::new(10, 0);
+        peer.on_message(&Message::Bitfield(&[0xff, 0xc0]), 0)
+            .unwrap();
+        peer.on_message(&Message::Unchoke, 0).unwrap();
+        peer
+    }
+
+    #[test]
+    fn test_initial_state() {
+        let peer = PeerConnection::new(10, 0);
+        assert!(peer.am_choking());
+        assert!(!peer.am_interested());
+        assert!(peer.peer_choking());
+        assert!(!peer.peer_interested());
+        assert!(!peer.peer_pieces().has(0));
+    }
+
+    #[test]
+    fn test_flags_only_change_once() {
+        let mut peer = PeerConnection::new(10, 0);
+        assert_eq!(peer.interested(0), Some(Message::Interested));
+        assert_eq!(peer.interested(0), None);
+        assert_eq!(peer.unchoke(0), Some(Message::Unchoke));
+        assert_eq!(peer.unchoke(0), None);
+        assert_eq!(peer.choke(0), [Message::Choke]);
+        assert_eq!(peer.not_interested(0), Some(Message::NotInterested));
+
+        peer.on_message(&Message::Interested, 0).unwrap();
+        assert!(peer.peer_interested());
+        peer.on_message(&Message::NotInterested, 0).unwrap();
+        assert!(!peer.peer_interested());
+    }
+
+    #[test]
+    fn test_bitfield_and_have() {
+        let mut peer = PeerConnection::new(10, 0);
+        assert_eq!(
+            peer.on_message(&Message::Bitfield(&[0xff]), 0),
+            Err(ProtocolError::InvalidBitfield(BitfieldError::InvalidLength))
+        );
+
+        let mut peer = PeerConnection::new(10, 0);
+        peer.on_message(&Message::Bitfield(&[0x80, 0x40]), 0)
+            .unwrap();
+        assert!(peer.peer_pieces().has(0));
+        assert!(peer.peer_pieces().has(9));
+        assert!(!peer.peer_pieces().has(1));
+
+        assert_eq!(
+            peer.on_message(&Message::Have { index: 1 }, 0),
+            Ok(Some(Event::Have(1)))
+        );
+        assert!(peer.peer_pieces().has(1));
+        assert_eq!(peer.on_message(&Message::Have { index: 1 }, 0), Ok(None));
+        assert_eq!(
+            peer.on_message(&Message::Have { index: 10 }, 0),
+            Err(ProtocolError::InvalidPiece(10))
+        );
+        assert_eq!(
+            peer.on_message(&Message::Bitfield(&[0, 0]), 0),
+            Err(ProtocolError::UnexpectedBitfield)
+        );
+    }
+
+    #[test]
+    fn test_request_and_block() {
+        let mut peer = PeerConnection::new(10, 0);
+        peer.on_message(&Message::Have { index: 1 }, 0).unwrap();
+        // still choked
+        assert_eq!(peer.request(BLOCK, 0), None);
+
+        peer.on_message(&Message::Unchoke, 0).unwrap();
+        assert_eq!(peer.request(BLOCK, 0), Some(Message::Request(BLOCK)));
+        assert_eq!(peer.request(BLOCK, 0), None);
+        assert_eq!(peer.our_requests().count(), 1);
+
+        let piece = Message::Piece {
+            index: 1,
+            begin: 0,
+            data: b"data",
+        };
+        assert_eq!(
+            peer.on_message(&piece, 10),
+            Ok(Some(Event::Block {
+                block: BLOCK,
+                data: b"data",
+                latency_ms: 10
+            }))
+        );
+        assert_eq!(peer.our_requests().count(), 0);
+        // a second copy wasn't requested anymore
+        assert_eq!(peer.on_message(&piece, 10), Ok(None));
+    }
+
+    #[test]
+    fn test_choke_drops_requests() {
+        let mut peer = unchoked_peer_with_all_pieces();
+        peer.request(BLOCK, 0).unwrap();
+
+        assert_eq!(
+            peer.on_message(&Message::Choke, 0),
+            Ok(Some(Event::Choked {
+                dropped: vec![BLOCK]
+            }))
+        );
+        assert_eq!(peer.our_requests().count(), 0);
+
+        let mut peer = unchoked_peer_with_all_pieces();
+        peer.request(BLOCK, 0).unwrap();
+        assert_eq!(peer.cancel(BLOCK, 0), Some(Message::Cancel(BLOCK)));
+        assert_eq!(peer.cancel(BLOCK, 0), None);
+    }
+
+    #[test]
+    fn test_peer_requests() {
+        let mut peer = PeerConnection::new(10, 0);
+        // e.g. sent before our choke arrived
+        assert_eq!(peer.on_message(&Message::Request(BLOCK), 0), Ok(None));
+        assert!(peer.peer_requests().is_empty());
+
+        peer.unchoke(0);
+        assert_eq!(
+            peer.on_message(&Message::Request(BLOCK), 0),
+            Ok(Some(Event::Request(BLOCK)))
+        );
+        assert_eq!(peer.peer_requests(), &[BLOCK]);
+        // a duplicate isn't served twice
+        assert_eq!(peer.on_message(&Message::Request(BLOCK), 0), Ok(None));
+        assert_eq!(peer.peer_requests(), &[BLOCK]);
+        peer.on_message(&Message::Cancel(BLOCK), 0).unwrap();
+        assert!(!peer.serve(&BLOCK, 0));
+
+        peer.on_message(&Message::Request(BLOCK), 0).unwrap();
+        assert!(peer.serve(&BLOCK, 0));
+        assert!(peer.peer_requests().is_empty());
+
+        peer.on_message(&Message::Request(BLOCK), 0).unwrap();
+        peer.choke(0);
+        assert!(peer.peer_requests().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        const HASHES: [[u8; 20]; 2] = [[0; 20]; 2];
+        let info = Info {
+            piece_length: 2 * BLOCK_LEN,
+            name: "test",
+            pieces: &HASHES,
+            length: 3 * BLOCK_LEN,
+            private: false,
+        };
+        let mut peer = PeerConnection::for_info(&info, 0);
+        peer.unchoke(0);
+
+        for block in [
+            BlockInfo {
+                index: 0,
+                begin: 0,
+                length: BLOCK_LEN + 1,
+            },
+            BlockInfo {
+                index: 0,
+                begin: BLOCK_LEN + 1,
+                length: BLOCK_LEN,
+            },
+            // the last piece is shorter
+            BlockInfo {
+                index: 1,
+                begin: BLOCK_LEN,
+                length: 1,
+            },
+            BlockInfo {
+                index: 1,
+                begin: 0,
+                length: 0,
+            },
+        ] {
+            assert_eq!(
+                peer.on_message(&Message::Request(block), 0),
+                Err(ProtocolError::InvalidRequest(block))
+            );
+        }
+        let last = BlockInfo {
+            index: 1,
+            begin: 0,
+            length: BLOCK_LEN,
+        };
+        assert_eq!(
+            peer.on_message(&Message::Request(last), 0),
+            Ok(Some(Event::Request(last)))
+        );
+    }
+
+    #[test]
+    fn test_peer_request_queue_is_bounded() {
+        let block = |begin| BlockInfo { begin, ..BLOCK };
+        let mut peer = PeerConnection::new(10, 0);
+        peer.unchoke(0);
+        for begin in 0..REQUEST_QUEUE_LEN {
+            peer.on_message(&Message::Request(block(begin)), 0).unwrap();
+        }
+
+        let extra = block(REQUEST_QUEUE_LEN);
+        assert_eq!(peer.on_message(&Message::Request(extra), 0), Ok(None));
+        assert_eq!(peer.peer_requests().len(), REQUEST_QUEUE_LEN as usize);
+
+        let mut peer = PeerConnection::new(10, 0).with_fast_extension();
+        peer.unchoke(0);
+        for begin in 0..REQUEST_QUEUE_LEN {
+            peer.on_message(&Message::Request(block(begin)), 0).unwrap();
+        }
+        assert_eq!(
+            peer.on_message(&Message::Request(extra), 0),
+            Ok(Some(Event::MustReject(extra)))
+        );
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut peer = unchoked_peer_with_all_pieces();
+        assert_eq!(peer.poll_timers(KEEP_ALIVE_INTERVAL_MS - 1), None);
+        assert_eq!(
+            peer.poll_timers(KEEP_ALIVE_INTERVAL_MS),
+            Some(TimerAction::SendKeepAlive)
+        );
+        assert_eq!(peer.poll_timers(KEEP_ALIVE_INTERVAL_MS + 1), None);
+
+        peer.on_message(&Message::KeepAlive, 100_000).unwrap();
+        assert_eq!(
+            peer.poll_timers(100_000 + IDLE_TIMEOUT_MS - 1),
+            Some(TimerAction::SendKeepAlive)
+        );
+        assert_eq!(
+            peer.poll_timers(100_000 + IDLE_TIMEOUT_MS),
+            Some(TimerAction::Disconnect)
+        );
+    }
+
+    #[test]
+    fn test_snubbing() {
+        let mut peer = unchoked_peer_with_all_pieces();
+        assert!(!peer.is_snubbing(SNUB_TIMEOUT_MS * 2));
+
+        peer.request(BLOCK, 1000).unwrap();
+        assert!(!peer.is_snubbing(1000 + SNUB_TIMEOUT_MS - 1));
+        assert!(peer.is_snubbing(1000 + SNUB_TIMEOUT_MS));
+        assert_eq!(
+            peer.timed_out_requests(SNUB_TIMEOUT_MS, 1000 + SNUB_TIMEOUT_MS)
+                .collect::<Vec<_>>(),
+            [&BLOCK]
+        );
+
+        let piece = Message::Piece {
+            index: 1,
+            begin: 0,
+            data: b"data",
+        };
+        peer.on_message(&piece, 1000 + SNUB_TIMEOUT_MS).unwrap();
+        assert!(!peer.is_snubbing(1000 + SNUB_TIMEOUT_MS));
+    }
+
+    #[test]
+    fn test_fast_messages_need_negotiation() {
+        let mut peer = PeerConnection::new(10, 0);
+        assert_eq!(
+            peer.on_message(&Message::HaveAll, 0),
+            Err(ProtocolError::FastExtensionDisabled)
+        );
+        assert_eq!(peer.reject(&BLOCK, 0), None);
+        assert_eq!(peer.allow_fast(1, 0), None);
+    }
+
+    #[test]
+    fn test_extended_messages_need_negotiation() {
+        let extended = Message::Extended {
+            id: 1,
+            payload: b"x",
+        };
+        let mut peer = PeerConnection::new(10, 0);
+        assert_eq!(
+            peer.on_message(&extended, 0),
+            Err(ProtocolError::ExtensionProtocolDisabled)
+        );
+
+        let mut peer = PeerConnection::new(10, 0).with_extension_protocol();
+        assert_eq!(
+            peer.on_message(&extended, 0),
+            Ok(Some(Event::Extended {
+                id: 1,
+                payload: b"x"
+            }))
+        );
+    }
+
+    #[test]
+    fn test_have_all_and_have_none() {
+        let mut peer = PeerConnection::new(10, 0).with_fast_extension();
+        assert_eq!(
+            peer.on_message(&Message::HaveAll, 0),
+            Ok(Some(Event::Bitfield))
+        );
+        assert!(peer.peer_pieces().is_complete());
+        assert_eq!(
+            peer.on_message(&Message::HaveNone, 0),
+            Err(ProtocolError::UnexpectedBitfield)
+        );
+
+        let mut peer = PeerConnection::new(10, 0).with_fast_extension();
+        assert_eq!(
+            peer.announce_pieces(&Bitfield::new(10), 0),
+            Message::HaveNone
+        );
+        assert_eq!(
+            peer.announce_pieces(&Bitfield::full(10), 0),
+            Message::HaveAll
+        );
+        let mut partial = Bitfield::new(10);
+        partial.set(0);
+        assert_eq!(
+            peer.announce_pieces(&partial, 0),
+            Message::Bitfield(&[0x80, 0])
+        );
+    }
+
+    #[test]
+    fn test_fast_choke_keeps_requests_until_rejected() {
+        let mut peer = PeerConnection::new(10, 0).with_fast_extension();
+        peer.on_message(&Message::HaveAll, 0).unwrap();
+        peer.on_message(&Message::Unchoke, 0).unwrap();
+        peer.request(BLOCK, 0).unwrap();
+
+        assert_eq!(
+            peer.on_message(&Message::Choke, 0),
+            Ok(Some(Event::Choked { dropped: vec![] }))
+        );
+        assert_eq!(peer.our_requests().count(), 1);
+        assert_eq!(
+            peer.on_message(&Message::RejectRequest(BLOCK), 0),
+            Ok(Some(Event::Rejected(BLOCK)))
+        );
+        assert_eq!(peer.our_requests().count(), 0);
+    }
+
+    #[test]
+    fn test_allowed_fast_while_choked() {
+        let mut peer = PeerConnection::new(10, 0).with_fast_extension();
+        peer.on_message(&Message::HaveAll, 0).unwrap();
+        assert_eq!(peer.request(BLOCK, 0), None);
+
+        assert_eq!(
+            peer.on_message(&Message::AllowedFast { index: 1 }, 0),
+            Ok(Some(Event::AllowedFast(1)))
+        );
+        assert!(peer.requestable_pieces().has(1));
+        assert!(!peer.requestable_pieces().has(2));
+        assert_eq!(peer.request(BLOCK, 0), Some(Message::Request(BLOCK)));
+    }
+
+    #[test]
+    fn test_rejecting_requests_of_the_peer() {
+        let mut peer = PeerConnection::new(10, 0).with_fast_extension();
+        let other = BlockInfo { index: 2, ..BLOCK };
+        assert_eq!(
+            peer.on_message(&Message::Request(BLOCK), 0),
+            Ok(Some(Event::MustReject(BLOCK)))
+        );
+
+        peer.allow_fast(1, 0).unwrap();
+        assert_eq!(
+            peer.on_message(&Message::Request(BLOCK), 0),
+            Ok(Some(Event::Request(BLOCK)))
+        );
+
+        peer.unchoke(0);
+        peer.on_message(&Message::Request(other), 0).unwrap();
+        assert_eq!(
+            peer.choke(0),
+            [Message::Choke, Message::RejectRequest(other)]
+        );
+        assert_eq!(peer.peer_requests(), &[BLOCK]);
+
+        assert_eq!(peer.reject(&BLOCK, 0), Some(Message::RejectRequest(BLOCK)));
+        assert!(peer.peer_requests().is_empty());
+    }
+}
@@ -0,0 +1,373 @@
+//! The extension protocol (BEP 10).
+//!
+//! After the regular handshake, peers that set the extension bit exchange a bencoded
+//! extended handshake. Its `m` dictionary maps extension names to the message ids
+//! the sender wants to receive them with, so every extension message is sent with
+//! the id the *receiving* side chose.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use bencode::{BencodeParser, BencodeWriter};
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use defmt::Format;
+
+use crate::core::peer::message::Message;
+
+/// The extended message id of the handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Our name and version, sent as `v`.
+pub const CLIENT_VERSION: &str = concat!("minitorrent ", env!("CARGO_PKG_VERSION"));
+
+/// The number of outstanding requests we queue for a peer, sent as `reqq`.
+pub const REQUEST_QUEUE_LEN: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ExtensionError {
+    /// The extended handshake isn't a valid bencoded dictionary.
+    InvalidHandshake,
+    /// A message of an extension couldn't be parsed.
+    InvalidMessage,
+    /// The peer used an id we never handed out.
+    UnknownId(u8),
+}
+
+impl From<bencode::Error> for ExtensionError {
+    fn from(_: bencode::Error) -> Self {
+        ExtensionError::InvalidMessage
+    }
+}
+
+/// The contents of an extended handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    /// extension names and the ids to send them with, id 0 disables an extension
+    pub extensions: Vec<(String, u8)>,
+    /// client name and version
+    pub client: Option<String>,
+    /// the TCP port the sender listens on
+    pub port: Option<u16>,
+    /// how many outstanding requests the sender queues
+    pub reqq: Option<u32>,
+    /// our address as the sender sees it
+    pub yourip: Option<IpAddr>,
+    /// size of the info dictionary, for `ut_metadata`
+    pub metadata_size: Option<u32>,
+}
+
+impl ExtensionHandshake {
+    pub fn parse(payload: &[u8]) -> Result<Self, ExtensionError> {
+        Self::parse_dict(payload).map_err(|_| ExtensionError::InvalidHandshake)
+    }
+
+    fn parse_dict(payload: &[u8]) -> Result<Self, bencode::Error> {
+        let mut p = BencodeParser::new(payload);
+        let mut handshake = Self::default();
+
+        p.expect_dict_start()?;
+        while !p.match_dict_end() {
+            match p.parse_str_bytes()? {
+                b"m" => {
+                    p.expect_dict_start()?;
+                    while !p.match_dict_end() {
+                        let name = p.parse_str()?;
+                        let id = p.parse_int()?;
+                        if let Ok(id) = u8::try_from(id) {
+                            handshake.extensions.push((name.to_string(), id));
+                        }
+                    }
+                }
+                b"v" => handshake.client = Some(p.parse_str()?.to_string()),
+                b"p" => handshake.port = u16::try_from(p.parse_int()?).ok(),
+                b"reqq" => handshake.reqq = u32::try_from(p.parse_int()?).ok(),
+                b"yourip" => {
+                    handshake.yourip = match *p.parse_str_bytes()? {
+                        [a, b, c, d] => Some(IpAddr::V4(Ipv4Addr::new(a, b, c, d))),
+                        ref octets => <[u8; 16]>::try_from(octets)
+                            .ok()
+                            .map(|octets| IpAddr::V6(Ipv6Addr::from(octets))),
+                    }
+                }
+                b"metadata_size" => handshake.metadata_size = u32::try_from(p.parse_int()?).ok(),
+                _ => p.skip_any()?,
+            }
+        }
+        Ok(handshake)
+    }
+
+    /// Bencodes the handshake, keys sorted as bencode requires.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mut w = BencodeWriter::new(out);
+        w.dict_start();
+
+        let mut extensions: Vec<&(String, u8)> = self.extensions.iter().collect();
+        extensions.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        w.str("m").dict_start();
+        for (name, id) in extensions {
+            w.str(name).int(*id as i64);
+        }
+        w.end();
+
+        if let Some(metadata_size) = self.metadata_size {
+            w.str("metadata_size").int(metadata_size as i64);
+        }
+        if let Some(port) = self.port {
+            w.str("p").int(port as i64);
+        }
+        if let Some(reqq) = self.reqq {
+            w.str("reqq").int(reqq as i64);
+        }
+        if let Some(client) = &self.client {
+            w.str("v").str(client);
+        }
+        match self.yourip {
+            Some(IpAddr::V4(ip)) => {
+                w.str("yourip").bytes(&ip.octets());
+            }
+            Some(IpAddr::V6(ip)) => {
+                w.str("yourip").bytes(&ip.octets());
+            }
+            None => {}
+        }
+        w.end();
+    }
+
+    /// The id the sender wants to receive `name` with, `None` if it doesn't support it.
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, id)| *id)
+            .filter(|id| *id != 0)
+    }
+}
+
+/// An extension that handles its messages, e.g. `ut_pex`.
+pub trait ExtensionHandler {
+    /// The name in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Handles a message the peer sent for this extension.
+    fn on_message(&mut self, payload: &[u8], now_ms: u64) -> Result<(), ExtensionError>;
+}
+
+/// The extensions of a single connection: the ids we handed out and the ids the peer
+/// wants to receive its messages with.
+pub struct ExtensionProtocol {
+    /// our extensions, the id of each is its position plus one
+    local: Vec<&'static str>,
+    /// the peer's extended handshake, once it arrived
+    remote: Option<ExtensionHandshake>,
+}
+
+impl ExtensionProtocol {
+    /// Assigns our ids to the extensions named `names`, those of the handlers that
+    /// are passed to [`ExtensionProtocol::on_message`].
+    pub fn new(names: &[&'static str]) -> Self {
+        Self {
+            local: names.to_vec(),
+            remote: None,
+        }
+    }
+
+    /// The id the peer should send `name` with.
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        let position = self.local.iter().position(|n| *n == name)?;
+        Some(position as u8 + 1)
+    }
+
+    /// The peer's extended handshake, once received.
+    pub fn remote(&self) -> Option<&ExtensionHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// The id to send a message of `name` with, if the peer supports it.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.as_ref()?.id_of(name)
+    }
+
+    /// Our extended handshake. The returned bytes are the payload of a
+    /// [`Message::Extended`] with [`HANDSHAKE_ID`].
+    pub fn handshake(
+        &self,
+        port: u16,
+        yourip: Option<IpAddr>,
+        metadata_size: Option<u32>,
+    ) -> Vec<u8> {
+        let handshake = ExtensionHandshake {
+            extensions: self
+                .local
+                .iter()
+                .map(|name| (name.to_string(), self.local_id(name).unwrap()))
+                .collect(),
+            client: Some(CLIENT_VERSION.to_string()),
+            port: Some(port),
+            reqq: Some(REQUEST_QUEUE_LEN),
+            yourip,
+            metadata_size,
+        };
+        let mut payload = Vec::new();
+        handshake.encode(&mut payload);
+        payload
+    }
+
+    /// Routes an extended message to the handler it was sent for.
+    /// `handlers` have to be those of the names the protocol was created with.
+    pub fn on_message(
+        &mut self,
+        id: u8,
+        payload: &[u8],
+        handlers: &mut [&mut dyn ExtensionHandler],
+        now_ms: u64,
+    ) -> Result<(), ExtensionError> {
+        if id == HANDSHAKE_ID {
+            // a later handshake updates the earlier one
+            self.remote = Some(ExtensionHandshake::parse(payload)?);
+            return Ok(());
+        }
+        let name = self
+            .local
+            .get(id as usize - 1)
+            .ok_or(ExtensionError::UnknownId(id))?;
+        let handler = handlers
+            .iter_mut()
+            .find(|handler| handler.name() == *name)
+            .ok_or(ExtensionError::UnknownId(id))?;
+        handler.on_message(payload, now_ms)
+    }
+
+    /// Wraps the payload of an extension in a message for the peer,
+    /// `None` if the peer doesn't support the extension.
+    pub fn message<'p>(&self, name: &str, payload: &'p [u8]) -> Option<Message<'p>> {
+        Some(Message::Extended {
+            id: self.remote_id(name)?,
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts its messages.
+    struct Counter {
+        name: &'static str,
+        received: usize,
+    }
+
+    impl ExtensionHandler for Counter {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn on_message(&mut self, payload: &[u8], _now_ms: u64) -> Result<(), ExtensionError> {
+            if payload.is_empty() {
+                return Err(ExtensionError::InvalidMessage);
+            }
+            self.received += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_handshake() {
+        let payload =
+            b"d1:md11:ut_metadatai3e6:ut_pexi1e7:lt_donti0ee13:metadata_sizei31235e1:pi6881e4:reqqi500e1:v13:qBittorrent 56:yourip4:\x0a\x00\x00\x01e";
+        let handshake = ExtensionHandshake::parse(payload).unwrap();
+
+        assert_eq!(handshake.id_of("ut_pex"), Some(1));
+        assert_eq!(handshake.id_of("ut_metadata"), Some(3));
+        assert_eq!(handshake.id_of("lt_dont"), None);
+        assert_eq!(handshake.id_of("missing"), None);
+        assert_eq!(handshake.client.as_deref(), Some("qBittorrent 5"));
+        assert_eq!(handshake.port, Some(6881));
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.metadata_size, Some(31235));
+        assert_eq!(handshake.yourip, Some("10.0.0.1".parse().unwrap()));
+
+        assert_eq!(
+            ExtensionHandshake::parse(b"li1ee"),
+            Err(ExtensionError::InvalidHandshake)
+        );
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let handshake = ExtensionHandshake {
+            extensions: alloc::vec![("ut_pex".to_string(), 1), ("ut_metadata".to_string(), 2)],
+            client: Some(CLIENT_VERSION.to_string()),
+            port: Some(6881),
+            reqq: Some(16),
+            yourip: Some("2001:db8::1".parse().unwrap()),
+            metadata_size: Some(1234),
+        };
+        let mut payload = Vec::new();
+        handshake.encode(&mut payload);
+        assert!(payload.starts_with(b"d1:md11:ut_metadatai2e6:ut_pexi1ee13:metadata_size"));
+
+        let mut parsed = ExtensionHandshake::parse(&payload).unwrap();
+        parsed.extensions.sort();
+        let mut expected = handshake.clone();
+        expected.extensions.sort();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn test_routing_by_negotiated_id() {
+        let mut pex = Counter {
+            name: "ut_pex",
+            received: 0,
+        };
+        let mut metadata = Counter {
+            name: "ut_metadata",
+            received: 0,
+        };
+        let mut protocol = ExtensionProtocol::new(&[pex.name(), metadata.name()]);
+        assert_eq!(protocol.local_id("ut_metadata"), Some(2));
+
+        // the peer announces different ids, which we have to use for sending
+        protocol
+            .on_message(
+                HANDSHAKE_ID,
+                b"d1:md11:ut_metadatai7e6:ut_pexi9eee",
+                &mut [&mut pex, &mut metadata],
+                0,
+            )
+            .unwrap();
+        assert_eq!(
+            protocol.message("ut_pex", b"x"),
+            Some(Message::Extended {
+                id: 9,
+                payload: b"x"
+            })
+        );
+        assert_eq!(protocol.message("lt_donthave", b"x"), None);
+
+        // while it sends with the ids we handed out
+        protocol
+            .on_message(2, b"x", &mut [&mut pex, &mut metadata], 0)
+            .unwrap();
+        assert_eq!(metadata.received, 1);
+        assert_eq!(pex.received, 0);
+        assert_eq!(
+            protocol.on_message(3, b"x", &mut [&mut pex, &mut metadata], 0),
+            Err(ExtensionError::UnknownId(3))
+        );
+    }
+
+    #[test]
+    fn test_our_handshake() {
+        let protocol = ExtensionProtocol::new(&["ut_pex"]);
+        let payload = protocol.handshake(6881, Some("10.0.0.2".parse().unwrap()), None);
+
+        let handshake = ExtensionHandshake::parse(&payload).unwrap();
+        assert_eq!(handshake.id_of("ut_pex"), Some(1));
+        assert_eq!(handshake.client.as_deref(), Some(CLIENT_VERSION));
+        assert_eq!(handshake.reqq, Some(REQUEST_QUEUE_LEN));
+        assert_eq!(handshake.yourip, Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(handshake.metadata_size, None);
+    }
+}
@@ -0,0 +1,73 @@
+//! The allowed fast set of the Fast Extension (BEP 6).
+//!
+//! Peers that just connected and have no pieces yet can't offer anything in return,
+//! so they'd wait for an optimistic unchoke. The allowed fast set gives them a few
+//! pieces they may download while choked. The set only depends on the peer's address
+//! and the torrent, so it is the same no matter how often the peer reconnects.
+
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+use sha1_smol::Sha1;
+
+use crate::core::InfoHash;
+
+/// The size of the allowed fast set we hand out.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// Generates the `k` pieces a peer at `ip` may request while choked.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &InfoHash, num_pieces: u32, k: usize) -> Vec<u32> {
+    let k = k.min(num_pieces as usize);
+    let mut set = Vec::with_capacity(k);
+    if k == 0 {
+        return set;
+    }
+
+    // the last octet is masked, so peers behind the same /24 get the same set
+    let mut input = [0u8; 24];
+    input[..4].copy_from_slice(&(ip.to_bits() & 0xffff_ff00).to_be_bytes());
+    input[4..].copy_from_slice(info_hash);
+
+    let mut x = Sha1::from(input).digest().bytes();
+    loop {
+        for chunk in x.chunks(4) {
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+            if set.len() == k {
+                return set;
+            }
+        }
+        x = Sha1::from(x).digest().bytes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example from BEP 6.
+    #[test]
+    fn test_allowed_fast_set() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    #[test]
+    fn test_small_torrent() {
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let mut set = allowed_fast_set(ip, &[1; 20], 3, ALLOWED_FAST_COUNT);
+        set.sort();
+        assert_eq!(set, [0, 1, 2]);
+        assert!(allowed_fast_set(ip, &[1; 20], 0, ALLOWED_FAST_COUNT).is_empty());
+    }
+}
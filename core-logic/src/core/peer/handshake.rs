@@ -0,0 +1,181 @@
+use defmt::Format;
+
+use crate::core::{InfoHash, PeerId};
+
+/// The protocol string every handshake starts with.
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Length of a complete handshake: pstrlen, pstr, reserved, info hash and peer id.
+pub const HANDSHAKE_LEN: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum HandshakeError {
+    /// The provided buffer can't hold [`HANDSHAKE_LEN`] bytes.
+    BufferTooSmall,
+    /// Not enough bytes have been received yet.
+    Incomplete,
+    /// The peer doesn't speak the BitTorrent protocol.
+    InvalidProtocol,
+    /// The peer wants to talk about a different torrent.
+    InfoHashMismatch,
+}
+
+/// Protocol extensions announced in the reserved bytes of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Feature {
+    /// Mainline DHT (BEP 5)
+    Dht,
+    /// Fast Extension (BEP 6)
+    Fast,
+    /// Extension Protocol (BEP 10)
+    Extension,
+}
+
+impl Feature {
+    /// The byte index and bit mask of the feature within the reserved bytes.
+    const fn position(self) -> (usize, u8) {
+        match self {
+            Feature::Dht => (7, 0x01),
+            Feature::Fast => (7, 0x04),
+            Feature::Extension => (5, 0x10),
+        }
+    }
+}
+
+/// The 8 reserved bytes of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct Reserved(pub [u8; 8]);
+
+impl Reserved {
+    pub fn with(mut self, feature: Feature) -> Self {
+        let (byte, mask) = feature.position();
+        self.0[byte] |= mask;
+        self
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        let (byte, mask) = feature.position();
+        self.0[byte] & mask != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Format)]
+pub struct Handshake {
+    pub reserved: Reserved,
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+}
+
+impl Handshake {
+    pub fn new(reserved: Reserved, info_hash: InfoHash, peer_id: PeerId) -> Self {
+        Self {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Writes the handshake to the beginning of `buf` and returns the written part.
+    pub fn encode<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8], HandshakeError> {
+        let buf = buf
+            .get_mut(..HANDSHAKE_LEN)
+            .ok_or(HandshakeError::BufferTooSmall)?;
+
+        buf[0] = PROTOCOL.len() as u8;
+        buf[1..20].copy_from_slice(PROTOCOL);
+        buf[20..28].copy_from_slice(&self.reserved.0);
+        buf[28..48].copy_from_slice(&self.info_hash);
+        buf[48..68].copy_from_slice(&self.peer_id);
+
+        Ok(buf)
+    }
+
+    /// Reads a handshake from the beginning of `buf`, regardless of which torrent it is for.
+    ///
+    /// Incoming connections use this to find out which torrent the peer is interested in.
+    pub fn decode(buf: &[u8]) -> Result<Self, HandshakeError> {
+        // validate the protocol as early as possible to drop non-BitTorrent connections
+        let received = buf.get(1..).unwrap_or_default();
+        let received = &received[..received.len().min(PROTOCOL.len())];
+        if buf
+            .first()
+            .is_some_and(|&len| usize::from(len) != PROTOCOL.len())
+            || !PROTOCOL.starts_with(received)
+        {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+        let buf = buf.get(..HANDSHAKE_LEN).ok_or(HandshakeError::Incomplete)?;
+
+        Ok(Self {
+            reserved: Reserved(buf[20..28].try_into().unwrap()),
+            info_hash: buf[28..48].try_into().unwrap(),
+            peer_id: buf[48..68].try_into().unwrap(),
+        })
+    }
+
+    /// Reads a handshake and makes sure it is about the torrent we expect.
+    pub fn decode_for(buf: &[u8], info_hash: &InfoHash) -> Result<Self, HandshakeError> {
+        let handshake = Self::decode(buf)?;
+        if handshake.info_hash != *info_hash {
+            return Err(HandshakeError::InfoHashMismatch);
+        }
+        Ok(handshake)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: InfoHash = [0xaa; 20];
+    const PEER_ID: PeerId = *b"-MT0001-abcdefghijkl";
+
+    #[test]
+    fn test_handshake_roundtrip() {
+        let reserved = Reserved::default()
+            .with(Feature::Dht)
+            .with(Feature::Extension);
+        let handshake = Handshake::new(reserved, INFO_HASH, PEER_ID);
+
+        let mut buf = [0u8; 100];
+        let encoded = handshake.encode(&mut buf).unwrap();
+        assert_eq!(encoded.len(), HANDSHAKE_LEN);
+        assert_eq!(&encoded[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&encoded[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+
+        let decoded = Handshake::decode_for(encoded, &INFO_HASH).unwrap();
+        assert_eq!(decoded, handshake);
+        assert!(decoded.reserved.supports(Feature::Dht));
+        assert!(decoded.reserved.supports(Feature::Extension));
+        assert!(!decoded.reserved.supports(Feature::Fast));
+    }
+
+    #[test]
+    fn test_handshake_errors() {
+        let handshake = Handshake::new(Reserved::default(), INFO_HASH, PEER_ID);
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        assert_eq!(
+            handshake.encode(&mut buf[..67]),
+            Err(HandshakeError::BufferTooSmall)
+        );
+
+        let encoded = handshake.encode(&mut buf).unwrap();
+        assert_eq!(
+            Handshake::decode(&encoded[..50]),
+            Err(HandshakeError::Incomplete)
+        );
+        assert_eq!(
+            Handshake::decode_for(encoded, &[0xbb; 20]),
+            Err(HandshakeError::InfoHashMismatch)
+        );
+        assert_eq!(
+            Handshake::decode(b"\x13BitTorrent protoc0l"),
+            Err(HandshakeError::InvalidProtocol)
+        );
+        // a wrong protocol is detected before the whole handshake arrived
+        assert_eq!(
+            Handshake::decode(b"GET / HTTP/1.1"),
+            Err(HandshakeError::InvalidProtocol)
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Accepting connections from peers that found us through a tracker or the DHT.
+
+use core::{cell::Cell, time::Duration};
+
+use crate::{
+    core::{
+        InfoHash, PeerId,
+        peer::{
+            PeerError,
+            handshake::{HANDSHAKE_LEN, Handshake, Reserved},
+            mse::{EncryptionPolicy, MseStream},
+        },
+        utp::{UtpSocket, UtpStream},
+    },
+    rng::Rng,
+    wifi::{TcpConnection, TcpError, UdpSocket, WifiStack},
+};
+
+/// How long an incoming peer gets to send its handshake, including the key exchange.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A bounded number of connection slots, shared by incoming and outgoing connections.
+///
+/// Every connection needs a socket of the network stack and its buffers, so the
+/// number of slots has to match what the device has set aside for peers.
+pub struct ConnectionSlots {
+    used: Cell<usize>,
+    max: usize,
+}
+
+impl ConnectionSlots {
+    pub const fn new(max: usize) -> Self {
+        Self {
+            used: Cell::new(0),
+            max,
+        }
+    }
+
+    /// Takes a slot, which is given back once the returned guard is dropped.
+    pub fn try_acquire(&self) -> Option<ConnectionSlot<'_>> {
+        if self.used.get() >= self.max {
+            return None;
+        }
+        self.used.set(self.used.get() + 1);
+        Some(ConnectionSlot(self))
+    }
+
+    pub fn available(&self) -> usize {
+        self.max - self.used.get()
+    }
+}
+
+/// A taken slot of [`ConnectionSlots`].
+pub struct ConnectionSlot<'s>(&'s ConnectionSlots);
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        self.0.used.set(self.0.used.get() - 1);
+    }
+}
+
+/// A peer that connected to us and completed the handshake.
+pub struct IncomingPeer<'s, C> {
+    pub connection: C,
+    /// the handshake the peer sent us
+    pub handshake: Handshake,
+    /// index of the torrent in the list the listener was created with
+    pub torrent: usize,
+    pub slot: ConnectionSlot<'s>,
+}
+
+/// Accepts peers on our announced port and routes them to our torrents by info hash.
+pub struct Listener<'t> {
+    port: u16,
+    reserved: Reserved,
+    peer_id: PeerId,
+    torrents: &'t [InfoHash],
+    encryption: EncryptionPolicy,
+}
+
+impl<'t> Listener<'t> {
+    pub fn new(port: u16, reserved: Reserved, peer_id: PeerId, torrents: &'t [InfoHash]) -> Self {
+        Self {
+            port,
+            reserved,
+            peer_id,
+            torrents,
+            encryption: EncryptionPolicy::default(),
+        }
+    }
+
+    /// Which peers are accepted, by default both encrypted and plain ones.
+    pub fn with_encryption(mut self, policy: EncryptionPolicy) -> Self {
+        self.encryption = policy;
+        self
+    }
+
+    /// Waits for one peer to connect and completes the key exchange, if the peer
+    /// starts one, and the handshake with it.
+    ///
+    /// The connection is dropped if no slot is free, the peer asks for a torrent we
+    /// don't have or doesn't meet our encryption policy. `rng` has to be a
+    /// cryptographic RNG for the key exchange.
+    ///
+    /// A socket only listens while `accept` waits, and the peer keeps the socket
+    /// and its buffers, see [`Listener::run_slot`] for serving several peers.
+    pub async fn accept<'a, 's, W: WifiStack, R: Rng>(
+        &self,
+        wifi: &'a W,
+        slots: &'s ConnectionSlots,
+        rng: &mut R,
+        rx_buf: &'a mut [u8],
+        tx_buf: &'a mut [u8],
+    ) -> Result<IncomingPeer<'s, MseStream<W::Connection<'a>>>, PeerError> {
+        let connection = wifi.accept(self.port, rx_buf, tx_buf).await?;
+        self.establish(connection, slots, rng).await
+    }
+
+    /// The accept loop of one connection slot: accepts a peer, hands it to `handle`
+    /// and listens again once `handle` returns.
+    ///
+    /// The peer keeps the listening socket and its buffers while it is handled, so
+    /// every slot runs a loop with buffers of its own, e.g. one task each, and the
+    /// sockets the network stack sets aside for peers are those of the loops.
+    /// Peers that fail the handshake are logged and dropped. Only returns if the
+    /// network stack can't provide a listening socket anymore.
+    pub async fn run_slot<W, R, F>(
+        &self,
+        wifi: &W,
+        slots: &ConnectionSlots,
+        rng: &mut R,
+        rx_buf: &mut [u8],
+        tx_buf: &mut [u8],
+        mut handle: F,
+    ) -> PeerError
+    where
+        W: WifiStack,
+        R: Rng,
+        F: AsyncFnMut(IncomingPeer<'_, MseStream<W::Connection<'_>>>),
+    {
+        loop {
+            match self.accept(wifi, slots, rng, rx_buf, tx_buf).await {
+                Ok(peer) => handle(peer).await,
+                Err(PeerError::Tcp(TcpError::NoSocket)) => {
+                    return PeerError::Tcp(TcpError::NoSocket);
+                }
+                Err(err) => defmt::debug!("dropped incoming peer: {}", err),
+            }
+        }
+    }
+
+    /// Waits for a peer to open a uTP connection on `utp`, which is bound to our
+    /// announced port, and completes the handshakes like [`Listener::accept`].
+    pub async fn accept_utp<'u, 's, S, U, C, R>(
+        &self,
+        utp: &'u UtpSocket<S, U, C>,
+        slots: &'s ConnectionSlots,
+        rng: &mut R,
+    ) -> Result<IncomingPeer<'s, MseStream<UtpStream<'u, S, U, C>>>, PeerError>
+    where
+        S: UdpSocket,
+        U: Rng,
+        C: Fn() -> u64,
+        R: Rng,
+    {
+        let connection = utp.accept().await?;
+        self.establish(connection, slots, rng).await
+    }
+
+    /// Takes a slot for a new connection and completes the key exchange and the handshake.
+    async fn establish<'s, C: TcpConnection, R: Rng>(
+        &self,
+        connection: C,
+        slots: &'s ConnectionSlots,
+        rng: &mut R,
+    ) -> Result<IncomingPeer<'s, MseStream<C>>, PeerError> {
+        let mut connection = MseStream::new(connection);
+        let Some(slot) = slots.try_acquire() else {
+            connection.close().await;
+            return Err(PeerError::TooManyConnections);
+        };
+
+        connection.set_timeout(Some(HANDSHAKE_TIMEOUT));
+        let result = match connection
+            .respond(self.encryption, self.torrents, rng)
+            .await
+        {
+            Ok(()) => self.handshake(&mut connection).await,
+            Err(err) => Err(err.into()),
+        };
+        match result {
+            Ok((handshake, torrent)) => {
+                connection.set_timeout(None);
+                Ok(IncomingPeer {
+                    connection,
+                    handshake,
+                    torrent,
+                    slot,
+                })
+            }
+            Err(err) => {
+                connection.close().await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Reads the peer's handshake, looks up the torrent and answers with ours.
+    async fn handshake<C: TcpConnection>(
+        &self,
+        connection: &mut C,
+    ) -> Result<(Handshake, usize), PeerError> {
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        connection.read_exact(&mut buf).await?;
+        let theirs = Handshake::decode(&buf)?;
+
+        let torrent = self
+            .torrents
+            .iter()
+            .position(|info_hash| *info_hash == theirs.info_hash)
+            .ok_or(PeerError::UnknownTorrent)?;
+
+        let ours = Handshake::new(self.reserved, theirs.info_hash, self.peer_id);
+        connection.write_all(ours.encode(&mut buf)?).await?;
+
+        Ok((theirs, torrent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_slots() {
+        let slots = ConnectionSlots::new(2);
+        let a = slots.try_acquire().unwrap();
+        let b = slots.try_acquire().unwrap();
+        assert!(slots.try_acquire().is_none());
+        assert_eq!(slots.available(), 0);
+
+        drop(a);
+        assert_eq!(slots.available(), 1);
+        let _c = slots.try_acquire().unwrap();
+        drop(b);
+        assert_eq!(slots.available(), 1);
+    }
+}
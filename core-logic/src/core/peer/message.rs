@@ -0,0 +1,574 @@
+//! Length-prefixed messages exchanged after the handshake.
+//!
+//! Every message is a 4 byte big-endian length followed by that many bytes:
+//! a one byte message id and the payload. A length of zero is a keep-alive.
+
+use alloc::{vec, vec::Vec};
+use defmt::Format;
+
+/// Size of the blocks pieces are requested in.
+pub const BLOCK_LEN: u32 = 16 * 1024;
+
+/// Largest message we accept unless told otherwise: a `piece` message carrying one block.
+pub const DEFAULT_MAX_MESSAGE_LEN: u32 = 9 + BLOCK_LEN;
+
+/// Size of the header in front of the block data of a `piece` message.
+pub const PIECE_HEADER_LEN: usize = 4 + 9;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+// Fast Extension (BEP 6)
+const SUGGEST_PIECE: u8 = 0x0d;
+const HAVE_ALL: u8 = 0x0e;
+const HAVE_NONE: u8 = 0x0f;
+const REJECT_REQUEST: u8 = 0x10;
+const ALLOWED_FAST: u8 = 0x11;
+// Extension Protocol (BEP 10)
+const EXTENDED: u8 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MessageError {
+    /// The buffer can't hold the encoded message.
+    BufferTooSmall,
+    /// The peer announced a message bigger than we are willing to buffer.
+    MessageTooLong(u32),
+    /// The payload doesn't have the size the message id requires.
+    InvalidLength { id: u8, len: u32 },
+}
+
+/// Identifies a block within a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Format)]
+pub struct BlockInfo {
+    /// zero-based piece index
+    pub index: u32,
+    /// zero-based byte offset within the piece
+    pub begin: u32,
+    /// length of the block, usually [`BLOCK_LEN`]
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Message<'a> {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    /// raw bitfield as sent on the wire, high bit of the first byte is piece 0
+    Bitfield(&'a [u8]),
+    Request(BlockInfo),
+    /// the block data borrows from the receive buffer, so it can be written out without copying
+    Piece {
+        index: u32,
+        begin: u32,
+        data: &'a [u8],
+    },
+    Cancel(BlockInfo),
+    /// the DHT port of the peer
+    Port(u16),
+    /// the peer recommends downloading this piece, e.g. because it is in its cache (BEP 6)
+    SuggestPiece {
+        index: u32,
+    },
+    /// replaces the bitfield of a seed (BEP 6)
+    HaveAll,
+    /// replaces the bitfield of a peer without pieces (BEP 6)
+    HaveNone,
+    /// the request won't be answered (BEP 6)
+    RejectRequest(BlockInfo),
+    /// this piece may be requested even while choked (BEP 6)
+    AllowedFast {
+        index: u32,
+    },
+    /// a message of the extension protocol (BEP 10), `id` 0 is the extended handshake
+    Extended {
+        id: u8,
+        payload: &'a [u8],
+    },
+    /// a message of an extension we don't know, which should be ignored
+    Unknown {
+        id: u8,
+        payload: &'a [u8],
+    },
+}
+
+impl<'a> Message<'a> {
+    /// Number of bytes the message takes on the wire, including the length prefix.
+    pub fn encoded_len(&self) -> usize {
+        4 + match self {
+            Message::KeepAlive => 0,
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => 1,
+            Message::Have { .. } | Message::SuggestPiece { .. } | Message::AllowedFast { .. } => 5,
+            Message::Bitfield(bitfield) => 1 + bitfield.len(),
+            Message::Request(_) | Message::Cancel(_) | Message::RejectRequest(_) => 13,
+            Message::Piece { data, .. } => 9 + data.len(),
+            Message::Port(_) => 3,
+            Message::Extended { payload, .. } => 2 + payload.len(),
+            Message::Unknown { payload, .. } => 1 + payload.len(),
+        }
+    }
+
+    /// Writes the message including its length prefix to the beginning of `buf`.
+    /// Returns the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, MessageError> {
+        let len = self.encoded_len();
+        let buf = buf.get_mut(..len).ok_or(MessageError::BufferTooSmall)?;
+        buf[..4].copy_from_slice(&(len as u32 - 4).to_be_bytes());
+
+        let payload = &mut buf[4..];
+        match *self {
+            Message::KeepAlive => {}
+            Message::Choke => payload[0] = CHOKE,
+            Message::Unchoke => payload[0] = UNCHOKE,
+            Message::Interested => payload[0] = INTERESTED,
+            Message::NotInterested => payload[0] = NOT_INTERESTED,
+            Message::Have { index } => encode_index(payload, HAVE, index),
+            Message::Bitfield(bitfield) => {
+                payload[0] = BITFIELD;
+                payload[1..].copy_from_slice(bitfield);
+            }
+            Message::Request(block) => encode_block(payload, REQUEST, &block),
+            Message::Piece { index, begin, data } => {
+                payload[..9].copy_from_slice(&piece_header(index, begin, data.len())[4..]);
+                payload[9..].copy_from_slice(data);
+            }
+            Message::Cancel(block) => encode_block(payload, CANCEL, &block),
+            Message::Port(port) => {
+                payload[0] = PORT;
+                payload[1..3].copy_from_slice(&port.to_be_bytes());
+            }
+            Message::SuggestPiece { index } => encode_index(payload, SUGGEST_PIECE, index),
+            Message::HaveAll => payload[0] = HAVE_ALL,
+            Message::HaveNone => payload[0] = HAVE_NONE,
+            Message::RejectRequest(block) => encode_block(payload, REJECT_REQUEST, &block),
+            Message::AllowedFast { index } => encode_index(payload, ALLOWED_FAST, index),
+            Message::Extended { id, payload: data } => {
+                payload[0] = EXTENDED;
+                payload[1] = id;
+                payload[2..].copy_from_slice(data);
+            }
+            Message::Unknown { id, payload: data } => {
+                payload[0] = id;
+                payload[1..].copy_from_slice(data);
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Decodes the first complete frame in `buf`.
+    ///
+    /// Returns the message and the number of bytes it occupied,
+    /// or `None` if more bytes are needed to complete the frame.
+    pub fn decode(buf: &'a [u8], max_len: u32) -> Result<Option<(Self, usize)>, MessageError> {
+        let Some(len) = buf.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(len.try_into().unwrap());
+        if len > max_len {
+            return Err(MessageError::MessageTooLong(len));
+        }
+        let Some(frame) = buf.get(4..4 + len as usize) else {
+            return Ok(None);
+        };
+
+        Ok(Some((Self::decode_payload(frame)?, 4 + len as usize)))
+    }
+
+    /// Decodes a frame without its length prefix.
+    fn decode_payload(frame: &'a [u8]) -> Result<Self, MessageError> {
+        let Some((&id, payload)) = frame.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+        let expect_len = |expected: usize| {
+            if payload.len() == expected {
+                Ok(())
+            } else {
+                Err(MessageError::InvalidLength {
+                    id,
+                    len: frame.len() as u32,
+                })
+            }
+        };
+
+        let message = match id {
+            CHOKE => expect_len(0).map(|_| Message::Choke)?,
+            UNCHOKE => expect_len(0).map(|_| Message::Unchoke)?,
+            INTERESTED => expect_len(0).map(|_| Message::Interested)?,
+            NOT_INTERESTED => expect_len(0).map(|_| Message::NotInterested)?,
+            HAVE => {
+                expect_len(4)?;
+                Message::Have {
+                    index: read_u32(payload, 0),
+                }
+            }
+            BITFIELD => Message::Bitfield(payload),
+            REQUEST => {
+                expect_len(12)?;
+                Message::Request(decode_block(payload))
+            }
+            PIECE => {
+                if payload.len() < 8 {
+                    expect_len(8)?;
+                }
+                Message::Piece {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    data: &payload[8..],
+                }
+            }
+            CANCEL => {
+                expect_len(12)?;
+                Message::Cancel(decode_block(payload))
+            }
+            PORT => {
+                expect_len(2)?;
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            SUGGEST_PIECE => {
+                expect_len(4)?;
+                Message::SuggestPiece {
+                    index: read_u32(payload, 0),
+                }
+            }
+            HAVE_ALL => expect_len(0).map(|_| Message::HaveAll)?,
+            HAVE_NONE => expect_len(0).map(|_| Message::HaveNone)?,
+            REJECT_REQUEST => {
+                expect_len(12)?;
+                Message::RejectRequest(decode_block(payload))
+            }
+            ALLOWED_FAST => {
+                expect_len(4)?;
+                Message::AllowedFast {
+                    index: read_u32(payload, 0),
+                }
+            }
+            EXTENDED => {
+                let Some((&id, payload)) = payload.split_first() else {
+                    return Err(MessageError::InvalidLength {
+                        id: EXTENDED,
+                        len: 1,
+                    });
+                };
+                Message::Extended { id, payload }
+            }
+            id => Message::Unknown { id, payload },
+        };
+
+        Ok(message)
+    }
+}
+
+/// Length prefix, id, index and begin of a `piece` message carrying `data_len` bytes.
+///
+/// Reading the block from the SD card right behind the header builds the message
+/// without copying the block, see
+/// [`read_piece_message`](crate::core::peer::upload::read_piece_message).
+pub fn piece_header(index: u32, begin: u32, data_len: usize) -> [u8; PIECE_HEADER_LEN] {
+    let mut header = [0u8; PIECE_HEADER_LEN];
+    header[..4].copy_from_slice(&(9 + data_len as u32).to_be_bytes());
+    header[4] = PIECE;
+    header[5..9].copy_from_slice(&index.to_be_bytes());
+    header[9..13].copy_from_slice(&begin.to_be_bytes());
+    header
+}
+
+fn encode_index(payload: &mut [u8], id: u8, index: u32) {
+    payload[0] = id;
+    payload[1..5].copy_from_slice(&index.to_be_bytes());
+}
+
+fn encode_block(payload: &mut [u8], id: u8, block: &BlockInfo) {
+    payload[0] = id;
+    payload[1..5].copy_from_slice(&block.index.to_be_bytes());
+    payload[5..9].copy_from_slice(&block.begin.to_be_bytes());
+    payload[9..13].copy_from_slice(&block.length.to_be_bytes());
+}
+
+fn decode_block(payload: &[u8]) -> BlockInfo {
+    BlockInfo {
+        index: read_u32(payload, 0),
+        begin: read_u32(payload, 4),
+        length: read_u32(payload, 8),
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// Reassembles messages from a byte stream that arrives in arbitrary chunks.
+///
+/// Read from the socket into [`MessageDecoder::read_buf`], report the number of
+/// bytes with [`MessageDecoder::advance`] and call [`MessageDecoder::next_message`]
+/// until it returns `None`.
+pub struct MessageDecoder {
+    buf: Vec<u8>,
+    /// start of the bytes that haven't been decoded yet
+    start: usize,
+    /// end of the bytes received so far
+    end: usize,
+    /// size of the frame returned by the last call to `next_message`, dropped on the following call
+    returned: usize,
+    max_len: u32,
+}
+
+impl MessageDecoder {
+    /// `max_len` bounds the size of a single message and thereby the memory the decoder needs.
+    pub fn new(max_len: u32) -> Self {
+        Self {
+            buf: vec![0u8; 4 + max_len as usize],
+            start: 0,
+            end: 0,
+            returned: 0,
+            max_len,
+        }
+    }
+
+    /// The free part of the buffer that new bytes from the stream should be read into.
+    pub fn read_buf(&mut self) -> &mut [u8] {
+        self.consume_returned();
+        if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        &mut self.buf[self.end..]
+    }
+
+    /// Marks `n` bytes of [`MessageDecoder::read_buf`] as filled.
+    pub fn advance(&mut self, n: usize) {
+        self.end = (self.end + n).min(self.buf.len());
+    }
+
+    /// Copies `bytes` into the decoder and returns how many of them fit.
+    pub fn push(&mut self, bytes: &[u8]) -> usize {
+        let read_buf = self.read_buf();
+        let n = bytes.len().min(read_buf.len());
+        read_buf[..n].copy_from_slice(&bytes[..n]);
+        self.advance(n);
+        n
+    }
+
+    /// Returns the next complete message, or `None` if more bytes are needed.
+    ///
+    /// After an error the stream can't be resynchronised and the connection should be dropped.
+    pub fn next_message(&mut self) -> Result<Option<Message<'_>>, MessageError> {
+        self.consume_returned();
+        match Message::decode(&self.buf[self.start..self.end], self.max_len)? {
+            Some((message, len)) => {
+                self.returned = len;
+                Ok(Some(message))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Number of received bytes that haven't been returned as a message yet.
+    pub fn buffered(&self) -> usize {
+        self.end - self.start - self.returned
+    }
+
+    fn consume_returned(&mut self) {
+        self.start += core::mem::take(&mut self.returned);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: BlockInfo = BlockInfo {
+        index: 3,
+        begin: BLOCK_LEN,
+        length: BLOCK_LEN,
+    };
+
+    fn all_messages<'a>(data: &'a [u8]) -> [Message<'a>; 18] {
+        [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { index: 0xdeadbeef },
+            Message::Bitfield(&data[..3]),
+            Message::Request(BLOCK),
+            Message::Piece {
+                index: 3,
+                begin: 16,
+                data,
+            },
+            Message::Cancel(BLOCK),
+            Message::Port(6881),
+            Message::SuggestPiece { index: 7 },
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest(BLOCK),
+            Message::AllowedFast { index: 8 },
+            Message::Extended {
+                id: 1,
+                payload: &data[..4],
+            },
+            Message::Unknown {
+                id: 42,
+                payload: &data[..5],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_roundtrip_all_messages() {
+        let data: Vec<u8> = (0..=255).collect();
+        for message in all_messages(&data) {
+            let mut buf = [0u8; 512];
+            let len = message.encode(&mut buf).unwrap();
+            assert_eq!(len, message.encoded_len());
+
+            let (decoded, consumed) = Message::decode(&buf[..len], DEFAULT_MAX_MESSAGE_LEN)
+                .unwrap()
+                .unwrap();
+            assert_eq!(decoded, message);
+            assert_eq!(consumed, len);
+
+            // every proper prefix is an incomplete frame
+            for partial in 0..len {
+                assert_eq!(
+                    Message::decode(&buf[..partial], DEFAULT_MAX_MESSAGE_LEN),
+                    Ok(None)
+                );
+            }
+            assert_eq!(
+                message.encode(&mut buf[..len - 1]),
+                Err(MessageError::BufferTooSmall)
+            );
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let mut buf = [0u8; 17];
+        let len = Message::Request(BLOCK).encode(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0, 0, 0, 13, 6, 0, 0, 0, 3, 0, 0, 0x40, 0, 0, 0, 0x40, 0]
+        );
+
+        let len = Message::Interested.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0, 0, 0, 1, 2]);
+
+        assert_eq!(
+            piece_header(1, 2, 3),
+            [0, 0, 0, 12, 7, 0, 0, 0, 1, 0, 0, 0, 2]
+        );
+
+        let len = Message::HaveAll.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0, 0, 0, 1, 0x0e]);
+        let len = Message::AllowedFast { index: 2 }.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0, 0, 0, 5, 0x11, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_decoder_byte_by_byte() {
+        let data: Vec<u8> = (0..100).collect();
+        let messages = all_messages(&data);
+        let mut stream = Vec::new();
+        for message in &messages {
+            let mut buf = [0u8; 256];
+            let len = message.encode(&mut buf).unwrap();
+            stream.extend_from_slice(&buf[..len]);
+        }
+
+        let mut decoder = MessageDecoder::new(DEFAULT_MAX_MESSAGE_LEN);
+        let mut decoded = 0;
+        for byte in stream {
+            assert_eq!(decoder.push(&[byte]), 1);
+            while let Some(message) = decoder.next_message().unwrap() {
+                assert_eq!(message, messages[decoded]);
+                decoded += 1;
+            }
+        }
+        assert_eq!(decoded, messages.len());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_decoder_full_block_in_chunks() {
+        let data = vec![0xab; BLOCK_LEN as usize];
+        let piece = Message::Piece {
+            index: 1,
+            begin: 0,
+            data: &data,
+        };
+        let mut stream = vec![0u8; piece.encoded_len()];
+        piece.encode(&mut stream).unwrap();
+        stream.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut decoder = MessageDecoder::new(DEFAULT_MAX_MESSAGE_LEN);
+        let mut messages = Vec::new();
+        for mut chunk in stream.chunks(1460) {
+            // like a socket read, only fill as much as the decoder has room for
+            while !chunk.is_empty() {
+                let n = decoder.push(chunk);
+                chunk = &chunk[n..];
+                while let Some(message) = decoder.next_message().unwrap() {
+                    messages.push(match message {
+                        Message::Piece { data, .. } => data.len(),
+                        _ => 0,
+                    });
+                }
+            }
+        }
+        assert_eq!(messages, [BLOCK_LEN as usize, 0]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            Message::decode(&[0, 0, 0x40, 0x0a], DEFAULT_MAX_MESSAGE_LEN),
+            Err(MessageError::MessageTooLong(0x400a))
+        );
+        assert_eq!(
+            Message::decode(&[0, 0, 0, 2, CHOKE, 0], DEFAULT_MAX_MESSAGE_LEN),
+            Err(MessageError::InvalidLength { id: CHOKE, len: 2 })
+        );
+        assert_eq!(
+            Message::decode(&[0, 0, 0, 4, HAVE, 0, 0, 0], DEFAULT_MAX_MESSAGE_LEN),
+            Err(MessageError::InvalidLength { id: HAVE, len: 4 })
+        );
+        assert_eq!(
+            Message::decode(&[0, 0, 0, 5, PIECE, 0, 0, 0, 0], DEFAULT_MAX_MESSAGE_LEN),
+            Err(MessageError::InvalidLength { id: PIECE, len: 5 })
+        );
+        assert_eq!(
+            Message::decode(&[0, 0, 0, 1, EXTENDED], DEFAULT_MAX_MESSAGE_LEN),
+            Err(MessageError::InvalidLength {
+                id: EXTENDED,
+                len: 1
+            })
+        );
+
+        let mut decoder = MessageDecoder::new(16);
+        decoder.push(&[0, 0, 0, 17]);
+        assert_eq!(
+            decoder.next_message(),
+            Err(MessageError::MessageTooLong(17))
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! Message Stream Encryption, also called Protocol Encryption (MSE/PE).
+//!
+//! Some networks throttle or block connections they recognize as BitTorrent. MSE
+//! hides the protocol behind a Diffie-Hellman key exchange and RC4, so nothing of
+//! a connection is recognizable, not even its handshake. It isn't meant to keep the
+//! data secret.
+//!
+//! The exchange between the initiator A and the receiver B:
+//!
+//! 1. A → B: public key Ya, random padding
+//! 2. B → A: public key Yb, random padding
+//! 3. A → B: `HASH("req1", S)`, `HASH("req2", SKEY) xor HASH("req3", S)`,
+//!    `ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA))`, `ENCRYPT(IA)`
+//! 4. B → A: `ENCRYPT(VC, crypto_select, len(PadD), PadD)`
+//!
+//! S is the shared secret, SKEY the info hash of the torrent and VC eight zero
+//! bytes. Both sides find the start of the other's encrypted part by searching for
+//! what they expect after the padding.
+
+use alloc::vec::Vec;
+use core::{net::SocketAddr, time::Duration};
+use defmt::Format;
+use sha1_smol::Sha1;
+
+use crate::{
+    core::{
+        InfoHash,
+        peer::{
+            handshake::PROTOCOL,
+            mse::{
+                dh::{KEY_LEN, PrivateKey},
+                rc4::Rc4,
+            },
+        },
+    },
+    rng::Rng,
+    wifi::{TcpConnection, TcpError},
+};
+
+pub mod dh;
+pub mod rc4;
+
+/// The most random padding either side may send.
+pub const MAX_PAD_LEN: usize = 512;
+
+/// The largest initial payload we take from an initiator.
+pub const MAX_INITIAL_PAYLOAD_LEN: usize = 512;
+
+/// Verification constant, eight zero bytes.
+const VC: [u8; 8] = [0; 8];
+
+/// Bits of `crypto_provide` and `crypto_select`.
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MseError {
+    Tcp(TcpError),
+    /// The expected data wasn't found within the padding the peer may send.
+    SyncFailed,
+    /// The peer sent a value the protocol doesn't allow.
+    InvalidMessage,
+    /// The peer encrypts for a torrent we don't have.
+    UnknownTorrent,
+    /// The peer and we have no crypto method in common.
+    NoCommonMethod,
+    /// The peer tried an encrypted connection, but our policy is plaintext only.
+    EncryptionRefused,
+    /// The peer tried a plaintext connection, but our policy requires encryption.
+    PlaintextRefused,
+}
+
+impl From<TcpError> for MseError {
+    fn from(err: TcpError) -> Self {
+        MseError::Tcp(err)
+    }
+}
+
+/// Whether connections are encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub enum EncryptionPolicy {
+    /// Plain BitTorrent only. Peers that try MSE are dropped.
+    PlaintextOnly,
+    /// Outgoing connections try MSE and offer plaintext as well, incoming
+    /// connections may use either.
+    ///
+    /// Peers that don't know MSE usually drop the connection during the key
+    /// exchange, [`connect_peer`](crate::core::peer::connect_peer) reconnects to
+    /// them with [`EncryptionPolicy::fallback`].
+    #[default]
+    PreferEncrypted,
+    /// RC4 in both directions, everything else is dropped.
+    RequireEncrypted,
+}
+
+impl EncryptionPolicy {
+    /// The policy to retry an outgoing connection with after the key exchange failed.
+    pub fn fallback(self) -> Option<Self> {
+        match self {
+            EncryptionPolicy::PreferEncrypted => Some(EncryptionPolicy::PlaintextOnly),
+            EncryptionPolicy::PlaintextOnly | EncryptionPolicy::RequireEncrypted => None,
+        }
+    }
+
+    /// The methods we offer as initiator.
+    fn provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::PlaintextOnly => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::PreferEncrypted => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::RequireEncrypted => CRYPTO_RC4,
+        }
+    }
+
+    /// The method we pick as receiver out of those the initiator provides.
+    fn select(self, provide: u32) -> Result<u32, MseError> {
+        [CRYPTO_RC4, CRYPTO_PLAINTEXT]
+            .into_iter()
+            .find(|&method| provide & self.provide() & method != 0)
+            .ok_or(MseError::NoCommonMethod)
+    }
+}
+
+/// A connection that is either plain or RC4 encrypted in both directions.
+///
+/// It starts out plain and is upgraded by [`MseStream::initiate`] or
+/// [`MseStream::respond`], after which the peer wire protocol runs on top of it
+/// unchanged.
+pub struct MseStream<C> {
+    connection: C,
+    /// The ciphers for what we write and what we read.
+    ciphers: Option<(Rc4, Rc4)>,
+    /// Plaintext that was already read from the connection, but not from the stream.
+    pending: Vec<u8>,
+}
+
+impl<C: TcpConnection> MseStream<C> {
+    pub fn new(connection: C) -> Self {
+        Self {
+            connection,
+            ciphers: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    /// Runs the key exchange of an outgoing connection for `info_hash`.
+    ///
+    /// Does nothing if `policy` is plaintext only. Fails if the peer selects a
+    /// method the policy doesn't allow.
+    pub async fn initiate<R: Rng>(
+        &mut self,
+        policy: EncryptionPolicy,
+        info_hash: &InfoHash,
+        rng: &mut R,
+    ) -> Result<(), MseError> {
+        if policy == EncryptionPolicy::PlaintextOnly {
+            return Ok(());
+        }
+        let key = PrivateKey::generate(rng);
+        self.send_public_key(&key, rng).await?;
+        let mut their_key = [0; KEY_LEN];
+        self.connection.read_exact(&mut their_key).await?;
+        let secret = key.shared_secret(&their_key);
+
+        let mut encrypt = Rc4::for_mse(b"keyA", &secret, info_hash);
+        let mut decrypt = Rc4::for_mse(b"keyB", &secret, info_hash);
+
+        let mut message = Vec::with_capacity(40 + VC.len() + 8);
+        message.extend_from_slice(&hash(b"req1", &secret));
+        let req2 = hash(b"req2", info_hash);
+        let req3 = hash(b"req3", &secret);
+        message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+        let encrypted_start = message.len();
+        message.extend_from_slice(&VC);
+        message.extend_from_slice(&policy.provide().to_be_bytes());
+        // neither PadC nor IA, the handshake follows once the method is known
+        message.extend_from_slice(&0u16.to_be_bytes());
+        message.extend_from_slice(&0u16.to_be_bytes());
+        encrypt.apply(&mut message[encrypted_start..]);
+        self.connection.write_all(&message).await?;
+
+        let mut expected = VC;
+        decrypt.clone().apply(&mut expected);
+        self.sync(&expected).await?;
+        decrypt.apply(&mut [0; VC.len()]);
+
+        let mut header = [0; 6];
+        self.connection.read_exact(&mut header).await?;
+        decrypt.apply(&mut header);
+        let select = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let pad_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if select.count_ones() != 1 || select & policy.provide() == 0 || pad_len > MAX_PAD_LEN {
+            return Err(MseError::InvalidMessage);
+        }
+        self.skip(pad_len, &mut decrypt).await?;
+
+        if select == CRYPTO_RC4 {
+            self.ciphers = Some((encrypt, decrypt));
+        }
+        Ok(())
+    }
+
+    /// Answers the key exchange of an incoming connection, for one of `torrents`.
+    ///
+    /// A peer that starts with a plain handshake is let through unless `policy`
+    /// requires encryption. Its bytes are kept and read from the stream again.
+    pub async fn respond<R: Rng>(
+        &mut self,
+        policy: EncryptionPolicy,
+        torrents: &[InfoHash],
+        rng: &mut R,
+    ) -> Result<(), MseError> {
+        // a public key is longer than the start of a plain handshake
+        let mut their_key = [0; KEY_LEN];
+        let start = &mut their_key[..1 + PROTOCOL.len()];
+        self.connection.read_exact(start).await?;
+        if start[0] as usize == PROTOCOL.len() && start[1..] == PROTOCOL[..] {
+            if policy == EncryptionPolicy::RequireEncrypted {
+                return Err(MseError::PlaintextRefused);
+            }
+            self.pending.extend_from_slice(start);
+            return Ok(());
+        }
+        if policy == EncryptionPolicy::PlaintextOnly {
+            return Err(MseError::EncryptionRefused);
+        }
+        self.connection
+            .read_exact(&mut their_key[1 + PROTOCOL.len()..])
+            .await?;
+
+        let key = PrivateKey::generate(rng);
+        self.send_public_key(&key, rng).await?;
+        let secret = key.shared_secret(&their_key);
+
+        self.sync(&hash(b"req1", &secret)).await?;
+        let mut skey_hash = [0; 20];
+        self.connection.read_exact(&mut skey_hash).await?;
+        for (byte, mask) in skey_hash.iter_mut().zip(hash(b"req3", &secret)) {
+            *byte ^= mask;
+        }
+        let info_hash = torrents
+            .iter()
+            .find(|info_hash| hash(b"req2", *info_hash) == skey_hash)
+            .ok_or(MseError::UnknownTorrent)?;
+
+        let mut encrypt = Rc4::for_mse(b"keyB", &secret, info_hash);
+        let mut decrypt = Rc4::for_mse(b"keyA", &secret, info_hash);
+
+        let mut header = [0; VC.len() + 6];
+        self.connection.read_exact(&mut header).await?;
+        decrypt.apply(&mut header);
+        let provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let pad_len = u16::from_be_bytes([header[12], header[13]]) as usize;
+        if header[..8] != VC || pad_len > MAX_PAD_LEN {
+            return Err(MseError::InvalidMessage);
+        }
+        self.skip(pad_len, &mut decrypt).await?;
+
+        let mut ia_len = [0; 2];
+        self.connection.read_exact(&mut ia_len).await?;
+        decrypt.apply(&mut ia_len);
+        let ia_len = u16::from_be_bytes(ia_len) as usize;
+        if ia_len > MAX_INITIAL_PAYLOAD_LEN {
+            return Err(MseError::InvalidMessage);
+        }
+        let select = policy.select(provide)?;
+
+        let mut initial_payload = alloc::vec![0; ia_len];
+        self.connection.read_exact(&mut initial_payload).await?;
+        decrypt.apply(&mut initial_payload);
+        self.pending = initial_payload;
+
+        let mut answer = [0; VC.len() + 6];
+        answer[8..12].copy_from_slice(&select.to_be_bytes());
+        encrypt.apply(&mut answer);
+        self.connection.write_all(&answer).await?;
+
+        if select == CRYPTO_RC4 {
+            self.ciphers = Some((encrypt, decrypt));
+        }
+        Ok(())
+    }
+
+    /// Sends our public key followed by random padding.
+    async fn send_public_key<R: Rng>(
+        &mut self,
+        key: &PrivateKey,
+        rng: &mut R,
+    ) -> Result<(), MseError> {
+        let mut message = [0; KEY_LEN + MAX_PAD_LEN];
+        message[..KEY_LEN].copy_from_slice(&key.public_key());
+        let len = KEY_LEN + rng.next_below(MAX_PAD_LEN as u32 + 1) as usize;
+        rng.fill_bytes(&mut message[KEY_LEN..len]);
+        self.connection.write_all(&message[..len]).await?;
+        Ok(())
+    }
+
+    /// Reads until right after `expected`, which follows at most [`MAX_PAD_LEN`]
+    /// bytes of padding.
+    async fn sync(&mut self, expected: &[u8]) -> Result<(), MseError> {
+        let mut window = [0; MAX_PAD_LEN + 20];
+        let window = &mut window[..MAX_PAD_LEN + expected.len()];
+        for len in 1..=window.len() {
+            self.connection
+                .read_exact(&mut window[len - 1..len])
+                .await?;
+            if window[..len].ends_with(expected) {
+                return Ok(());
+            }
+        }
+        Err(MseError::SyncFailed)
+    }
+
+    /// Reads and drops `len` bytes of encrypted padding, which still advance `decrypt`.
+    async fn skip(&mut self, len: usize, decrypt: &mut Rc4) -> Result<(), MseError> {
+        let mut padding = [0; MAX_PAD_LEN];
+        self.connection.read_exact(&mut padding[..len]).await?;
+        decrypt.apply(&mut padding[..len]);
+        Ok(())
+    }
+}
+
+impl<C: TcpConnection> TcpConnection for MseStream<C> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        if !self.pending.is_empty() {
+            let len = buf.len().min(self.pending.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            return Ok(len);
+        }
+        let len = self.connection.read(buf).await?;
+        if let Some((_, decrypt)) = &mut self.ciphers {
+            decrypt.apply(&mut buf[..len]);
+        }
+        Ok(len)
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), TcpError> {
+        let Some((encrypt, _)) = &mut self.ciphers else {
+            return self.connection.write_all(buf).await;
+        };
+        let mut chunk = [0; 512];
+        for plain in buf.chunks(chunk.len()) {
+            let chunk = &mut chunk[..plain.len()];
+            chunk.copy_from_slice(plain);
+            encrypt.apply(chunk);
+            self.connection.write_all(chunk).await?;
+        }
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.connection.set_timeout(timeout);
+    }
+
+    fn remote_addr(&self) -> SocketAddr {
+        self.connection.remote_addr()
+    }
+
+    async fn close(self) {
+        self.connection.close().await;
+    }
+}
+
+fn hash(name: &[u8; 4], data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(name);
+    hasher.update(data);
+    hasher.digest().bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let both = CRYPTO_RC4 | CRYPTO_PLAINTEXT;
+        assert_eq!(
+            EncryptionPolicy::PreferEncrypted.select(both),
+            Ok(CRYPTO_RC4)
+        );
+        assert_eq!(
+            EncryptionPolicy::PreferEncrypted.select(CRYPTO_PLAINTEXT),
+            Ok(CRYPTO_PLAINTEXT)
+        );
+        assert_eq!(
+            EncryptionPolicy::RequireEncrypted.select(both),
+            Ok(CRYPTO_RC4)
+        );
+        assert_eq!(
+            EncryptionPolicy::RequireEncrypted.select(CRYPTO_PLAINTEXT),
+            Err(MseError::NoCommonMethod)
+        );
+        // unknown methods are ignored
+        assert_eq!(
+            EncryptionPolicy::PreferEncrypted.select(0x10),
+            Err(MseError::NoCommonMethod)
+        );
+    }
+
+    #[test]
+    fn test_fallback() {
+        assert_eq!(
+            EncryptionPolicy::PreferEncrypted.fallback(),
+            Some(EncryptionPolicy::PlaintextOnly)
+        );
+        assert_eq!(EncryptionPolicy::RequireEncrypted.fallback(), None);
+    }
+}
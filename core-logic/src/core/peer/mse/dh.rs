@@ -0,0 +1,231 @@
+//! The Diffie-Hellman key exchange of MSE over its fixed 768-bit prime.
+//!
+//! Exponentiation uses Montgomery multiplication on fixed-size limbs, so it needs
+//! no allocation and no general purpose bignum library.
+
+use crate::rng::Rng;
+
+/// Length of public keys and of the shared secret.
+pub const KEY_LEN: usize = 96;
+
+/// Length of the private key. 160 bits are what the spec recommends.
+pub const PRIVATE_KEY_LEN: usize = 20;
+
+const LIMBS: usize = KEY_LEN / 4;
+
+type Limbs = [u32; LIMBS];
+
+/// The prime P, big-endian.
+const PRIME: [u8; KEY_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+
+/// The generator G.
+const GENERATOR: u32 = 2;
+
+/// Our secret exponent X, from which the public key `G^X mod P` is derived.
+pub struct PrivateKey([u8; PRIVATE_KEY_LEN]);
+
+impl PrivateKey {
+    /// Draws a new key, which is only secret if `rng` is a cryptographic RNG.
+    pub fn generate<R: Rng>(rng: &mut R) -> Self {
+        let mut key = [0; PRIVATE_KEY_LEN];
+        rng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn from_bytes(key: [u8; PRIVATE_KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    /// `G^X mod P`, which is sent to the peer.
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        let mut base = [0; LIMBS];
+        base[0] = GENERATOR;
+        to_bytes(&Montgomery::new().pow(&base, &self.0))
+    }
+
+    /// `Y^X mod P`, the secret both sides end up with.
+    pub fn shared_secret(&self, their_public_key: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        to_bytes(&Montgomery::new().pow(&from_bytes(their_public_key), &self.0))
+    }
+}
+
+/// Arithmetic modulo P in Montgomery form, with R = 2^768.
+struct Montgomery {
+    prime: Limbs,
+    /// `-P^-1 mod 2^32`
+    prime_inv: u32,
+    /// `R^2 mod P`, which moves numbers into Montgomery form.
+    r_squared: Limbs,
+}
+
+impl Montgomery {
+    fn new() -> Self {
+        let prime = from_bytes(&PRIME);
+
+        // Newton's iteration doubles the number of correct low bits each round
+        let mut inv = 1u32;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(prime[0].wrapping_mul(inv)));
+        }
+
+        // P has its top bit set, so R mod P = R - P, which is the two's complement of P
+        let mut r_squared = [0; LIMBS];
+        let mut carry = 1u64;
+        for (limb, p) in r_squared.iter_mut().zip(prime) {
+            let sum = u64::from(!p) + carry;
+            *limb = sum as u32;
+            carry = sum >> 32;
+        }
+        // doubling R mod P another 768 times gives R^2 mod P
+        for _ in 0..KEY_LEN * 8 {
+            let overflow = r_squared[LIMBS - 1] >> 31 != 0;
+            for i in (1..LIMBS).rev() {
+                r_squared[i] = r_squared[i] << 1 | r_squared[i - 1] >> 31;
+            }
+            r_squared[0] <<= 1;
+            if overflow || !less_than(&r_squared, &prime) {
+                subtract(&mut r_squared, &prime);
+            }
+        }
+
+        Self {
+            prime,
+            prime_inv: inv.wrapping_neg(),
+            r_squared,
+        }
+    }
+
+    /// `a * b / R mod P`
+    fn multiply(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let mut t = [0u32; LIMBS + 2];
+        for &b in b {
+            let mut carry = 0u64;
+            for (t, &a) in t.iter_mut().zip(a) {
+                let sum = u64::from(*t) + u64::from(a) * u64::from(b) + carry;
+                *t = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = u64::from(t[LIMBS]) + carry;
+            t[LIMBS] = sum as u32;
+            t[LIMBS + 1] = (sum >> 32) as u32;
+
+            // add a multiple of P that clears the lowest limb, then shift it out
+            let m = u64::from(t[0].wrapping_mul(self.prime_inv));
+            let mut carry = (u64::from(t[0]) + m * u64::from(self.prime[0])) >> 32;
+            for j in 1..LIMBS {
+                let sum = u64::from(t[j]) + m * u64::from(self.prime[j]) + carry;
+                t[j - 1] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = u64::from(t[LIMBS]) + carry;
+            t[LIMBS - 1] = sum as u32;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 32) as u32;
+        }
+
+        let mut result: Limbs = t[..LIMBS].try_into().unwrap();
+        if t[LIMBS] != 0 || !less_than(&result, &self.prime) {
+            subtract(&mut result, &self.prime);
+        }
+        result
+    }
+
+    /// `base^exponent mod P`, with a big-endian exponent.
+    fn pow(&self, base: &Limbs, exponent: &[u8]) -> Limbs {
+        let mut one = [0; LIMBS];
+        one[0] = 1;
+        let base = self.multiply(base, &self.r_squared);
+        let mut result = self.multiply(&one, &self.r_squared);
+        for byte in exponent {
+            for bit in (0..8).rev() {
+                result = self.multiply(&result, &result);
+                if byte >> bit & 1 != 0 {
+                    result = self.multiply(&result, &base);
+                }
+            }
+        }
+        self.multiply(&result, &one)
+    }
+}
+
+fn less_than(a: &Limbs, b: &Limbs) -> bool {
+    a.iter().rev().cmp(b.iter().rev()).is_lt()
+}
+
+/// `a -= b`, wrapping around below zero.
+fn subtract(a: &mut Limbs, b: &Limbs) {
+    let mut borrow = false;
+    for (a, &b) in a.iter_mut().zip(b) {
+        let (diff, borrow_a) = a.overflowing_sub(b);
+        let (diff, borrow_b) = diff.overflowing_sub(u32::from(borrow));
+        *a = diff;
+        borrow = borrow_a || borrow_b;
+    }
+}
+
+fn from_bytes(bytes: &[u8; KEY_LEN]) -> Limbs {
+    let mut limbs = [0; LIMBS];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.as_chunks::<4>().0.iter().rev()) {
+        *limb = u32::from_be_bytes(*chunk);
+    }
+    limbs
+}
+
+fn to_bytes(limbs: &Limbs) -> [u8; KEY_LEN] {
+    let mut bytes = [0; KEY_LEN];
+    for (chunk, limb) in bytes.as_chunks_mut::<4>().0.iter_mut().rev().zip(limbs) {
+        *chunk = limb.to_be_bytes();
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift32;
+
+    fn test_key() -> PrivateKey {
+        PrivateKey::from_bytes(core::array::from_fn(|i| i as u8 + 1))
+    }
+
+    #[test]
+    fn test_public_key() {
+        let public_key = test_key().public_key();
+
+        assert_eq!(
+            &public_key[..8],
+            &hex::decode("96e112dab29e8c52").unwrap()[..]
+        );
+        assert_eq!(
+            &public_key[88..],
+            &hex::decode("39e1a633821eb693").unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn test_shared_secret_with_other_base() {
+        let mut three = [0; KEY_LEN];
+        three[KEY_LEN - 1] = 3;
+        let secret = test_key().shared_secret(&three);
+
+        assert_eq!(&secret[..8], &hex::decode("53bb88c8d242866e").unwrap()[..]);
+        assert_eq!(&secret[88..], &hex::decode("3e7f6fc6b448afcb").unwrap()[..]);
+    }
+
+    #[test]
+    fn test_both_sides_agree() {
+        let mut rng = XorShift32::new(1);
+        let a = PrivateKey::generate(&mut rng);
+        let b = PrivateKey::generate(&mut rng);
+
+        let secret = a.shared_secret(&b.public_key());
+        assert_eq!(secret, b.shared_secret(&a.public_key()));
+        assert_ne!(secret, a.public_key());
+    }
+}
@@ -0,0 +1,83 @@
+//! The RC4 stream cipher that MSE obfuscates connections with.
+//!
+//! RC4 is long broken as encryption, MSE only uses it to hide the protocol from
+//! traffic shaping.
+
+use sha1_smol::Sha1;
+
+/// How much of the keystream MSE throws away, its start leaks the key.
+pub const DISCARD_LEN: usize = 1024;
+
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state: [u8; 256] = core::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// The cipher for one direction of an MSE connection, `name` being `keyA` for
+    /// the initiator and `keyB` for the receiver.
+    pub fn for_mse(name: &[u8; 4], secret: &[u8], info_hash: &[u8; 20]) -> Self {
+        let mut hasher = Sha1::new();
+        hasher.update(name);
+        hasher.update(secret);
+        hasher.update(info_hash);
+        let mut rc4 = Self::new(&hasher.digest().bytes());
+        rc4.apply(&mut [0; DISCARD_LEN]);
+        rc4
+    }
+
+    /// Encrypts or decrypts `data` in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_vectors() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data[..], hex::decode("bbf316e8d940af0ad3").unwrap());
+
+        let mut data = *b"Attack at dawn";
+        Rc4::new(b"Secret").apply(&mut data);
+        assert_eq!(
+            data[..],
+            hex::decode("45a01f645fc35b383552544b9bf5").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_in_pieces() {
+        let mut encrypt = Rc4::for_mse(b"keyA", &[1; 96], &[2; 20]);
+        let mut decrypt = encrypt.clone();
+        let mut data = *b"hello world";
+
+        encrypt.apply(&mut data);
+        assert_ne!(&data, b"hello world");
+        decrypt.apply(&mut data[..4]);
+        decrypt.apply(&mut data[4..]);
+        assert_eq!(&data, b"hello world");
+    }
+}
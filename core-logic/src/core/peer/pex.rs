@@ -0,0 +1,409 @@
+//! Peer Exchange (BEP 11), the `ut_pex` extension.
+//!
+//! Connected peers periodically tell each other which peers they connected to and
+//! which ones they dropped since their last message. This keeps the candidate list
+//! filled while the tracker is unreachable. Private torrents (BEP 27) must only get
+//! peers from their tracker, so PEX is never enabled for them.
+
+use alloc::vec::Vec;
+use bencode::{BencodeParser, BencodeWriter};
+use core::net::SocketAddr;
+use defmt::Format;
+
+use crate::core::{
+    metainfo::Info,
+    net::{
+        COMPACT_PEER_V4_LEN, decode_compact_peers_v4, decode_compact_peers_v6, encode_compact_peer,
+    },
+    peer::{
+        candidates::{Candidates, Source},
+        extension::{ExtensionError, ExtensionHandler},
+    },
+};
+
+/// The name of the extension in the extended handshake.
+pub const PEX_NAME: &str = "ut_pex";
+
+/// How often we send a PEX message to a peer.
+pub const PEX_INTERVAL_MS: u64 = 60_000;
+
+/// Messages that arrive faster than this are ignored. Leaves some slack below
+/// [`PEX_INTERVAL_MS`] for peers whose timers jitter.
+pub const MIN_RECEIVE_INTERVAL_MS: u64 = 45_000;
+
+/// The most added or dropped peers of one message we look at, and the most we send.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// What a peer knows about an added peer, sent as `added.f` and `added6.f`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    /// prefers encrypted connections
+    pub const ENCRYPTION: u8 = 0x01;
+    /// is a seed
+    pub const SEED: u8 = 0x02;
+    /// supports uTP
+    pub const UTP: u8 = 0x04;
+    /// supports the holepunch extension
+    pub const HOLEPUNCH: u8 = 0x08;
+    /// accepted an incoming connection from the sender
+    pub const REACHABLE: u8 = 0x10;
+
+    pub fn contains(&self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: PexFlags,
+}
+
+/// The contents of a `ut_pex` message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn parse(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let mut p = BencodeParser::new(payload);
+        let mut added_v4 = Vec::new();
+        let mut added_v6 = Vec::new();
+        let mut flags_v4: &[u8] = &[];
+        let mut flags_v6: &[u8] = &[];
+        let mut message = Self::default();
+
+        p.expect_dict_start()?;
+        while !p.match_dict_end() {
+            match p.parse_str_bytes()? {
+                b"added" => added_v4 = compact_v4(p.parse_str_bytes()?)?,
+                b"added6" => added_v6 = compact_v6(p.parse_str_bytes()?)?,
+                b"added.f" => flags_v4 = p.parse_str_bytes()?,
+                b"added6.f" => flags_v6 = p.parse_str_bytes()?,
+                b"dropped" => message.dropped.extend(compact_v4(p.parse_str_bytes()?)?),
+                b"dropped6" => message.dropped.extend(compact_v6(p.parse_str_bytes()?)?),
+                _ => p.skip_any()?,
+            }
+        }
+
+        for (peers, flags) in [(added_v4, flags_v4), (added_v6, flags_v6)] {
+            // flags that don't line up with the peers are useless, not an error
+            let flags = if flags.len() == peers.len() {
+                flags
+            } else {
+                &[]
+            };
+            message
+                .added
+                .extend(peers.into_iter().enumerate().map(|(i, addr)| PexPeer {
+                    addr,
+                    flags: PexFlags(flags.get(i).copied().unwrap_or(0)),
+                }));
+        }
+        Ok(message)
+    }
+
+    /// Bencodes the message, keys sorted as bencode requires.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let (added_v4, added_v6): (Vec<&PexPeer>, Vec<&PexPeer>) =
+            self.added.iter().partition(|peer| peer.addr.is_ipv4());
+        let (dropped_v4, dropped_v6): (Vec<&SocketAddr>, Vec<&SocketAddr>) =
+            self.dropped.iter().partition(|addr| addr.is_ipv4());
+
+        let compact = |addrs: &mut dyn Iterator<Item = &SocketAddr>| {
+            let mut bytes = Vec::new();
+            addrs.for_each(|addr| encode_compact_peer(addr, &mut bytes));
+            bytes
+        };
+        let flags = |peers: &[&PexPeer]| peers.iter().map(|peer| peer.flags.0).collect::<Vec<u8>>();
+
+        let mut w = BencodeWriter::new(out);
+        w.dict_start();
+        w.str("added")
+            .bytes(&compact(&mut added_v4.iter().map(|peer| &peer.addr)));
+        w.str("added.f").bytes(&flags(&added_v4));
+        w.str("added6")
+            .bytes(&compact(&mut added_v6.iter().map(|peer| &peer.addr)));
+        w.str("added6.f").bytes(&flags(&added_v6));
+        w.str("dropped")
+            .bytes(&compact(&mut dropped_v4.into_iter()));
+        w.str("dropped6")
+            .bytes(&compact(&mut dropped_v6.into_iter()));
+        w.end();
+    }
+}
+
+fn compact_v4(bytes: &[u8]) -> Result<Vec<SocketAddr>, ExtensionError> {
+    decode_compact_peers_v4(bytes).ok_or(ExtensionError::InvalidMessage)
+}
+
+fn compact_v6(bytes: &[u8]) -> Result<Vec<SocketAddr>, ExtensionError> {
+    decode_compact_peers_v6(bytes).ok_or(ExtensionError::InvalidMessage)
+}
+
+/// PEX with a single peer: collects the peers it tells us about and builds the
+/// messages we send it.
+pub struct PexHandler {
+    /// peers we learned about and haven't handed to the candidate list yet
+    discovered: Vec<PexPeer>,
+    /// peers that left the swarm according to the peer
+    dropped: Vec<SocketAddr>,
+    last_received_ms: Option<u64>,
+    last_sent_ms: Option<u64>,
+    /// the connected peers as of our last message
+    advertised: Vec<SocketAddr>,
+}
+
+impl PexHandler {
+    /// A handler for a torrent, `None` for private torrents.
+    pub fn for_torrent(info: &Info) -> Option<Self> {
+        if info.private {
+            return None;
+        }
+        Some(Self {
+            discovered: Vec::new(),
+            dropped: Vec::new(),
+            last_received_ms: None,
+            last_sent_ms: None,
+            advertised: Vec::new(),
+        })
+    }
+
+    /// Takes the peers learned since the last call.
+    pub fn take_discovered(&mut self) -> Vec<PexPeer> {
+        core::mem::take(&mut self.discovered)
+    }
+
+    /// Moves what the peer told us into the candidate list, returns how many peers were new.
+    pub fn update_candidates(&mut self, candidates: &mut Candidates) -> usize {
+        for addr in self.dropped.drain(..) {
+            candidates.remove(&addr);
+        }
+        let added = self.take_discovered();
+        candidates.extend(added.into_iter().map(|peer| peer.addr), Source::Pex)
+    }
+
+    /// Our next message for the peer, with the changes to `connected` since the last one.
+    /// Each connected peer comes with the flags we know of it, e.g. whether it is a seed.
+    ///
+    /// Returns `None` before [`PEX_INTERVAL_MS`] have passed or if nothing changed.
+    /// The bytes are the payload of an extended message for [`PEX_NAME`].
+    pub fn next_message(&mut self, connected: &[PexPeer], now_ms: u64) -> Option<Vec<u8>> {
+        if self
+            .last_sent_ms
+            .is_some_and(|sent| now_ms.saturating_sub(sent) < PEX_INTERVAL_MS)
+        {
+            return None;
+        }
+
+        let added: Vec<PexPeer> = connected
+            .iter()
+            .filter(|peer| !self.advertised.contains(&peer.addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .iter()
+            .filter(|addr| !connected.iter().any(|peer| peer.addr == **addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        // peers beyond the limits are picked up by the next message
+        self.advertised.retain(|addr| !dropped.contains(addr));
+        self.advertised.extend(added.iter().map(|peer| peer.addr));
+        self.last_sent_ms = Some(now_ms);
+
+        let mut payload = Vec::with_capacity(32 + added.len() * (COMPACT_PEER_V4_LEN + 1));
+        PexMessage { added, dropped }.encode(&mut payload);
+        Some(payload)
+    }
+}
+
+impl ExtensionHandler for PexHandler {
+    fn name(&self) -> &'static str {
+        PEX_NAME
+    }
+
+    fn on_message(&mut self, payload: &[u8], now_ms: u64) -> Result<(), ExtensionError> {
+        if self
+            .last_received_ms
+            .is_some_and(|received| now_ms.saturating_sub(received) < MIN_RECEIVE_INTERVAL_MS)
+        {
+            defmt::debug!("ignoring PEX message that arrived too early");
+            return Ok(());
+        }
+        self.last_received_ms = Some(now_ms);
+
+        let message = PexMessage::parse(payload)?;
+        let room = MAX_PEX_PEERS.saturating_sub(self.discovered.len());
+        for peer in message.added.into_iter().take(room) {
+            if !self.discovered.iter().any(|known| known.addr == peer.addr) {
+                self.discovered.push(peer);
+            }
+        }
+        self.discovered
+            .retain(|peer| !message.dropped.contains(&peer.addr));
+        self.dropped
+            .extend(message.dropped.into_iter().take(MAX_PEX_PEERS));
+        self.dropped.truncate(MAX_PEX_PEERS);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn info(private: bool) -> Info<'static> {
+        Info {
+            piece_length: 16384,
+            name: "test",
+            pieces: &[],
+            length: 0,
+            private,
+        }
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let payload = b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe27:added.f2:\x02\x106:added618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe17:dropped6:\x0a\x00\x00\x03\x1a\xe1e";
+        let message = PexMessage::parse(payload).unwrap();
+
+        assert_eq!(
+            message.added,
+            [
+                PexPeer {
+                    addr: addr("10.0.0.1:6881"),
+                    flags: PexFlags(PexFlags::SEED),
+                },
+                PexPeer {
+                    addr: addr("10.0.0.2:6882"),
+                    flags: PexFlags(PexFlags::REACHABLE),
+                },
+                PexPeer {
+                    addr: addr("[2001:db8::1]:6881"),
+                    flags: PexFlags(0),
+                },
+            ]
+        );
+        assert!(message.added[0].flags.contains(PexFlags::SEED));
+        assert_eq!(message.dropped, [addr("10.0.0.3:6881")]);
+
+        assert_eq!(
+            PexMessage::parse(b"d5:added5:abcdee"),
+            Err(ExtensionError::InvalidMessage)
+        );
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let message = PexMessage {
+            added: alloc::vec![
+                PexPeer {
+                    addr: addr("10.0.0.1:6881"),
+                    flags: PexFlags(PexFlags::ENCRYPTION | PexFlags::UTP),
+                },
+                PexPeer {
+                    addr: addr("[2001:db8::2]:51413"),
+                    flags: PexFlags(PexFlags::SEED),
+                },
+            ],
+            dropped: alloc::vec![addr("10.0.0.9:6881"), addr("[2001:db8::9]:6881")],
+        };
+        let mut payload = Vec::new();
+        message.encode(&mut payload);
+        assert!(payload.starts_with(b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x05"));
+        assert_eq!(PexMessage::parse(&payload).unwrap(), message);
+    }
+
+    #[test]
+    fn test_disabled_for_private_torrents() {
+        assert!(PexHandler::for_torrent(&info(true)).is_none());
+        assert!(PexHandler::for_torrent(&info(false)).is_some());
+    }
+
+    #[test]
+    fn test_incoming_messages_are_rate_limited() {
+        let mut pex = PexHandler::for_torrent(&info(false)).unwrap();
+        let first = b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e";
+        let second = b"d5:added6:\x0a\x00\x00\x02\x1a\xe17:dropped6:\x0a\x00\x00\x01\x1a\xe1e";
+
+        pex.on_message(first, 0).unwrap();
+        pex.on_message(second, 1000).unwrap();
+        assert_eq!(pex.discovered.len(), 1);
+
+        let mut candidates = Candidates::new(10);
+        candidates.add(addr("10.0.0.1:6881"), Source::Tracker);
+        pex.on_message(second, MIN_RECEIVE_INTERVAL_MS).unwrap();
+        assert_eq!(pex.update_candidates(&mut candidates), 1);
+        assert_eq!(
+            candidates
+                .to_vec()
+                .iter()
+                .map(|c| c.addr)
+                .collect::<Vec<_>>(),
+            [addr("10.0.0.2:6881")]
+        );
+        assert_eq!(candidates.pop().unwrap().source, Source::Pex);
+    }
+
+    #[test]
+    fn test_added_peers_are_capped() {
+        let mut pex = PexHandler::for_torrent(&info(false)).unwrap();
+        let mut message = PexMessage::default();
+        for i in 0..MAX_PEX_PEERS + 20 {
+            message.added.push(PexPeer {
+                addr: SocketAddr::new(
+                    core::net::Ipv4Addr::new(10, 1, (i / 256) as u8, i as u8).into(),
+                    6881,
+                ),
+                flags: PexFlags::default(),
+            });
+        }
+        let mut payload = Vec::new();
+        message.encode(&mut payload);
+
+        pex.on_message(&payload, 0).unwrap();
+        assert_eq!(pex.take_discovered().len(), MAX_PEX_PEERS);
+        assert!(pex.take_discovered().is_empty());
+    }
+
+    #[test]
+    fn test_next_message_sends_changes() {
+        let mut pex = PexHandler::for_torrent(&info(false)).unwrap();
+        let a = PexPeer {
+            addr: addr("10.0.0.1:6881"),
+            flags: PexFlags(PexFlags::SEED | PexFlags::UTP),
+        };
+        let b = PexPeer {
+            addr: addr("10.0.0.2:6881"),
+            flags: PexFlags::default(),
+        };
+
+        let payload = pex.next_message(&[a, b], 0).unwrap();
+        let message = PexMessage::parse(&payload).unwrap();
+        assert_eq!(message.added, [a, b]);
+        assert!(message.dropped.is_empty());
+
+        assert_eq!(pex.next_message(&[a], 1000), None);
+        let payload = pex.next_message(&[a], PEX_INTERVAL_MS).unwrap();
+        let message = PexMessage::parse(&payload).unwrap();
+        assert!(message.added.is_empty());
+        assert_eq!(message.dropped, [b.addr]);
+
+        assert_eq!(pex.next_message(&[a], 2 * PEX_INTERVAL_MS), None);
+    }
+}
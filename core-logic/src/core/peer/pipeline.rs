@@ -0,0 +1,606 @@
+//! Keeping several block requests in flight per peer.
+//!
+//! A single outstanding request would leave the connection idle for a full round
+//! trip after every block, which is most of the time on Wi-Fi. The number of
+//! requests in flight follows the bandwidth-delay product of each peer, and the
+//! total is capped so the received blocks fit into the heap.
+
+use alloc::vec::Vec;
+
+use crate::{
+    core::{
+        bitfield::Availability,
+        peer::{
+            connection::PeerConnection,
+            message::{BLOCK_LEN, BlockInfo, Message},
+        },
+        piece_picker::PiecePicker,
+    },
+    rng::Rng,
+};
+
+/// Requests in flight to a new peer, before its speed is known.
+pub const INITIAL_QUEUE_DEPTH: usize = 4;
+pub const MIN_QUEUE_DEPTH: usize = 2;
+pub const MAX_QUEUE_DEPTH: usize = 32;
+
+/// A request is never considered timed out before this long.
+pub const MIN_REQUEST_TIMEOUT_MS: u64 = 5_000;
+/// Throughput is measured over windows of at least this length.
+const THROUGHPUT_WINDOW_MS: u64 = 1_000;
+/// A block that timed out at a peer is offered to it again after this long, in
+/// case no other peer took it.
+pub const TIMED_OUT_BACKOFF_MS: u64 = 30_000;
+
+/// The bytes of all requests in flight, over all peers.
+pub struct InFlightBudget {
+    used: u32,
+    max: u32,
+}
+
+impl InFlightBudget {
+    pub fn new(max_bytes: u32) -> Self {
+        Self {
+            used: 0,
+            max: max_bytes,
+        }
+    }
+
+    pub fn used(&self) -> u32 {
+        self.used
+    }
+
+    fn try_reserve(&mut self, len: u32) -> bool {
+        if self.used + len > self.max {
+            return false;
+        }
+        self.used += len;
+        true
+    }
+
+    fn release(&mut self, len: u32) {
+        self.used = self.used.saturating_sub(len);
+    }
+}
+
+/// The request queue of a single peer.
+pub struct RequestPipeline {
+    depth: usize,
+    /// smoothed round trip time of a request, including the time it waited
+    /// behind the other requests in flight
+    srtt_ms: Option<u64>,
+    /// the smallest round trip seen, which is a request that didn't wait behind
+    /// others, like the first one on an idle connection
+    min_rtt_ms: Option<u64>,
+    window_start: u64,
+    window_bytes: u32,
+    /// blocks that timed out at this peer and should go to other peers, with the
+    /// time they timed out
+    timed_out: Vec<(BlockInfo, u64)>,
+}
+
+impl RequestPipeline {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            depth: INITIAL_QUEUE_DEPTH,
+            srtt_ms: None,
+            min_rtt_ms: None,
+            window_start: now_ms,
+            window_bytes: 0,
+            timed_out: Vec::new(),
+        }
+    }
+
+    /// The number of requests that should be in flight.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// How long a request may take before it is handed to another peer.
+    pub fn request_timeout_ms(&self) -> u64 {
+        self.srtt_ms
+            .map_or(MIN_REQUEST_TIMEOUT_MS, |srtt| 4 * srtt)
+            .max(MIN_REQUEST_TIMEOUT_MS)
+    }
+
+    /// Tops the queue of `peer` up to [`RequestPipeline::depth`] requests,
+    /// as far as the budget allows. Returns the messages to send.
+    ///
+    /// Blocks that timed out here are left to other peers, unless no other peer
+    /// has their piece (`availability` counts this peer as well) or they weren't
+    /// taken within [`TIMED_OUT_BACKOFF_MS`].
+    pub fn fill<R: Rng>(
+        &mut self,
+        peer: &mut PeerConnection,
+        picker: &mut PiecePicker,
+        availability: &Availability,
+        rng: &mut R,
+        budget: &mut InFlightBudget,
+        now_ms: u64,
+    ) -> Vec<Message<'static>> {
+        self.timed_out.retain(|(block, timed_out_ms)| {
+            picker.needs(block)
+                && availability.get(block.index) > 1
+                && now_ms < timed_out_ms + TIMED_OUT_BACKOFF_MS
+        });
+        let in_flight = peer.our_requests().count();
+        if in_flight >= self.depth {
+            return Vec::new();
+        }
+        let wanted =
+            (self.depth - in_flight).min(((budget.max - budget.used) / BLOCK_LEN) as usize);
+
+        // blocks that timed out here are picked as well and given back, so ask for extra
+        let picked = picker.pick(peer, availability, rng, wanted + self.timed_out.len());
+        let mut messages = Vec::new();
+        for block in picked {
+            if messages.len() >= wanted
+                || self
+                    .timed_out
+                    .iter()
+                    .any(|(timed_out, _)| *timed_out == block)
+                || !budget.try_reserve(block.length)
+            {
+                picker.on_request_dropped(&block);
+                continue;
+            }
+            match peer.request(block, now_ms) {
+                Some(message) => messages.push(message),
+                None => {
+                    budget.release(block.length);
+                    picker.on_request_dropped(&block);
+                }
+            }
+        }
+        messages
+    }
+
+    /// Accounts for a block received from this peer, see [`Event::Block`].
+    ///
+    /// [`Event::Block`]: crate::core::peer::connection::Event::Block
+    pub fn on_block(
+        &mut self,
+        block: &BlockInfo,
+        latency_ms: u64,
+        budget: &mut InFlightBudget,
+        now_ms: u64,
+    ) {
+        budget.release(block.length);
+        self.timed_out.retain(|(timed_out, _)| timed_out != block);
+        self.srtt_ms = Some(match self.srtt_ms {
+            Some(srtt) => (7 * srtt + latency_ms) / 8,
+            None => latency_ms,
+        });
+        self.min_rtt_ms = Some(
+            self.min_rtt_ms
+                .map_or(latency_ms, |min| min.min(latency_ms)),
+        );
+
+        self.window_bytes += block.length;
+        let elapsed = now_ms.saturating_sub(self.window_start);
+        if elapsed >= THROUGHPUT_WINDOW_MS {
+            let bytes_per_second = self.window_bytes as u64 * 1000 / elapsed;
+            self.adapt_depth(bytes_per_second);
+            self.window_start = now_ms;
+            self.window_bytes = 0;
+        }
+    }
+
+    /// Gives back requests that won't be answered anymore, e.g. after a choke or a disconnect.
+    pub fn on_dropped<'b>(
+        &mut self,
+        blocks: impl IntoIterator<Item = &'b BlockInfo>,
+        picker: &mut PiecePicker,
+        budget: &mut InFlightBudget,
+    ) {
+        for block in blocks {
+            budget.release(block.length);
+            picker.on_request_dropped(block);
+        }
+    }
+
+    /// Cancels requests that took too long, so other peers can pick them up.
+    /// Returns the `cancel` messages to send.
+    pub fn expire(
+        &mut self,
+        peer: &mut PeerConnection,
+        picker: &mut PiecePicker,
+        budget: &mut InFlightBudget,
+        now_ms: u64,
+    ) -> Vec<Message<'static>> {
+        let expired: Vec<BlockInfo> = peer
+            .timed_out_requests(self.request_timeout_ms(), now_ms)
+            .copied()
+            .collect();
+        if expired.is_empty() {
+            return Vec::new();
+        }
+
+        // the peer is slower than we thought
+        self.depth = (self.depth / 2).max(MIN_QUEUE_DEPTH);
+        self.on_dropped(&expired, picker, budget);
+        if self.timed_out.len() + expired.len() > MAX_QUEUE_DEPTH {
+            self.timed_out.clear();
+        }
+        self.timed_out
+            .extend(expired.iter().map(|block| (*block, now_ms)));
+
+        expired
+            .iter()
+            .filter_map(|block| peer.cancel(*block, now_ms))
+            .collect()
+    }
+
+    /// Sizes the queue to the bandwidth-delay product, plus some slack for jitter.
+    ///
+    /// The delay is the smallest round trip, the smoothed one grows with the queue
+    /// and would make it grow further.
+    fn adapt_depth(&mut self, bytes_per_second: u64) {
+        let rtt_ms = self.min_rtt_ms.unwrap_or(0);
+        let bandwidth_delay = bytes_per_second * rtt_ms / 1000 / BLOCK_LEN as u64;
+        self.depth = (bandwidth_delay as usize + 2).clamp(MIN_QUEUE_DEPTH, MAX_QUEUE_DEPTH);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{
+            bitfield::Bitfield,
+            metainfo::Info,
+            peer::connection::Event,
+            piece_picker::{BlockOutcome, PickMode},
+        },
+        rng::XorShift32,
+    };
+
+    const HASHES: [[u8; 20]; 8] = [[0; 20]; 8];
+
+    fn picker() -> PiecePicker {
+        let info = Info {
+            piece_length: 4 * BLOCK_LEN,
+            name: "test",
+            pieces: &HASHES,
+            length: 32 * BLOCK_LEN,
+            private: false,
+        };
+        PiecePicker::new(&info, PickMode::Sequential)
+    }
+
+    fn seed() -> PeerConnection {
+        let mut peer = PeerConnection::new(8, 0);
+        peer.on_message(&Message::Bitfield(&[0xff]), 0).unwrap();
+        peer.on_message(&Message::Unchoke, 0).unwrap();
+        peer
+    }
+
+    /// Lets `peer` answer its oldest request.
+    fn deliver(
+        peer: &mut PeerConnection,
+        pipeline: &mut RequestPipeline,
+        picker: &mut PiecePicker,
+        budget: &mut InFlightBudget,
+        now_ms: u64,
+    ) {
+        let block = *peer.our_requests().next().unwrap();
+        let data = alloc::vec![0; block.length as usize];
+        let piece = Message::Piece {
+            index: block.index,
+            begin: block.begin,
+            data: &data,
+        };
+        let Ok(Some(Event::Block { latency_ms, .. })) = peer.on_message(&piece, now_ms) else {
+            panic!("block wasn't requested");
+        };
+        assert!(matches!(
+            picker.on_block(&block),
+            BlockOutcome::Needed { .. }
+        ));
+        pipeline.on_block(&block, latency_ms, budget, now_ms);
+    }
+
+    #[test]
+    fn test_fill_keeps_depth_requests_in_flight() {
+        let mut picker = picker();
+        let mut peer = seed();
+        let mut pipeline = RequestPipeline::new(0);
+        let mut budget = InFlightBudget::new(64 * BLOCK_LEN);
+        let availability = Availability::new(8);
+        let mut rng = XorShift32::new(1);
+
+        let messages = pipeline.fill(
+            &mut peer,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            0,
+        );
+        assert_eq!(messages.len(), INITIAL_QUEUE_DEPTH);
+        assert_eq!(budget.used(), INITIAL_QUEUE_DEPTH as u32 * BLOCK_LEN);
+        assert!(
+            pipeline
+                .fill(
+                    &mut peer,
+                    &mut picker,
+                    &availability,
+                    &mut rng,
+                    &mut budget,
+                    0
+                )
+                .is_empty()
+        );
+
+        deliver(&mut peer, &mut pipeline, &mut picker, &mut budget, 100);
+        assert_eq!(budget.used(), (INITIAL_QUEUE_DEPTH as u32 - 1) * BLOCK_LEN);
+        assert_eq!(
+            pipeline
+                .fill(
+                    &mut peer,
+                    &mut picker,
+                    &availability,
+                    &mut rng,
+                    &mut budget,
+                    100
+                )
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_budget_caps_requests() {
+        let mut picker = picker();
+        let mut peer = seed();
+        let mut pipeline = RequestPipeline::new(0);
+        let mut budget = InFlightBudget::new(3 * BLOCK_LEN);
+        let availability = Availability::new(8);
+        let mut rng = XorShift32::new(1);
+
+        let messages = pipeline.fill(
+            &mut peer,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            0,
+        );
+        assert_eq!(messages.len(), 3);
+
+        // the blocks that didn't fit can be picked for another peer
+        let mut other = seed();
+        let mut other_pipeline = RequestPipeline::new(0);
+        let mut other_budget = InFlightBudget::new(64 * BLOCK_LEN);
+        let messages = other_pipeline.fill(
+            &mut other,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut other_budget,
+            0,
+        );
+        assert_eq!(
+            messages[0],
+            Message::Request(BlockInfo {
+                index: 0,
+                begin: 3 * BLOCK_LEN,
+                length: BLOCK_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn test_depth_follows_bandwidth_delay_product() {
+        let mut pipeline = RequestPipeline::new(0);
+        let mut budget = InFlightBudget::new(64 * BLOCK_LEN);
+        let block = BlockInfo {
+            index: 0,
+            begin: 0,
+            length: BLOCK_LEN,
+        };
+
+        // 64 blocks per second with a round trip of 100ms: 6.4 blocks in flight
+        for i in 1..=64 {
+            pipeline.on_block(&block, 100, &mut budget, i * 1000 / 64);
+        }
+        assert_eq!(pipeline.depth(), 8);
+
+        // a slow peer with a short round trip gets the minimum
+        let mut pipeline = RequestPipeline::new(0);
+        pipeline.on_block(&block, 10, &mut budget, 1000);
+        assert_eq!(pipeline.depth(), MIN_QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn test_depth_settles_for_a_steady_peer() {
+        let mut pipeline = RequestPipeline::new(0);
+        let mut budget = InFlightBudget::new(64 * BLOCK_LEN);
+        let block = BlockInfo {
+            index: 0,
+            begin: 0,
+            length: BLOCK_LEN,
+        };
+
+        // a peer 40ms away that uploads a block every 20ms: a request that doesn't
+        // wait takes 100ms and 5 blocks are in flight at full speed
+        let (one_way_ms, upload_ms) = (40, 20);
+        let mut sent = alloc::collections::VecDeque::new();
+        let (mut now, mut peer_busy_until) = (0, 0);
+        while now < 30_000 {
+            while sent.len() < pipeline.depth() {
+                sent.push_back(now);
+            }
+            let sent_at = sent.pop_front().unwrap();
+            peer_busy_until = (sent_at + one_way_ms).max(peer_busy_until) + upload_ms;
+            now = peer_busy_until + one_way_ms;
+            pipeline.on_block(&block, now - sent_at, &mut budget, now);
+        }
+
+        assert_eq!(pipeline.depth(), 5 + 2);
+    }
+
+    #[test]
+    fn test_timed_out_requests_go_to_other_peers() {
+        let mut picker = picker();
+        let mut slow = seed();
+        let mut pipeline = RequestPipeline::new(0);
+        let mut budget = InFlightBudget::new(64 * BLOCK_LEN);
+        // the slow and the fast peer have all pieces
+        let mut availability = Availability::new(8);
+        availability.add_bitfield(&Bitfield::full(8));
+        availability.add_bitfield(&Bitfield::full(8));
+        let mut rng = XorShift32::new(1);
+
+        pipeline.fill(
+            &mut slow,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            0,
+        );
+        assert!(
+            pipeline
+                .expire(&mut slow, &mut picker, &mut budget, 1000)
+                .is_empty()
+        );
+
+        let cancels = pipeline.expire(&mut slow, &mut picker, &mut budget, MIN_REQUEST_TIMEOUT_MS);
+        assert_eq!(cancels.len(), INITIAL_QUEUE_DEPTH);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(pipeline.depth(), MIN_QUEUE_DEPTH);
+
+        // the slow peer gets other blocks
+        let messages = pipeline.fill(
+            &mut slow,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            MIN_REQUEST_TIMEOUT_MS,
+        );
+        assert_eq!(
+            messages[0],
+            Message::Request(BlockInfo {
+                index: 1,
+                begin: 0,
+                length: BLOCK_LEN
+            })
+        );
+
+        // while another peer gets the ones that timed out
+        let mut fast = seed();
+        let mut fast_pipeline = RequestPipeline::new(0);
+        let messages = fast_pipeline.fill(
+            &mut fast,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            MIN_REQUEST_TIMEOUT_MS,
+        );
+        assert_eq!(
+            messages[0],
+            Message::Request(BlockInfo {
+                index: 0,
+                begin: 0,
+                length: BLOCK_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn test_timed_out_block_returns_to_the_only_peer() {
+        let mut picker = picker();
+        let mut slow = seed();
+        let mut pipeline = RequestPipeline::new(0);
+        let mut budget = InFlightBudget::new(64 * BLOCK_LEN);
+        let mut availability = Availability::new(8);
+        availability.add_bitfield(&Bitfield::full(8));
+        let mut rng = XorShift32::new(1);
+
+        pipeline.fill(
+            &mut slow,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            0,
+        );
+        let cancels = pipeline.expire(&mut slow, &mut picker, &mut budget, MIN_REQUEST_TIMEOUT_MS);
+        assert_eq!(cancels.len(), INITIAL_QUEUE_DEPTH);
+
+        // nobody else has the piece, so the slow peer gets the blocks again
+        let messages = pipeline.fill(
+            &mut slow,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            MIN_REQUEST_TIMEOUT_MS,
+        );
+        assert_eq!(
+            messages[0],
+            Message::Request(BlockInfo {
+                index: 0,
+                begin: 0,
+                length: BLOCK_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn test_timed_out_block_returns_after_the_backoff() {
+        let mut picker = picker();
+        let mut slow = seed();
+        let mut pipeline = RequestPipeline::new(0);
+        let mut budget = InFlightBudget::new(64 * BLOCK_LEN);
+        let mut availability = Availability::new(8);
+        availability.add_bitfield(&Bitfield::full(8));
+        availability.add_bitfield(&Bitfield::full(8));
+        let mut rng = XorShift32::new(1);
+        pipeline.fill(
+            &mut slow,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            0,
+        );
+        let timed_out_at = MIN_REQUEST_TIMEOUT_MS;
+        pipeline.expire(&mut slow, &mut picker, &mut budget, timed_out_at);
+        let first = Message::Request(BlockInfo {
+            index: 0,
+            begin: 0,
+            length: BLOCK_LEN,
+        });
+
+        let messages = pipeline.fill(
+            &mut slow,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            timed_out_at,
+        );
+        assert_ne!(messages[0], first);
+
+        // nobody took the block in the meantime
+        let now = timed_out_at + TIMED_OUT_BACKOFF_MS;
+        let in_flight: Vec<BlockInfo> = slow.our_requests().copied().collect();
+        for block in &in_flight {
+            slow.cancel(*block, now);
+        }
+        pipeline.on_dropped(&in_flight, &mut picker, &mut budget);
+        let messages = pipeline.fill(
+            &mut slow,
+            &mut picker,
+            &availability,
+            &mut rng,
+            &mut budget,
+            now,
+        );
+        assert_eq!(messages[0], first);
+    }
+}
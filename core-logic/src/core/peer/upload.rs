@@ -0,0 +1,104 @@
+//! Answering `request`s with blocks read from the SD card.
+
+use defmt::Format;
+
+use crate::{
+    core::{
+        bitfield::Bitfield,
+        metainfo::Info,
+        peer::{
+            connection::PeerConnection,
+            message::{BLOCK_LEN, BlockInfo, PIECE_HEADER_LEN, piece_header},
+        },
+        storage::Storage,
+    },
+    fs::{FileSystem, VolumeMgr},
+    wifi::{TcpConnection, TcpError},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum UploadError {
+    /// We don't have the piece, or the block is outside of it or too large.
+    NotAvailable,
+    BufferTooSmall,
+    /// The block couldn't be read from the SD card.
+    Storage,
+    Tcp(TcpError),
+}
+
+impl From<TcpError> for UploadError {
+    fn from(err: TcpError) -> Self {
+        UploadError::Tcp(err)
+    }
+}
+
+/// Where uploaded blocks are read from: the torrent's files on the SD card.
+pub struct UploadSource<'u, 'a, V: VolumeMgr> {
+    pub fs: &'u mut FileSystem<V>,
+    pub storage: &'u mut Storage<'a>,
+    pub info: &'u Info<'a>,
+}
+
+/// Reads a block from the torrent's files and returns the complete `piece` message,
+/// written to `buf`. The block is read right behind the header, so it isn't copied.
+pub fn read_piece_message<'b, V: VolumeMgr>(
+    source: &mut UploadSource<'_, '_, V>,
+    have: &Bitfield,
+    block: &BlockInfo,
+    buf: &'b mut [u8],
+) -> Result<&'b [u8], UploadError> {
+    let info = source.info;
+    let piece_start = block.index as u64 * info.piece_length as u64;
+    let piece_len = (info.length as u64)
+        .saturating_sub(piece_start)
+        .min(info.piece_length as u64);
+    if !have.has(block.index)
+        || block.length > BLOCK_LEN
+        || block.begin as u64 + block.length as u64 > piece_len
+    {
+        return Err(UploadError::NotAvailable);
+    }
+
+    let len = PIECE_HEADER_LEN + block.length as usize;
+    let message = buf.get_mut(..len).ok_or(UploadError::BufferTooSmall)?;
+    let (header, data) = message.split_at_mut(PIECE_HEADER_LEN);
+    header.copy_from_slice(&piece_header(block.index, block.begin, data.len()));
+
+    match source
+        .storage
+        .read_at(source.fs, piece_start + block.begin as u64, data)
+    {
+        Ok(()) => Ok(message),
+        Err(err) => {
+            defmt::warn!(
+                "couldn't read block {}: {}",
+                block,
+                defmt::Debug2Format(&err)
+            );
+            Err(UploadError::Storage)
+        }
+    }
+}
+
+/// Sends a block the peer requested, unless it cancelled the request in the meantime.
+/// Returns the number of block bytes sent.
+pub async fn serve_request<C, V>(
+    connection: &mut C,
+    peer: &mut PeerConnection,
+    source: &mut UploadSource<'_, '_, V>,
+    have: &Bitfield,
+    block: &BlockInfo,
+    buf: &mut [u8],
+    now_ms: u64,
+) -> Result<u32, UploadError>
+where
+    C: TcpConnection,
+    V: VolumeMgr,
+{
+    if !peer.serve(block, now_ms) {
+        return Ok(0);
+    }
+    let message = read_piece_message(source, have, block, buf)?;
+    connection.write_all(message).await?;
+    Ok(block.length)
+}
@@ -0,0 +1,217 @@
+//! Generating our own peer id and recognising the ones of other clients.
+
+use defmt::Format;
+
+use crate::{core::PeerId, rng::Rng};
+
+/// Azureus-style prefix identifying this client: `MT` followed by the version `0001`.
+pub const CLIENT_PREFIX: &[u8; 8] = b"-MT0001-";
+
+/// Characters used for the random part, so peer ids stay readable in logs.
+const ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Generates a fresh peer id: [`CLIENT_PREFIX`] followed by 12 random characters.
+pub fn generate_peer_id<R: Rng>(rng: &mut R) -> PeerId {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(CLIENT_PREFIX);
+    for b in &mut peer_id[8..] {
+        *b = ALPHABET[rng.next_below(ALPHABET.len() as u32) as usize];
+    }
+    peer_id
+}
+
+/// The peer-id convention a client follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PeerIdStyle {
+    /// `-XXVVVV-` followed by random bytes, used by most modern clients
+    Azureus,
+    /// one letter for the client, version characters and padding dashes, e.g. `S58B-----`
+    Shadow,
+    /// `M4-3-6--`: the original client by Bram Cohen
+    Mainline,
+}
+
+/// What a peer id tells us about the client on the other side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Client<'a> {
+    pub style: PeerIdStyle,
+    /// the client code as it appears in the peer id, e.g. `qB`
+    pub code: &'a str,
+    /// the human readable name, if the code is known
+    pub name: Option<&'static str>,
+    /// the raw version characters, e.g. `4630` or `4-3-6`
+    pub version: &'a str,
+}
+
+/// Recognises the client behind a peer id.
+/// Returns `None` if the peer id doesn't follow any known convention.
+pub fn parse_peer_id(peer_id: &PeerId) -> Option<Client<'_>> {
+    parse_azureus(peer_id)
+        .or_else(|| parse_mainline(peer_id))
+        .or_else(|| parse_shadow(peer_id))
+}
+
+fn parse_azureus(peer_id: &PeerId) -> Option<Client<'_>> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    // e.g. `A~` of Ares
+    let code = ascii_str(&peer_id[1..3], |c| c.is_ascii_alphabetic() || *c == b'~')?;
+    let version = ascii_str(&peer_id[3..7], u8::is_ascii_alphanumeric)?;
+    Some(Client {
+        style: PeerIdStyle::Azureus,
+        code,
+        name: azureus_name(code),
+        version,
+    })
+}
+
+fn parse_mainline(peer_id: &PeerId) -> Option<Client<'_>> {
+    if peer_id[0] != b'M' {
+        return None;
+    }
+    let end = peer_id[1..8].iter().rposition(|&b| b != b'-')? + 2;
+    let version = ascii_str(&peer_id[1..end], |b| b.is_ascii_digit() || *b == b'-')?;
+    // at least "1-0-0"
+    if version.split('-').count() < 3 {
+        return None;
+    }
+    Some(Client {
+        style: PeerIdStyle::Mainline,
+        code: "M",
+        name: Some("Mainline"),
+        version,
+    })
+}
+
+fn parse_shadow(peer_id: &PeerId) -> Option<Client<'_>> {
+    let name = shadow_name(peer_id[0])?;
+    let dashes = peer_id[1..9].iter().position(|&b| b == b'-')? + 1;
+    if dashes < 2 || peer_id[dashes..dashes + 3] != *b"---" {
+        return None;
+    }
+    Some(Client {
+        style: PeerIdStyle::Shadow,
+        code: ascii_str(&peer_id[..1], u8::is_ascii_alphabetic)?,
+        name: Some(name),
+        version: ascii_str(&peer_id[1..dashes], u8::is_ascii_alphanumeric)?,
+    })
+}
+
+fn ascii_str(bytes: &[u8], allowed: impl Fn(&u8) -> bool) -> Option<&str> {
+    if bytes.iter().all(allowed) {
+        core::str::from_utf8(bytes).ok()
+    } else {
+        None
+    }
+}
+
+fn azureus_name(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "MT" => "minitorrent",
+        "AZ" => "Vuze",
+        "BC" => "BitComet",
+        "BT" => "BitTorrent",
+        "DE" => "Deluge",
+        "KT" => "KTorrent",
+        "LT" => "libtorrent (rakshasa)",
+        "lt" => "libtorrent (Rasterbar)",
+        "qB" => "qBittorrent",
+        "TR" => "Transmission",
+        "UT" => "µTorrent",
+        "UW" => "µTorrent Web",
+        "WW" => "WebTorrent",
+        "BI" => "BiglyBT",
+        "FD" => "Free Download Manager",
+        "LW" => "LimeWire",
+        "XL" => "Xunlei",
+        "SD" => "Thunder",
+        "RT" => "rTorrent",
+        "AG" | "A~" => "Ares",
+        _ => return None,
+    })
+}
+
+fn shadow_name(code: u8) -> Option<&'static str> {
+    Some(match code {
+        b'A' => "ABC",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow's client",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift32;
+
+    fn peer_id(prefix: &[u8]) -> PeerId {
+        let mut peer_id = [b'x'; 20];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        peer_id
+    }
+
+    #[test]
+    fn test_generate_peer_id() {
+        let a = generate_peer_id(&mut XorShift32::new(1));
+        let b = generate_peer_id(&mut XorShift32::new(2));
+
+        assert_eq!(&a[..8], CLIENT_PREFIX);
+        assert!(a[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(a, b);
+        assert_eq!(a, generate_peer_id(&mut XorShift32::new(1)));
+
+        let client = parse_peer_id(&a).unwrap();
+        assert_eq!(client.name, Some("minitorrent"));
+        assert_eq!(client.version, "0001");
+    }
+
+    #[test]
+    fn test_parse_azureus() {
+        let id = peer_id(b"-qB4630-");
+        let client = parse_peer_id(&id).unwrap();
+        assert_eq!(
+            client,
+            Client {
+                style: PeerIdStyle::Azureus,
+                code: "qB",
+                name: Some("qBittorrent"),
+                version: "4630"
+            }
+        );
+
+        let id = peer_id(b"-ZZ1000-");
+        let client = parse_peer_id(&id).unwrap();
+        assert_eq!(client.code, "ZZ");
+        assert_eq!(client.name, None);
+
+        let id = peer_id(b"-A~0010-");
+        assert_eq!(parse_peer_id(&id).unwrap().name, Some("Ares"));
+    }
+
+    #[test]
+    fn test_parse_mainline_and_shadow() {
+        let id = peer_id(b"M4-3-6--");
+        let client = parse_peer_id(&id).unwrap();
+        assert_eq!(client.style, PeerIdStyle::Mainline);
+        assert_eq!(client.version, "4-3-6");
+
+        let id = peer_id(b"S58B-----");
+        let client = parse_peer_id(&id).unwrap();
+        assert_eq!(client.style, PeerIdStyle::Shadow);
+        assert_eq!(client.name, Some("Shadow's client"));
+        assert_eq!(client.version, "58B");
+    }
+
+    #[test]
+    fn test_parse_unknown() {
+        assert_eq!(parse_peer_id(&[0u8; 20]), None);
+        assert_eq!(parse_peer_id(&peer_id(b"-q\xff4630-")), None);
+        assert_eq!(parse_peer_id(&peer_id(b"Mxyz")), None);
+    }
+}
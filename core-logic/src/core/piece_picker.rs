@@ -0,0 +1,612 @@
+//! Decides which blocks to request from which peer.
+
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+use defmt::Format;
+
+use crate::{
+    core::{
+        bitfield::{Availability, Bitfield},
+        metainfo::Info,
+        peer::{
+            connection::PeerConnection,
+            message::{BLOCK_LEN, BlockInfo},
+        },
+    },
+    rng::Rng,
+};
+
+/// The order new pieces are started in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PickMode {
+    /// The piece the fewest peers have, ties are broken randomly.
+    RarestFirst,
+    /// The lowest piece index, so files can be read while they are downloading.
+    Sequential,
+}
+
+/// Pieces with a higher priority are always started before pieces with a lower one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum Priority {
+    /// Don't download the piece at all.
+    Skip,
+    Normal,
+    High,
+}
+
+/// What happened to a block handed to [`PiecePicker::on_block`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BlockOutcome {
+    /// We already have the block, or never asked for it.
+    Unneeded,
+    /// The block was needed and should be written.
+    Needed {
+        /// All blocks of the piece have been received, it can be verified now.
+        piece_complete: bool,
+        /// The block was requested from other peers as well during endgame,
+        /// they should get a `cancel`.
+        cancel_others: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    /// requested from this many peers
+    Requested(u8),
+    Received,
+}
+
+/// A piece some of whose blocks have been requested.
+struct PartialPiece {
+    index: u32,
+    blocks: Vec<BlockState>,
+}
+
+/// Tracks the pieces we have and the blocks that are in flight, and picks the
+/// next blocks to request from a peer.
+///
+/// Pieces that have been started are finished first, so as few pieces as possible
+/// are only partially downloaded. Once every missing block has been requested,
+/// endgame mode requests the remaining blocks from more than one peer.
+pub struct PiecePicker {
+    piece_length: u32,
+    length: u32,
+    have: Bitfield,
+    priorities: Vec<Priority>,
+    partial: Vec<PartialPiece>,
+    mode: PickMode,
+    /// pieces that aren't skipped and haven't been verified yet
+    missing: u32,
+}
+
+impl PiecePicker {
+    pub fn new(info: &Info, mode: PickMode) -> Self {
+        Self {
+            piece_length: info.piece_length,
+            length: info.length,
+            have: Bitfield::for_info(info),
+            priorities: vec![Priority::Normal; info.pieces.len()],
+            partial: Vec::new(),
+            mode,
+            missing: info.pieces.len() as u32,
+        }
+    }
+
+    /// The pieces that have been verified.
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn set_mode(&mut self, mode: PickMode) {
+        self.mode = mode;
+    }
+
+    /// Sets the priority of a range of pieces, e.g. those of a single file.
+    pub fn set_priority(&mut self, pieces: Range<u32>, priority: Priority) {
+        let end = (pieces.end as usize).min(self.priorities.len());
+        let start = (pieces.start as usize).min(end);
+        for index in start..end {
+            let was_wanted = self.wanted(index as u32);
+            self.priorities[index] = priority;
+            match (was_wanted, self.wanted(index as u32)) {
+                (true, false) => self.missing -= 1,
+                (false, true) => self.missing += 1,
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether all pieces that aren't skipped have been verified.
+    pub fn is_complete(&self) -> bool {
+        self.missing == 0
+    }
+
+    /// Whether every missing block has been requested, so blocks get requested from
+    /// more than one peer.
+    pub fn in_endgame(&self) -> bool {
+        self.all_started()
+            && self
+                .partial
+                .iter()
+                .filter(|piece| self.wanted(piece.index))
+                .all(|piece| !piece.blocks.contains(&BlockState::Missing))
+    }
+
+    /// Picks up to `max` blocks `peer` can be asked for right now and marks them as requested.
+    pub fn pick<R: Rng>(
+        &mut self,
+        peer: &PeerConnection,
+        availability: &Availability,
+        rng: &mut R,
+        max: usize,
+    ) -> Vec<BlockInfo> {
+        let mut picked = Vec::new();
+        let pieces = peer.requestable_pieces();
+
+        // finish what has been started first
+        for position in 0..self.partial.len() {
+            if picked.len() >= max {
+                return picked;
+            }
+            let index = self.partial[position].index;
+            // a piece may have been skipped since it was started
+            if pieces.has(index) && self.wanted(index) {
+                self.pick_from_partial(position, max, &mut picked);
+            }
+        }
+
+        while picked.len() < max {
+            let Some(index) = self.next_piece(&pieces, availability, rng) else {
+                break;
+            };
+            self.partial.push(PartialPiece {
+                index,
+                blocks: vec![BlockState::Missing; self.num_blocks(index) as usize],
+            });
+            self.pick_from_partial(self.partial.len() - 1, max, &mut picked);
+        }
+
+        if picked.len() < max && self.in_endgame() {
+            let requested: Vec<&BlockInfo> = peer.our_requests().collect();
+            for position in 0..self.partial.len() {
+                let index = self.partial[position].index;
+                if !pieces.has(index) || !self.wanted(index) {
+                    continue;
+                }
+                for block in 0..self.partial[position].blocks.len() {
+                    if picked.len() >= max {
+                        return picked;
+                    }
+                    let info = self.block_info(index, block as u32);
+                    let state = &mut self.partial[position].blocks[block];
+                    if let BlockState::Requested(count) = state
+                        && !requested.contains(&&info)
+                        && !picked.contains(&info)
+                    {
+                        *count = count.saturating_add(1);
+                        picked.push(info);
+                    }
+                }
+            }
+        }
+
+        picked
+    }
+
+    /// Records a received block.
+    pub fn on_block(&mut self, block: &BlockInfo) -> BlockOutcome {
+        let Some(position) = self.position(block.index) else {
+            return BlockOutcome::Unneeded;
+        };
+        if !block.begin.is_multiple_of(BLOCK_LEN)
+            || block.length != self.block_len(block.index, block.begin)
+        {
+            return BlockOutcome::Unneeded;
+        }
+        let blocks = &mut self.partial[position].blocks;
+        let Some(state) = blocks.get_mut((block.begin / BLOCK_LEN) as usize) else {
+            return BlockOutcome::Unneeded;
+        };
+
+        let cancel_others = match *state {
+            BlockState::Received => return BlockOutcome::Unneeded,
+            BlockState::Missing => false,
+            BlockState::Requested(count) => count > 1,
+        };
+        *state = BlockState::Received;
+
+        BlockOutcome::Needed {
+            piece_complete: blocks.iter().all(|state| *state == BlockState::Received),
+            cancel_others,
+        }
+    }
+
+    /// Whether `block` still has to be received, it may be requested already.
+    pub fn needs(&self, block: &BlockInfo) -> bool {
+        if !self.wanted(block.index) {
+            return false;
+        }
+        self.position(block.index).is_none_or(|position| {
+            self.partial[position]
+                .blocks
+                .get((block.begin / BLOCK_LEN) as usize)
+                .is_some_and(|state| *state != BlockState::Received)
+        })
+    }
+
+    /// Gives back a request that won't be answered, e.g. because the peer choked us,
+    /// disconnected or took too long.
+    pub fn on_request_dropped(&mut self, block: &BlockInfo) {
+        let Some(position) = self.position(block.index) else {
+            return;
+        };
+        let blocks = &mut self.partial[position].blocks;
+        if let Some(state) = blocks.get_mut((block.begin / BLOCK_LEN) as usize) {
+            *state = match *state {
+                BlockState::Requested(count) if count > 1 => BlockState::Requested(count - 1),
+                BlockState::Requested(_) => BlockState::Missing,
+                other => other,
+            };
+        }
+    }
+
+    /// Marks a piece as present, after it was verified or found on disk.
+    pub fn on_piece_verified(&mut self, index: u32) {
+        if let Some(position) = self.position(index) {
+            self.partial.swap_remove(position);
+        }
+        if self.wanted(index) {
+            self.missing -= 1;
+        }
+        self.have.set(index);
+    }
+
+    /// Starts over with a piece that failed verification.
+    pub fn on_piece_failed(&mut self, index: u32) {
+        if let Some(position) = self.position(index) {
+            self.partial.swap_remove(position);
+        }
+    }
+
+    fn wanted(&self, index: u32) -> bool {
+        !self.have.has(index) && self.priorities[index as usize] != Priority::Skip
+    }
+
+    /// Whether every missing piece has been started.
+    fn all_started(&self) -> bool {
+        let started = self
+            .partial
+            .iter()
+            .filter(|piece| self.wanted(piece.index))
+            .count();
+        started as u32 == self.missing
+    }
+
+    fn position(&self, index: u32) -> Option<usize> {
+        self.partial.iter().position(|piece| piece.index == index)
+    }
+
+    fn partial(&self, index: u32) -> Option<&PartialPiece> {
+        self.partial.iter().find(|piece| piece.index == index)
+    }
+
+    /// The next piece to start that `pieces` contains.
+    ///
+    /// Only the best candidate so far is kept: ties in [`PickMode::RarestFirst`]
+    /// replace it with a probability of one over the number of ties, which picks
+    /// each of them equally likely.
+    fn next_piece<R: Rng>(
+        &self,
+        pieces: &Bitfield,
+        availability: &Availability,
+        rng: &mut R,
+    ) -> Option<u32> {
+        if self.all_started() {
+            return None;
+        }
+        let candidates = pieces
+            .iter()
+            .filter(|index| self.wanted(*index) && self.partial(*index).is_none());
+
+        let mut best = None;
+        let mut ties = 0;
+        for index in candidates {
+            let priority = self.priorities[index as usize];
+            let key = match self.mode {
+                // lower availability is better, so it's inverted
+                PickMode::RarestFirst => (priority, u16::MAX - availability.get(index)),
+                PickMode::Sequential => (priority, 0),
+            };
+            match best {
+                Some((best_key, _)) if key < best_key => {}
+                // candidates come in ascending order, so the first one wins
+                Some((best_key, _)) if key == best_key && self.mode == PickMode::Sequential => {}
+                Some((best_key, _)) if key == best_key => {
+                    ties += 1;
+                    if rng.next_below(ties) == 0 {
+                        best = Some((key, index));
+                    }
+                }
+                _ => {
+                    best = Some((key, index));
+                    ties = 1;
+                }
+            }
+        }
+        best.map(|(_, index)| index)
+    }
+
+    fn pick_from_partial(&mut self, position: usize, max: usize, picked: &mut Vec<BlockInfo>) {
+        let index = self.partial[position].index;
+        for block in 0..self.partial[position].blocks.len() {
+            if picked.len() >= max {
+                return;
+            }
+            if self.partial[position].blocks[block] == BlockState::Missing {
+                self.partial[position].blocks[block] = BlockState::Requested(1);
+                picked.push(self.block_info(index, block as u32));
+            }
+        }
+    }
+
+    fn piece_len(&self, index: u32) -> u32 {
+        let start = index * self.piece_length;
+        self.piece_length.min(self.length - start)
+    }
+
+    fn num_blocks(&self, index: u32) -> u32 {
+        self.piece_len(index).div_ceil(BLOCK_LEN)
+    }
+
+    fn block_len(&self, index: u32, begin: u32) -> u32 {
+        BLOCK_LEN.min(self.piece_len(index).saturating_sub(begin))
+    }
+
+    fn block_info(&self, index: u32, block: u32) -> BlockInfo {
+        let begin = block * BLOCK_LEN;
+        BlockInfo {
+            index,
+            begin,
+            length: self.block_len(index, begin),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::peer::message::Message, rng::XorShift32};
+
+    const HASHES: [[u8; 20]; 4] = [[0; 20]; 4];
+
+    /// 4 pieces of 2 blocks each, the last piece is a single, short block
+    fn info() -> Info<'static> {
+        Info {
+            piece_length: 2 * BLOCK_LEN,
+            name: "test",
+            pieces: &HASHES,
+            length: 6 * BLOCK_LEN + 100,
+            private: false,
+        }
+    }
+
+    fn peer(bitfield: u8) -> PeerConnection {
+        let mut peer = PeerConnection::new(4, 0);
+        peer.on_message(&Message::Bitfield(&[bitfield]), 0).unwrap();
+        peer.on_message(&Message::Unchoke, 0).unwrap();
+        peer
+    }
+
+    /// Picks blocks and requests them from `peer`, like the download loop does.
+    fn pick_and_request(
+        picker: &mut PiecePicker,
+        peer: &mut PeerConnection,
+        rng: &mut XorShift32,
+        max: usize,
+    ) -> Vec<BlockInfo> {
+        let picked = picker.pick(peer, &Availability::new(4), rng, max);
+        for block in &picked {
+            peer.request(*block, 0).unwrap();
+        }
+        picked
+    }
+
+    fn block(index: u32, block: u32) -> BlockInfo {
+        BlockInfo {
+            index,
+            begin: block * BLOCK_LEN,
+            length: BLOCK_LEN,
+        }
+    }
+
+    #[test]
+    fn test_sequential() {
+        let mut picker = PiecePicker::new(&info(), PickMode::Sequential);
+        let mut rng = XorShift32::new(1);
+        let mut seed = peer(0xf0);
+
+        assert_eq!(
+            pick_and_request(&mut picker, &mut seed, &mut rng, 3),
+            [block(0, 0), block(0, 1), block(1, 0)]
+        );
+        assert_eq!(
+            pick_and_request(&mut picker, &mut seed, &mut rng, 3),
+            [block(1, 1), block(2, 0), block(2, 1)]
+        );
+        assert_eq!(
+            pick_and_request(&mut picker, &mut seed, &mut rng, 3),
+            [BlockInfo {
+                index: 3,
+                begin: 0,
+                length: 100
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut picker = PiecePicker::new(&info(), PickMode::RarestFirst);
+        let mut availability = Availability::new(4);
+        let mut rng = XorShift32::new(1);
+        for bitfield in [0xf0, 0xd0, 0xb0] {
+            availability.add_bitfield(peer(bitfield).peer_pieces());
+        }
+
+        // piece 1 and 2 are the rarest
+        let seed = peer(0xf0);
+        let picked = picker.pick(&seed, &availability, &mut rng, 1);
+        assert!(picked[0].index == 1 || picked[0].index == 2);
+
+        // a peer without the rarest pieces gets piece 0 or 3
+        let picked = picker.pick(&peer(0x90), &availability, &mut rng, 1);
+        assert!(picked[0].index == 0 || picked[0].index == 3);
+    }
+
+    #[test]
+    fn test_random_tie_break() {
+        let availability = Availability::new(4);
+        let seed = peer(0xf0);
+        let mut rng = XorShift32::new(7);
+
+        let mut first_pieces = Vec::new();
+        for _ in 0..20 {
+            let mut picker = PiecePicker::new(&info(), PickMode::RarestFirst);
+            first_pieces.push(picker.pick(&seed, &availability, &mut rng, 1)[0].index);
+        }
+        // every tied piece gets its turn
+        for index in 0..4 {
+            assert!(first_pieces.contains(&index), "{first_pieces:?}");
+        }
+    }
+
+    #[test]
+    fn test_priorities() {
+        let mut picker = PiecePicker::new(&info(), PickMode::Sequential);
+        let availability = Availability::new(4);
+        let mut rng = XorShift32::new(1);
+        picker.set_priority(2..3, Priority::High);
+        picker.set_priority(0..2, Priority::Skip);
+
+        let picked = picker.pick(&peer(0xf0), &availability, &mut rng, 10);
+        let pieces: Vec<u32> = picked.iter().map(|block| block.index).collect();
+        assert_eq!(pieces, [2, 2, 3]);
+    }
+
+    #[test]
+    fn test_started_piece_that_is_skipped() {
+        let mut picker = PiecePicker::new(&info(), PickMode::Sequential);
+        let availability = Availability::new(4);
+        let mut rng = XorShift32::new(1);
+        assert_eq!(
+            picker.pick(&peer(0xf0), &availability, &mut rng, 1),
+            [block(0, 0)]
+        );
+
+        picker.set_priority(0..1, Priority::Skip);
+        let picked = picker.pick(&peer(0xf0), &availability, &mut rng, 10);
+        assert!(picked.iter().all(|block| block.index != 0));
+    }
+
+    #[test]
+    fn test_completion_follows_priorities() {
+        let mut picker = PiecePicker::new(&info(), PickMode::Sequential);
+        picker.set_priority(0..4, Priority::Skip);
+        assert!(picker.is_complete());
+        assert!(picker.in_endgame());
+
+        picker.set_priority(1..3, Priority::High);
+        picker.on_piece_verified(1);
+        picker.on_piece_verified(1);
+        picker.on_piece_verified(3);
+        assert!(!picker.is_complete());
+
+        // skipping a verified piece doesn't count it twice
+        picker.set_priority(1..2, Priority::Skip);
+        picker.on_piece_verified(2);
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn test_blocks_and_verification() {
+        let mut picker = PiecePicker::new(&info(), PickMode::Sequential);
+        let availability = Availability::new(4);
+        let mut rng = XorShift32::new(1);
+        picker.pick(&peer(0xf0), &availability, &mut rng, 2);
+
+        assert_eq!(
+            picker.on_block(&block(0, 0)),
+            BlockOutcome::Needed {
+                piece_complete: false,
+                cancel_others: false
+            }
+        );
+        assert_eq!(picker.on_block(&block(0, 0)), BlockOutcome::Unneeded);
+        assert_eq!(
+            picker.on_block(&block(0, 1)),
+            BlockOutcome::Needed {
+                piece_complete: true,
+                cancel_others: false
+            }
+        );
+
+        picker.on_piece_failed(0);
+        assert_eq!(picker.on_block(&block(0, 0)), BlockOutcome::Unneeded);
+        assert_eq!(
+            picker.pick(&peer(0x80), &availability, &mut rng, 2),
+            [block(0, 0), block(0, 1)]
+        );
+        picker.on_piece_verified(0);
+        assert!(picker.have().has(0));
+        assert!(!picker.is_complete());
+    }
+
+    #[test]
+    fn test_dropped_requests_are_picked_again() {
+        let mut picker = PiecePicker::new(&info(), PickMode::Sequential);
+        let mut rng = XorShift32::new(1);
+        let mut first = peer(0x40);
+        pick_and_request(&mut picker, &mut first, &mut rng, 2);
+
+        // another peer gets the request the first one dropped, before anything else
+        picker.on_request_dropped(&block(1, 1));
+        let mut second = peer(0xc0);
+        assert_eq!(
+            pick_and_request(&mut picker, &mut second, &mut rng, 2),
+            [block(1, 1), block(0, 0)]
+        );
+    }
+
+    #[test]
+    fn test_endgame() {
+        let mut picker = PiecePicker::new(&info(), PickMode::Sequential);
+        let availability = Availability::new(4);
+        let mut rng = XorShift32::new(1);
+        picker.set_priority(1..4, Priority::Skip);
+
+        let mut first = peer(0x80);
+        for block in picker.pick(&first, &availability, &mut rng, 10) {
+            first.request(block, 0).unwrap();
+        }
+        assert!(picker.in_endgame());
+        // nothing new for the same peer
+        assert!(picker.pick(&first, &availability, &mut rng, 10).is_empty());
+
+        // but a second peer gets duplicates
+        let second = peer(0x80);
+        assert_eq!(
+            picker.pick(&second, &availability, &mut rng, 10),
+            [block(0, 0), block(0, 1)]
+        );
+        assert_eq!(
+            picker.on_block(&block(0, 0)),
+            BlockOutcome::Needed {
+                piece_complete: false,
+                cancel_others: true
+            }
+        );
+
+        picker.on_piece_verified(0);
+        assert!(picker.is_complete());
+    }
+}
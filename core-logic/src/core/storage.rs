@@ -0,0 +1,300 @@
+//! Writing downloaded blocks to the SD card, at their place in the torrent's files.
+//!
+//! The files of a torrent follow each other in one stream of bytes, which is cut
+//! into pieces. [`Storage`] maps an absolute offset of that stream to a file and a
+//! position in it, so blocks can be written in whatever order they arrive and a
+//! block that runs past the end of one file continues in the next.
+//!
+//! The files are filled to their full length once, with [`Storage::allocate`] when
+//! the torrent is added, so writing a block never has to extend a file first.
+//! Blocks are written through [`FileHandle`]s that stay open between blocks.
+
+use alloc::vec::Vec;
+use defmt::Format;
+use embedded_sdmmc::Mode;
+
+use crate::{
+    core::{metainfo::Info, peer::message::BlockInfo},
+    fs::{FileHandle, FileSystem, FsError, VolumeMgr},
+};
+
+/// A file of a torrent, in the current directory of the filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct TorrentFile<'a> {
+    pub name: &'a str,
+    pub length: u32,
+}
+
+/// The part of a range of the torrent that lies in one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Span {
+    /// index of the file
+    pub file: usize,
+    /// position in the file
+    pub offset: u32,
+    pub len: usize,
+}
+
+/// The files of a torrent, laid out one after the other.
+pub struct Storage<'a> {
+    piece_length: u32,
+    files: Vec<TorrentFile<'a>>,
+    length: u64,
+    /// the handles of the files that are open, by index of `files`
+    handles: Vec<Option<FileHandle>>,
+}
+
+impl<'a> Storage<'a> {
+    pub fn new(piece_length: u32, files: Vec<TorrentFile<'a>>) -> Self {
+        let length = files.iter().map(|file| file.length as u64).sum();
+        Self {
+            piece_length,
+            handles: alloc::vec![None; files.len()],
+            files,
+            length,
+        }
+    }
+
+    /// The layout of a single file torrent, whose file is named like the torrent.
+    ///
+    /// That only works for names that are valid 8.3 names, others are mapped with
+    /// [`NameMap`](crate::fs::short_names::NameMap) and passed to [`Storage::new`].
+    pub fn single_file(info: &Info<'a>) -> Self {
+        Self::new(
+            info.piece_length,
+            alloc::vec![TorrentFile {
+                name: info.name,
+                length: info.length,
+            }],
+        )
+    }
+
+    pub fn files(&self) -> &[TorrentFile<'a>] {
+        &self.files
+    }
+
+    /// The length of all files together.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// The parts of the files that the `len` bytes at `offset` are stored in, in order.
+    /// `None` if the range doesn't fit into the torrent.
+    pub fn spans(&self, offset: u64, len: usize) -> Option<Vec<Span>> {
+        if offset.checked_add(len as u64)? > self.length {
+            return None;
+        }
+        let mut spans = Vec::new();
+        let mut file_start = 0u64;
+        let (mut offset, mut remaining) = (offset, len);
+        for (index, file) in self.files.iter().enumerate() {
+            let file_end = file_start + file.length as u64;
+            if remaining > 0 && offset < file_end {
+                let len = remaining.min((file_end - offset) as usize);
+                spans.push(Span {
+                    file: index,
+                    offset: (offset - file_start) as u32,
+                    len,
+                });
+                offset += len as u64;
+                remaining -= len;
+            }
+            file_start = file_end;
+        }
+        Some(spans)
+    }
+
+    /// Creates the files in the current directory and fills them with zeros up to
+    /// their full length. Files that have their length already, e.g. after a
+    /// restart, are kept as they are.
+    pub fn allocate<V: VolumeMgr>(&mut self, fs: &mut FileSystem<V>) -> Result<(), FsError<V>> {
+        let zeros = [0u8; 512];
+        for index in 0..self.files.len() {
+            let handle = self.handle(fs, index)?;
+            let length = self.files[index].length;
+            let mut allocated = fs.file_length(handle)?;
+            while allocated < length {
+                let len = zeros.len().min((length - allocated) as usize);
+                fs.write_file_at(handle, allocated, &zeros[..len])?;
+                allocated += len as u32;
+            }
+            fs.flush_file(handle)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the files that are still open.
+    pub fn close<V: VolumeMgr>(&mut self, fs: &mut FileSystem<V>) -> Result<(), FsError<V>> {
+        for handle in self.handles.iter_mut().filter_map(Option::take) {
+            fs.close_file(handle)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at the absolute `offset` of the torrent.
+    ///
+    /// Fails with [`embedded_sdmmc::Error::InvalidOffset`] if it doesn't fit into the torrent.
+    pub fn write_at<V: VolumeMgr>(
+        &mut self,
+        fs: &mut FileSystem<V>,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), FsError<V>> {
+        let spans = self
+            .spans(offset, data.len())
+            .ok_or(embedded_sdmmc::Error::InvalidOffset)?;
+        let mut data = data;
+        for span in spans {
+            let (part, rest) = data.split_at(span.len);
+            let handle = self.handle(fs, span.file)?;
+            fs.write_file_at(handle, span.offset, part)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Fills `buf` with the bytes at the absolute `offset` of the torrent.
+    ///
+    /// Fails with [`embedded_sdmmc::Error::EndOfFile`] if they weren't written yet.
+    pub fn read_at<V: VolumeMgr>(
+        &mut self,
+        fs: &mut FileSystem<V>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), FsError<V>> {
+        let spans = self
+            .spans(offset, buf.len())
+            .ok_or(embedded_sdmmc::Error::InvalidOffset)?;
+        let mut buf = buf;
+        for span in spans {
+            let (part, rest) = buf.split_at_mut(span.len);
+            let handle = self.handle(fs, span.file)?;
+            if fs.read_file_at(handle, span.offset, part)? < part.len() {
+                return Err(embedded_sdmmc::Error::EndOfFile);
+            }
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// Writes a received block of a piece to where it belongs.
+    ///
+    /// Fails with [`embedded_sdmmc::Error::InvalidOffset`] if `data` doesn't match
+    /// the block or the block lies outside of its piece.
+    pub fn write_block<V: VolumeMgr>(
+        &mut self,
+        fs: &mut FileSystem<V>,
+        block: &BlockInfo,
+        data: &[u8],
+    ) -> Result<(), FsError<V>> {
+        if data.len() != block.length as usize
+            || block.begin as u64 + block.length as u64 > self.piece_length as u64
+        {
+            return Err(embedded_sdmmc::Error::InvalidOffset);
+        }
+        let offset = block.index as u64 * self.piece_length as u64 + block.begin as u64;
+        self.write_at(fs, offset, data)
+    }
+
+    /// The handle of the `index`th file, which is opened in the current directory
+    /// if it isn't yet. When the filesystem has no handle left, another file of the
+    /// torrent is closed to make room.
+    fn handle<V: VolumeMgr>(
+        &mut self,
+        fs: &mut FileSystem<V>,
+        index: usize,
+    ) -> Result<FileHandle, FsError<V>> {
+        if let Some(handle) = self.handles[index] {
+            return Ok(handle);
+        }
+        let name = self.files[index].name;
+        let handle = match fs.open_file(name, Mode::ReadWriteCreateOrAppend) {
+            Err(embedded_sdmmc::Error::TooManyOpenFiles) => {
+                let Some(other) = self.handles.iter_mut().find_map(Option::take) else {
+                    return Err(embedded_sdmmc::Error::TooManyOpenFiles);
+                };
+                fs.close_file(other)?;
+                fs.open_file(name, Mode::ReadWriteCreateOrAppend)?
+            }
+            result => result?,
+        };
+        self.handles[index] = Some(handle);
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> Storage<'static> {
+        Storage::new(
+            16,
+            alloc::vec![
+                TorrentFile {
+                    name: "a.bin",
+                    length: 10,
+                },
+                TorrentFile {
+                    name: "empty.bin",
+                    length: 0,
+                },
+                TorrentFile {
+                    name: "b.bin",
+                    length: 20,
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn test_spans_within_a_file() {
+        let storage = storage();
+        assert_eq!(storage.length(), 30);
+
+        assert_eq!(
+            storage.spans(2, 5),
+            Some(alloc::vec![Span {
+                file: 0,
+                offset: 2,
+                len: 5
+            }])
+        );
+        assert_eq!(
+            storage.spans(12, 18),
+            Some(alloc::vec![Span {
+                file: 2,
+                offset: 2,
+                len: 18
+            }])
+        );
+    }
+
+    #[test]
+    fn test_spans_across_files() {
+        assert_eq!(
+            storage().spans(8, 6),
+            Some(alloc::vec![
+                Span {
+                    file: 0,
+                    offset: 8,
+                    len: 2
+                },
+                Span {
+                    file: 2,
+                    offset: 0,
+                    len: 4
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_spans_out_of_bounds() {
+        let storage = storage();
+
+        assert_eq!(storage.spans(30, 0), Some(Vec::new()));
+        assert_eq!(storage.spans(25, 6), None);
+        assert_eq!(storage.spans(u64::MAX, 1), None);
+    }
+}
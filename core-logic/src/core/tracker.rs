@@ -1,11 +1,115 @@
-use crate::core::{InfoHash, PeerId, net::percent_encode};
-use alloc::string::String;
-use core::fmt::Write;
+use crate::{
+    core::{
+        InfoHash, PeerId,
+        net::{decode_compact_peers_v4, decode_compact_peers_v6, host_and_port, percent_encode},
+    },
+    wifi::WifiStack,
+};
+use alloc::{string::String, vec, vec::Vec};
+use bencode::BencodeParser;
+use core::{
+    fmt::Write,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+use defmt::Format;
 
-pub struct TrackerResponse<'a> {
+pub mod scrape;
+mod udp;
+
+pub use scrape::ScrapeStats;
+
+/// Errors that can occur while talking to a tracker.
+#[derive(Debug, Format)]
+pub enum TrackerError<E> {
+    /// The request couldn't be sent or no answer was received.
+    Network(E),
+    /// The announce url doesn't support the requested operation.
+    InvalidUrl,
+    /// The tracker answered with something that isn't a valid response.
+    InvalidResponse,
+    /// The tracker refused the request, e.g. because it doesn't know the torrent.
+    Rejected,
+}
+
+impl<E> From<bencode::Error> for TrackerError<E> {
+    fn from(_: bencode::Error) -> Self {
+        TrackerError::InvalidResponse
+    }
+}
+
+#[derive(Debug, PartialEq, Format)]
+pub struct TrackerResponse {
+    /// the number of seconds to wait before announcing again
     pub interval: u32,
-    pub peers: &'a [(core::net::Ipv4Addr, u16)],
+    /// IPv4 peers from `peers` followed by IPv6 peers from `peers6`
+    pub peers: Vec<SocketAddr>,
+}
+
+impl TrackerResponse {
+    /// Parses the bencoded body of an http announce response.
+    /// Supports compact (BEP 23) and dictionary peer lists as well as `peers6` (BEP 7).
+    pub fn parse<E>(input: &[u8]) -> Result<Self, TrackerError<E>> {
+        let mut p = BencodeParser::new(input);
+        let mut interval = None;
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+
+        p.expect_dict_start()?;
+        while !p.match_dict_end() {
+            match p.parse_str()? {
+                "interval" => interval = Some(p.parse_int()? as u32),
+                "peers" if p.peek() == Some(b'l') => peers = parse_peer_dicts(&mut p)?,
+                "peers" => {
+                    peers = decode_compact_peers_v4(p.parse_str_bytes()?)
+                        .ok_or(TrackerError::InvalidResponse)?;
+                }
+                "peers6" => {
+                    peers6 = decode_compact_peers_v6(p.parse_str_bytes()?)
+                        .ok_or(TrackerError::InvalidResponse)?;
+                }
+                "failure reason" => {
+                    defmt::warn!("tracker error: {}", p.parse_str()?);
+                    return Err(TrackerError::Rejected);
+                }
+                _ => p.skip_any()?,
+            }
+        }
+
+        peers.append(&mut peers6);
+        Ok(TrackerResponse {
+            interval: interval.ok_or(TrackerError::InvalidResponse)?,
+            peers,
+        })
+    }
 }
+
+/// Parses the original, non-compact peer list: a list of dicts with `ip` and `port`.
+fn parse_peer_dicts<E>(p: &mut BencodeParser) -> Result<Vec<SocketAddr>, TrackerError<E>> {
+    let mut peers = Vec::new();
+
+    p.expect_list_start()?;
+    while !p.match_list_end() {
+        let mut ip = None;
+        let mut port = None;
+
+        p.expect_dict_start()?;
+        while !p.match_dict_end() {
+            match p.parse_str()? {
+                "ip" => ip = p.parse_str()?.parse::<IpAddr>().ok(),
+                "port" => port = Some(p.parse_int()? as u16),
+                _ => p.skip_any()?,
+            }
+        }
+
+        // peers announcing a hostname instead of an address are ignored
+        if let (Some(ip), Some(port)) = (ip, port) {
+            peers.push(SocketAddr::new(ip, port));
+        }
+    }
+
+    Ok(peers)
+}
+
 #[derive(Debug, Clone)]
 pub struct TrackerRequest<'a> {
     /// the info hash of the torrent
@@ -23,6 +127,8 @@ pub struct TrackerRequest<'a> {
     /// whether the peer list should use the compact representation
     /// The compact representation is more commonly used in the wild, the non-compact representation is mostly supported for backward-compatibility.
     compact: u8,
+    /// our global IPv6 address, so the tracker can hand it to IPv6 peers (BEP 7)
+    ipv6: Option<Ipv6Addr>,
 }
 
 impl<'a> TrackerRequest<'a> {
@@ -35,23 +141,83 @@ impl<'a> TrackerRequest<'a> {
             downloaded: 0,
             left,
             compact: 1,
+            ipv6: None,
         }
     }
 
-    pub(crate) fn to_url_encoded(&self) -> String {
+    /// Announce our IPv6 address in addition to the address the request is sent from.
+    pub fn with_ipv6(mut self, ipv6: Ipv6Addr) -> Self {
+        self.ipv6 = Some(ipv6);
+        self
+    }
+
+    pub fn to_url_encoded(&self) -> String {
         let mut url_encoded = String::with_capacity(256);
 
-        write!(url_encoded, "info_hash={}", &percent_encode(self.info_hash)).unwrap();
-        write!(url_encoded, "&peer_id={}", &percent_encode(self.peer_id)).unwrap();
+        write!(url_encoded, "info_hash={}", percent_encode(self.info_hash)).unwrap();
+        write!(url_encoded, "&peer_id={}", percent_encode(self.peer_id)).unwrap();
         write!(url_encoded, "&port={}", self.port).unwrap();
         write!(url_encoded, "&uploaded={}", self.uploaded).unwrap();
         write!(url_encoded, "&downloaded={}", self.downloaded).unwrap();
         write!(url_encoded, "&left={}", self.left).unwrap();
         write!(url_encoded, "&compact={}", self.compact).unwrap();
+        if let Some(ipv6) = self.ipv6 {
+            // colons are reserved in query strings
+            let ipv6 = alloc::format!("{}", ipv6).replace(':', "%3A");
+            write!(url_encoded, "&ipv6={}", ipv6).unwrap();
+        }
         url_encoded
     }
 }
 
+/// Asks the tracker behind `announce` for the swarm statistics of the given torrents
+/// without joining their swarms.
+///
+/// Both `http(s)://` and `udp://` trackers are supported.
+pub async fn scrape<W: WifiStack>(
+    wifi: &W,
+    announce: &str,
+    info_hashes: &[InfoHash],
+    transaction_id: u32,
+) -> Result<Vec<ScrapeStats>, TrackerError<W::Error>> {
+    if announce.starts_with("udp://") {
+        let (host, port) = host_and_port(announce).ok_or(TrackerError::InvalidUrl)?;
+        let mut rx_buf = vec![0u8; 8 + 12 * udp::MAX_SCRAPE_HASHES];
+
+        let response = wifi
+            .make_udp_request(
+                host,
+                port,
+                &udp::connect_request(transaction_id),
+                &mut rx_buf,
+            )
+            .await
+            .map_err(TrackerError::Network)?;
+        let connection_id = udp::parse_connect_response(response, transaction_id)?;
+
+        // a single datagram only fits a limited number of info hashes
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for batch in info_hashes.chunks(udp::MAX_SCRAPE_HASHES) {
+            let request = udp::scrape_request(connection_id, transaction_id, batch);
+            let response = wifi
+                .make_udp_request(host, port, &request, &mut rx_buf)
+                .await
+                .map_err(TrackerError::Network)?;
+            stats.extend(udp::parse_scrape_response(response, transaction_id, batch)?);
+        }
+        Ok(stats)
+    } else {
+        let url = scrape::scrape_url(announce, info_hashes).ok_or(TrackerError::InvalidUrl)?;
+        let mut rx_buf = vec![0u8; 1024 + 100 * info_hashes.len()];
+
+        let response = wifi
+            .make_http_request(&url, &mut rx_buf)
+            .await
+            .map_err(TrackerError::Network)?;
+        scrape::parse_scrape_response(response)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,5 +241,57 @@ mod tests {
         assert!(url_encoded.contains("downloaded=0"));
         assert!(url_encoded.contains("left=1000"));
         assert!(url_encoded.contains("compact=1"));
+        assert!(!url_encoded.contains("ipv6="));
+    }
+
+    #[test]
+    fn test_tracker_request_ipv6() {
+        let info_hash: InfoHash = [0u8; 20];
+        let peer_id: PeerId = [1u8; 20];
+        let request = TrackerRequest::new(&info_hash, &peer_id, 6881, 1000)
+            .with_ipv6("2001:db8::1".parse().unwrap());
+
+        assert!(
+            request
+                .to_url_encoded()
+                .ends_with("&ipv6=2001%3Adb8%3A%3A1")
+        );
+    }
+
+    #[test]
+    fn test_tracker_response_compact() {
+        let mut input = Vec::new();
+        input.extend_from_slice(b"d8:intervali1800e5:peers12:");
+        input.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+        input.extend_from_slice(b"6:peers618:");
+        input.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        input.extend_from_slice(&[0; 11]);
+        input.extend_from_slice(&[1, 0x1a, 0xe1]);
+        input.extend_from_slice(b"e");
+
+        let response = TrackerResponse::parse::<()>(&input).unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(
+            response.peers,
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tracker_response_dict_peers() {
+        let input = b"d8:intervali60e5:peersld2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip7:2001::14:porti1eeee";
+
+        let response = TrackerResponse::parse::<()>(input).unwrap();
+        assert_eq!(
+            response.peers,
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "[2001::1]:1".parse().unwrap()
+            ]
+        );
     }
 }
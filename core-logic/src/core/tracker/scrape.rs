@@ -0,0 +1,147 @@
+use alloc::{string::String, vec::Vec};
+use bencode::BencodeParser;
+use core::fmt::Write;
+use defmt::Format;
+
+use crate::core::{InfoHash, net::percent_encode, tracker::TrackerError};
+
+/// Swarm statistics of a single torrent as reported by a tracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ScrapeStats {
+    pub info_hash: InfoHash,
+    /// number of peers that have the complete torrent
+    pub seeders: u32,
+    /// number of times the torrent has been downloaded to completion
+    pub completed: u32,
+    /// number of peers that are still downloading
+    pub leechers: u32,
+}
+
+/// Derives the scrape url from an http announce url.
+///
+/// By convention this only works if the last path segment starts with `announce`,
+/// which is then replaced by `scrape`, e.g. `http://example.com/announce.php?key=1`
+/// becomes `http://example.com/scrape.php?key=1`.
+pub fn scrape_url(announce: &str, info_hashes: &[InfoHash]) -> Option<String> {
+    let (path, query) = match announce.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce, None),
+    };
+    let (base, last_segment) = path.rsplit_once('/')?;
+    let suffix = last_segment.strip_prefix("announce")?;
+
+    let mut url = String::with_capacity(announce.len() + 70 * info_hashes.len());
+    write!(url, "{}/scrape{}", base, suffix).unwrap();
+
+    let mut separator = '?';
+    if let Some(query) = query {
+        write!(url, "?{}", query).unwrap();
+        separator = '&';
+    }
+    for info_hash in info_hashes {
+        write!(url, "{}info_hash={}", separator, percent_encode(info_hash)).unwrap();
+        separator = '&';
+    }
+    Some(url)
+}
+
+/// Parses the bencoded body of an http scrape response.
+///
+/// Only the torrents the tracker knows about are contained in the result.
+pub fn parse_scrape_response<E>(input: &[u8]) -> Result<Vec<ScrapeStats>, TrackerError<E>> {
+    let mut p = BencodeParser::new(input);
+    let mut stats = Vec::new();
+
+    p.expect_dict_start()?;
+    while !p.match_dict_end() {
+        match p.parse_str()? {
+            "files" => {
+                p.expect_dict_start()?;
+                while !p.match_dict_end() {
+                    let info_hash = p
+                        .parse_str_bytes()?
+                        .try_into()
+                        .map_err(|_| TrackerError::InvalidResponse)?;
+                    stats.push(parse_stats(&mut p, info_hash)?);
+                }
+            }
+            "failure reason" => {
+                defmt::warn!("tracker error: {}", p.parse_str()?);
+                return Err(TrackerError::Rejected);
+            }
+            _ => p.skip_any()?,
+        }
+    }
+
+    Ok(stats)
+}
+
+fn parse_stats(p: &mut BencodeParser, info_hash: InfoHash) -> bencode::Result<ScrapeStats> {
+    let mut stats = ScrapeStats {
+        info_hash,
+        seeders: 0,
+        completed: 0,
+        leechers: 0,
+    };
+
+    p.expect_dict_start()?;
+    while !p.match_dict_end() {
+        match p.parse_str()? {
+            "complete" => stats.seeders = p.parse_int()? as u32,
+            "downloaded" => stats.completed = p.parse_int()? as u32,
+            "incomplete" => stats.leechers = p.parse_int()? as u32,
+            _ => p.skip_any()?,
+        }
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_url() {
+        let info_hash = [0xab; 20];
+        assert_eq!(
+            scrape_url("http://example.com/announce", &[info_hash]).unwrap(),
+            format!("http://example.com/scrape?info_hash={}", "%AB".repeat(20))
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?key=1", &[]).unwrap(),
+            "http://example.com/x/scrape.php?key=1"
+        );
+        assert!(scrape_url("http://example.com/a", &[info_hash]).is_none());
+        assert!(scrape_url("http://example.com/announce/x", &[info_hash]).is_none());
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let mut input = Vec::new();
+        input.extend_from_slice(b"d5:filesd20:");
+        input.extend_from_slice(&[b'a'; 20]);
+        input.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10e4:name1:xe");
+        input.extend_from_slice(b"e5:flagsd20:min_request_intervali900eee");
+
+        let stats = parse_scrape_response::<()>(&input).unwrap();
+        assert_eq!(
+            stats,
+            [ScrapeStats {
+                info_hash: [b'a'; 20],
+                seeders: 5,
+                completed: 50,
+                leechers: 10
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_scrape_failure() {
+        let result = parse_scrape_response::<()>(b"d14:failure reason7:go awaye");
+        assert!(matches!(result, Err(TrackerError::Rejected)));
+
+        let result = parse_scrape_response::<()>(b"d5:filesd3:abcdeee");
+        assert!(matches!(result, Err(TrackerError::InvalidResponse)));
+    }
+}
@@ -0,0 +1,173 @@
+//! Packet layout of the UDP tracker protocol (BEP 15).
+
+use alloc::vec::Vec;
+
+use crate::core::{InfoHash, tracker::TrackerError};
+
+use super::scrape::ScrapeStats;
+
+/// magic constant that identifies a connect request
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// The most info hashes that fit into a single scrape request.
+pub(crate) const MAX_SCRAPE_HASHES: usize = 74;
+
+pub(crate) fn connect_request(transaction_id: u32) -> [u8; 16] {
+    let mut packet = [0u8; 16];
+    packet[..8].copy_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet[8..12].copy_from_slice(&ACTION_CONNECT.to_be_bytes());
+    packet[12..].copy_from_slice(&transaction_id.to_be_bytes());
+    packet
+}
+
+/// Returns the connection id handed out by the tracker.
+pub(crate) fn parse_connect_response<E>(
+    packet: &[u8],
+    transaction_id: u32,
+) -> Result<u64, TrackerError<E>> {
+    let body = check_header(packet, ACTION_CONNECT, transaction_id)?;
+    let connection_id = body.get(..8).ok_or(TrackerError::InvalidResponse)?;
+    Ok(u64::from_be_bytes(connection_id.try_into().unwrap()))
+}
+
+pub(crate) fn scrape_request(
+    connection_id: u64,
+    transaction_id: u32,
+    info_hashes: &[InfoHash],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16 + 20 * info_hashes.len());
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    for info_hash in info_hashes {
+        packet.extend_from_slice(info_hash);
+    }
+    packet
+}
+
+/// The tracker answers in the same order as the info hashes were requested.
+pub(crate) fn parse_scrape_response<E>(
+    packet: &[u8],
+    transaction_id: u32,
+    info_hashes: &[InfoHash],
+) -> Result<Vec<ScrapeStats>, TrackerError<E>> {
+    let body = check_header(packet, ACTION_SCRAPE, transaction_id)?;
+    let (entries, _) = body.as_chunks::<12>();
+    if entries.len() < info_hashes.len() {
+        return Err(TrackerError::InvalidResponse);
+    }
+
+    Ok(info_hashes
+        .iter()
+        .zip(entries)
+        .map(|(info_hash, entry)| ScrapeStats {
+            info_hash: *info_hash,
+            seeders: read_u32(&entry[0..4]),
+            completed: read_u32(&entry[4..8]),
+            leechers: read_u32(&entry[8..12]),
+        })
+        .collect())
+}
+
+/// Checks action and transaction id and returns the rest of the packet.
+fn check_header<E>(
+    packet: &[u8],
+    action: u32,
+    transaction_id: u32,
+) -> Result<&[u8], TrackerError<E>> {
+    if packet.len() < 8 {
+        return Err(TrackerError::InvalidResponse);
+    }
+    if read_u32(&packet[4..8]) != transaction_id {
+        return Err(TrackerError::InvalidResponse);
+    }
+    match read_u32(&packet[0..4]) {
+        a if a == action => Ok(&packet[8..]),
+        ACTION_ERROR => {
+            defmt::warn!(
+                "tracker error: {}",
+                core::str::from_utf8(&packet[8..]).unwrap_or("<invalid utf-8>")
+            );
+            Err(TrackerError::Rejected)
+        }
+        _ => Err(TrackerError::InvalidResponse),
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Error = TrackerError<()>;
+
+    #[test]
+    fn test_connect_roundtrip() {
+        let request = connect_request(0xdeadbeef);
+        assert_eq!(&request[..8], &[0, 0, 0x04, 0x17, 0x27, 0x10, 0x19, 0x80]);
+        assert_eq!(&request[8..12], &[0, 0, 0, 0]);
+        assert_eq!(&request[12..], &[0xde, 0xad, 0xbe, 0xef]);
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        response.extend_from_slice(&0xdeadbeef_u32.to_be_bytes());
+        response.extend_from_slice(&42u64.to_be_bytes());
+
+        assert_eq!(
+            parse_connect_response::<()>(&response, 0xdeadbeef).unwrap(),
+            42
+        );
+        assert!(matches!(
+            parse_connect_response::<()>(&response, 1),
+            Err(Error::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn test_scrape_roundtrip() {
+        let info_hashes = [[1u8; 20], [2u8; 20]];
+        let request = scrape_request(42, 7, &info_hashes);
+        assert_eq!(request.len(), 16 + 40);
+        assert_eq!(&request[16..36], &info_hashes[0]);
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+        response.extend_from_slice(&7u32.to_be_bytes());
+        for n in [5u32, 50, 10, 0, 3, 1] {
+            response.extend_from_slice(&n.to_be_bytes());
+        }
+
+        let stats = parse_scrape_response::<()>(&response, 7, &info_hashes).unwrap();
+        assert_eq!(
+            stats[0],
+            ScrapeStats {
+                info_hash: info_hashes[0],
+                seeders: 5,
+                completed: 50,
+                leechers: 10
+            }
+        );
+        assert_eq!(stats[1].completed, 3);
+        assert_eq!(stats[1].leechers, 1);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let mut response = Vec::new();
+        response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+        response.extend_from_slice(&7u32.to_be_bytes());
+        response.extend_from_slice(b"unknown torrent");
+
+        assert!(matches!(
+            parse_scrape_response::<()>(&response, 7, &[[0u8; 20]]),
+            Err(Error::Rejected)
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+//! The Micro Transport Protocol (BEP 29), a reliable stream over UDP.
+//!
+//! uTP delivers the same byte stream as TCP, but its LEDBAT congestion control
+//! backs off as soon as it adds delay to a link, which keeps shared Wi-Fi usable.
+//! [`UtpStream`] implements [`TcpConnection`], so the peer wire protocol runs on
+//! top of it unchanged.
+//!
+//! All connections share the UDP socket of a [`UtpSocket`], which tells their
+//! packets apart by sender and connection id.
+//!
+//! [`TcpConnection`]: crate::wifi::TcpConnection
+
+use defmt::Format;
+
+use crate::wifi::{TcpError, UdpError};
+
+pub mod connection;
+pub mod ledbat;
+pub mod packet;
+mod socket;
+
+pub use socket::{UtpSocket, UtpStream};
+
+/// The largest packet we take, larger than ours to allow for peers with a bigger payload.
+pub const MAX_PACKET_LEN: usize = 1500;
+
+/// How long a [`UtpSocket`] waits for a packet when no timer is pending.
+pub const POLL_INTERVAL_MS: u64 = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum UtpError {
+    /// The packet is too short or malformed.
+    InvalidPacket,
+    /// The packet isn't uTP version 1.
+    UnsupportedVersion,
+    /// The packet doesn't fit the connection, e.g. a SYN on an open connection.
+    UnexpectedPacket,
+    /// The packet belongs to another connection.
+    WrongConnection,
+    /// The peer reset the connection.
+    Reset,
+    /// The peer stopped acking our packets.
+    TimedOut,
+}
+
+/// Sizes of a uTP connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct UtpConfig {
+    /// The most data we put into one packet. Keeps packets below the MTU.
+    pub max_payload: usize,
+    /// How much written data we buffer until the peer acked it.
+    pub send_buffer: usize,
+    /// How much received data we buffer until it is read, which is the window
+    /// we offer the peer.
+    pub recv_buffer: usize,
+}
+
+impl UtpConfig {
+    pub const DEFAULT: Self = Self {
+        max_payload: 1400,
+        send_buffer: 32 * 1024,
+        recv_buffer: 32 * 1024,
+    };
+
+    /// Sizes for devices with little heap, like the ESP32-C3: 12 KiB per connection
+    /// instead of 64 KiB, which still keeps several packets in flight each way.
+    pub const EMBEDDED: Self = Self {
+        max_payload: 1400,
+        send_buffer: 4 * 1024,
+        recv_buffer: 8 * 1024,
+    };
+}
+
+impl Default for UtpConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+fn tcp_error(err: UdpError) -> TcpError {
+    match err {
+        UdpError::NoRoute => TcpError::NoRoute,
+        UdpError::TimedOut => TcpError::TimedOut,
+        UdpError::NoSocket => TcpError::NoSocket,
+        UdpError::Truncated | UdpError::TooLarge | UdpError::Multicast => TcpError::ConnectionReset,
+    }
+}
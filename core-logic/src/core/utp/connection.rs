@@ -0,0 +1,883 @@
+//! The state of a single uTP connection, independent of the socket it runs on.
+//!
+//! [`Connection`] takes incoming packets and the current time and hands back the
+//! packets to send, like [`crate::core::dht::Dht`]. Lost packets are found by
+//! duplicate and selective acks or by the retransmit timer, the amount of data in
+//! flight is bounded by both the LEDBAT window and the receive window of the peer.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use defmt::Format;
+
+use crate::{
+    core::utp::{
+        UtpConfig, UtpError,
+        ledbat::Ledbat,
+        packet::{Packet, PacketType, seq_lt},
+    },
+    rng::Rng,
+};
+
+/// The retransmit timeout until the first round trip was measured.
+pub const INITIAL_TIMEOUT_MS: u64 = 1_000;
+
+/// BEP 29 never lets the retransmit timeout drop below this.
+pub const MIN_TIMEOUT_MS: u64 = 500;
+
+pub const MAX_TIMEOUT_MS: u64 = 30_000;
+
+/// Timeouts in a row without hearing from the peer after which the connection is given up.
+pub const MAX_RETRANSMITS: u8 = 5;
+
+/// Duplicate acks, or packets selectively acked after a missing one, that mark it as lost.
+pub const DUPLICATE_ACKS: u8 = 3;
+
+/// How far ahead of the next expected packet we keep packets that arrive out of order.
+/// Matches the reach of the selective ack we send.
+pub const MAX_REORDER: u16 = 32;
+
+const SELECTIVE_ACK_LEN: usize = MAX_REORDER as usize / 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum State {
+    /// Our SYN wasn't answered yet.
+    SynSent,
+    Connected,
+    /// We sent our FIN, the peer may still send data.
+    FinSent,
+    /// Both sides are done, or the connection was reset or timed out.
+    Closed,
+}
+
+/// A packet that takes up a sequence number, kept until the peer acks it.
+struct Sent {
+    seq_nr: u16,
+    kind: PacketType,
+    payload: Vec<u8>,
+    sent_ms: u64,
+    transmissions: u8,
+    resend: bool,
+}
+
+/// A packet that arrived before the ones preceding it.
+struct Received {
+    seq_nr: u16,
+    fin: bool,
+    payload: Vec<u8>,
+}
+
+pub struct Connection {
+    config: UtpConfig,
+    state: State,
+    error: Option<UtpError>,
+    recv_id: u16,
+    send_id: u16,
+    /// The sequence number of our next packet.
+    seq_nr: u16,
+    /// The last sequence number we received in order.
+    ack_nr: u16,
+    /// The sequence number of our answer to the SYN, when we accepted the connection.
+    syn_ack_seq_nr: Option<u16>,
+    /// The peer sent its SYN again, so our answer got lost.
+    resend_syn_ack: bool,
+    /// The last ack of the peer, to count duplicates.
+    peer_ack_nr: u16,
+    duplicate_acks: u8,
+    /// Losses of packets up to this one already shrank the window.
+    recovery_seq_nr: Option<u16>,
+    peer_window: u32,
+    /// The delay of the peer's last packet, which it gets back in our next one.
+    reply_diff_us: u32,
+    ledbat: Ledbat,
+    /// Smoothed round trip time and its variation.
+    rtt_ms: Option<(u64, u64)>,
+    timeout_ms: u64,
+    timeout_at_ms: Option<u64>,
+    /// When we send data although the peer's window is full, to find out when it opens again.
+    probe_at_ms: Option<u64>,
+    retransmits: u8,
+    unsent: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    closing: bool,
+    readable: VecDeque<u8>,
+    out_of_order: Vec<Received>,
+    peer_finished: bool,
+    ack_pending: bool,
+}
+
+impl Connection {
+    /// Opens a connection, the SYN goes out with the first [`Connection::poll`].
+    pub fn connect<R: Rng>(config: UtpConfig, rng: &mut R) -> Self {
+        let recv_id = rng.next_u32() as u16;
+        let mut connection = Self::new(config, State::SynSent, recv_id, recv_id.wrapping_add(1), 1);
+        connection.in_flight.push_back(Sent {
+            seq_nr: 1,
+            kind: PacketType::Syn,
+            payload: Vec::new(),
+            sent_ms: 0,
+            transmissions: 0,
+            resend: true,
+        });
+        connection.seq_nr = 2;
+        connection
+    }
+
+    /// Accepts the connection a peer opened with `syn`.
+    pub fn accept<R: Rng>(
+        syn: &[u8],
+        config: UtpConfig,
+        rng: &mut R,
+        now_ms: u64,
+    ) -> Result<Self, UtpError> {
+        let syn = Packet::parse(syn)?;
+        if syn.kind != PacketType::Syn {
+            return Err(UtpError::UnexpectedPacket);
+        }
+        let seq_nr = rng.next_u32() as u16;
+        let mut connection = Self::new(
+            config,
+            State::Connected,
+            syn.conn_id.wrapping_add(1),
+            syn.conn_id,
+            seq_nr,
+        );
+        connection.ack_nr = syn.seq_nr;
+        connection.syn_ack_seq_nr = Some(seq_nr);
+        connection.peer_window = syn.wnd_size;
+        connection.reply_diff_us = timestamp_us(now_ms).wrapping_sub(syn.timestamp_us);
+        connection.ack_pending = true;
+        Ok(connection)
+    }
+
+    fn new(config: UtpConfig, state: State, recv_id: u16, send_id: u16, seq_nr: u16) -> Self {
+        Self {
+            config,
+            state,
+            error: None,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            syn_ack_seq_nr: None,
+            resend_syn_ack: false,
+            peer_ack_nr: seq_nr.wrapping_sub(1),
+            duplicate_acks: 0,
+            recovery_seq_nr: None,
+            peer_window: config.max_payload as u32,
+            reply_diff_us: 0,
+            ledbat: Ledbat::new(config.max_payload as u32, config.send_buffer as u32),
+            rtt_ms: None,
+            timeout_ms: INITIAL_TIMEOUT_MS,
+            timeout_at_ms: None,
+            probe_at_ms: None,
+            retransmits: 0,
+            unsent: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            closing: false,
+            readable: VecDeque::new(),
+            out_of_order: Vec::new(),
+            peer_finished: false,
+            ack_pending: false,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The connection id of the packets the peer sends us.
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    /// Why the connection closed, if the peer reset it or stopped answering.
+    pub fn error(&self) -> Option<UtpError> {
+        self.error
+    }
+
+    /// The peer sent its FIN and all data before it was read.
+    pub fn peer_finished(&self) -> bool {
+        self.peer_finished && self.readable.is_empty()
+    }
+
+    /// Our FIN went out and the peer acked everything we sent.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, State::FinSent | State::Closed) && self.in_flight.is_empty()
+    }
+
+    /// Queues as much of `data` as the send buffer has room for and returns how much that was.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        if self.closing || !matches!(self.state, State::SynSent | State::Connected) {
+            return 0;
+        }
+        let buffered = self.unsent.len() + self.in_flight_bytes(|_| true);
+        let len = data
+            .len()
+            .min(self.config.send_buffer.saturating_sub(buffered));
+        self.unsent.extend(&data[..len]);
+        len
+    }
+
+    /// Reads what arrived in order, at most `buf.len()` bytes.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let was_full = (self.receive_window() as usize) < self.config.max_payload;
+        let len = buf.len().min(self.readable.len());
+        for (b, byte) in buf.iter_mut().zip(self.readable.drain(..len)) {
+            *b = byte;
+        }
+        // tell the peer that it can send again
+        if was_full && self.receive_window() as usize >= self.config.max_payload {
+            self.ack_pending = true;
+        }
+        len
+    }
+
+    /// Sends a FIN once all written data is out.
+    pub fn close(&mut self) {
+        self.closing = true;
+        if self.state == State::SynSent {
+            self.state = State::Closed;
+            self.in_flight.clear();
+            self.unsent.clear();
+        }
+    }
+
+    /// Handles a packet from the peer.
+    pub fn on_packet(&mut self, bytes: &[u8], now_ms: u64) -> Result<(), UtpError> {
+        let packet = Packet::parse(bytes)?;
+        if packet.kind == PacketType::Syn {
+            if self.syn_ack_seq_nr.is_some() && packet.conn_id.wrapping_add(1) == self.recv_id {
+                self.resend_syn_ack = true;
+                return Ok(());
+            }
+            return Err(UtpError::UnexpectedPacket);
+        }
+        if packet.conn_id != self.recv_id {
+            return Err(UtpError::WrongConnection);
+        }
+        if self.error.is_some() {
+            return Ok(());
+        }
+        if packet.kind == PacketType::Reset {
+            self.fail(UtpError::Reset);
+            return Ok(());
+        }
+        if self.state == State::SynSent {
+            // data before the answer to our SYN can't be placed, it is sent again later
+            if packet.kind != PacketType::State {
+                return Ok(());
+            }
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+        }
+
+        self.retransmits = 0;
+        self.reply_diff_us = timestamp_us(now_ms).wrapping_sub(packet.timestamp_us);
+        self.on_ack(&packet, now_ms);
+        if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            self.on_sequenced(
+                packet.seq_nr,
+                packet.kind == PacketType::Fin,
+                packet.payload,
+            );
+        }
+        self.update_state();
+        Ok(())
+    }
+
+    /// The packets to send now: retransmissions, new data within the window and acks.
+    pub fn poll(&mut self, now_ms: u64) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        if self.error.is_some() {
+            return out;
+        }
+        if self.timeout_at_ms.is_some_and(|at| now_ms >= at) {
+            self.on_timeout(now_ms);
+            if self.state == State::Closed {
+                return out;
+            }
+        }
+        if let Some(seq_nr) = self.syn_ack_seq_nr
+            && core::mem::take(&mut self.resend_syn_ack)
+        {
+            out.push(self.encode(PacketType::State, seq_nr, &[], now_ms));
+        }
+
+        let window = self.ledbat.window() as usize;
+        let mut flight = self.in_flight_bytes(|sent| sent.transmissions > 0 && !sent.resend);
+        for i in 0..self.in_flight.len() {
+            let sent = &self.in_flight[i];
+            if !sent.resend {
+                continue;
+            }
+            if flight > 0 && flight + sent.payload.len() > window {
+                break;
+            }
+            flight += sent.payload.len();
+            out.push(self.encode(sent.kind, sent.seq_nr, &sent.payload, now_ms));
+            let sent = &mut self.in_flight[i];
+            sent.resend = false;
+            sent.transmissions += 1;
+            sent.sent_ms = now_ms;
+        }
+
+        if self.state == State::Connected {
+            while !self.unsent.is_empty() {
+                let len = self.unsent.len().min(self.config.max_payload);
+                if flight > 0 && flight + len > window {
+                    break;
+                }
+                if flight + len > self.peer_window as usize {
+                    if flight > 0 || !self.probe_due(now_ms) {
+                        break;
+                    }
+                } else {
+                    self.probe_at_ms = None;
+                }
+                let payload: Vec<u8> = self.unsent.drain(..len).collect();
+                flight += len;
+                self.send_new(PacketType::Data, payload, now_ms, &mut out);
+            }
+            if self.closing && self.unsent.is_empty() {
+                self.send_new(PacketType::Fin, Vec::new(), now_ms, &mut out);
+                self.state = State::FinSent;
+            }
+        }
+
+        if core::mem::take(&mut self.ack_pending) && out.is_empty() {
+            out.push(self.encode(PacketType::State, self.seq_nr, &[], now_ms));
+        }
+        if self.in_flight.is_empty() {
+            self.timeout_at_ms = None;
+        } else if self.timeout_at_ms.is_none() {
+            self.timeout_at_ms = Some(now_ms + self.timeout_ms);
+        }
+        self.update_state();
+        out
+    }
+
+    /// How long until [`Connection::poll`] has to run again, `None` if nothing is pending.
+    pub fn next_timeout_ms(&self, now_ms: u64) -> Option<u64> {
+        [self.timeout_at_ms, self.probe_at_ms]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|at| at.saturating_sub(now_ms))
+    }
+
+    fn send_new(
+        &mut self,
+        kind: PacketType,
+        payload: Vec<u8>,
+        now_ms: u64,
+        out: &mut Vec<Vec<u8>>,
+    ) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        out.push(self.encode(kind, seq_nr, &payload, now_ms));
+        self.in_flight.push_back(Sent {
+            seq_nr,
+            kind,
+            payload,
+            sent_ms: now_ms,
+            transmissions: 1,
+            resend: false,
+        });
+    }
+
+    fn probe_due(&mut self, now_ms: u64) -> bool {
+        match self.probe_at_ms {
+            Some(at) if now_ms >= at => {
+                self.probe_at_ms = None;
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.probe_at_ms = Some(now_ms + self.timeout_ms);
+                false
+            }
+        }
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now_ms: u64) {
+        let opened = packet.wnd_size as usize >= self.config.max_payload
+            && (self.peer_window as usize) < self.config.max_payload;
+        self.peer_window = packet.wnd_size;
+
+        let mut progress = false;
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        self.in_flight.retain(|sent| {
+            let acked = sent.transmissions > 0
+                && (!seq_lt(packet.ack_nr, sent.seq_nr) || packet.selectively_acked(sent.seq_nr));
+            if acked {
+                progress = true;
+                acked_bytes += sent.payload.len() as u32;
+                if sent.transmissions == 1 {
+                    rtt_sample = Some(now_ms.saturating_sub(sent.sent_ms));
+                }
+            }
+            !acked
+        });
+
+        if progress {
+            self.duplicate_acks = 0;
+            if let Some(rtt) = rtt_sample {
+                self.update_rtt(rtt);
+            }
+            // the peer is answering again, so the backoff of earlier timeouts is over
+            self.timeout_ms = self.retransmit_timeout();
+            if acked_bytes > 0 {
+                self.ledbat
+                    .on_ack(packet.timestamp_diff_us, acked_bytes, now_ms);
+            }
+            self.timeout_at_ms = (!self.in_flight.is_empty()).then_some(now_ms + self.timeout_ms);
+        } else if packet.kind == PacketType::State
+            && packet.ack_nr == self.peer_ack_nr
+            && !self.in_flight.is_empty()
+        {
+            self.duplicate_acks += 1;
+        }
+        self.peer_ack_nr = packet.ack_nr;
+
+        // a packet is lost when enough packets after it arrived, every packet is
+        // retransmitted this way only once, after that it's up to the timer
+        let mut lost = None;
+        let duplicate_acks = core::mem::take(&mut self.duplicate_acks);
+        for (i, sent) in self.in_flight.iter_mut().enumerate() {
+            if sent.transmissions != 1 || sent.resend {
+                continue;
+            }
+            let acked_after = selectively_acked_after(packet, sent.seq_nr);
+            if acked_after >= DUPLICATE_ACKS as usize
+                || (i == 0 && duplicate_acks >= DUPLICATE_ACKS)
+            {
+                sent.resend = true;
+                lost = Some(sent.seq_nr);
+            }
+        }
+        self.duplicate_acks = if lost.is_some() { 0 } else { duplicate_acks };
+        if let Some(lost) = lost
+            && self
+                .recovery_seq_nr
+                .is_none_or(|recovery| seq_lt(recovery, lost))
+        {
+            self.ledbat.on_loss();
+            self.recovery_seq_nr = Some(self.seq_nr.wrapping_sub(1));
+        }
+
+        // the peer dropped our probe while its window was full
+        if opened
+            && !progress
+            && let Some(front) = self.in_flight.front_mut()
+            && front.transmissions > 0
+        {
+            front.resend = true;
+        }
+    }
+
+    fn on_sequenced(&mut self, seq_nr: u16, fin: bool, payload: &[u8]) {
+        self.ack_pending = true;
+        if self.peer_finished {
+            return;
+        }
+        let expected = self.ack_nr.wrapping_add(1);
+        if seq_nr == expected {
+            // packets in order are taken while there is any room left, so the
+            // peer's probes get through once we read again
+            if self.receive_window() == 0 {
+                return;
+            }
+            self.deliver(fin, payload);
+            self.ack_nr = seq_nr;
+            while let Some(i) = self
+                .out_of_order
+                .iter()
+                .position(|received| received.seq_nr == self.ack_nr.wrapping_add(1))
+            {
+                let received = self.out_of_order.swap_remove(i);
+                self.deliver(received.fin, &received.payload);
+                self.ack_nr = received.seq_nr;
+            }
+        } else if seq_lt(expected, seq_nr)
+            && seq_nr.wrapping_sub(expected) <= MAX_REORDER
+            && payload.len() <= self.receive_window() as usize
+            && !self
+                .out_of_order
+                .iter()
+                .any(|received| received.seq_nr == seq_nr)
+        {
+            self.out_of_order.push(Received {
+                seq_nr,
+                fin,
+                payload: payload.to_vec(),
+            });
+        }
+    }
+
+    fn deliver(&mut self, fin: bool, payload: &[u8]) {
+        if fin {
+            self.peer_finished = true;
+            self.out_of_order.clear();
+        } else {
+            self.readable.extend(payload);
+        }
+    }
+
+    fn on_timeout(&mut self, now_ms: u64) {
+        if self.in_flight.is_empty() {
+            self.timeout_at_ms = None;
+            return;
+        }
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            self.fail(UtpError::TimedOut);
+            return;
+        }
+        self.ledbat.on_timeout();
+        self.timeout_ms = (self.timeout_ms * 2).min(MAX_TIMEOUT_MS);
+        self.timeout_at_ms = Some(now_ms + self.timeout_ms);
+        for sent in &mut self.in_flight {
+            sent.resend = true;
+        }
+    }
+
+    fn update_rtt(&mut self, sample_ms: u64) {
+        let (rtt, variation) = match self.rtt_ms {
+            None => (sample_ms, sample_ms / 2),
+            Some((rtt, variation)) => (
+                rtt - rtt / 8 + sample_ms / 8,
+                variation - variation / 4 + rtt.abs_diff(sample_ms) / 4,
+            ),
+        };
+        self.rtt_ms = Some((rtt, variation));
+    }
+
+    fn retransmit_timeout(&self) -> u64 {
+        match self.rtt_ms {
+            Some((rtt, variation)) => (rtt + 4 * variation).clamp(MIN_TIMEOUT_MS, MAX_TIMEOUT_MS),
+            None => INITIAL_TIMEOUT_MS,
+        }
+    }
+
+    fn update_state(&mut self) {
+        if self.state == State::FinSent && self.in_flight.is_empty() && self.peer_finished {
+            self.state = State::Closed;
+        }
+    }
+
+    fn fail(&mut self, error: UtpError) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.unsent.clear();
+        self.in_flight.clear();
+        self.timeout_at_ms = None;
+        self.probe_at_ms = None;
+    }
+
+    fn in_flight_bytes(&self, filter: impl Fn(&Sent) -> bool) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|sent| filter(sent))
+            .map(|sent| sent.payload.len())
+            .sum()
+    }
+
+    /// How many more bytes we can take from the peer.
+    fn receive_window(&self) -> u32 {
+        let buffered = self.readable.len()
+            + self
+                .out_of_order
+                .iter()
+                .map(|received| received.payload.len())
+                .sum::<usize>();
+        self.config.recv_buffer.saturating_sub(buffered) as u32
+    }
+
+    fn encode(&self, kind: PacketType, seq_nr: u16, payload: &[u8], now_ms: u64) -> Vec<u8> {
+        let mut selective_ack = [0u8; SELECTIVE_ACK_LEN];
+        for received in &self.out_of_order {
+            let bit = received.seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if bit < MAX_REORDER as usize {
+                selective_ack[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Packet {
+            kind,
+            conn_id: match kind {
+                PacketType::Syn => self.recv_id,
+                _ => self.send_id,
+            },
+            timestamp_us: timestamp_us(now_ms),
+            timestamp_diff_us: self.reply_diff_us,
+            wnd_size: self.receive_window(),
+            seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack: (!self.out_of_order.is_empty()).then_some(&selective_ack[..]),
+            payload,
+        }
+        .encode()
+    }
+}
+
+/// How many packets after `seq_nr` the selective ack of `packet` reports.
+fn selectively_acked_after(packet: &Packet, seq_nr: u16) -> usize {
+    let Some(selective_ack) = packet.selective_ack else {
+        return 0;
+    };
+    (0..selective_ack.len() * 8)
+        .map(|bit| packet.ack_nr.wrapping_add(2).wrapping_add(bit as u16))
+        .filter(|&acked| seq_lt(seq_nr, acked) && packet.selectively_acked(acked))
+        .count()
+}
+
+/// Packet timestamps only have millisecond resolution, which is plenty for LEDBAT.
+fn timestamp_us(now_ms: u64) -> u32 {
+    now_ms.wrapping_mul(1_000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift32;
+
+    const CONFIG: UtpConfig = UtpConfig {
+        max_payload: 100,
+        send_buffer: 10_000,
+        recv_buffer: 1_000,
+    };
+
+    /// An initiator and a responder that completed the handshake.
+    fn connected() -> (Connection, Connection) {
+        let mut rng = XorShift32::new(1);
+        let mut initiator = Connection::connect(CONFIG, &mut rng);
+        let syn = initiator.poll(0);
+        assert_eq!(syn.len(), 1);
+        assert_eq!(Packet::parse(&syn[0]).unwrap().kind, PacketType::Syn);
+
+        let mut responder = Connection::accept(&syn[0], CONFIG, &mut rng, 0).unwrap();
+        for packet in responder.poll(0) {
+            initiator.on_packet(&packet, 0).unwrap();
+        }
+        assert_eq!(initiator.state(), State::Connected);
+        (initiator, responder)
+    }
+
+    fn deliver(from: &mut Connection, to: &mut Connection, now_ms: u64) -> usize {
+        let packets = from.poll(now_ms);
+        for packet in &packets {
+            to.on_packet(packet, now_ms).unwrap();
+        }
+        packets.len()
+    }
+
+    fn read_all(connection: &mut Connection) -> Vec<u8> {
+        let mut buf = [0; 10_000];
+        let len = connection.read(&mut buf);
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn test_handshake_and_data() {
+        let (mut initiator, mut responder) = connected();
+
+        assert_eq!(initiator.write(b"hello"), 5);
+        assert_eq!(responder.write(b"world"), 5);
+        deliver(&mut initiator, &mut responder, 10);
+        deliver(&mut responder, &mut initiator, 10);
+        assert_eq!(read_all(&mut responder), b"hello");
+        assert_eq!(read_all(&mut initiator), b"world");
+
+        // the data packets acked each other, the last one gets a state packet
+        deliver(&mut initiator, &mut responder, 20);
+        assert_eq!(initiator.next_timeout_ms(20), None);
+        assert_eq!(responder.next_timeout_ms(20), None);
+    }
+
+    #[test]
+    fn test_lost_syn_ack() {
+        let mut rng = XorShift32::new(1);
+        let mut initiator = Connection::connect(CONFIG, &mut rng);
+        let syn = initiator.poll(0).remove(0);
+        let mut responder = Connection::accept(&syn, CONFIG, &mut rng, 0).unwrap();
+        responder.poll(0);
+
+        // the responder's data can't be placed before its state packet arrived
+        responder.write(b"early");
+        deliver(&mut responder, &mut initiator, 10);
+        assert_eq!(initiator.state(), State::SynSent);
+
+        // the SYN times out and is answered again
+        let resent = initiator.poll(INITIAL_TIMEOUT_MS);
+        assert_eq!(resent.len(), 1);
+        responder.on_packet(&resent[0], INITIAL_TIMEOUT_MS).unwrap();
+        deliver(&mut responder, &mut initiator, INITIAL_TIMEOUT_MS);
+        assert_eq!(initiator.state(), State::Connected);
+
+        deliver(&mut responder, &mut initiator, 2 * INITIAL_TIMEOUT_MS);
+        assert_eq!(read_all(&mut initiator), b"early");
+    }
+
+    #[test]
+    fn test_out_of_order_and_selective_ack() {
+        let (mut initiator, mut responder) = connected();
+        initiator.write(&[7; 500]);
+        let packets = initiator.poll(10);
+        assert_eq!(packets.len(), 1);
+        responder.on_packet(&packets[0], 10).unwrap();
+        deliver(&mut responder, &mut initiator, 10);
+
+        // the window grew, the first of the next packets gets lost
+        let packets = initiator.poll(20);
+        assert!(packets.len() >= 4);
+        for packet in &packets[1..] {
+            responder.on_packet(packet, 20).unwrap();
+        }
+        assert_eq!(read_all(&mut responder).len(), 100);
+        let acks = responder.poll(20);
+        let ack = Packet::parse(&acks[0]).unwrap();
+        assert!(ack.selective_ack.is_some());
+        initiator.on_packet(&acks[0], 20).unwrap();
+
+        // the selective ack shows the loss, so it is sent again before the timeout
+        let resent = initiator.poll(21);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(
+            Packet::parse(&resent[0]).unwrap().seq_nr,
+            Packet::parse(&packets[0]).unwrap().seq_nr
+        );
+        responder.on_packet(&resent[0], 21).unwrap();
+        deliver(&mut initiator, &mut responder, 21);
+        assert_eq!(read_all(&mut responder), [7; 400]);
+    }
+
+    #[test]
+    fn test_retransmit_on_timeout() {
+        let (mut initiator, mut responder) = connected();
+        initiator.write(b"lost");
+        let lost = initiator.poll(0);
+        // the handshake measured a round trip time of zero
+        assert_eq!(initiator.next_timeout_ms(0), Some(MIN_TIMEOUT_MS));
+        assert!(initiator.poll(MIN_TIMEOUT_MS - 1).is_empty());
+
+        let resent = initiator.poll(MIN_TIMEOUT_MS);
+        assert_eq!(Packet::parse(&resent[0]).unwrap().payload, b"lost");
+        assert_eq!(
+            Packet::parse(&resent[0]).unwrap().seq_nr,
+            Packet::parse(&lost[0]).unwrap().seq_nr
+        );
+        responder.on_packet(&resent[0], MIN_TIMEOUT_MS).unwrap();
+        assert_eq!(read_all(&mut responder), b"lost");
+    }
+
+    #[test]
+    fn test_gives_up_after_retransmits() {
+        let (mut initiator, _) = connected();
+        initiator.write(b"nobody listens");
+        let mut now = 0;
+        while initiator.state() != State::Closed {
+            initiator.poll(now);
+            now += 1_000;
+        }
+        assert_eq!(initiator.error(), Some(UtpError::TimedOut));
+        assert_eq!(initiator.write(b"more"), 0);
+    }
+
+    #[test]
+    fn test_receive_window() {
+        let (mut initiator, mut responder) = connected();
+        initiator.write(&[1; 3_000]);
+
+        // the peer's window stops the sender once the reader falls behind
+        for now in 1..50 {
+            deliver(&mut initiator, &mut responder, now);
+            deliver(&mut responder, &mut initiator, now);
+        }
+        assert!(responder.readable.len() < CONFIG.recv_buffer + CONFIG.max_payload);
+        assert!(!initiator.unsent.is_empty());
+
+        // reading opens the window again
+        let mut received = Vec::new();
+        for now in 50..200 {
+            received.extend(read_all(&mut responder));
+            deliver(&mut initiator, &mut responder, now);
+            deliver(&mut responder, &mut initiator, now);
+        }
+        received.extend(read_all(&mut responder));
+        assert_eq!(received.len(), 3_000);
+        assert!(received.iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn test_send_buffer_is_bounded() {
+        let (mut initiator, _) = connected();
+
+        assert_eq!(initiator.write(&[0; 20_000]), CONFIG.send_buffer);
+        assert_eq!(initiator.write(&[0; 1]), 0);
+    }
+
+    #[test]
+    fn test_close() {
+        let (mut initiator, mut responder) = connected();
+        initiator.write(b"bye");
+        initiator.close();
+        assert_eq!(initiator.write(b"more"), 0);
+
+        let packets = initiator.poll(10);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(initiator.state(), State::FinSent);
+        for packet in &packets {
+            responder.on_packet(packet, 10).unwrap();
+        }
+        assert!(!responder.peer_finished());
+        assert_eq!(read_all(&mut responder), b"bye");
+        assert!(responder.peer_finished());
+
+        deliver(&mut responder, &mut initiator, 10);
+        assert!(initiator.is_finished());
+        responder.close();
+        deliver(&mut responder, &mut initiator, 20);
+        deliver(&mut initiator, &mut responder, 20);
+        assert_eq!(initiator.state(), State::Closed);
+        assert_eq!(responder.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_reset() {
+        let (mut initiator, responder) = connected();
+        let reset = Packet {
+            kind: PacketType::Reset,
+            conn_id: responder.send_id,
+            timestamp_us: 0,
+            timestamp_diff_us: 0,
+            wnd_size: 0,
+            seq_nr: responder.seq_nr,
+            ack_nr: responder.ack_nr,
+            selective_ack: None,
+            payload: &[],
+        }
+        .encode();
+
+        initiator.on_packet(&reset, 10).unwrap();
+        assert_eq!(initiator.state(), State::Closed);
+        assert_eq!(initiator.error(), Some(UtpError::Reset));
+    }
+
+    #[test]
+    fn test_foreign_packets() {
+        let (mut initiator, mut responder) = connected();
+        let mut other = Connection::connect(CONFIG, &mut XorShift32::new(99));
+        let syn = other.poll(0).remove(0);
+
+        assert_eq!(
+            initiator.on_packet(&syn, 0),
+            Err(UtpError::UnexpectedPacket)
+        );
+        other.state = State::Connected;
+        other.write(b"x");
+        let data = other.poll(0).pop().unwrap();
+        assert_eq!(
+            responder.on_packet(&data, 0),
+            Err(UtpError::WrongConnection)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! LEDBAT congestion control (RFC 6817) as used by uTP.
+//!
+//! The window grows while the one-way delay stays below [`TARGET_DELAY_US`] and
+//! shrinks as soon as our packets start queueing up in a buffer along the path,
+//! so uTP backs off before it slows down other traffic on a shared link.
+
+/// The queueing delay LEDBAT aims for.
+pub const TARGET_DELAY_US: u32 = 100_000;
+
+/// How much the window grows at most per round trip.
+pub const MAX_WINDOW_INCREASE: u32 = 3_000;
+
+/// The base delay is the smallest delay seen in the last two of these periods, so
+/// it follows route changes and clock drift.
+pub const BASE_DELAY_PERIOD_MS: u64 = 60_000;
+
+#[derive(Debug, Clone)]
+pub struct Ledbat {
+    window: u32,
+    min_window: u32,
+    max_window: u32,
+    /// The smallest delay of the current and of the previous period.
+    base_delays: [Option<u32>; 2],
+    period_start_ms: u64,
+}
+
+impl Ledbat {
+    /// The window is kept between `min_window` and `max_window` bytes and starts at `min_window`.
+    pub fn new(min_window: u32, max_window: u32) -> Self {
+        Self {
+            window: min_window,
+            min_window,
+            max_window,
+            base_delays: [None; 2],
+            period_start_ms: 0,
+        }
+    }
+
+    /// How many bytes may be in flight.
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Grows or shrinks the window after `bytes_acked` were acked by a packet
+    /// that reported a one-way delay of `delay_us`.
+    pub fn on_ack(&mut self, delay_us: u32, bytes_acked: u32, now_ms: u64) {
+        if now_ms.saturating_sub(self.period_start_ms) >= BASE_DELAY_PERIOD_MS {
+            self.base_delays = [None, self.base_delays[0]];
+            self.period_start_ms = now_ms;
+        }
+        let current = &mut self.base_delays[0];
+        *current = Some(current.map_or(delay_us, |base| base.min(delay_us)));
+        let base_delay = self
+            .base_delays
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+            .unwrap_or(delay_us);
+
+        let queueing_delay = delay_us.saturating_sub(base_delay) as i64;
+        let off_target = TARGET_DELAY_US as i64 - queueing_delay.min(2 * TARGET_DELAY_US as i64);
+        let gain = MAX_WINDOW_INCREASE as i64 * off_target * bytes_acked as i64
+            / (TARGET_DELAY_US as i64 * self.window as i64);
+        self.window = (self.window as i64 + gain)
+            .clamp(self.min_window as i64, self.max_window as i64) as u32;
+    }
+
+    /// Halves the window after a packet was lost.
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2).max(self.min_window);
+    }
+
+    /// Falls back to the smallest window when nothing was acked for a whole timeout.
+    pub fn on_timeout(&mut self) {
+        self.window = self.min_window;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_grows_below_target() {
+        let mut ledbat = Ledbat::new(1_000, 100_000);
+
+        ledbat.on_ack(5_000, 1_000, 0);
+        ledbat.on_ack(5_000, 1_000, 10);
+        assert_eq!(ledbat.window(), 1_000 + 3_000 + 750);
+    }
+
+    #[test]
+    fn test_window_shrinks_above_target() {
+        let mut ledbat = Ledbat::new(1_000, 100_000);
+        ledbat.on_ack(1_000, 10_000, 0);
+        let window = ledbat.window();
+
+        ledbat.on_ack(1_000 + 2 * TARGET_DELAY_US, 1_000, 10);
+        assert!(ledbat.window() < window);
+        for _ in 0..100 {
+            ledbat.on_ack(1_000 + 2 * TARGET_DELAY_US, 10_000, 20);
+        }
+        assert_eq!(ledbat.window(), 1_000);
+    }
+
+    #[test]
+    fn test_base_delay_expires() {
+        let mut ledbat = Ledbat::new(1_000, 100_000);
+        ledbat.on_ack(1_000, 1_000, 0);
+        ledbat.on_ack(500_000, 1_000, BASE_DELAY_PERIOD_MS);
+        let window = ledbat.window();
+
+        // two periods later the old minimum is forgotten and the new delay is the base
+        ledbat.on_ack(500_000, 1_000, 2 * BASE_DELAY_PERIOD_MS);
+        assert!(ledbat.window() > window);
+    }
+
+    #[test]
+    fn test_loss_and_timeout() {
+        let mut ledbat = Ledbat::new(1_000, 100_000);
+        for now in 0..200 {
+            ledbat.on_ack(0, 10_000, now);
+        }
+        assert_eq!(ledbat.window(), 100_000);
+
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), 50_000);
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), 1_000);
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), 1_000);
+    }
+}
@@ -0,0 +1,244 @@
+//! The uTP packet header and its selective ack extension.
+
+use alloc::vec::Vec;
+use defmt::Format;
+
+use crate::core::utp::UtpError;
+
+pub const HEADER_LEN: usize = 20;
+
+/// The only uTP version there is.
+pub const VERSION: u8 = 1;
+
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PacketType {
+    Data = 0,
+    /// The last packet of a stream, after which the sender sends no more data.
+    Fin = 1,
+    /// An ack without data, which doesn't take up a sequence number.
+    State = 2,
+    /// Aborts the connection.
+    Reset = 3,
+    /// Opens a connection.
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub kind: PacketType,
+    pub conn_id: u16,
+    /// When the packet was sent, in microseconds of the sender's clock.
+    pub timestamp_us: u32,
+    /// How long the last packet the sender received took to arrive, including the
+    /// offset between both clocks.
+    pub timestamp_diff_us: u32,
+    /// How many bytes the sender can still receive.
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    /// The last sequence number the sender received in order.
+    pub ack_nr: u16,
+    /// Bit `i` is set if packet `ack_nr + 2 + i` arrived. The length is a multiple of 4.
+    pub selective_ack: Option<&'a [u8]>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, UtpError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(UtpError::InvalidPacket);
+        }
+        if bytes[0] & 0x0f != VERSION {
+            return Err(UtpError::UnsupportedVersion);
+        }
+        let kind = PacketType::from_u8(bytes[0] >> 4).ok_or(UtpError::InvalidPacket)?;
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut pos = HEADER_LEN;
+        while extension != EXTENSION_NONE {
+            let (&[next, len], rest) = bytes[pos..]
+                .split_first_chunk()
+                .ok_or(UtpError::InvalidPacket)?;
+            let data = rest.get(..len as usize).ok_or(UtpError::InvalidPacket)?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                if data.is_empty() || data.len() % 4 != 0 {
+                    return Err(UtpError::InvalidPacket);
+                }
+                selective_ack = Some(data);
+            }
+            extension = next;
+            pos += 2 + data.len();
+        }
+
+        Ok(Self {
+            kind,
+            conn_id: u16_at(2),
+            timestamp_us: u32_at(4),
+            timestamp_diff_us: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: &bytes[pos..],
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let sack_len = self.selective_ack.map_or(0, |sack| 2 + sack.len());
+        let mut out = Vec::with_capacity(HEADER_LEN + sack_len + self.payload.len());
+        out.push((self.kind as u8) << 4 | VERSION);
+        out.push(match self.selective_ack {
+            Some(_) => EXTENSION_SELECTIVE_ACK,
+            None => EXTENSION_NONE,
+        });
+        out.extend_from_slice(&self.conn_id.to_be_bytes());
+        out.extend_from_slice(&self.timestamp_us.to_be_bytes());
+        out.extend_from_slice(&self.timestamp_diff_us.to_be_bytes());
+        out.extend_from_slice(&self.wnd_size.to_be_bytes());
+        out.extend_from_slice(&self.seq_nr.to_be_bytes());
+        out.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(sack) = self.selective_ack {
+            out.push(EXTENSION_NONE);
+            out.push(sack.len() as u8);
+            out.extend_from_slice(sack);
+        }
+        out.extend_from_slice(self.payload);
+        out
+    }
+
+    /// Whether the selective ack reports `seq_nr` as received.
+    pub fn selectively_acked(&self, seq_nr: u16) -> bool {
+        let Some(sack) = self.selective_ack else {
+            return false;
+        };
+        let bit = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+        sack.get(bit / 8)
+            .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrap around.
+pub fn seq_lt(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let packet = Packet {
+            kind: PacketType::Data,
+            conn_id: 0x1234,
+            timestamp_us: 1_000_000,
+            timestamp_diff_us: 250,
+            wnd_size: 65536,
+            seq_nr: 7,
+            ack_nr: 3,
+            selective_ack: Some(&[0b101, 0, 0, 0]),
+            payload: b"hello",
+        };
+        let encoded = packet.encode();
+
+        assert_eq!(&encoded[..4], &[0x01, 0x01, 0x12, 0x34]);
+        assert_eq!(encoded.len(), HEADER_LEN + 6 + 5);
+        assert_eq!(Packet::parse(&encoded), Ok(packet));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut encoded = Packet {
+            kind: PacketType::Syn,
+            conn_id: 1,
+            timestamp_us: 0,
+            timestamp_diff_us: 0,
+            wnd_size: 0,
+            seq_nr: 1,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: &[],
+        }
+        .encode();
+
+        assert_eq!(Packet::parse(&encoded[..19]), Err(UtpError::InvalidPacket));
+        encoded[0] = 0x42;
+        assert_eq!(Packet::parse(&encoded), Err(UtpError::UnsupportedVersion));
+        encoded[0] = 0x51;
+        assert_eq!(Packet::parse(&encoded), Err(UtpError::InvalidPacket));
+        // an extension that runs past the end of the packet
+        encoded[0] = 0x41;
+        encoded[1] = EXTENSION_SELECTIVE_ACK;
+        assert_eq!(Packet::parse(&encoded), Err(UtpError::InvalidPacket));
+    }
+
+    #[test]
+    fn test_unknown_extensions_are_skipped() {
+        let mut encoded = Packet {
+            kind: PacketType::State,
+            conn_id: 1,
+            timestamp_us: 0,
+            timestamp_diff_us: 0,
+            wnd_size: 0,
+            seq_nr: 1,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: &[],
+        }
+        .encode();
+        encoded[1] = 2;
+        encoded.extend_from_slice(&[EXTENSION_NONE, 8, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let packet = Packet::parse(&encoded).unwrap();
+        assert_eq!(packet.selective_ack, None);
+        assert!(packet.payload.is_empty());
+    }
+
+    #[test]
+    fn test_selectively_acked() {
+        let packet = Packet {
+            kind: PacketType::State,
+            conn_id: 1,
+            timestamp_us: 0,
+            timestamp_diff_us: 0,
+            wnd_size: 0,
+            seq_nr: 1,
+            ack_nr: 0xfffe,
+            selective_ack: Some(&[0b1000_0001, 0, 0, 1]),
+            payload: &[],
+        };
+
+        assert!(packet.selectively_acked(0));
+        assert!(!packet.selectively_acked(1));
+        assert!(packet.selectively_acked(7));
+        assert!(packet.selectively_acked(24));
+        assert!(!packet.selectively_acked(0xfffe));
+        assert!(!packet.selectively_acked(100));
+    }
+
+    #[test]
+    fn test_seq_lt() {
+        assert!(seq_lt(1, 2));
+        assert!(!seq_lt(2, 2));
+        assert!(seq_lt(0xfffe, 1));
+        assert!(!seq_lt(1, 0xfffe));
+    }
+}
@@ -0,0 +1,556 @@
+//! Many uTP connections on a single UDP socket.
+//!
+//! Peers connect to the one port we announce, so all connections share a socket
+//! and its datagrams are told apart by their sender and connection id.
+//!
+//! Nothing runs in the background. The first stream that has to wait receives for
+//! all connections of the socket and sends their packets, until a packet arrived
+//! or a timer of any connection is due. Streams that have to wait meanwhile queue
+//! their packets for it and wait for that round to end. The peer loop always waits
+//! in a read, so that keeps every connection going.
+
+use alloc::{vec, vec::Vec};
+use core::{
+    cell::RefCell,
+    future::{Future, poll_fn},
+    net::SocketAddr,
+    pin::pin,
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+use crate::{
+    core::utp::{
+        MAX_PACKET_LEN, POLL_INTERVAL_MS, UtpConfig, UtpError,
+        connection::{Connection, State},
+        packet::{Packet, PacketType},
+        tcp_error,
+    },
+    rng::Rng,
+    wifi::{TcpConnection, TcpError, UdpError, UdpSocket},
+};
+
+/// A connection of the socket, by remote address and the id its packets carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    remote: SocketAddr,
+    recv_id: u16,
+}
+
+struct Entry {
+    key: Key,
+    connection: Connection,
+    /// how long the stream of this connection waits at most
+    deadline_ms: Option<u64>,
+    /// whether a stream owns the connection, accepted connections wait for [`UtpSocket::accept`]
+    claimed: bool,
+}
+
+struct Shared<R> {
+    rng: R,
+    entries: Vec<Entry>,
+    /// packets of streams that waited while another stream had the socket
+    outbox: Vec<(Vec<u8>, SocketAddr)>,
+    /// whether a stream receives for all connections right now
+    receiving: bool,
+    /// wakes the receiving stream when the outbox has to go out
+    receiver: Option<Waker>,
+    /// counts the rounds of the receiving stream
+    round: u32,
+    waiting: Vec<Waker>,
+    /// the socket failed, which ends all connections
+    error: Option<TcpError>,
+}
+
+/// A UDP socket that carries the uTP connections to and from many peers.
+pub struct UtpSocket<S, R, C> {
+    /// taken by the receiving stream for its round
+    socket: RefCell<Option<S>>,
+    buf: RefCell<Vec<u8>>,
+    shared: RefCell<Shared<R>>,
+    config: UtpConfig,
+    max_connections: usize,
+    now_ms: C,
+}
+
+impl<S: UdpSocket, R: Rng, C: Fn() -> u64> UtpSocket<S, R, C> {
+    /// Takes over a bound socket. New connections beyond `max_connections` are refused.
+    pub fn new(socket: S, config: UtpConfig, max_connections: usize, rng: R, now_ms: C) -> Self {
+        Self {
+            socket: RefCell::new(Some(socket)),
+            buf: RefCell::new(vec![0; MAX_PACKET_LEN]),
+            shared: RefCell::new(Shared {
+                rng,
+                entries: Vec::new(),
+                outbox: Vec::new(),
+                receiving: false,
+                receiver: None,
+                round: 0,
+                waiting: Vec::new(),
+                error: None,
+            }),
+            config,
+            max_connections,
+            now_ms,
+        }
+    }
+
+    /// The connections of the socket, including those that wait for [`UtpSocket::accept`].
+    pub fn connections(&self) -> usize {
+        self.shared.borrow().entries.len()
+    }
+
+    /// Opens a connection to `remote`, giving up after `timeout`.
+    ///
+    /// Fails with [`TcpError::NoSocket`] if the socket has no room for another connection.
+    pub async fn connect(
+        &self,
+        remote: SocketAddr,
+        timeout: Duration,
+    ) -> Result<UtpStream<'_, S, R, C>, TcpError> {
+        let key = {
+            let mut shared = self.shared.borrow_mut();
+            if shared.entries.len() >= self.max_connections {
+                return Err(TcpError::NoSocket);
+            }
+            let connection = loop {
+                let connection = Connection::connect(self.config, &mut shared.rng);
+                let recv_id = connection.recv_id();
+                if !shared
+                    .entries
+                    .iter()
+                    .any(|entry| entry.key == Key { remote, recv_id })
+                {
+                    break connection;
+                }
+            };
+            let key = Key {
+                remote,
+                recv_id: connection.recv_id(),
+            };
+            shared.entries.push(Entry {
+                key,
+                connection,
+                deadline_ms: None,
+                claimed: true,
+            });
+            key
+        };
+        // removes the connection again if it fails
+        let stream = UtpStream {
+            socket: self,
+            key,
+            timeout: None,
+        };
+
+        let deadline = Some((self.now_ms)() + timeout.as_millis() as u64);
+        while stream.with(|connection| connection.state()) == Some(State::SynSent) {
+            self.wait(Some(key), deadline).await?;
+        }
+        stream.check()?;
+        Ok(stream)
+    }
+
+    /// Waits for a peer to open a connection.
+    pub async fn accept(&self) -> Result<UtpStream<'_, S, R, C>, TcpError> {
+        loop {
+            let accepted = {
+                let mut shared = self.shared.borrow_mut();
+                shared
+                    .entries
+                    .iter_mut()
+                    .find(|entry| !entry.claimed && entry.connection.error().is_none())
+                    .map(|entry| {
+                        entry.claimed = true;
+                        entry.key
+                    })
+            };
+            if let Some(key) = accepted {
+                let stream = UtpStream {
+                    socket: self,
+                    key,
+                    timeout: None,
+                };
+                // answers the SYN
+                self.flush().await?;
+                return Ok(stream);
+            }
+            self.wait(None, None).await?;
+        }
+    }
+
+    /// Waits until something happened on the socket: a packet arrived, a timer is
+    /// due or `deadline` passed. The packets of `key`'s connection go out first.
+    async fn wait(&self, key: Option<Key>, deadline: Option<u64>) -> Result<(), TcpError> {
+        let now = (self.now_ms)();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            return Err(TcpError::TimedOut);
+        }
+        let receiving = {
+            let mut shared = self.shared.borrow_mut();
+            if let Some(err) = shared.error {
+                return Err(err);
+            }
+            if let Some(entry) = key.and_then(|key| shared.entry(key)) {
+                entry.deadline_ms = deadline;
+            }
+            shared.receiving
+        };
+
+        let result = if receiving {
+            self.queue(key);
+            self.next_round().await;
+            Ok(())
+        } else {
+            self.receive().await
+        };
+
+        if let Some(key) = key
+            && let Some(entry) = self.shared.borrow_mut().entry(key)
+        {
+            entry.deadline_ms = None;
+        }
+        result
+    }
+
+    /// Sends what the connections have to send, or leaves it to the receiving stream.
+    async fn flush(&self) -> Result<(), TcpError> {
+        if self.shared.borrow().receiving {
+            self.queue(None);
+            return Ok(());
+        }
+        let mut receiving = Receiving::start(self);
+        self.send_all(receiving.parts().0).await
+    }
+
+    /// Queues the packets of `key`'s connection, or of all of them, for the
+    /// receiving stream and wakes it to send them.
+    fn queue(&self, key: Option<Key>) {
+        let now = (self.now_ms)();
+        let mut shared = self.shared.borrow_mut();
+        let Shared {
+            entries, outbox, ..
+        } = &mut *shared;
+        for entry in entries
+            .iter_mut()
+            .filter(|entry| key.is_none_or(|key| entry.key == key))
+        {
+            let remote = entry.key.remote;
+            outbox.extend(
+                entry
+                    .connection
+                    .poll(now)
+                    .into_iter()
+                    .map(|packet| (packet, remote)),
+            );
+        }
+        if !shared.outbox.is_empty()
+            && let Some(receiver) = shared.receiver.take()
+        {
+            receiver.wake();
+        }
+    }
+
+    /// Waits until the receiving stream finished its round.
+    async fn next_round(&self) {
+        let round = self.shared.borrow().round;
+        poll_fn(|cx| {
+            let mut shared = self.shared.borrow_mut();
+            if shared.round != round {
+                return Poll::Ready(());
+            }
+            if !shared
+                .waiting
+                .iter()
+                .any(|waker| waker.will_wake(cx.waker()))
+            {
+                shared.waiting.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// A round of receiving for all connections: sends their packets, waits for a
+    /// datagram until the next timer and hands it to its connection.
+    async fn receive(&self) -> Result<(), TcpError> {
+        let mut receiving = Receiving::start(self);
+        let (socket, buf) = receiving.parts();
+        self.send_all(socket).await?;
+
+        let now = (self.now_ms)();
+        let wait = {
+            let shared = self.shared.borrow();
+            shared
+                .entries
+                .iter()
+                .flat_map(|entry| {
+                    let deadline = entry.deadline_ms.map(|at| at.saturating_sub(now));
+                    [entry.connection.next_timeout_ms(now), deadline]
+                })
+                .flatten()
+                .min()
+                .unwrap_or(POLL_INTERVAL_MS)
+                .min(POLL_INTERVAL_MS)
+        };
+        socket.set_timeout(Some(Duration::from_millis(wait.max(1))));
+
+        let received = {
+            let mut recv = pin!(socket.recv_from(buf));
+            // stops early when other streams queued packets
+            poll_fn(|cx| {
+                let mut shared = self.shared.borrow_mut();
+                if !shared.outbox.is_empty() {
+                    return Poll::Ready(None);
+                }
+                shared.receiver = Some(cx.waker().clone());
+                drop(shared);
+                recv.as_mut().poll(cx).map(Some)
+            })
+            .await
+        };
+        match received {
+            Some(Ok((len, from))) => self.dispatch(&buf[..len], from),
+            Some(Err(UdpError::TimedOut | UdpError::Truncated)) | None => {}
+            Some(Err(err)) => return Err(self.fail(tcp_error(err))),
+        }
+        self.send_all(socket).await
+    }
+
+    /// Sends the queued packets and whatever the connections have to send.
+    async fn send_all(&self, socket: &mut S) -> Result<(), TcpError> {
+        let now = (self.now_ms)();
+        let packets = {
+            let mut shared = self.shared.borrow_mut();
+            let mut packets = core::mem::take(&mut shared.outbox);
+            for entry in &mut shared.entries {
+                let remote = entry.key.remote;
+                packets.extend(
+                    entry
+                        .connection
+                        .poll(now)
+                        .into_iter()
+                        .map(|packet| (packet, remote)),
+                );
+            }
+            // accepted connections that failed before anyone took them
+            shared
+                .entries
+                .retain(|entry| entry.claimed || entry.connection.state() != State::Closed);
+            packets
+        };
+        for (packet, remote) in packets {
+            match socket.send_to(&packet, remote).await {
+                Ok(()) => {}
+                Err(UdpError::NoSocket) => return Err(self.fail(TcpError::NoSocket)),
+                // the connection sends it again if it matters
+                Err(err) => defmt::debug!("couldn't send uTP packet to {}: {}", remote, err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands a datagram to its connection, or accepts the connection a SYN opens.
+    fn dispatch(&self, bytes: &[u8], from: SocketAddr) {
+        let packet = match Packet::parse(bytes) {
+            Ok(packet) => packet,
+            Err(err) => {
+                defmt::debug!("dropped datagram from {}: {}", from, err);
+                return;
+            }
+        };
+        let now = (self.now_ms)();
+        let key = Key {
+            remote: from,
+            recv_id: match packet.kind {
+                // our answers use the id after the one of the SYN
+                PacketType::Syn => packet.conn_id.wrapping_add(1),
+                _ => packet.conn_id,
+            },
+        };
+
+        let mut shared = self.shared.borrow_mut();
+        if let Some(entry) = shared.entry(key) {
+            if let Err(err) = entry.connection.on_packet(bytes, now) {
+                defmt::debug!("dropped uTP packet from {}: {}", from, err);
+            }
+        } else if packet.kind != PacketType::Syn {
+            defmt::debug!("uTP packet from {} for no connection", from);
+        } else if shared.entries.len() >= self.max_connections {
+            defmt::debug!("refused uTP connection from {}, no room", from);
+        } else if let Ok(connection) = Connection::accept(bytes, self.config, &mut shared.rng, now)
+        {
+            shared.entries.push(Entry {
+                key,
+                connection,
+                deadline_ms: None,
+                claimed: false,
+            });
+        }
+    }
+
+    fn fail(&self, err: TcpError) -> TcpError {
+        self.shared.borrow_mut().error = Some(err);
+        err
+    }
+}
+
+impl<R> Shared<R> {
+    fn entry(&mut self, key: Key) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|entry| entry.key == key)
+    }
+}
+
+/// Marks a stream as the receiving one, which holds the socket and the receive
+/// buffer for its round. Ends the round when dropped, also when the stream's
+/// future is dropped while it waits for a datagram.
+struct Receiving<'a, S, R, C> {
+    utp: &'a UtpSocket<S, R, C>,
+    /// always `Some` until dropped
+    socket: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<'a, S, R, C> Receiving<'a, S, R, C> {
+    fn start(utp: &'a UtpSocket<S, R, C>) -> Self {
+        utp.shared.borrow_mut().receiving = true;
+        Self {
+            utp,
+            socket: utp.socket.take(),
+            buf: utp.buf.take(),
+        }
+    }
+
+    fn parts(&mut self) -> (&mut S, &mut Vec<u8>) {
+        let socket = self
+            .socket
+            .as_mut()
+            .expect("only the receiving stream takes the socket");
+        (socket, &mut self.buf)
+    }
+}
+
+impl<S, R, C> Drop for Receiving<'_, S, R, C> {
+    fn drop(&mut self) {
+        self.utp.socket.replace(self.socket.take());
+        self.utp.buf.replace(core::mem::take(&mut self.buf));
+        let mut shared = self.utp.shared.borrow_mut();
+        shared.receiving = false;
+        shared.receiver = None;
+        shared.round = shared.round.wrapping_add(1);
+        for waker in shared.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A uTP connection of a [`UtpSocket`].
+///
+/// Dropping the stream abandons the connection, [`TcpConnection::close`] ends it
+/// gracefully.
+pub struct UtpStream<'s, S: UdpSocket, R: Rng, C: Fn() -> u64> {
+    socket: &'s UtpSocket<S, R, C>,
+    key: Key,
+    timeout: Option<Duration>,
+}
+
+impl<S: UdpSocket, R: Rng, C: Fn() -> u64> UtpStream<'_, S, R, C> {
+    pub fn state(&self) -> State {
+        self.with(|connection| connection.state())
+            .unwrap_or(State::Closed)
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Connection) -> T) -> Option<T> {
+        let mut shared = self.socket.shared.borrow_mut();
+        shared.entry(self.key).map(|entry| f(&mut entry.connection))
+    }
+
+    /// Fails if the peer reset the connection or stopped answering.
+    fn check(&self) -> Result<(), TcpError> {
+        match self.with(|connection| connection.error()) {
+            Some(None) => Ok(()),
+            Some(Some(UtpError::TimedOut)) => Err(TcpError::TimedOut),
+            _ => Err(TcpError::ConnectionReset),
+        }
+    }
+
+    fn deadline(&self) -> Option<u64> {
+        self.timeout
+            .map(|timeout| (self.socket.now_ms)() + timeout.as_millis() as u64)
+    }
+}
+
+impl<S: UdpSocket, R: Rng, C: Fn() -> u64> TcpConnection for UtpStream<'_, S, R, C> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.deadline();
+        loop {
+            let (len, finished) = self
+                .with(|connection| (connection.read(buf), connection.peer_finished()))
+                .ok_or(TcpError::ConnectionReset)?;
+            if len > 0 {
+                // the window update is sent again with the next packet if this fails
+                let _ = self.socket.flush().await;
+                return Ok(len);
+            }
+            if finished {
+                return Ok(0);
+            }
+            self.check()?;
+            self.socket.wait(Some(self.key), deadline).await?;
+        }
+    }
+
+    async fn write_all(&mut self, mut buf: &[u8]) -> Result<(), TcpError> {
+        let deadline = self.deadline();
+        loop {
+            let written = self
+                .with(|connection| connection.write(buf))
+                .ok_or(TcpError::ConnectionReset)?;
+            buf = &buf[written..];
+            if buf.is_empty() {
+                return self.socket.flush().await;
+            }
+            self.check()?;
+            if !matches!(self.state(), State::SynSent | State::Connected) {
+                return Err(TcpError::Closed);
+            }
+            self.socket.wait(Some(self.key), deadline).await?;
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn remote_addr(&self) -> SocketAddr {
+        self.key.remote
+    }
+
+    /// Waits until the peer acked everything, within the timeout of the stream.
+    /// Without a timeout it waits until the peer stops answering.
+    async fn close(self) {
+        self.with(Connection::close);
+        let deadline = self.deadline();
+        while self.with(|connection| !connection.is_finished() && connection.error().is_none())
+            == Some(true)
+        {
+            if self.socket.wait(Some(self.key), deadline).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl<S: UdpSocket, R: Rng, C: Fn() -> u64> Drop for UtpStream<'_, S, R, C> {
+    fn drop(&mut self) {
+        let key = self.key;
+        self.socket
+            .shared
+            .borrow_mut()
+            .entries
+            .retain(|entry| entry.key != key);
+    }
+}
@@ -0,0 +1,355 @@
+//! Checking completed pieces against the SHA-1 hashes of the metainfo.
+//!
+//! Blocks that arrive in order are hashed right away, so most pieces are verified
+//! without touching the SD card again. Pieces whose blocks arrived out of order are
+//! read back from storage with [`hash_piece`], one block-sized buffer at a time.
+//!
+//! Blocks aren't hashed one by one while downloading. Only pieces that failed, and
+//! their next download, are read back with [`hash_blocks`] to find the peer that
+//! sent the bad block.
+
+use alloc::vec::Vec;
+use core::net::IpAddr;
+use defmt::Format;
+use sha1_smol::Sha1;
+
+use crate::core::{
+    metainfo::Info,
+    peer::message::{BLOCK_LEN, BlockInfo},
+    piece_picker::PiecePicker,
+};
+
+/// The SHA-1 hash of a piece or a block.
+pub type Sha1Digest = [u8; 20];
+
+/// A block of a piece and the peer it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Contribution {
+    pub begin: u32,
+    pub peer: IpAddr,
+}
+
+/// The hash of a block, from [`hash_blocks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct BlockDigest {
+    pub begin: u32,
+    pub digest: Sha1Digest,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Format)]
+pub enum Verdict {
+    /// The piece matches its hash and has been marked as done.
+    Passed {
+        /// who sent which block of the piece
+        contributions: Vec<Contribution>,
+    },
+    /// The piece is corrupt and has to be downloaded again.
+    Failed {
+        /// who sent which block of the corrupt piece
+        contributions: Vec<Contribution>,
+    },
+}
+
+/// A piece that is being downloaded.
+struct PendingPiece {
+    index: u32,
+    sha1: Sha1,
+    /// number of bytes that have been hashed, only counts blocks received in order
+    hashed: u32,
+    /// whether a block arrived before the ones in front of it
+    out_of_order: bool,
+    contributions: Vec<Contribution>,
+}
+
+/// Hashes received blocks and decides whether completed pieces are valid.
+pub struct Verifier {
+    piece_length: u32,
+    length: u32,
+    pending: Vec<PendingPiece>,
+}
+
+impl Verifier {
+    pub fn new(info: &Info) -> Self {
+        Self {
+            piece_length: info.piece_length,
+            length: info.length,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Feeds a block that the picker reported as needed.
+    pub fn on_block(&mut self, block: &BlockInfo, data: &[u8], from: IpAddr) {
+        let position = match self.pending.iter().position(|p| p.index == block.index) {
+            Some(position) => position,
+            None => {
+                self.pending.push(PendingPiece {
+                    index: block.index,
+                    sha1: Sha1::new(),
+                    hashed: 0,
+                    out_of_order: false,
+                    contributions: Vec::new(),
+                });
+                self.pending.len() - 1
+            }
+        };
+        let piece = &mut self.pending[position];
+
+        piece.contributions.push(Contribution {
+            begin: block.begin,
+            peer: from,
+        });
+        if !piece.out_of_order && block.begin == piece.hashed {
+            piece.sha1.update(data);
+            piece.hashed += data.len() as u32;
+        } else {
+            piece.out_of_order = true;
+        }
+    }
+
+    /// Verifies a completed piece whose blocks all arrived in order.
+    ///
+    /// Returns `None` if the piece has to be read back with [`hash_piece`] and
+    /// passed to [`Verifier::verify_digest`] instead.
+    pub fn verify(&mut self, index: u32, info: &Info, picker: &mut PiecePicker) -> Option<Verdict> {
+        let piece = self.pending.iter().find(|p| p.index == index)?;
+        if piece.out_of_order || piece.hashed != self.piece_len(index) {
+            return None;
+        }
+        let digest = piece.sha1.digest().bytes();
+        Some(self.verify_digest(index, &digest, info, picker))
+    }
+
+    /// Compares the hash of a completed piece to the metainfo and tells the picker.
+    pub fn verify_digest(
+        &mut self,
+        index: u32,
+        digest: &Sha1Digest,
+        info: &Info,
+        picker: &mut PiecePicker,
+    ) -> Verdict {
+        let contributions = match self.pending.iter().position(|p| p.index == index) {
+            Some(position) => self.pending.swap_remove(position).contributions,
+            None => Vec::new(),
+        };
+
+        if info.pieces.get(index as usize) == Some(digest) {
+            picker.on_piece_verified(index);
+            Verdict::Passed { contributions }
+        } else {
+            defmt::warn!("piece {} failed verification", index);
+            picker.on_piece_failed(index);
+            Verdict::Failed { contributions }
+        }
+    }
+
+    /// The length of a piece, only the last one can be shorter.
+    pub fn piece_len(&self, index: u32) -> u32 {
+        self.piece_length
+            .min(self.length - index * self.piece_length)
+    }
+}
+
+/// Hashes a piece of `piece_len` bytes that is read in chunks of `buf.len()` bytes.
+///
+/// `read` fills the buffer with the bytes at the given offset within the piece.
+pub async fn hash_piece<E, F>(piece_len: u32, buf: &mut [u8], mut read: F) -> Result<Sha1Digest, E>
+where
+    F: AsyncFnMut(u32, &mut [u8]) -> Result<(), E>,
+{
+    let mut sha1 = Sha1::new();
+    hash_range(0, piece_len, &mut sha1, buf, &mut read).await?;
+    Ok(sha1.digest().bytes())
+}
+
+/// Hashes each block of a piece of `piece_len` bytes on its own, reading it like
+/// [`hash_piece`]. Used for failed pieces and their next download, see
+/// [`BanList`](crate::core::peer::ban::BanList).
+pub async fn hash_blocks<E, F>(
+    piece_len: u32,
+    buf: &mut [u8],
+    mut read: F,
+) -> Result<Vec<BlockDigest>, E>
+where
+    F: AsyncFnMut(u32, &mut [u8]) -> Result<(), E>,
+{
+    let mut blocks = Vec::new();
+    let mut begin = 0;
+    while begin < piece_len {
+        let end = piece_len.min(begin + BLOCK_LEN);
+        let mut sha1 = Sha1::new();
+        hash_range(begin, end, &mut sha1, buf, &mut read).await?;
+        blocks.push(BlockDigest {
+            begin,
+            digest: sha1.digest().bytes(),
+        });
+        begin = end;
+    }
+    Ok(blocks)
+}
+
+async fn hash_range<E, F>(
+    start: u32,
+    end: u32,
+    sha1: &mut Sha1,
+    buf: &mut [u8],
+    read: &mut F,
+) -> Result<(), E>
+where
+    F: AsyncFnMut(u32, &mut [u8]) -> Result<(), E>,
+{
+    let mut offset = start;
+    while offset < end {
+        let len = buf.len().min((end - offset) as usize);
+        read(offset, &mut buf[..len]).await?;
+        sha1.update(&buf[..len]);
+        offset += len as u32;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::piece_picker::PickMode;
+    const PEER: IpAddr = IpAddr::V4(core::net::Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER: IpAddr = IpAddr::V4(core::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    fn block(begin: u32, length: u32) -> BlockInfo {
+        BlockInfo {
+            index: 0,
+            begin,
+            length,
+        }
+    }
+
+    /// A single piece of two and a half blocks, filled with `byte`.
+    fn piece(byte: u8) -> Vec<u8> {
+        alloc::vec![byte; (2 * BLOCK_LEN + 100) as usize]
+    }
+
+    fn info(hashes: &[Sha1Digest]) -> Info<'_> {
+        Info {
+            piece_length: 4 * BLOCK_LEN,
+            name: "test",
+            pieces: hashes,
+            length: 2 * BLOCK_LEN + 100,
+            private: false,
+        }
+    }
+
+    #[test]
+    fn test_in_order_piece_passes() {
+        let hashes = [Sha1::from(piece(1)).digest().bytes()];
+        let info = info(&hashes);
+        let mut picker = PiecePicker::new(&info, PickMode::Sequential);
+        let mut verifier = Verifier::new(&info);
+        let data = piece(1);
+
+        for begin in [0, BLOCK_LEN, 2 * BLOCK_LEN] {
+            let end = (begin + BLOCK_LEN).min(data.len() as u32);
+            let block = block(begin, end - begin);
+            verifier.on_block(&block, &data[begin as usize..end as usize], PEER);
+        }
+
+        assert!(matches!(
+            verifier.verify(0, &info, &mut picker),
+            Some(Verdict::Passed { .. })
+        ));
+        assert!(picker.have().has(0));
+    }
+
+    #[test]
+    fn test_out_of_order_piece_is_read_back() {
+        let hashes = [Sha1::from(piece(1)).digest().bytes()];
+        let info = info(&hashes);
+        let mut picker = PiecePicker::new(&info, PickMode::Sequential);
+        let mut verifier = Verifier::new(&info);
+        let data = piece(1);
+
+        verifier.on_block(
+            &block(BLOCK_LEN, BLOCK_LEN),
+            &data[..BLOCK_LEN as usize],
+            PEER,
+        );
+        verifier.on_block(&block(0, BLOCK_LEN), &data[..BLOCK_LEN as usize], PEER);
+        assert_eq!(verifier.verify(0, &info, &mut picker), None);
+
+        let mut buf = [0u8; 1000];
+        let digest = block_on(hash_piece::<(), _>(
+            verifier.piece_len(0),
+            &mut buf,
+            async |offset, buf: &mut [u8]| {
+                buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()]);
+                Ok(())
+            },
+        ))
+        .unwrap();
+        assert!(matches!(
+            verifier.verify_digest(0, &digest, &info, &mut picker),
+            Verdict::Passed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_failed_piece_reports_contributors() {
+        let hashes = [Sha1::from(piece(1)).digest().bytes()];
+        let info = info(&hashes);
+        let mut picker = PiecePicker::new(&info, PickMode::Sequential);
+        let mut verifier = Verifier::new(&info);
+        let good = piece(1);
+        let bad = piece(2);
+
+        verifier.on_block(&block(0, BLOCK_LEN), &good[..BLOCK_LEN as usize], PEER);
+        verifier.on_block(
+            &block(BLOCK_LEN, BLOCK_LEN),
+            &bad[..BLOCK_LEN as usize],
+            OTHER,
+        );
+        verifier.on_block(&block(2 * BLOCK_LEN, 100), &good[..100], PEER);
+
+        let Some(Verdict::Failed { contributions }) = verifier.verify(0, &info, &mut picker) else {
+            panic!("corrupt piece passed");
+        };
+        let senders: Vec<(u32, IpAddr)> = contributions.iter().map(|c| (c.begin, c.peer)).collect();
+        assert_eq!(
+            senders,
+            [(0, PEER), (BLOCK_LEN, OTHER), (2 * BLOCK_LEN, PEER)]
+        );
+        assert!(!picker.have().has(0));
+    }
+
+    #[test]
+    fn test_hash_blocks() {
+        let mut data = piece(1);
+        data[BLOCK_LEN as usize + 5] = 2;
+
+        let mut buf = [0u8; 1000];
+        let blocks = block_on(hash_blocks::<(), _>(
+            data.len() as u32,
+            &mut buf,
+            async |offset, buf: &mut [u8]| {
+                buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()]);
+                Ok(())
+            },
+        ))
+        .unwrap();
+
+        let expected: Vec<BlockDigest> = data
+            .chunks(BLOCK_LEN as usize)
+            .zip([0, BLOCK_LEN, 2 * BLOCK_LEN])
+            .map(|(block, begin)| BlockDigest {
+                begin,
+                digest: Sha1::from(block).digest().bytes(),
+            })
+            .collect();
+        assert_eq!(blocks, expected);
+    }
+
+    /// Drives a future that never actually waits.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+}
@@ -1,21 +1,23 @@
-use embedded_sdmmc::{RawDirectory, RawFile, RawVolume, filesystem::ToShortFileName};
+use alloc::vec::Vec;
+use embedded_sdmmc::{BlockDevice, RawDirectory, RawVolume, filesystem::ToShortFileName};
 
+use crate::fs::files::FileTable;
+
+mod files;
 mod operations;
+pub mod short_names;
 pub mod torrent_retrieval;
 mod volume_mgr;
+pub use files::{FileHandle, MAX_HANDLES, MAX_OPEN_FILES};
 pub use volume_mgr::VolumeMgr;
 
+/// Errors of the SD card and its filesystem.
+pub type FsError<V> = embedded_sdmmc::Error<<<V as VolumeMgr>::BlockDevice as BlockDevice>::Error>;
+
 pub(crate) trait FileSystemExt {
     type Error: core::fmt::Debug;
 
-    /// opens a file with ReadWriteCreateOrAppend mode
-    fn _open_file<N: ToShortFileName>(&mut self, file_name: N) -> Result<(), Self::Error>;
-
     fn open_dir<N: ToShortFileName>(&mut self, dir_name: N) -> Result<(), Self::Error>;
-
-    fn _write_to_opened_file(&mut self, buf: &[u8]) -> Result<(), Self::Error>;
-
-    fn _read_to_end(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
 }
 
 /// Struct to interact with the filesystem on the ESP32C3.
@@ -28,9 +30,8 @@ where
     /// The directory that is currently open.
     /// At the beginning this will be the root directory of the filesystem.
     opened_dir: Option<RawDirectory>,
-    // TODO: allow multiple opened files (two, for DB and file which is written to)
-    /// The file that is currently open.
-    open_file: Option<RawFile>,
+    /// The files that were opened with a handle.
+    files: FileTable,
 }
 
 impl<V> Drop for FileSystem<V>
@@ -38,14 +39,20 @@ where
     V: VolumeMgr,
 {
     fn drop(&mut self) {
-        // Close directory
-        if let Some(dir) = self.opened_dir.take() {
-            let _close_dir_result = self.get_volume_mgr().close_dir(dir);
+        // Close files
+        let mut dirs: Vec<RawDirectory> = self.opened_dir.take().into_iter().collect();
+        for (dir, file) in self.files.drain() {
+            if let Some(file) = file {
+                let _close_file_result = self.volume_mgr.close_file(file);
+            }
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
         }
 
-        // Close file
-        if let Some(file) = self.open_file {
-            let _close_file_result = self.get_volume_mgr().close_file(file);
+        // Close directories
+        for dir in dirs {
+            let _close_dir_result = self.volume_mgr.close_dir(dir);
         }
 
         // Close volume
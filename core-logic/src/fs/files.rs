@@ -0,0 +1,312 @@
+//! Files that stay open between operations, behind typed handles.
+//!
+//! The volume manager only has room for a few open files, fewer than a multi-file
+//! torrent, its resume data and the torrent file need together. The table hands
+//! out [`FileHandle`]s for up to [`MAX_HANDLES`] files, of which at most
+//! [`MAX_OPEN_FILES`] are open on the volume manager. When another one is needed,
+//! the least recently used file is closed and opened again the next time its
+//! handle is used.
+
+use alloc::vec::Vec;
+use defmt::Format;
+use embedded_sdmmc::{Mode, RawDirectory, RawFile, ShortFileName, filesystem::ToShortFileName};
+
+use crate::fs::{FileSystem, FsError, VolumeMgr};
+
+/// How many files can have a handle at the same time.
+pub const MAX_HANDLES: usize = 8;
+
+/// How many of them are open on the volume manager at the same time. The volume
+/// manager has room for four files, the last one is left for the operations that
+/// open a file by name.
+pub const MAX_OPEN_FILES: usize = 3;
+
+/// A file opened with [`FileSystem::open_file`].
+///
+/// A handle stays valid until it is closed, even while the table has closed its
+/// file on the volume manager to make room for another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct FileHandle {
+    index: u8,
+    /// tells a handle apart from the earlier handles of the same slot
+    generation: u16,
+}
+
+struct Entry {
+    dir: RawDirectory,
+    name: ShortFileName,
+    read_only: bool,
+    /// the file on the volume manager, `None` while it is closed to make room
+    raw: Option<RawFile>,
+    last_used: u32,
+}
+
+#[derive(Default)]
+struct Slot {
+    generation: u16,
+    entry: Option<Entry>,
+}
+
+/// The files with a handle, see the [module docs](self).
+#[derive(Default)]
+pub(crate) struct FileTable {
+    slots: Vec<Slot>,
+    /// counts up with every use of a handle, to find the least recently used file
+    clock: u32,
+}
+
+impl FileTable {
+    fn entry(&self, handle: FileHandle) -> Option<&Entry> {
+        let slot = self.slots.get(handle.index as usize)?;
+        (slot.generation == handle.generation)
+            .then_some(slot.entry.as_ref())
+            .flatten()
+    }
+
+    fn entry_mut(&mut self, handle: FileHandle) -> Option<&mut Entry> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        (slot.generation == handle.generation)
+            .then_some(slot.entry.as_mut())
+            .flatten()
+    }
+
+    fn entries_mut(&mut self) -> impl Iterator<Item = &mut Entry> {
+        self.slots.iter_mut().filter_map(|slot| slot.entry.as_mut())
+    }
+
+    /// Whether a file with a handle is in `dir`.
+    pub(crate) fn uses_dir(&self, dir: RawDirectory) -> bool {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.entry.as_ref())
+            .any(|entry| entry.dir == dir)
+    }
+
+    /// Takes all files and directories the table still has open on the volume manager.
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = (RawDirectory, Option<RawFile>)> {
+        self.slots
+            .drain(..)
+            .filter_map(|slot| slot.entry)
+            .map(|entry| (entry.dir, entry.raw))
+    }
+
+    fn tick(&mut self) -> u32 {
+        self.clock = self.clock.wrapping_add(1);
+        self.clock
+    }
+}
+
+impl<V> FileSystem<V>
+where
+    V: VolumeMgr,
+{
+    /// Opens a file in the current directory and returns a handle to it, which
+    /// stays valid when the current directory changes.
+    ///
+    /// `mode` only applies now, a file the table has to open again is opened
+    /// read-only or for writing without truncating it.
+    pub fn open_file<N: ToShortFileName>(
+        &mut self,
+        file_name: N,
+        mode: Mode,
+    ) -> Result<FileHandle, FsError<V>> {
+        let dir = self.opened_dir.ok_or(embedded_sdmmc::Error::BadHandle)?;
+        let name = file_name
+            .to_short_filename()
+            .map_err(embedded_sdmmc::Error::FilenameError)?;
+        if self
+            .files
+            .entries_mut()
+            .any(|entry| entry.dir == dir && entry.name == name)
+        {
+            return Err(embedded_sdmmc::Error::FileAlreadyOpen);
+        }
+        let index = match self
+            .files
+            .slots
+            .iter()
+            .position(|slot| slot.entry.is_none())
+        {
+            Some(index) => index,
+            None if self.files.slots.len() < MAX_HANDLES => {
+                self.files.slots.push(Slot::default());
+                self.files.slots.len() - 1
+            }
+            None => return Err(embedded_sdmmc::Error::TooManyOpenFiles),
+        };
+
+        self.make_room()?;
+        let raw = self.volume_mgr.open_file_in_dir(dir, &name, mode)?;
+        let last_used = self.files.tick();
+        let slot = &mut self.files.slots[index];
+        slot.entry = Some(Entry {
+            dir,
+            name,
+            read_only: mode == Mode::ReadOnly,
+            raw: Some(raw),
+            last_used,
+        });
+        Ok(FileHandle {
+            index: index as u8,
+            generation: slot.generation,
+        })
+    }
+
+    /// Reads up to `buf.len()` bytes at `offset` of the file.
+    /// Returns the number of bytes read, which is only short at the end of the file.
+    pub fn read_file_at(
+        &mut self,
+        handle: FileHandle,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError<V>> {
+        let file = self.raw_file(handle)?;
+        self.volume_mgr.file_seek_from_start(file, offset)?;
+        self.volume_mgr.read(file, buf)
+    }
+
+    /// Writes `data` at `offset` of the file, like [`FileSystem::write_at`].
+    pub fn write_file_at(
+        &mut self,
+        handle: FileHandle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), FsError<V>> {
+        let file = self.raw_file(handle)?;
+        self.write_raw_at(file, offset, data)
+    }
+
+    pub fn file_length(&mut self, handle: FileHandle) -> Result<u32, FsError<V>> {
+        let file = self.raw_file(handle)?;
+        self.volume_mgr.file_length(file)
+    }
+
+    /// Writes the directory entry of the file, so its new length survives a power loss.
+    pub fn flush_file(&mut self, handle: FileHandle) -> Result<(), FsError<V>> {
+        let entry = self
+            .files
+            .entry(handle)
+            .ok_or(embedded_sdmmc::Error::BadHandle)?;
+        match entry.raw {
+            Some(file) => self.volume_mgr.flush_file(file),
+            // closing it to make room already flushed it
+            None => Ok(()),
+        }
+    }
+
+    /// Closes the file, after which its handle is invalid.
+    pub fn close_file(&mut self, handle: FileHandle) -> Result<(), FsError<V>> {
+        let slot = self
+            .files
+            .slots
+            .get_mut(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation && slot.entry.is_some())
+            .ok_or(embedded_sdmmc::Error::BadHandle)?;
+        let entry = slot.entry.take().expect("checked above");
+        slot.generation = slot.generation.wrapping_add(1);
+
+        let result = match entry.raw {
+            Some(file) => self.volume_mgr.close_file(file),
+            None => Ok(()),
+        };
+        self.release_dir(entry.dir);
+        result
+    }
+
+    /// Closes the files with a handle that are named `file_name`, so it can be
+    /// opened by name. Their handles stay valid.
+    ///
+    /// Files of other directories are closed as well, the same directory may have
+    /// been opened twice and the handles don't tell.
+    pub(crate) fn release_file(&mut self, file_name: &ShortFileName) -> Result<(), FsError<V>> {
+        let files: Vec<_> = self
+            .files
+            .entries_mut()
+            .filter(|entry| entry.name == *file_name)
+            .filter_map(|entry| entry.raw.take())
+            .collect();
+        for file in files {
+            self.volume_mgr.close_file(file)?;
+        }
+        Ok(())
+    }
+
+    /// The file of `handle` on the volume manager, opened again if needed.
+    fn raw_file(&mut self, handle: FileHandle) -> Result<RawFile, FsError<V>> {
+        let last_used = self.files.tick();
+        let entry = self
+            .files
+            .entry_mut(handle)
+            .ok_or(embedded_sdmmc::Error::BadHandle)?;
+        entry.last_used = last_used;
+        if let Some(file) = entry.raw {
+            return Ok(file);
+        }
+
+        self.make_room()?;
+        let entry = self.files.entry_mut(handle).expect("checked above");
+        let mode = if entry.read_only {
+            Mode::ReadOnly
+        } else {
+            Mode::ReadWriteAppend
+        };
+        let file = self
+            .volume_mgr
+            .open_file_in_dir(entry.dir, &entry.name, mode)?;
+        entry.raw = Some(file);
+        Ok(file)
+    }
+
+    /// Closes the least recently used file if [`MAX_OPEN_FILES`] files are open.
+    fn make_room(&mut self) -> Result<(), FsError<V>> {
+        // the clock may have wrapped, so the oldest is the furthest behind it
+        let clock = self.files.clock;
+        let mut open: Vec<_> = self
+            .files
+            .entries_mut()
+            .filter(|entry| entry.raw.is_some())
+            .collect();
+        if open.len() < MAX_OPEN_FILES {
+            return Ok(());
+        }
+        let oldest = open
+            .iter_mut()
+            .max_by_key(|entry| clock.wrapping_sub(entry.last_used))
+            .expect("MAX_OPEN_FILES isn't zero");
+        let file = oldest.raw.take().expect("only open files are considered");
+        self.volume_mgr.close_file(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(index: u8, generation: u16) -> FileHandle {
+        FileHandle { index, generation }
+    }
+
+    #[test]
+    fn test_stale_handles_are_rejected() {
+        let mut table = FileTable::default();
+        table.slots.push(Slot {
+            generation: 1,
+            entry: None,
+        });
+
+        assert!(table.entry(handle(0, 1)).is_none());
+        assert!(table.entry(handle(0, 0)).is_none());
+        assert!(table.entry(handle(1, 1)).is_none());
+    }
+
+    #[test]
+    fn test_tick_wraps() {
+        let mut table = FileTable {
+            clock: u32::MAX,
+            ..Default::default()
+        };
+
+        assert_eq!(table.tick(), 0);
+        assert_eq!(table.tick(), 1);
+    }
+}
@@ -1,6 +1,7 @@
-use embedded_sdmmc::{BlockDevice, RawDirectory, RawFile, filesystem::ToShortFileName};
+use alloc::{vec, vec::Vec};
+use embedded_sdmmc::{BlockDevice, Mode, RawDirectory, RawFile, filesystem::ToShortFileName};
 
-use crate::fs::{FileSystem, FileSystemExt, VolumeMgr};
+use crate::fs::{FileSystem, FileSystemExt, FsError, VolumeMgr, files::FileTable};
 
 impl<V> FileSystem<V>
 where
@@ -14,7 +15,7 @@ where
             volume_mgr,
             vol0,
             opened_dir: Some(root_dir),
-            open_file: None,
+            files: FileTable::default(),
         }
     }
 
@@ -22,8 +23,17 @@ where
         &self.volume_mgr
     }
 
+    /// Takes the current directory, which the caller has to close.
+    ///
+    /// If files with a handle are in it, the caller gets a second handle of the
+    /// directory, so closing it doesn't affect them.
     pub fn take_current_dir(&mut self) -> Option<RawDirectory> {
-        self.opened_dir.take()
+        let dir = self.opened_dir.take()?;
+        if self.files.uses_dir(dir) {
+            self.volume_mgr.open_dir(dir, ".").ok()
+        } else {
+            Some(dir)
+        }
     }
 
     pub fn go_to_root_dir(&mut self) {
@@ -31,21 +41,129 @@ where
     }
 
     fn set_current_dir(&mut self, dir: RawDirectory) {
-        let dir = self.opened_dir.replace(dir);
-        if let Some(dir) = dir {
+        if let Some(dir) = self.opened_dir.replace(dir) {
+            self.release_dir(dir);
+        }
+    }
+
+    /// Closes `dir` unless it is the current directory or files with a handle are in it.
+    pub(crate) fn release_dir(&mut self, dir: RawDirectory) {
+        if self.opened_dir != Some(dir) && !self.files.uses_dir(dir) {
             let _closing_result = self.get_volume_mgr().close_dir(dir);
         }
     }
 
-    pub fn take_open_file(&mut self) -> Option<RawFile> {
-        self.open_file.take()
+    /// Opens a file of the current directory by name. A file that is open with a
+    /// handle is closed first, its handle opens it again when it is used.
+    fn open_by_name<N: ToShortFileName>(
+        &mut self,
+        file_name: N,
+        mode: Mode,
+    ) -> Result<RawFile, FsError<V>> {
+        let dir = self.opened_dir.ok_or(embedded_sdmmc::Error::BadHandle)?;
+        let name = file_name
+            .to_short_filename()
+            .map_err(embedded_sdmmc::Error::FilenameError)?;
+        self.release_file(&name)?;
+        self.volume_mgr.open_file_in_dir(dir, &name, mode)
+    }
+
+    /// Reads up to `buf.len()` bytes at `offset` of a file in the current directory.
+    /// Returns the number of bytes read, which is only short at the end of the file.
+    ///
+    /// The file is opened and closed again, the current directory is untouched.
+    pub fn read_at<N: ToShortFileName>(
+        &mut self,
+        file_name: N,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError<V>> {
+        let file = self.open_by_name(file_name, Mode::ReadOnly)?;
+        let volume_mgr = self.get_volume_mgr();
+
+        let result = volume_mgr
+            .file_seek_from_start(file, offset)
+            .and_then(|()| volume_mgr.read(file, buf));
+        volume_mgr.close_file(file)?;
+        result
+    }
+
+    /// Reads a whole file of the current directory.
+    ///
+    /// The file is opened and closed again, the current directory is untouched.
+    pub fn read_file<N: ToShortFileName>(&mut self, file_name: N) -> Result<Vec<u8>, FsError<V>> {
+        let file = self.open_by_name(file_name, Mode::ReadOnly)?;
+        let volume_mgr = self.get_volume_mgr();
+
+        let result = volume_mgr.file_length(file).and_then(|length| {
+            let mut bytes = vec![0u8; length as usize];
+            let mut read = 0;
+            while read < bytes.len() {
+                match volume_mgr.read(file, &mut bytes[read..])? {
+                    0 => break,
+                    len => read += len,
+                }
+            }
+            bytes.truncate(read);
+            Ok(bytes)
+        });
+        volume_mgr.close_file(file)?;
+        result
+    }
+
+    /// Replaces the contents of a file in the current directory, creating it if needed.
+    ///
+    /// The current directory is untouched.
+    pub fn write_file<N: ToShortFileName>(
+        &mut self,
+        file_name: N,
+        data: &[u8],
+    ) -> Result<(), FsError<V>> {
+        let file = self.open_by_name(file_name, Mode::ReadWriteCreateOrTruncate)?;
+        let volume_mgr = self.get_volume_mgr();
+
+        let result = volume_mgr.write(file, data);
+        volume_mgr.close_file(file)?;
+        result
     }
 
-    /// Set the open file and return the previous one.
-    fn _set_open_file(&mut self, file: RawFile) {
-        if let Some(file) = self.open_file.replace(file) {
-            let _closing_result = self.get_volume_mgr().close_file(file);
+    /// Writes `data` at `offset` of a file in the current directory, creating it if needed.
+    ///
+    /// Data in front of and behind the written range is kept. If the file ends before
+    /// `offset`, the gap is filled with zeros first.
+    /// The current directory is untouched.
+    pub fn write_at<N: ToShortFileName>(
+        &mut self,
+        file_name: N,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), FsError<V>> {
+        let file = self.open_by_name(file_name, Mode::ReadWriteCreateOrAppend)?;
+
+        let result = self.write_raw_at(file, offset, data);
+        self.get_volume_mgr().close_file(file)?;
+        result
+    }
+
+    pub(crate) fn write_raw_at(
+        &self,
+        file: RawFile,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), FsError<V>> {
+        let volume_mgr = self.get_volume_mgr();
+        let mut length = volume_mgr.file_length(file)?;
+        if length < offset {
+            volume_mgr.file_seek_from_end(file, 0)?;
+            let zeros = [0u8; 512];
+            while length < offset {
+                let len = zeros.len().min((offset - length) as usize);
+                volume_mgr.write(file, &zeros[..len])?;
+                length += len as u32;
+            }
         }
+        volume_mgr.file_seek_from_start(file, offset)?;
+        volume_mgr.write(file, data)
     }
 }
 
@@ -55,39 +173,6 @@ where
 {
     type Error = embedded_sdmmc::Error<<V::BlockDevice as BlockDevice>::Error>;
 
-    fn _write_to_opened_file(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
-        let file = self
-            .take_open_file()
-            .expect("File not opened!")
-            .to_file(self.get_volume_mgr());
-
-        file.write(buf)
-    }
-
-    fn _read_to_end(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let file = self
-            .take_open_file()
-            .expect("File not opened!")
-            .to_file(self.get_volume_mgr());
-
-        file.read(buf)
-    }
-
-    fn _open_file<N: ToShortFileName>(&mut self, file_name: N) -> Result<(), Self::Error> {
-        let raw_file = {
-            let dir = if let Some(dir) = self.take_current_dir() {
-                dir.to_directory(self.get_volume_mgr())
-            } else {
-                return Err(embedded_sdmmc::Error::BadHandle);
-            };
-            dir.open_file_in_dir(file_name, embedded_sdmmc::Mode::ReadWriteCreateOrAppend)?
-                .to_raw_file()
-        };
-        self._set_open_file(raw_file);
-
-        Ok(())
-    }
-
     fn open_dir<N: ToShortFileName>(&mut self, dir_name: N) -> Result<(), Self::Error> {
         let raw_dir = {
             let dir = if let Some(dir) = self.take_current_dir() {
@@ -0,0 +1,261 @@
+//! Names for torrent files on a card that only takes 8.3 names.
+//!
+//! `embedded_sdmmc` can't create long file names, so every file of a torrent gets
+//! a short name derived from its path. Names that are valid 8.3 names already are
+//! kept, others are made of the first letters of the name, a hash of the whole
+//! path and the extension: `ubuntu-24.04-desktop-amd64.iso` becomes something like
+//! `UBUN3F0A.ISO`. Nested paths are flattened into the torrent's directory.
+//!
+//! The mapping only depends on the torrent's list of files, so the same torrent
+//! always ends up with the same names. [`INDEX_FILE`] lists every short name with
+//! the path it stands for, one `SHORT.EXT<tab>dir/original name` per line, so the
+//! files can be given their real names again on a PC.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+use sha1_smol::Sha1;
+
+use crate::fs::{FileSystem, FsError, VolumeMgr};
+
+/// The index of the short names, next to the files.
+pub const INDEX_FILE: &str = "NAMES.TXT";
+
+/// Length of the name part of a short name, the extension has up to three characters.
+const BASE_LEN: usize = 8;
+const EXTENSION_LEN: usize = 3;
+
+/// How many characters of the original name a hashed short name keeps.
+const PREFIX_LEN: usize = 4;
+
+/// The short names of a torrent's files, in the order of the torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameMap {
+    /// short name and original path, with `/` between the path's components
+    entries: Vec<(String, String)>,
+}
+
+impl NameMap {
+    /// Assigns a short name to each path, a path being the components of a file's
+    /// path in the torrent, e.g. `["docs", "README.md"]`.
+    pub fn new<'a, P>(paths: impl IntoIterator<Item = P>) -> Self
+    where
+        P: AsRef<[&'a str]>,
+    {
+        let mut entries: Vec<(String, String)> = Vec::new();
+        let taken = |entries: &[(String, String)], name: &str| {
+            name == INDEX_FILE || entries.iter().any(|(short, _)| short == name)
+        };
+        for path in paths {
+            let path = path.as_ref();
+            let original = path
+                .iter()
+                .map(|component| component.replace(|c: char| c.is_control(), "_"))
+                .collect::<Vec<_>>()
+                .join("/");
+            let file_name = path.last().copied().unwrap_or_default();
+
+            let short = match as_short_name(file_name) {
+                Some(short) if !taken(&entries, &short) => short,
+                _ => (0..)
+                    .map(|attempt| hashed_name(file_name, &original, attempt))
+                    .find(|short| !taken(&entries, short))
+                    .expect("there are more hashes than files"),
+            };
+            entries.push((short, original));
+        }
+        Self { entries }
+    }
+
+    /// The short name of the `index`th file.
+    pub fn short_name(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(|(short, _)| short.as_str())
+    }
+
+    /// The path a short name stands for.
+    pub fn original(&self, short_name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(short, _)| short.eq_ignore_ascii_case(short_name))
+            .map(|(_, original)| original.as_str())
+    }
+
+    /// Short names and original paths, in the order of the torrent.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(short, original)| (short.as_str(), original.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The contents of [`INDEX_FILE`].
+    pub fn encode(&self) -> String {
+        let mut index = String::new();
+        for (short, original) in &self.entries {
+            let _ = writeln!(index, "{short}\t{original}");
+        }
+        index
+    }
+
+    /// Reads the contents of [`INDEX_FILE`], `None` if it is corrupt.
+    pub fn decode(index: &str) -> Option<Self> {
+        let entries = index
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (short, original) = line.split_once('\t')?;
+                Some((as_short_name(short)?, original.to_string()))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self { entries })
+    }
+}
+
+/// Writes the index of the short names to [`INDEX_FILE`] in the current directory.
+pub fn save_name_index<V: VolumeMgr>(
+    fs: &mut FileSystem<V>,
+    names: &NameMap,
+) -> Result<(), FsError<V>> {
+    fs.write_file(INDEX_FILE, names.encode().as_bytes())
+}
+
+/// Reads the index saved with [`save_name_index`], `None` if there is none or it is corrupt.
+pub fn load_name_index<V: VolumeMgr>(fs: &mut FileSystem<V>) -> Option<NameMap> {
+    let bytes = fs.read_file(INDEX_FILE).ok()?;
+    NameMap::decode(core::str::from_utf8(&bytes).ok()?)
+}
+
+/// Whether `c` may appear in a short name. Lowercase letters are stored uppercase.
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// `name` in uppercase, if it is a valid 8.3 name already.
+fn as_short_name(name: &str) -> Option<String> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    let valid =
+        |part: &str, max_len: usize| part.len() <= max_len && part.chars().all(is_short_name_char);
+    (!base.is_empty() && valid(base, BASE_LEN) && valid(extension, EXTENSION_LEN))
+        .then(|| name.to_ascii_uppercase())
+}
+
+/// A short name made of the start of `file_name`, a hash of the whole path and the
+/// extension. Another `attempt` gives another hash, in case the name is taken.
+fn hashed_name(file_name: &str, path: &str, attempt: u32) -> String {
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+        _ => (file_name, ""),
+    };
+    let valid = |part: &str, len: usize| -> String {
+        part.chars()
+            .filter(|&c| is_short_name_char(c))
+            .take(len)
+            .map(|c| c.to_ascii_uppercase())
+            .collect()
+    };
+
+    let mut sha1 = Sha1::new();
+    sha1.update(path.as_bytes());
+    sha1.update(&attempt.to_be_bytes());
+    let digest = sha1.digest().bytes();
+    let prefix = valid(stem, PREFIX_LEN);
+    let hash_len = BASE_LEN - prefix.len();
+    let hash: String = digest
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0xf])
+        .take(hash_len)
+        .map(|nibble| {
+            char::from_digit(nibble as u32, 16)
+                .unwrap()
+                .to_ascii_uppercase()
+        })
+        .collect();
+
+    match valid(extension, EXTENSION_LEN) {
+        extension if extension.is_empty() => format!("{prefix}{hash}"),
+        extension => format!("{prefix}{hash}.{extension}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_names_are_kept() {
+        let names = NameMap::new([["test.txt"], ["README"], ["a.b"]]);
+
+        assert_eq!(names.short_name(0), Some("TEST.TXT"));
+        assert_eq!(names.short_name(1), Some("README"));
+        assert_eq!(names.short_name(2), Some("A.B"));
+    }
+
+    #[test]
+    fn test_long_names_are_hashed() {
+        let names = NameMap::new([
+            &["ubuntu-24.04-desktop-amd64.iso"][..],
+            &["docs", "über wörter.markdown"],
+            &[".hidden"],
+        ]);
+
+        let iso = names.short_name(0).unwrap();
+        assert!(iso.starts_with("UBUN") && iso.ends_with(".ISO"), "{iso}");
+        assert_eq!(iso.len(), 12);
+        let docs = names.short_name(1).unwrap();
+        assert!(docs.starts_with("BERW") && docs.ends_with(".MAR"), "{docs}");
+        let hidden = names.short_name(2).unwrap();
+        assert!(
+            hidden.starts_with("HIDD") && !hidden.contains('.'),
+            "{hidden}"
+        );
+
+        for (short, _) in names.entries() {
+            assert!(embedded_sdmmc::ShortFileName::create_from_str(short).is_ok());
+        }
+        assert_eq!(names.original(iso), Some("ubuntu-24.04-desktop-amd64.iso"));
+        assert_eq!(names.original(docs), Some("docs/über wörter.markdown"));
+    }
+
+    #[test]
+    fn test_mapping_is_deterministic() {
+        let paths = [["a", "long file name.txt"], ["b", "long file name.txt"]];
+
+        assert_eq!(NameMap::new(paths), NameMap::new(paths));
+    }
+
+    #[test]
+    fn test_collisions_get_other_names() {
+        let names = NameMap::new([
+            &["a", "readme.txt"][..],
+            &["b", "README.TXT"],
+            &["names.txt"],
+        ]);
+
+        assert_eq!(names.short_name(0), Some("README.TXT"));
+        let second = names.short_name(1).unwrap();
+        assert!(second.starts_with("READ") && second != "README.TXT");
+        assert_ne!(names.short_name(2), Some(INDEX_FILE));
+        assert_eq!(names.original(second), Some("b/README.TXT"));
+    }
+
+    #[test]
+    fn test_index_roundtrip() {
+        let names = NameMap::new([&["dir", "some file.bin"][..], &["x.y"]]);
+        let index = names.encode();
+
+        assert!(index.ends_with("X.Y\tx.y\n"));
+        assert_eq!(NameMap::decode(&index), Some(names));
+        assert_eq!(NameMap::decode("TOO-LONG-NAME.TXT\tfile"), None);
+        assert_eq!(NameMap::decode("NOTAB.TXT"), None);
+    }
+}
@@ -3,32 +3,54 @@
 extern crate alloc;
 
 use crate::{
+    core::{PeerId, peer_id::generate_peer_id},
     fs::{FileSystem, VolumeMgr},
+    rng::Rng,
     wifi::WifiStack,
 };
 
 pub mod core;
 pub mod fs;
+pub mod rng;
 pub mod wifi;
 
 pub use core::metainfo::{Info, MetaInfoFile};
 
-pub struct BitTorrenter<WIFI, V>
+pub struct BitTorrenter<WIFI, V, R>
 where
     WIFI: WifiStack,
     V: VolumeMgr,
+    R: Rng,
 {
     wifi: WIFI,
     fs: FileSystem<V>,
+    rng: R,
+    /// Our peer id, freshly generated on every boot.
+    peer_id: PeerId,
 }
 
-impl<WIFI, V> BitTorrenter<WIFI, V>
+impl<WIFI, V, R> BitTorrenter<WIFI, V, R>
 where
     WIFI: WifiStack,
     V: VolumeMgr,
+    R: Rng,
 {
-    pub fn new(wifi: WIFI, fs: FileSystem<V>) -> Self {
-        Self { wifi, fs }
+    pub fn new(wifi: WIFI, fs: FileSystem<V>, mut rng: R) -> Self {
+        let peer_id = generate_peer_id(&mut rng);
+        Self {
+            wifi,
+            fs,
+            rng,
+            peer_id,
+        }
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    pub fn rng(&mut self) -> &mut R {
+        &mut self.rng
     }
 
     pub fn fs(&mut self) -> &mut FileSystem<V> {
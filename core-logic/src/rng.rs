@@ -0,0 +1,66 @@
+/// Source of randomness, e.g. the hardware RNG of the ESP32C3.
+///
+/// None of the users need cryptographic strength except where noted,
+/// so a seeded [`XorShift32`] is fine on the host and in tests.
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(4) {
+            let random = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+
+    /// Returns a number in `0..bound`. `bound` must not be zero.
+    fn next_below(&mut self, bound: u32) -> u32 {
+        // the modulo bias is negligible for the small bounds we use
+        self.next_u32() % bound
+    }
+}
+
+/// Small deterministic pseudo random number generator.
+///
+/// The same seed always produces the same sequence, which makes tests reproducible.
+#[derive(Debug, Clone)]
+pub struct XorShift32(u32);
+
+impl XorShift32 {
+    pub fn new(seed: u32) -> Self {
+        // zero is the only state xorshift can't escape from
+        Self(if seed == 0 { 0x9e37_79b9 } else { seed })
+    }
+}
+
+impl Rng for XorShift32 {
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xorshift_is_deterministic() {
+        let mut a = XorShift32::new(42);
+        let mut b = XorShift32::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u32(), b.next_u32());
+        }
+        assert_ne!(XorShift32::new(0).next_u32(), 0);
+    }
+
+    #[test]
+    fn test_fill_bytes_odd_length() {
+        let mut buf = [0u8; 7];
+        XorShift32::new(1).fill_bytes(&mut buf);
+        assert!(buf.iter().any(|&b| b != 0));
+    }
+}
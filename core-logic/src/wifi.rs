@@ -1,14 +1,145 @@
-use core::net::Ipv4Addr;
+use core::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
 #[allow(async_fn_in_trait)]
 pub trait WifiStack {
     type Error: defmt::Format;
 
+    /// An open TCP connection, using the buffers that were handed to
+    /// [`WifiStack::connect`] or [`WifiStack::accept`].
+    type Connection<'a>: TcpConnection
+    where
+        Self: 'a;
+
+    /// A bound UDP socket, using the buffers that were handed to [`WifiStack::bind_udp`].
+    type Socket<'a>: UdpSocket
+    where
+        Self: 'a;
+
     async fn make_http_request<'a>(
         &self,
         url: &str,
         rx_buf: &'a mut [u8],
     ) -> Result<&'a mut [u8], Self::Error>;
 
+    /// Sends a single UDP datagram to `host:port` and waits for the first datagram that comes back.
+    async fn make_udp_request<'a>(
+        &self,
+        host: &str,
+        port: u16,
+        payload: &[u8],
+        rx_buf: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Self::Error>;
+
+    /// Opens a TCP connection to `remote`, giving up after `timeout`.
+    ///
+    /// The socket buffers are provided by the caller, so the number of
+    /// simultaneous connections is bounded by the memory set aside for them.
+    async fn connect<'a>(
+        &'a self,
+        remote: SocketAddr,
+        timeout: Duration,
+        rx_buf: &'a mut [u8],
+        tx_buf: &'a mut [u8],
+    ) -> Result<Self::Connection<'a>, TcpError>;
+
+    /// Waits for a remote host to connect to `port`.
+    async fn accept<'a>(
+        &'a self,
+        port: u16,
+        rx_buf: &'a mut [u8],
+        tx_buf: &'a mut [u8],
+    ) -> Result<Self::Connection<'a>, TcpError>;
+
+    /// Binds a UDP socket to `port` that sends to and receives from any host,
+    /// e.g. for the DHT. The buffers hold the queued datagrams.
+    async fn bind_udp<'a>(
+        &'a self,
+        port: u16,
+        rx_buf: &'a mut [u8],
+        tx_buf: &'a mut [u8],
+    ) -> Result<Self::Socket<'a>, UdpError>;
+
     fn get_ipv4(&self) -> Ipv4Addr;
+
+    /// Our IPv6 address, if the network has handed one out.
+    fn get_ipv6(&self) -> Option<Ipv6Addr>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TcpError {
+    /// The remote host refused or reset the connection.
+    ConnectionReset,
+    /// The remote host closed the connection.
+    Closed,
+    /// The operation didn't complete within the timeout.
+    TimedOut,
+    /// The remote host can't be reached from our network.
+    NoRoute,
+    /// No socket is available for a new connection.
+    NoSocket,
+}
+
+/// A TCP connection to a peer.
+#[allow(async_fn_in_trait)]
+pub trait TcpConnection {
+    /// Reads what is available, at most `buf.len()` bytes.
+    /// Returns `Ok(0)` once the remote host closed the connection.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError>;
+
+    /// Writes all of `buf`.
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), TcpError>;
+
+    /// Sets the timeout for every following read and write, `None` waits forever.
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+
+    fn remote_addr(&self) -> SocketAddr;
+
+    /// Closes the connection gracefully.
+    async fn close(self);
+
+    /// Fills all of `buf`, failing with [`TcpError::Closed`] if the connection ends before.
+    async fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), TcpError> {
+        while !buf.is_empty() {
+            match self.read(buf).await? {
+                0 => return Err(TcpError::Closed),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UdpError {
+    /// No socket is available or the port is taken.
+    NoSocket,
+    /// The remote host can't be reached from our network.
+    NoRoute,
+    /// No datagram arrived within the timeout.
+    TimedOut,
+    /// A datagram was larger than the buffer it was read into.
+    Truncated,
+    /// A datagram doesn't fit into the send buffer.
+    TooLarge,
+    /// The multicast group can't be joined, e.g. because the group table is full.
+    Multicast,
+}
+
+/// A bound UDP socket.
+#[allow(async_fn_in_trait)]
+pub trait UdpSocket {
+    /// Sends `buf` as a single datagram to `remote`.
+    async fn send_to(&mut self, buf: &[u8], remote: SocketAddr) -> Result<(), UdpError>;
+
+    /// Waits for the next datagram and returns its length and sender.
+    async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), UdpError>;
+
+    /// Sets the timeout for every following receive, `None` waits forever.
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+
+    /// Also receives the datagrams sent to the multicast `group`.
+    fn join_multicast(&mut self, group: IpAddr) -> Result<(), UdpError>;
 }
@@ -0,0 +1,144 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use core_logic::{
+    Info,
+    core::{
+        dht::{
+            Dht, DhtConfig, DhtEvent, MAX_DATAGRAM_LEN, QUERY_TIMEOUT_MS, routing::RoutingTable,
+        },
+        net::send_all,
+    },
+    rng::XorShift32,
+    wifi::{UdpSocket, WifiStack},
+};
+
+use crate::wifi_helper::{UdpSocketDuple, WifiStackDuple};
+
+mod wifi_helper;
+
+const INFO_HASH: [u8; 20] = [7; 20];
+const INFO: Info = Info {
+    piece_length: 16384,
+    name: "test",
+    pieces: &[],
+    length: 0,
+    private: false,
+};
+
+struct Node {
+    dht: Dht,
+    socket: UdpSocketDuple,
+    rng: XorShift32,
+}
+
+impl Node {
+    async fn bind(id: u8) -> Self {
+        let mut rng = XorShift32::new(id as u32);
+        let socket = WifiStackDuple.bind_udp(0, &mut [], &mut []).await.unwrap();
+        Self {
+            dht: Dht::new(
+                DhtConfig::FULL,
+                RoutingTable::new([id; 20]),
+                &mut rng,
+                now_ms(),
+            ),
+            socket,
+            rng,
+        }
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.socket.socket.local_addr().unwrap()
+    }
+
+    /// Handles the datagrams that arrive within a short time.
+    async fn pump(&mut self) {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        self.socket.set_timeout(Some(Duration::from_millis(20)));
+        while let Ok((len, from)) = self.socket.recv_from(&mut buf).await {
+            let out = self.dht.on_datagram(from, &buf[..len], now_ms()).unwrap();
+            send_all(&mut self.socket, out).await.unwrap();
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    // the DHT treats 0 as "never", so the clock starts at 1
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64 + 1
+}
+
+/// Polls all nodes until none of them has anything to do.
+async fn settle(nodes: &mut [&mut Node]) {
+    for _ in 0..5 {
+        for node in nodes.iter_mut() {
+            node.pump().await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_announce_and_find_peers() {
+    let mut tracker_node = Node::bind(1).await;
+    let mut seeder = Node::bind(2).await;
+    let mut leecher = Node::bind(3).await;
+
+    // the seeder and the leecher both know the first node only
+    for node in [&mut seeder, &mut leecher] {
+        let ping = node.dht.ping(tracker_node.addr(), now_ms()).unwrap();
+        send_all(&mut node.socket, vec![ping]).await.unwrap();
+    }
+    settle(&mut [&mut tracker_node, &mut seeder, &mut leecher]).await;
+    assert_eq!(tracker_node.dht.table().len(), 2);
+    assert_eq!(seeder.dht.table().len(), 1);
+
+    let queries = seeder
+        .dht
+        .get_peers(INFO_HASH, &INFO, Some(51413), now_ms());
+    send_all(&mut seeder.socket, queries).await.unwrap();
+    settle(&mut [&mut tracker_node, &mut seeder, &mut leecher]).await;
+    assert!(seeder.dht.take_events().iter().any(|event| matches!(
+        event,
+        DhtEvent::LookupDone {
+            target: INFO_HASH,
+            ..
+        }
+    )));
+
+    let queries = leecher.dht.get_peers(INFO_HASH, &INFO, None, now_ms());
+    send_all(&mut leecher.socket, queries).await.unwrap();
+    settle(&mut [&mut tracker_node, &mut seeder, &mut leecher]).await;
+    let peers: Vec<SocketAddr> = leecher
+        .dht
+        .take_events()
+        .into_iter()
+        .filter_map(|event| match event {
+            DhtEvent::Peers { info_hash, peers } if info_hash == INFO_HASH => Some(peers),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    assert!(peers.contains(&SocketAddr::new(seeder.addr().ip(), 51413)));
+}
+
+#[tokio::test]
+async fn test_poll_expires_unanswered_queries() {
+    let mut node = Node::bind(4).await;
+    // nobody listens there
+    let silent = Node::bind(5).await.addr();
+    let ping = node.dht.ping(silent, now_ms()).unwrap();
+    send_all(&mut node.socket, vec![ping]).await.unwrap();
+
+    let mut buf = [0u8; MAX_DATAGRAM_LEN];
+    let start = now_ms();
+    while now_ms() - start < QUERY_TIMEOUT_MS {
+        node.dht
+            .poll(&mut node.socket, &mut node.rng, &mut buf, now_ms)
+            .await
+            .unwrap();
+    }
+    assert!(node.dht.table().is_empty());
+}
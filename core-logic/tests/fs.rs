@@ -1,4 +1,28 @@
-use embedded_sdmmc::{Directory, Error, ShortFileName};
+use core_logic::{
+    Info,
+    core::{
+        bitfield::Bitfield,
+        dht::{
+            DhtConfig,
+            krpc::NodeInfo,
+            load_routing_table,
+            routing::{K, RoutingTable},
+            save_routing_table,
+        },
+        peer::{
+            ban::{BanList, BanPolicy, load_ban_list, save_ban_list},
+            message::{BlockInfo, DEFAULT_MAX_MESSAGE_LEN, Message},
+            upload::{UploadError, UploadSource, read_piece_message},
+        },
+        storage::{Storage, TorrentFile},
+    },
+    fs::{
+        MAX_HANDLES, MAX_OPEN_FILES,
+        short_names::{NameMap, load_name_index, save_name_index},
+    },
+    rng::{Rng, XorShift32},
+};
+use embedded_sdmmc::{Directory, Error, Mode, ShortFileName};
 
 use crate::fs_helper::{
     TORRENT_STRING,
@@ -68,3 +92,418 @@ pub fn list_dir(
     }
     Ok(())
 }
+
+#[test]
+fn test_read_at() {
+    let mut fs_duple = init_fs_duple();
+    let mut buf = [0u8; 32];
+
+    let read = fs_duple.read_at("test.txt", 6, &mut buf).unwrap();
+    assert_eq!(&buf[..read], b"from FAT32!");
+    assert_eq!(fs_duple.read_at("test.txt", 17, &mut buf).unwrap(), 0);
+    assert!(fs_duple.read_at("missing.txt", 0, &mut buf).is_err());
+}
+
+#[test]
+fn test_read_file() {
+    let mut fs_duple = init_fs_duple();
+    let data: Vec<u8> = (0..1300u32).map(|n| n as u8).collect();
+
+    fs_duple.write_file("whole.bin", &data).unwrap();
+    assert_eq!(fs_duple.read_file("whole.bin").unwrap(), data);
+    assert!(fs_duple.read_file("missing.txt").is_err());
+}
+
+#[test]
+fn test_read_piece_message() {
+    let mut fs_duple = init_fs_duple();
+    let hashes = [[0; 20]; 2];
+    // "Hello from FAT32!" in pieces of 16 bytes
+    let info = Info {
+        piece_length: 16,
+        name: "test.txt",
+        pieces: &hashes,
+        length: 17,
+        private: false,
+    };
+    let mut storage = Storage::single_file(&info);
+    let mut source = UploadSource {
+        fs: &mut fs_duple,
+        storage: &mut storage,
+        info: &info,
+    };
+    let mut buf = [0u8; 64];
+
+    let block = BlockInfo {
+        index: 0,
+        begin: 6,
+        length: 4,
+    };
+    let message = read_piece_message(&mut source, &Bitfield::full(2), &block, &mut buf).unwrap();
+    assert_eq!(
+        Message::decode(message, DEFAULT_MAX_MESSAGE_LEN).unwrap(),
+        Some((
+            Message::Piece {
+                index: 0,
+                begin: 6,
+                data: b"from"
+            },
+            message.len()
+        ))
+    );
+
+    let last = BlockInfo {
+        index: 1,
+        begin: 0,
+        length: 2,
+    };
+    assert_eq!(
+        read_piece_message(&mut source, &Bitfield::full(2), &last, &mut buf),
+        Err(UploadError::NotAvailable)
+    );
+    assert_eq!(
+        read_piece_message(&mut source, &Bitfield::new(2), &block, &mut buf),
+        Err(UploadError::NotAvailable)
+    );
+}
+
+#[test]
+fn test_routing_table_persistence() {
+    let mut fs_duple = init_fs_duple();
+    let mut table = RoutingTable::new([3; 20]);
+    for n in 1..=20u8 {
+        table.heard_from(
+            NodeInfo {
+                id: [n; 20],
+                addr: std::net::SocketAddr::new([10, 0, 0, n].into(), 6881),
+            },
+            1,
+        );
+    }
+
+    save_routing_table(&mut fs_duple, &table).unwrap();
+    let restored = load_routing_table(&mut fs_duple, &DhtConfig::FULL, 2).unwrap();
+    assert_eq!(restored.own_id(), &[3; 20]);
+    assert_eq!(restored.len(), table.len());
+    assert_eq!(restored.closest(&[0; 20], K), table.closest(&[0; 20], K));
+
+    // saving again replaces the old table
+    save_routing_table(&mut fs_duple, &RoutingTable::new([4; 20])).unwrap();
+    let restored = load_routing_table(&mut fs_duple, &DhtConfig::FULL, 3).unwrap();
+    assert_eq!(restored.own_id(), &[4; 20]);
+    assert!(restored.is_empty());
+}
+
+#[test]
+fn test_ban_list_persistence() {
+    let mut fs_duple = init_fs_duple();
+    let banned = [
+        std::net::IpAddr::from([10, 0, 0, 2]),
+        std::net::IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]),
+    ];
+    let mut bans = BanList::new(BanPolicy::default());
+    for ip in banned {
+        bans.ban(ip);
+    }
+
+    save_ban_list(&mut fs_duple, &bans).unwrap();
+    let restored = load_ban_list(&mut fs_duple, BanPolicy::default()).unwrap();
+    assert_eq!(restored.banned(), banned);
+
+    // the policy of the loading side applies
+    let policy = BanPolicy {
+        max_banned: 1,
+        ..BanPolicy::default()
+    };
+    let restored = load_ban_list(&mut fs_duple, policy).unwrap();
+    assert_eq!(restored.banned(), &banned[1..]);
+
+    // saving again replaces the old list
+    save_ban_list(&mut fs_duple, &BanList::new(BanPolicy::default())).unwrap();
+    let restored = load_ban_list(&mut fs_duple, BanPolicy::default()).unwrap();
+    assert!(restored.banned().is_empty());
+}
+
+#[test]
+fn test_write_at() {
+    let mut fs_duple = init_fs_duple();
+    let mut buf = [0xffu8; 700];
+
+    fs_duple.write_at("gap.bin", 600, b"end").unwrap();
+    fs_duple.write_at("gap.bin", 10, b"start").unwrap();
+    assert_eq!(fs_duple.read_at("gap.bin", 0, &mut buf).unwrap(), 603);
+    assert!(buf[..10].iter().all(|&byte| byte == 0));
+    assert_eq!(&buf[10..15], b"start");
+    assert!(buf[15..600].iter().all(|&byte| byte == 0));
+    assert_eq!(&buf[600..603], b"end");
+}
+
+#[test]
+fn test_storage_writes_blocks_in_any_order() {
+    let mut fs_duple = init_fs_duple();
+    let mut storage = Storage::new(
+        512,
+        vec![
+            TorrentFile {
+                name: "part1.bin",
+                length: 1000,
+            },
+            TorrentFile {
+                name: "part2.bin",
+                length: 1500,
+            },
+        ],
+    );
+    storage.allocate(&mut fs_duple).unwrap();
+    let mut file = vec![0u8; 1600];
+    assert_eq!(fs_duple.read_at("part2.bin", 0, &mut file).unwrap(), 1500);
+
+    let mut rng = XorShift32::new(1);
+    let mut data = vec![0u8; storage.length() as usize];
+    rng.fill_bytes(&mut data);
+
+    let mut blocks: Vec<BlockInfo> = (0..5)
+        .flat_map(|index| {
+            (0..512).step_by(128).map(move |begin| BlockInfo {
+                index,
+                begin,
+                length: 128,
+            })
+        })
+        .filter(|block| (block.index * 512 + block.begin) < 2500)
+        .map(|block| BlockInfo {
+            length: block.length.min(2500 - block.index * 512 - block.begin),
+            ..block
+        })
+        .collect();
+    // shuffle, so that blocks arrive out of order and files are written back to front
+    for i in (1..blocks.len()).rev() {
+        blocks.swap(i, rng.next_below(i as u32 + 1) as usize);
+    }
+    for block in &blocks {
+        let start = (block.index * 512 + block.begin) as usize;
+        storage
+            .write_block(
+                &mut fs_duple,
+                block,
+                &data[start..start + block.length as usize],
+            )
+            .unwrap();
+    }
+
+    let mut stored = vec![0u8; data.len()];
+    storage.read_at(&mut fs_duple, 0, &mut stored).unwrap();
+    assert!(stored == data);
+    assert_eq!(fs_duple.read_at("part1.bin", 0, &mut file).unwrap(), 1000);
+    assert!(file[..1000] == data[..1000]);
+    assert_eq!(fs_duple.read_at("part2.bin", 0, &mut file).unwrap(), 1500);
+    assert!(file[..1500] == data[1000..]);
+}
+
+#[test]
+fn test_storage_with_more_files_than_handles() {
+    let mut fs_duple = init_fs_duple();
+    let names: Vec<String> = (0..MAX_HANDLES + 2)
+        .map(|n| format!("file{n}.bin"))
+        .collect();
+    let mut storage = Storage::new(
+        16,
+        names
+            .iter()
+            .map(|name| TorrentFile { name, length: 10 })
+            .collect(),
+    );
+    // another user of the filesystem keeps a handle
+    let other = fs_duple
+        .open_file("other.bin", Mode::ReadWriteCreateOrAppend)
+        .unwrap();
+
+    storage.allocate(&mut fs_duple).unwrap();
+    let data: Vec<u8> = (0..storage.length() as u8).collect();
+    storage.write_at(&mut fs_duple, 0, &data).unwrap();
+    let mut stored = vec![0u8; data.len()];
+    storage.read_at(&mut fs_duple, 0, &mut stored).unwrap();
+    assert!(stored == data);
+
+    storage.close(&mut fs_duple).unwrap();
+    assert_eq!(fs_duple.file_length(other).unwrap(), 0);
+    fs_duple.close_file(other).unwrap();
+}
+
+#[test]
+fn test_storage_rejects_blocks_outside_the_torrent() {
+    let mut fs_duple = init_fs_duple();
+    let mut storage = Storage::new(
+        16,
+        vec![TorrentFile {
+            name: "small.bin",
+            length: 20,
+        }],
+    );
+    let beyond = BlockInfo {
+        index: 1,
+        begin: 0,
+        length: 8,
+    };
+    let outside_piece = BlockInfo {
+        index: 0,
+        begin: 12,
+        length: 8,
+    };
+
+    assert!(matches!(
+        storage.write_block(&mut fs_duple, &beyond, &[1; 8]),
+        Err(Error::InvalidOffset)
+    ));
+    assert!(matches!(
+        storage.write_block(&mut fs_duple, &outside_piece, &[1; 8]),
+        Err(Error::InvalidOffset)
+    ));
+    storage.write_at(&mut fs_duple, 16, &[1; 4]).unwrap();
+}
+
+#[test]
+fn test_more_file_handles_than_open_files() {
+    let mut fs_duple = init_fs_duple();
+    let names = ["h0.bin", "h1.bin", "h2.bin", "h3.bin", "h4.bin"];
+    assert!(names.len() > MAX_OPEN_FILES);
+    let handles: Vec<_> = names
+        .iter()
+        .map(|name| {
+            fs_duple
+                .open_file(*name, Mode::ReadWriteCreateOrTruncate)
+                .unwrap()
+        })
+        .collect();
+
+    // interleaved writes reopen the files the table closed to make room
+    for round in 0..3u8 {
+        for (n, &handle) in handles.iter().enumerate() {
+            fs_duple
+                .write_file_at(handle, round as u32 * 4, &[n as u8 * 10 + round; 4])
+                .unwrap();
+        }
+    }
+    for (n, &handle) in handles.iter().enumerate() {
+        let mut buf = [0u8; 16];
+        assert_eq!(fs_duple.file_length(handle).unwrap(), 12);
+        assert_eq!(fs_duple.read_file_at(handle, 0, &mut buf).unwrap(), 12);
+        let n = n as u8 * 10;
+        assert_eq!(buf[..12], [[n; 4], [n + 1; 4], [n + 2; 4]].concat());
+    }
+
+    // a file with a handle can still be used by name
+    let mut buf = [0u8; 4];
+    assert_eq!(fs_duple.read_at("h4.bin", 8, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [42; 4]);
+    fs_duple.flush_file(handles[4]).unwrap();
+
+    for handle in handles {
+        fs_duple.close_file(handle).unwrap();
+        assert!(matches!(
+            fs_duple.read_file_at(handle, 0, &mut buf),
+            Err(Error::BadHandle)
+        ));
+    }
+}
+
+#[test]
+fn test_file_handle_limit() {
+    let mut fs_duple = init_fs_duple();
+    let names: Vec<String> = (0..=MAX_HANDLES).map(|n| format!("lim{n}.bin")).collect();
+    let mut handles: Vec<_> = names[..MAX_HANDLES]
+        .iter()
+        .map(|name| {
+            fs_duple
+                .open_file(name.as_str(), Mode::ReadWriteCreateOrTruncate)
+                .unwrap()
+        })
+        .collect();
+
+    assert!(matches!(
+        fs_duple.open_file(names[MAX_HANDLES].as_str(), Mode::ReadWriteCreateOrTruncate),
+        Err(Error::TooManyOpenFiles)
+    ));
+    assert!(matches!(
+        fs_duple.open_file(names[0].as_str(), Mode::ReadOnly),
+        Err(Error::FileAlreadyOpen)
+    ));
+
+    // a closed slot is reused, the old handle doesn't reach the new file
+    let closed = handles.remove(0);
+    fs_duple.close_file(closed).unwrap();
+    let reused = fs_duple
+        .open_file(names[MAX_HANDLES].as_str(), Mode::ReadWriteCreateOrTruncate)
+        .unwrap();
+    assert_ne!(reused, closed);
+    assert!(matches!(
+        fs_duple.file_length(closed),
+        Err(Error::BadHandle)
+    ));
+    assert_eq!(fs_duple.file_length(reused).unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_file_handle_survives_directory_change() {
+    let mut fs_duple = init_fs_duple();
+    let handle = fs_duple
+        .open_file("keep.bin", Mode::ReadWriteCreateOrTruncate)
+        .unwrap();
+    fs_duple.write_file_at(handle, 0, b"kept").unwrap();
+
+    // moves into the torrents directory and takes it, closing the old root directory
+    assert!(fs_duple.get_torrent_from_file().await.is_some());
+    fs_duple.go_to_root_dir();
+    let others: Vec<_> = ["o0.bin", "o1.bin", "o2.bin"]
+        .iter()
+        .map(|name| {
+            fs_duple
+                .open_file(*name, Mode::ReadWriteCreateOrTruncate)
+                .unwrap()
+        })
+        .collect();
+
+    let mut buf = [0u8; 4];
+    assert_eq!(fs_duple.read_file_at(handle, 0, &mut buf).unwrap(), 4);
+    assert_eq!(&buf, b"kept");
+    assert_eq!(fs_duple.read_at("keep.bin", 0, &mut buf).unwrap(), 4);
+    for handle in others {
+        fs_duple.close_file(handle).unwrap();
+    }
+    fs_duple.close_file(handle).unwrap();
+}
+
+#[test]
+fn test_long_torrent_names_on_the_card() {
+    let mut fs_duple = init_fs_duple();
+    let paths = [
+        &["ubuntu-24.04-desktop-amd64.iso"][..],
+        &["extras", "release notes.txt"],
+    ];
+    let names = NameMap::new(paths);
+    let mut storage = Storage::new(
+        16,
+        vec![
+            TorrentFile {
+                name: names.short_name(0).unwrap(),
+                length: 20,
+            },
+            TorrentFile {
+                name: names.short_name(1).unwrap(),
+                length: 12,
+            },
+        ],
+    );
+
+    let data: Vec<u8> = (0..32).collect();
+    storage.write_at(&mut fs_duple, 0, &data).unwrap();
+    save_name_index(&mut fs_duple, &names).unwrap();
+
+    let restored = load_name_index(&mut fs_duple).unwrap();
+    assert_eq!(restored, names);
+    let mut buf = [0u8; 12];
+    let short = restored.short_name(1).unwrap();
+    assert_eq!(restored.original(short), Some("extras/release notes.txt"));
+    assert_eq!(fs_duple.read_at(short, 0, &mut buf).unwrap(), 12);
+    assert_eq!(buf[..], data[20..]);
+}
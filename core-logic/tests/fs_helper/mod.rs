@@ -12,7 +12,7 @@ use crate::fs_helper::{
     volume_mgr::VolumeMgrDuple,
 };
 
-pub const TORRENT_STRING: &'static [u8] = include_bytes!("../sample.torrent");
+pub const TORRENT_STRING: &[u8] = include_bytes!("../sample.torrent");
 
 pub mod blockdevice;
 pub mod volume_mgr;
@@ -20,7 +20,7 @@ impl VolumeMgr for VolumeMgrDuple {
 
     fn get_vol0(&self) -> embedded_sdmmc::RawVolume {
         match self.0.open_volume(embedded_sdmmc::VolumeIdx(0)) {
-            Ok(volume0) => return volume0.to_raw_volume(),
+            Ok(volume0) => volume0.to_raw_volume(),
             Err(e) => {
                 panic!("failed to open volume 0 with error {:?}", e);
             }
@@ -29,7 +29,7 @@ impl VolumeMgr for VolumeMgrDuple {
 
     fn get_root_dir(&self, volume: embedded_sdmmc::RawVolume) -> embedded_sdmmc::RawDirectory {
         match volume.to_volume(&self.0).open_root_dir() {
-            Ok(root_dir) => return root_dir.to_raw_directory(),
+            Ok(root_dir) => root_dir.to_raw_directory(),
             Err(e) => {
                 panic!("failed to open root directory with error {:?}", e);
             }
@@ -28,7 +28,7 @@ async fn integration_test() {
         .await
         .unwrap();
 
-    assert!(response.len() > 0);
+    assert!(!response.is_empty());
 
     // Further processing of the response can be done here
 }
@@ -0,0 +1,59 @@
+use std::time::Instant;
+
+use core_logic::{
+    Info,
+    core::lsd::{Lsd, LsdPeer, MAX_DATAGRAM_LEN},
+    rng::XorShift32,
+    wifi::{UdpSocket, WifiStack},
+};
+
+use crate::wifi_helper::WifiStackDuple;
+
+mod wifi_helper;
+
+const INFO_HASH: [u8; 20] = [7; 20];
+
+const INFO: Info = Info {
+    piece_length: 16384,
+    name: "test",
+    pieces: &[],
+    length: 0,
+    private: false,
+};
+
+/// There is no multicast on the loopback interface of the test host, so the
+/// announce is forwarded to the other socket by hand.
+#[tokio::test]
+async fn test_peers_on_the_lan_find_each_other() {
+    let start = Instant::now();
+    let now_ms = || start.elapsed().as_millis() as u64;
+    let mut sender = WifiStackDuple.bind_udp(0, &mut [], &mut []).await.unwrap();
+    let mut receiver = WifiStackDuple.bind_udp(0, &mut [], &mut []).await.unwrap();
+    let sender_addr = sender.socket.local_addr().unwrap();
+    let receiver_addr = receiver.socket.local_addr().unwrap();
+
+    let mut announcing = Lsd::new(6881, &mut XorShift32::new(1));
+    announcing.add_torrent(INFO_HASH, &INFO);
+    let mut listening = Lsd::new(6882, &mut XorShift32::new(2));
+    listening.add_torrent(INFO_HASH, &INFO);
+
+    for datagram in announcing.poll_announce(now_ms()) {
+        sender
+            .send_to(&datagram.payload, receiver_addr)
+            .await
+            .unwrap();
+    }
+    let mut buf = [0; MAX_DATAGRAM_LEN];
+    let peers = listening
+        .poll(&mut receiver, &mut buf, now_ms)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        peers,
+        [LsdPeer {
+            info_hash: INFO_HASH,
+            addr: std::net::SocketAddr::new(sender_addr.ip(), 6881),
+        }]
+    );
+}
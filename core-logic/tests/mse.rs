@@ -0,0 +1,334 @@
+use std::time::Duration;
+
+use core_logic::{
+    core::peer::{
+        OutgoingPeer, PeerError, connect_peer, exchange_handshakes,
+        handshake::{HANDSHAKE_LEN, Handshake, Reserved},
+        listener::{ConnectionSlots, IncomingPeer, Listener},
+        mse::{EncryptionPolicy, MseError, MseStream},
+    },
+    rng::XorShift32,
+    wifi::{TcpConnection, WifiStack},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::wifi_helper::{TcpConnectionDuple, WifiStackDuple};
+
+mod wifi_helper;
+
+const INFO_HASH: [u8; 20] = [7; 20];
+
+type Incoming<'s> = Result<IncomingPeer<'s, MseStream<TcpConnectionDuple>>, PeerError>;
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Connects to the listener on `port` once it is up and runs the key exchange and
+/// the handshake for `info_hash` with `policy`.
+async fn outgoing_peer(
+    port: u16,
+    policy: EncryptionPolicy,
+    info_hash: [u8; 20],
+) -> Result<MseStream<TcpConnectionDuple>, PeerError> {
+    let remote = ([127, 0, 0, 1], port).into();
+    let connection = loop {
+        match WifiStackDuple
+            .connect(remote, Duration::from_secs(1), &mut [], &mut [])
+            .await
+        {
+            Ok(connection) => break connection,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let mut stream = MseStream::new(connection);
+    stream.set_timeout(Some(Duration::from_secs(2)));
+    stream
+        .initiate(policy, &info_hash, &mut XorShift32::new(2))
+        .await?;
+
+    let ours = Handshake::new(Reserved::default(), info_hash, *b"-TR3000-abcdefghijkl");
+    exchange_handshakes(&mut stream, &ours).await?;
+    Ok(stream)
+}
+
+/// Lets a peer with the `outgoing` policy connect to a listener with the `incoming` policy.
+async fn connect(
+    incoming: EncryptionPolicy,
+    outgoing: EncryptionPolicy,
+    info_hash: [u8; 20],
+    check: impl AsyncFnOnce(Incoming<'_>, Result<MseStream<TcpConnectionDuple>, PeerError>),
+) {
+    let port = free_port();
+    let torrents = [[1; 20], INFO_HASH];
+    let listener = Listener::new(
+        port,
+        Reserved::default(),
+        *b"-MT0001-abcdefghijkl",
+        &torrents,
+    )
+    .with_encryption(incoming);
+    let slots = ConnectionSlots::new(1);
+    let (mut rx_buf, mut tx_buf) = ([0u8; 1024], [0u8; 1024]);
+    let mut rng = XorShift32::new(1);
+
+    let (accepted, connected) = tokio::join!(
+        listener.accept(&WifiStackDuple, &slots, &mut rng, &mut rx_buf, &mut tx_buf),
+        outgoing_peer(port, outgoing, info_hash),
+    );
+    check(accepted, connected).await;
+}
+
+#[tokio::test]
+async fn test_encrypted_connection() {
+    for (incoming, outgoing) in [
+        (
+            EncryptionPolicy::PreferEncrypted,
+            EncryptionPolicy::PreferEncrypted,
+        ),
+        (
+            EncryptionPolicy::PreferEncrypted,
+            EncryptionPolicy::RequireEncrypted,
+        ),
+        (
+            EncryptionPolicy::RequireEncrypted,
+            EncryptionPolicy::PreferEncrypted,
+        ),
+    ] {
+        connect(
+            incoming,
+            outgoing,
+            INFO_HASH,
+            async |accepted, connected| {
+                let mut accepted = accepted.unwrap();
+                let mut connected = connected.unwrap();
+                assert!(accepted.connection.is_encrypted() && connected.is_encrypted());
+                assert_eq!(accepted.torrent, 1);
+                assert_eq!(&accepted.handshake.peer_id[..8], b"-TR3000-");
+
+                let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+                connected.write_all(&data).await.unwrap();
+                let mut received = vec![0; data.len()];
+                accepted.connection.read_exact(&mut received).await.unwrap();
+                assert!(received == data);
+
+                accepted.connection.write_all(b"pong").await.unwrap();
+                let mut pong = [0; 4];
+                connected.read_exact(&mut pong).await.unwrap();
+                assert_eq!(&pong, b"pong");
+            },
+        )
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn test_plaintext_connection() {
+    for (incoming, outgoing) in [
+        (
+            EncryptionPolicy::PreferEncrypted,
+            EncryptionPolicy::PlaintextOnly,
+        ),
+        (
+            EncryptionPolicy::PlaintextOnly,
+            EncryptionPolicy::PlaintextOnly,
+        ),
+    ] {
+        connect(
+            incoming,
+            outgoing,
+            INFO_HASH,
+            async |accepted, connected| {
+                let accepted = accepted.unwrap();
+                assert!(!accepted.connection.is_encrypted());
+                assert!(!connected.unwrap().is_encrypted());
+            },
+        )
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn test_plaintext_refused() {
+    connect(
+        EncryptionPolicy::RequireEncrypted,
+        EncryptionPolicy::PlaintextOnly,
+        INFO_HASH,
+        async |accepted, connected| {
+            assert!(matches!(
+                accepted,
+                Err(PeerError::Mse(MseError::PlaintextRefused))
+            ));
+            assert!(connected.is_err());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_encryption_refused() {
+    connect(
+        EncryptionPolicy::PlaintextOnly,
+        EncryptionPolicy::RequireEncrypted,
+        INFO_HASH,
+        async |accepted, connected| {
+            assert!(matches!(
+                accepted,
+                Err(PeerError::Mse(MseError::EncryptionRefused))
+            ));
+            assert!(connected.is_err());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_encrypted_connection_for_unknown_torrent() {
+    connect(
+        EncryptionPolicy::PreferEncrypted,
+        EncryptionPolicy::PreferEncrypted,
+        [9; 20],
+        async |accepted, connected| {
+            assert!(matches!(accepted, Err(PeerError::UnknownTorrent)));
+            assert!(connected.is_err());
+        },
+    )
+    .await;
+}
+
+/// A peer that doesn't know MSE: it drops connections that don't start with a plain
+/// handshake and answers the first one that does. Returns how many connections it took.
+async fn plain_peer(listener: TcpListener) -> usize {
+    let mut connections = 0;
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        connections += 1;
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        if stream.read_exact(&mut buf).await.is_err() {
+            continue;
+        }
+        let Ok(theirs) = Handshake::decode(&buf) else {
+            continue;
+        };
+        let ours = Handshake::new(
+            Reserved::default(),
+            theirs.info_hash,
+            *b"-qB4630-abcdefghijkl",
+        );
+        stream
+            .write_all(ours.encode(&mut buf).unwrap())
+            .await
+            .unwrap();
+        return connections;
+    }
+}
+
+#[tokio::test]
+async fn test_connect_peer_encrypted() {
+    let port = free_port();
+    let torrents = [INFO_HASH];
+    let listener = Listener::new(
+        port,
+        Reserved::default(),
+        *b"-MT0001-abcdefghijkl",
+        &torrents,
+    );
+    let slots = ConnectionSlots::new(1);
+    let (mut rx_a, mut tx_a) = ([0u8; 1024], [0u8; 1024]);
+    let (mut rx_b, mut tx_b) = ([0u8; 1024], [0u8; 1024]);
+    let (mut rng_a, mut rng_b) = (XorShift32::new(1), XorShift32::new(2));
+
+    let ours = Handshake::new(Reserved::default(), INFO_HASH, *b"-TR3000-abcdefghijkl");
+    let peer = OutgoingPeer {
+        remote: ([127, 0, 0, 1], port).into(),
+        policy: EncryptionPolicy::PreferEncrypted,
+        handshake: &ours,
+    };
+    let (accepted, connected) = tokio::join!(
+        listener.accept(&WifiStackDuple, &slots, &mut rng_a, &mut rx_a, &mut tx_a),
+        async {
+            // until the listener is up
+            loop {
+                match connect_peer(
+                    &WifiStackDuple,
+                    &peer,
+                    &mut rng_b,
+                    &mut rx_b,
+                    &mut tx_b,
+                    async |connection, theirs| (connection.is_encrypted(), theirs.peer_id),
+                )
+                .await
+                {
+                    Err(PeerError::Tcp(_)) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    connected => break connected,
+                }
+            }
+        },
+    );
+
+    assert!(accepted.unwrap().connection.is_encrypted());
+    assert_eq!(connected.unwrap(), (true, *b"-MT0001-abcdefghijkl"));
+}
+
+#[tokio::test]
+async fn test_connect_peer_falls_back_to_plaintext() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    let fake = tokio::spawn(plain_peer(listener));
+    let (mut rx_buf, mut tx_buf) = ([0u8; 1024], [0u8; 1024]);
+
+    let ours = Handshake::new(Reserved::default(), INFO_HASH, *b"-TR3000-abcdefghijkl");
+    let peer = OutgoingPeer {
+        remote,
+        policy: EncryptionPolicy::PreferEncrypted,
+        handshake: &ours,
+    };
+    let connected = connect_peer(
+        &WifiStackDuple,
+        &peer,
+        &mut XorShift32::new(2),
+        &mut rx_buf,
+        &mut tx_buf,
+        async |connection, theirs| (connection.is_encrypted(), theirs.peer_id),
+    )
+    .await;
+
+    assert_eq!(connected.unwrap(), (false, *b"-qB4630-abcdefghijkl"));
+    // the key exchange and the plain retry
+    assert_eq!(fake.await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_connect_peer_without_fallback() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    let fake = tokio::spawn(plain_peer(listener));
+    let (mut rx_buf, mut tx_buf) = ([0u8; 1024], [0u8; 1024]);
+
+    let ours = Handshake::new(Reserved::default(), INFO_HASH, *b"-TR3000-abcdefghijkl");
+    let peer = OutgoingPeer {
+        remote,
+        policy: EncryptionPolicy::RequireEncrypted,
+        handshake: &ours,
+    };
+    let connected = connect_peer(
+        &WifiStackDuple,
+        &peer,
+        &mut XorShift32::new(2),
+        &mut rx_buf,
+        &mut tx_buf,
+        async |_, _| (),
+    )
+    .await;
+
+    assert!(connected.is_err());
+    fake.abort();
+}
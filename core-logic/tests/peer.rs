@@ -0,0 +1,256 @@
+use std::time::Duration;
+
+use core_logic::{
+    core::peer::{
+        PeerError, exchange_handshakes,
+        handshake::{HANDSHAKE_LEN, Handshake, HandshakeError, Reserved},
+        listener::{ConnectionSlots, IncomingPeer, Listener},
+        mse::MseStream,
+    },
+    rng::XorShift32,
+    wifi::{TcpConnection, WifiStack},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+mod wifi_helper;
+
+use wifi_helper::TcpConnectionDuple;
+
+const INFO_HASH: [u8; 20] = [7; 20];
+
+/// Accepts one connection and answers the handshake with the given info hash.
+async fn fake_peer(listener: TcpListener, info_hash: [u8; 20]) -> Handshake {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    stream.read_exact(&mut buf).await.unwrap();
+    let theirs = Handshake::decode(&buf).unwrap();
+
+    let ours = Handshake::new(Reserved::default(), info_hash, *b"-qB4630-abcdefghijkl");
+    stream
+        .write_all(ours.encode(&mut buf).unwrap())
+        .await
+        .unwrap();
+    theirs
+}
+
+#[tokio::test]
+async fn test_outgoing_handshake() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    let peer = tokio::spawn(fake_peer(listener, INFO_HASH));
+
+    let wifi = wifi_helper::WifiStackDuple;
+    let (mut rx_buf, mut tx_buf) = ([0u8; 1024], [0u8; 1024]);
+    let mut connection = wifi
+        .connect(remote, Duration::from_secs(1), &mut rx_buf, &mut tx_buf)
+        .await
+        .unwrap();
+    assert_eq!(connection.remote_addr(), remote);
+    connection.set_timeout(Some(Duration::from_secs(1)));
+
+    let ours = Handshake::new(Reserved::default(), INFO_HASH, *b"-MT0001-abcdefghijkl");
+    let theirs = exchange_handshakes(&mut connection, &ours).await.unwrap();
+    connection.close().await;
+
+    assert_eq!(&theirs.peer_id[..8], b"-qB4630-");
+    assert_eq!(peer.await.unwrap(), ours);
+}
+
+#[tokio::test]
+async fn test_outgoing_handshake_wrong_torrent() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    let peer = tokio::spawn(fake_peer(listener, [8; 20]));
+
+    let wifi = wifi_helper::WifiStackDuple;
+    let (mut rx_buf, mut tx_buf) = ([0u8; 1024], [0u8; 1024]);
+    let mut connection = wifi
+        .connect(remote, Duration::from_secs(1), &mut rx_buf, &mut tx_buf)
+        .await
+        .unwrap();
+
+    let ours = Handshake::new(Reserved::default(), INFO_HASH, *b"-MT0001-abcdefghijkl");
+    assert_eq!(
+        exchange_handshakes(&mut connection, &ours).await,
+        Err(PeerError::Handshake(HandshakeError::InfoHashMismatch))
+    );
+    peer.await.unwrap();
+}
+
+/// Connects to the listener on `port` once it is up and sends a handshake for `info_hash`.
+async fn fake_incoming_peer(port: u16, info_hash: [u8; 20]) -> Option<Handshake> {
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let ours = Handshake::new(Reserved::default(), info_hash, *b"-TR3000-abcdefghijkl");
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    stream
+        .write_all(ours.encode(&mut buf).unwrap())
+        .await
+        .unwrap();
+
+    match stream.read_exact(&mut buf).await {
+        Ok(_) => Some(Handshake::decode(&buf).unwrap()),
+        Err(_) => None,
+    }
+}
+
+async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn test_incoming_peer_is_routed_by_info_hash() {
+    let port = free_port().await;
+    let torrents = [[1; 20], INFO_HASH];
+    let listener = Listener::new(
+        port,
+        Reserved::default(),
+        *b"-MT0001-abcdefghijkl",
+        &torrents,
+    );
+    let slots = ConnectionSlots::new(1);
+    let peer = tokio::spawn(fake_incoming_peer(port, INFO_HASH));
+
+    let wifi = wifi_helper::WifiStackDuple;
+    let (mut rx_buf, mut tx_buf) = ([0u8; 1024], [0u8; 1024]);
+    let incoming = listener
+        .accept(
+            &wifi,
+            &slots,
+            &mut XorShift32::new(1),
+            &mut rx_buf,
+            &mut tx_buf,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(incoming.torrent, 1);
+    assert_eq!(&incoming.handshake.peer_id[..8], b"-TR3000-");
+    assert_eq!(slots.available(), 0);
+
+    let answer = peer.await.unwrap().unwrap();
+    assert_eq!(answer.info_hash, INFO_HASH);
+    assert_eq!(&answer.peer_id[..8], b"-MT0001-");
+
+    drop(incoming);
+    assert_eq!(slots.available(), 1);
+}
+
+#[tokio::test]
+async fn test_incoming_peer_for_unknown_torrent() {
+    let port = free_port().await;
+    let torrents = [INFO_HASH];
+    let listener = Listener::new(
+        port,
+        Reserved::default(),
+        *b"-MT0001-abcdefghijkl",
+        &torrents,
+    );
+    let slots = ConnectionSlots::new(1);
+    let peer = tokio::spawn(fake_incoming_peer(port, [9; 20]));
+
+    let wifi = wifi_helper::WifiStackDuple;
+    let (mut rx_buf, mut tx_buf) = ([0u8; 1024], [0u8; 1024]);
+    let result = listener
+        .accept(
+            &wifi,
+            &slots,
+            &mut XorShift32::new(1),
+            &mut rx_buf,
+            &mut tx_buf,
+        )
+        .await;
+
+    assert!(matches!(result, Err(PeerError::UnknownTorrent)));
+    assert_eq!(peer.await.unwrap(), None);
+    assert_eq!(slots.available(), 1);
+}
+
+#[tokio::test]
+async fn test_listener_keeps_accepting_after_bad_peer() {
+    let port = free_port().await;
+    let torrents = [INFO_HASH];
+    let listener = Listener::new(
+        port,
+        Reserved::default(),
+        *b"-MT0001-abcdefghijkl",
+        &torrents,
+    );
+    let slots = ConnectionSlots::new(1);
+    let peers = tokio::spawn(async move {
+        assert_eq!(fake_incoming_peer(port, [9; 20]).await, None);
+        fake_incoming_peer(port, INFO_HASH).await.unwrap()
+    });
+
+    let wifi = wifi_helper::WifiStackDuple;
+    let (mut rx_buf, mut tx_buf) = ([0u8; 1024], [0u8; 1024]);
+    let mut handled = Vec::new();
+    let mut rng = XorShift32::new(1);
+    let run = listener.run_slot(
+        &wifi,
+        &slots,
+        &mut rng,
+        &mut rx_buf,
+        &mut tx_buf,
+        async |peer| handled.push(peer.handshake.peer_id),
+    );
+    let _ = tokio::time::timeout(Duration::from_millis(500), run).await;
+
+    peers.await.unwrap();
+    assert_eq!(handled, [*b"-TR3000-abcdefghijkl"]);
+}
+
+#[tokio::test]
+async fn test_two_incoming_peers_at_once() {
+    let port = free_port().await;
+    let torrents = [INFO_HASH];
+    let listener = Listener::new(
+        port,
+        Reserved::default(),
+        *b"-MT0001-abcdefghijkl",
+        &torrents,
+    );
+    let slots = ConnectionSlots::new(2);
+    let peers = tokio::spawn(async move {
+        tokio::join!(
+            fake_incoming_peer(port, INFO_HASH),
+            fake_incoming_peer(port, INFO_HASH)
+        )
+    });
+
+    // a loop per slot, each with buffers of its own
+    let wifi = wifi_helper::WifiStackDuple;
+    let (mut rx_a, mut tx_a) = ([0u8; 1024], [0u8; 1024]);
+    let (mut rx_b, mut tx_b) = ([0u8; 1024], [0u8; 1024]);
+    let (mut rng_a, mut rng_b) = (XorShift32::new(1), XorShift32::new(2));
+    // each handler waits for the other one, which only returns if both peers
+    // are handled at the same time
+    let both = tokio::sync::Barrier::new(2);
+    let remotes = std::cell::RefCell::new(Vec::new());
+    let handle = async |peer: IncomingPeer<'_, MseStream<TcpConnectionDuple>>| {
+        remotes.borrow_mut().push(peer.connection.remote_addr());
+        both.wait().await;
+    };
+    let loops = async {
+        tokio::join!(
+            listener.run_slot(&wifi, &slots, &mut rng_a, &mut rx_a, &mut tx_a, handle),
+            listener.run_slot(&wifi, &slots, &mut rng_b, &mut rx_b, &mut tx_b, handle),
+        )
+    };
+    let _ = tokio::time::timeout(Duration::from_secs(1), loops).await;
+
+    let (first, second) = peers.await.unwrap();
+    assert!(first.is_some() && second.is_some());
+    let remotes = remotes.into_inner();
+    assert_eq!(remotes.len(), 2);
+    assert_ne!(remotes[0], remotes[1]);
+    assert_eq!(slots.available(), 2);
+}
@@ -0,0 +1,61 @@
+use core_logic::core::tracker::{self, ScrapeStats};
+use tokio::net::UdpSocket;
+
+mod wifi_helper;
+
+/// Answers one connect and one scrape request like a BEP 15 tracker would.
+async fn fake_udp_tracker(socket: UdpSocket) {
+    let mut buf = [0u8; 1024];
+
+    let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+    assert_eq!(len, 16);
+    let mut response = vec![0, 0, 0, 0];
+    response.extend_from_slice(&buf[12..16]);
+    response.extend_from_slice(&1337u64.to_be_bytes());
+    socket.send_to(&response, peer).await.unwrap();
+
+    let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..8], &1337u64.to_be_bytes());
+    let mut response = vec![0, 0, 0, 2];
+    response.extend_from_slice(&buf[12..16]);
+    for i in 0..(len as u32 - 16) / 20 {
+        for n in [i + 1, i + 2, i + 3] {
+            response.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+    socket.send_to(&response, peer).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_udp_scrape() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let announce = format!(
+        "udp://127.0.0.1:{}/announce",
+        socket.local_addr().unwrap().port()
+    );
+    let tracker = tokio::spawn(fake_udp_tracker(socket));
+
+    let info_hashes = [[1u8; 20], [2u8; 20]];
+    let stats = tracker::scrape(&wifi_helper::WifiStackDuple, &announce, &info_hashes, 99)
+        .await
+        .unwrap();
+    tracker.await.unwrap();
+
+    assert_eq!(
+        stats,
+        [
+            ScrapeStats {
+                info_hash: info_hashes[0],
+                seeders: 1,
+                completed: 2,
+                leechers: 3
+            },
+            ScrapeStats {
+                info_hash: info_hashes[1],
+                seeders: 2,
+                completed: 3,
+                leechers: 4
+            }
+        ]
+    );
+}
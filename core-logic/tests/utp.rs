@@ -0,0 +1,338 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use core_logic::{
+    core::{
+        peer::{
+            exchange_handshakes,
+            handshake::{HANDSHAKE_LEN, Handshake, Reserved},
+            listener::{ConnectionSlots, Listener},
+        },
+        utp::{UtpConfig, UtpSocket, UtpStream},
+    },
+    rng::{Rng, XorShift32},
+    wifi::{TcpConnection, TcpError, UdpError, UdpSocket, WifiStack},
+};
+
+use crate::wifi_helper::{UdpSocketDuple, WifiStackDuple};
+
+mod wifi_helper;
+
+const INFO_HASH: [u8; 20] = [7; 20];
+
+/// A loopback socket that loses and reorders some of the datagrams it sends.
+struct LossySocket {
+    inner: UdpSocketDuple,
+    rng: XorShift32,
+    /// Percentage of datagrams that are dropped.
+    loss: u32,
+    /// Percentage of datagrams that are held back until after the next one.
+    reorder: u32,
+    held: Option<(Vec<u8>, SocketAddr)>,
+}
+
+impl LossySocket {
+    async fn bind(seed: u32, loss: u32, reorder: u32) -> Self {
+        Self {
+            inner: WifiStackDuple.bind_udp(0, &mut [], &mut []).await.unwrap(),
+            rng: XorShift32::new(seed),
+            loss,
+            reorder,
+            held: None,
+        }
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.socket.local_addr().unwrap()
+    }
+
+    async fn send_held(&mut self) -> Result<(), UdpError> {
+        match self.held.take() {
+            Some((held, remote)) => self.inner.send_to(&held, remote).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl UdpSocket for LossySocket {
+    async fn send_to(&mut self, buf: &[u8], remote: SocketAddr) -> Result<(), UdpError> {
+        if self.rng.next_below(100) < self.loss {
+            return Ok(());
+        }
+        if self.held.is_none() && self.rng.next_below(100) < self.reorder {
+            self.held = Some((buf.to_vec(), remote));
+            return Ok(());
+        }
+        self.inner.send_to(buf, remote).await?;
+        self.send_held().await
+    }
+
+    async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), UdpError> {
+        // a held back datagram must not wait for a next one that never comes
+        self.send_held().await?;
+        self.inner.recv_from(buf).await
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.inner.set_timeout(timeout);
+    }
+
+    fn join_multicast(&mut self, group: std::net::IpAddr) -> Result<(), UdpError> {
+        self.inner.join_multicast(group)
+    }
+}
+
+type Clock = Box<dyn Fn() -> u64>;
+type Socket = UtpSocket<LossySocket, XorShift32, Clock>;
+type Stream<'s> = UtpStream<'s, LossySocket, XorShift32, Clock>;
+
+fn clock() -> Clock {
+    let start = Instant::now();
+    Box::new(move || start.elapsed().as_millis() as u64)
+}
+
+/// uTP sockets over loopback that lose and reorder datagrams.
+struct Sockets {
+    client: Socket,
+    server: Socket,
+    server_addr: SocketAddr,
+}
+
+async fn sockets(loss: u32, reorder: u32) -> Sockets {
+    let client = LossySocket::bind(1, loss, reorder).await;
+    let server = LossySocket::bind(2, loss, reorder).await;
+    let server_addr = server.local_addr();
+    Sockets {
+        client: UtpSocket::new(client, UtpConfig::DEFAULT, 4, XorShift32::new(3), clock()),
+        server: UtpSocket::new(server, UtpConfig::DEFAULT, 4, XorShift32::new(4), clock()),
+        server_addr,
+    }
+}
+
+impl Sockets {
+    /// Connects a stream of the client socket to the server socket.
+    async fn connect_pair(&self) -> (Stream<'_>, Stream<'_>) {
+        let (client, server) = tokio::join!(
+            self.client
+                .connect(self.server_addr, Duration::from_secs(10)),
+            self.server.accept(),
+        );
+        (client.unwrap(), server.unwrap())
+    }
+}
+
+fn test_data(len: usize) -> Vec<u8> {
+    let mut rng = XorShift32::new(5);
+    let mut data = vec![0; len];
+    rng.fill_bytes(&mut data);
+    data
+}
+
+/// Sends `data` from one stream to the other and checks that it arrives intact.
+/// Closing keeps the sender going until the receiver acked everything.
+async fn transfer(sender: Stream<'_>, mut receiver: Stream<'_>, data: &[u8]) {
+    let mut received = Vec::new();
+    let read = async {
+        let mut buf = [0; 4096];
+        loop {
+            match receiver.read(&mut buf).await.unwrap() {
+                0 => break,
+                len => received.extend_from_slice(&buf[..len]),
+            }
+        }
+    };
+    let write = async {
+        let mut sender = sender;
+        sender.write_all(data).await.unwrap();
+        sender.close().await;
+    };
+    tokio::join!(read, write);
+
+    assert!(received == data, "the data arrived corrupted");
+}
+
+#[tokio::test]
+async fn test_peer_handshake_over_utp() {
+    let sockets = sockets(0, 0).await;
+    let (mut client, mut server) = sockets.connect_pair().await;
+    let ours = Handshake::new(Reserved::default(), INFO_HASH, [1; 20]);
+
+    let answer = async {
+        let mut buf = [0; HANDSHAKE_LEN];
+        server.read_exact(&mut buf).await.unwrap();
+        let theirs = Handshake::decode(&buf).unwrap();
+        let answer = Handshake::new(Reserved::default(), theirs.info_hash, [2; 20]);
+        server
+            .write_all(answer.encode(&mut buf).unwrap())
+            .await
+            .unwrap();
+        theirs
+    };
+    let (theirs, answer) = tokio::join!(exchange_handshakes(&mut client, &ours), answer);
+
+    assert_eq!(answer, ours);
+    assert_eq!(theirs.unwrap().peer_id, [2; 20]);
+}
+
+#[tokio::test]
+async fn test_listener_accepts_utp_peers() {
+    let sockets = sockets(0, 0).await;
+    let torrents = [[1; 20], INFO_HASH];
+    let listener = Listener::new(
+        sockets.server_addr.port(),
+        Reserved::default(),
+        [2; 20],
+        &torrents,
+    );
+    let slots = ConnectionSlots::new(1);
+    let ours = Handshake::new(Reserved::default(), INFO_HASH, [1; 20]);
+
+    let connect = async {
+        let mut client = sockets
+            .client
+            .connect(sockets.server_addr, Duration::from_secs(10))
+            .await
+            .unwrap();
+        exchange_handshakes(&mut client, &ours).await
+    };
+    let mut rng = XorShift32::new(5);
+    let (theirs, incoming) = tokio::join!(
+        connect,
+        listener.accept_utp(&sockets.server, &slots, &mut rng),
+    );
+
+    let incoming = incoming.unwrap();
+    assert_eq!(incoming.torrent, 1);
+    assert_eq!(incoming.handshake, ours);
+    assert_eq!(theirs.unwrap().peer_id, [2; 20]);
+    assert_eq!(slots.available(), 0);
+}
+
+#[tokio::test]
+async fn test_transfer() {
+    let sockets = sockets(0, 0).await;
+    let (client, server) = sockets.connect_pair().await;
+    transfer(client, server, &test_data(256 * 1024)).await;
+
+    let (client, server) = sockets.connect_pair().await;
+    transfer(server, client, &test_data(64 * 1024)).await;
+}
+
+#[tokio::test]
+async fn test_transfer_with_packet_loss() {
+    let sockets = sockets(10, 0).await;
+    let (client, server) = sockets.connect_pair().await;
+
+    transfer(client, server, &test_data(128 * 1024)).await;
+}
+
+#[tokio::test]
+async fn test_transfer_with_reordering() {
+    let sockets = sockets(0, 20).await;
+    let (client, server) = sockets.connect_pair().await;
+
+    transfer(client, server, &test_data(128 * 1024)).await;
+}
+
+#[tokio::test]
+async fn test_transfer_with_loss_and_reordering_both_ways() {
+    let sockets = sockets(5, 10).await;
+    let (mut client, mut server) = sockets.connect_pair().await;
+    let upload = test_data(64 * 1024);
+    let download: Vec<u8> = test_data(64 * 1024).into_iter().rev().collect();
+
+    let mut uploaded = vec![0; upload.len()];
+    let mut downloaded = vec![0; download.len()];
+    let (client_result, server_result) = tokio::join!(
+        async {
+            client.write_all(&upload).await?;
+            client.read_exact(&mut downloaded).await
+        },
+        async {
+            server.write_all(&download).await?;
+            server.read_exact(&mut uploaded).await
+        },
+    );
+    client_result.unwrap();
+    server_result.unwrap();
+    assert!(uploaded == upload && downloaded == download);
+}
+
+#[tokio::test]
+async fn test_close_with_loss_and_reordering() {
+    let sockets = sockets(10, 10).await;
+    let (client, server) = sockets.connect_pair().await;
+
+    transfer(client, server, &test_data(10_000)).await;
+}
+
+#[tokio::test]
+async fn test_connect_times_out() {
+    let socket = LossySocket::bind(1, 0, 0).await;
+    let silent = LossySocket::bind(2, 0, 0).await;
+    let socket = UtpSocket::new(socket, UtpConfig::DEFAULT, 4, XorShift32::new(3), clock());
+
+    let result = socket
+        .connect(silent.local_addr(), Duration::from_millis(300))
+        .await;
+    assert!(matches!(result, Err(TcpError::TimedOut)));
+    assert_eq!(socket.connections(), 0);
+}
+
+#[tokio::test]
+async fn test_read_times_out() {
+    let sockets = sockets(0, 0).await;
+    let (_client, mut server) = sockets.connect_pair().await;
+    server.set_timeout(Some(Duration::from_millis(100)));
+
+    let mut buf = [0; 16];
+    assert_eq!(server.read(&mut buf).await, Err(TcpError::TimedOut));
+}
+
+#[tokio::test]
+async fn test_connections_share_a_socket() {
+    let sockets = sockets(5, 5).await;
+    let (first_client, first_server) = sockets.connect_pair().await;
+    let (second_client, second_server) = sockets.connect_pair().await;
+    assert_eq!(first_client.remote_addr(), second_client.remote_addr());
+    assert_eq!(sockets.server.connections(), 2);
+
+    // both ways at once, on the same pair of sockets
+    let upload = test_data(64 * 1024);
+    let download: Vec<u8> = upload.iter().rev().copied().collect();
+    tokio::join!(
+        transfer(first_client, first_server, &upload),
+        transfer(second_server, second_client, &download),
+    );
+    assert_eq!(sockets.server.connections(), 0);
+}
+
+#[tokio::test]
+async fn test_connections_beyond_the_limit_are_refused() {
+    let client = LossySocket::bind(1, 0, 0).await;
+    let server = LossySocket::bind(2, 0, 0).await;
+    let server_addr = server.local_addr();
+    let client = UtpSocket::new(client, UtpConfig::DEFAULT, 2, XorShift32::new(3), clock());
+    let server = UtpSocket::new(server, UtpConfig::DEFAULT, 1, XorShift32::new(4), clock());
+
+    let (accepted, connected) = tokio::join!(
+        server.accept(),
+        client.connect(server_addr, Duration::from_secs(1)),
+    );
+    let (_accepted, _connected) = (accepted.unwrap(), connected.unwrap());
+
+    // the server keeps answering the first connection, but ignores the SYN
+    let refused = async {
+        let result = client
+            .connect(server_addr, Duration::from_millis(300))
+            .await;
+        assert!(matches!(result, Err(TcpError::TimedOut)));
+    };
+    let served = async {
+        let _ = tokio::time::timeout(Duration::from_millis(500), server.accept()).await;
+    };
+    tokio::join!(refused, served);
+    assert_eq!(server.connections(), 1);
+}
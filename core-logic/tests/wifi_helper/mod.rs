@@ -1,8 +1,17 @@
-use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, btree_map::Entry};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
 
-use core_logic::wifi::WifiStack;
+use core_logic::wifi::{TcpConnection, TcpError, UdpError, UdpSocket, WifiStack};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
 pub const IP_ADDRESS: &Ipv4Addr = &std::net::Ipv4Addr::new(192, 168, 1, 42);
+pub const IPV6_ADDRESS: &Ipv6Addr = &std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 42);
+
+/// How long to wait for the answer to a UDP request.
+const UDP_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub struct WifiError(pub std::io::Error);
@@ -21,30 +30,213 @@ impl From<std::io::Error> for WifiError {
 
 pub struct WifiStackDuple;
 
+/// The listening sockets by port. Like on the device, several `accept`s may wait
+/// on the same port at the same time.
+static LISTENERS: Mutex<BTreeMap<u16, std::net::TcpListener>> = Mutex::new(BTreeMap::new());
+
+fn listener(port: u16) -> std::io::Result<tokio::net::TcpListener> {
+    let mut listeners = LISTENERS.lock().unwrap();
+    let listener = match listeners.entry(port) {
+        Entry::Occupied(entry) => entry.get().try_clone()?,
+        Entry::Vacant(entry) => {
+            let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+            listener.set_nonblocking(true)?;
+            entry.insert(listener).try_clone()?
+        }
+    };
+    tokio::net::TcpListener::from_std(listener)
+}
+
 impl WifiStack for WifiStackDuple {
     type Error = WifiError;
+    type Connection<'a> = TcpConnectionDuple;
+    type Socket<'a> = UdpSocketDuple;
 
     async fn make_http_request<'a>(
         &self,
         url: &str,
         rx_buf: &'a mut [u8],
     ) -> Result<&'a mut [u8], Self::Error> {
-        let response = reqwest::get(url).await.map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("HTTP request error: {}", e),
-            )
-        })?;
-        let bytes = response.bytes().await.map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, format!("HTTP read error: {}", e))
-        })?;
+        let response = reqwest::get(url)
+            .await
+            .map_err(|e| std::io::Error::other(format!("HTTP request error: {}", e)))?;
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| std::io::Error::other(format!("HTTP read error: {}", e)))?;
 
         let len = bytes.len().min(rx_buf.len());
         rx_buf[..len].copy_from_slice(&bytes[..len]);
         Ok(&mut rx_buf[..len])
     }
 
+    async fn make_udp_request<'a>(
+        &self,
+        host: &str,
+        port: u16,
+        payload: &[u8],
+        rx_buf: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Self::Error> {
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect((host, port)).await?;
+        socket.send(payload).await?;
+
+        let len = tokio::time::timeout(UDP_TIMEOUT, socket.recv(rx_buf))
+            .await
+            .map_err(std::io::Error::other)??;
+        Ok(&mut rx_buf[..len])
+    }
+
+    async fn connect<'a>(
+        &'a self,
+        remote: SocketAddr,
+        timeout: Duration,
+        _rx_buf: &'a mut [u8],
+        _tx_buf: &'a mut [u8],
+    ) -> Result<Self::Connection<'a>, TcpError> {
+        let stream = tokio::time::timeout(timeout, TcpStream::connect(remote))
+            .await
+            .map_err(|_| TcpError::TimedOut)?
+            .map_err(tcp_error)?;
+
+        Ok(TcpConnectionDuple {
+            stream,
+            remote,
+            timeout: None,
+        })
+    }
+
+    async fn accept<'a>(
+        &'a self,
+        port: u16,
+        _rx_buf: &'a mut [u8],
+        _tx_buf: &'a mut [u8],
+    ) -> Result<Self::Connection<'a>, TcpError> {
+        let listener = listener(port).map_err(|_| TcpError::NoSocket)?;
+        let (stream, remote) = listener.accept().await.map_err(tcp_error)?;
+
+        Ok(TcpConnectionDuple {
+            stream,
+            remote,
+            timeout: None,
+        })
+    }
+
+    async fn bind_udp<'a>(
+        &'a self,
+        port: u16,
+        _rx_buf: &'a mut [u8],
+        _tx_buf: &'a mut [u8],
+    ) -> Result<Self::Socket<'a>, UdpError> {
+        let socket = tokio::net::UdpSocket::bind(("127.0.0.1", port))
+            .await
+            .map_err(|_| UdpError::NoSocket)?;
+
+        Ok(UdpSocketDuple {
+            socket,
+            timeout: None,
+        })
+    }
+
     fn get_ipv4(&self) -> std::net::Ipv4Addr {
         *IP_ADDRESS
     }
+
+    fn get_ipv6(&self) -> Option<std::net::Ipv6Addr> {
+        Some(*IPV6_ADDRESS)
+    }
+}
+
+/// TCP connection on the host, the socket buffers are managed by the OS.
+pub struct TcpConnectionDuple {
+    stream: TcpStream,
+    remote: SocketAddr,
+    timeout: Option<Duration>,
+}
+
+impl TcpConnection for TcpConnectionDuple {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        with_timeout(self.timeout, self.stream.read(buf)).await
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), TcpError> {
+        with_timeout(self.timeout, self.stream.write_all(buf)).await
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    async fn close(mut self) {
+        let _ = self.stream.shutdown().await;
+    }
+}
+
+/// UDP socket on the host, the datagram buffers are managed by the OS.
+pub struct UdpSocketDuple {
+    pub socket: tokio::net::UdpSocket,
+    timeout: Option<Duration>,
+}
+
+impl UdpSocket for UdpSocketDuple {
+    async fn send_to(&mut self, buf: &[u8], remote: SocketAddr) -> Result<(), UdpError> {
+        self.socket
+            .send_to(buf, remote)
+            .await
+            .map(|_| ())
+            .map_err(|_| UdpError::NoRoute)
+    }
+
+    async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), UdpError> {
+        let recv = self.socket.recv_from(buf);
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, recv)
+                .await
+                .map_err(|_| UdpError::TimedOut)?,
+            None => recv.await,
+        }
+        .map_err(|_| UdpError::Truncated)
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn join_multicast(&mut self, group: std::net::IpAddr) -> Result<(), UdpError> {
+        match group {
+            std::net::IpAddr::V4(group) => {
+                self.socket.join_multicast_v4(group, Ipv4Addr::LOCALHOST)
+            }
+            std::net::IpAddr::V6(group) => self.socket.join_multicast_v6(&group, 0),
+        }
+        .map_err(|_| UdpError::Multicast)
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = std::io::Result<T>>,
+) -> Result<T, TcpError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| TcpError::TimedOut)?,
+        None => future.await,
+    }
+    .map_err(tcp_error)
+}
+
+fn tcp_error(err: std::io::Error) -> TcpError {
+    match err.kind() {
+        std::io::ErrorKind::TimedOut => TcpError::TimedOut,
+        std::io::ErrorKind::UnexpectedEof => TcpError::Closed,
+        std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::NetworkUnreachable => {
+            TcpError::NoRoute
+        }
+        _ => TcpError::ConnectionReset,
+    }
 }
@@ -2,6 +2,7 @@
 #![no_main]
 
 pub mod fs;
+pub mod rng;
 pub mod setup;
 pub mod wifi;
 
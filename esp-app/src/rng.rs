@@ -0,0 +1,29 @@
+use core_logic::rng::Rng;
+
+/// The hardware random number generator of the ESP32C3.
+///
+/// It only produces true random numbers while the radio is running,
+/// which is always the case once the Wi-Fi is set up.
+pub struct EspRng(esp_hal::rng::Rng);
+
+impl EspRng {
+    pub fn new() -> Self {
+        Self(esp_hal::rng::Rng::new())
+    }
+}
+
+impl Default for EspRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rng for EspRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        self.0.read(buf);
+    }
+}
@@ -4,10 +4,13 @@ use esp_hal::{clock::CpuClock, timer::timg::TimerGroup};
 
 use crate::{
     fs::{initialize_esp_fs, sd_card, volume_mgr::EspVolumeMgr},
+    rng::EspRng,
     wifi::{self, EspWifiStack},
 };
 
-pub async fn setup(spawner: embassy_executor::Spawner) -> BitTorrenter<EspWifiStack, EspVolumeMgr> {
+pub async fn setup(
+    spawner: embassy_executor::Spawner,
+) -> BitTorrenter<EspWifiStack, EspVolumeMgr, EspRng> {
     // generator version: 1.0.1
 
     rtt_target::rtt_init_defmt!();
@@ -39,5 +42,5 @@ pub async fn setup(spawner: embassy_executor::Spawner) -> BitTorrenter<EspWifiSt
 
     info!("Done initializing.");
 
-    BitTorrenter::new(wifi, fs)
+    BitTorrenter::new(wifi, fs, EspRng::new())
 }
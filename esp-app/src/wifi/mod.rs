@@ -1,19 +1,101 @@
-use core::net::Ipv4Addr;
+use alloc::vec;
+use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
-use core_logic::wifi::WifiStack;
+use core_logic::{
+    core::{dht::DhtConfig, utp::UtpConfig},
+    wifi::{TcpError, UdpError, WifiStack},
+};
 use embassy_net::{
-    Stack,
-    dns::DnsSocket,
-    tcp::client::{TcpClient, TcpClientState},
+    IpAddress, Stack,
+    dns::{DnsQueryType, DnsSocket},
+    tcp::{
+        TcpSocket,
+        client::{TcpClient, TcpClientState},
+    },
+    udp::{PacketMetadata, UdpSocket},
 };
+use embassy_time::{Duration, with_timeout};
+
+pub use tcp::EspTcpConnection;
+pub use udp::EspUdpSocket;
 
 mod network;
 pub(crate) mod setup;
+mod tcp;
+mod udp;
+
+/// Peer connections the device keeps open at most, incoming and outgoing together.
+///
+/// Every connection takes a socket of the network stack plus its rx and tx buffers,
+/// so this should be used to size the `ConnectionSlots` of the peer listener.
+pub const MAX_PEER_CONNECTIONS: usize = 2;
+
+/// How the device takes part in the DHT. A full node doesn't fit next to the
+/// peer connections, so it only looks up peers.
+pub const DHT_CONFIG: DhtConfig = DhtConfig::CLIENT_ONLY;
+
+/// Sizes of the uTP connections, the default buffers don't fit the heap.
+pub const UTP_CONFIG: UtpConfig = UtpConfig::EMBEDDED;
+
+/// The IPv6 address of the device.
+///
+/// **IPv6 stays off until this is set**: embassy-net doesn't do SLAAC or DHCPv6,
+/// so with `None` the device has no IPv6 address at all, connects to IPv4 peers
+/// only and never resolves AAAA records. Set it for your network, e.g.
+/// `Some(Ipv6Config::Eui64 { prefix, gateway })` with the prefix your router
+/// advertises.
+pub const IPV6_CONFIG: Option<Ipv6Config> = None;
+
+/// How the device gets its IPv6 address, see [`IPV6_CONFIG`].
+#[derive(Debug, Clone, Copy)]
+pub enum Ipv6Config {
+    /// A fixed address.
+    Static {
+        address: Ipv6Addr,
+        prefix_len: u8,
+        gateway: Option<Ipv6Addr>,
+    },
+    /// A /64 prefix, completed with the modified EUI-64 interface id made from the
+    /// MAC address, the address SLAAC would pick. The prefix is the one the router
+    /// advertises, it isn't learned from the advertisements. The gateway is usually
+    /// the router's link-local address.
+    Eui64 {
+        prefix: Ipv6Addr,
+        gateway: Option<Ipv6Addr>,
+    },
+}
+
+/// How long to wait for the answer to a UDP request.
+const UDP_TIMEOUT: Duration = Duration::from_secs(15);
 
 pub struct EspWifiStack(Stack<'static>);
 
+#[derive(Debug, defmt::Format)]
+pub enum EspWifiError {
+    Http(reqwless::Error),
+    Dns(embassy_net::dns::Error),
+    UdpBind(embassy_net::udp::BindError),
+    UdpSend(embassy_net::udp::SendError),
+    UdpRecv(embassy_net::udp::RecvError),
+    Timeout,
+}
+
+impl From<reqwless::Error> for EspWifiError {
+    fn from(err: reqwless::Error) -> Self {
+        EspWifiError::Http(err)
+    }
+}
+
+impl From<embassy_net::dns::Error> for EspWifiError {
+    fn from(err: embassy_net::dns::Error) -> Self {
+        EspWifiError::Dns(err)
+    }
+}
+
 impl WifiStack for EspWifiStack {
-    type Error = reqwless::Error;
+    type Error = EspWifiError;
+    type Connection<'a> = EspTcpConnection<'a>;
+    type Socket<'a> = EspUdpSocket<'a>;
     /// makes a GET request to the provided url and returns the response body
     async fn make_http_request<'a>(
         &self,
@@ -35,6 +117,74 @@ impl WifiStack for EspWifiStack {
             .body()
             .read_to_end()
             .await
+            .map_err(EspWifiError::Http)
+    }
+
+    /// sends the payload to the given host and returns the first datagram that comes back
+    async fn make_udp_request<'a>(
+        &self,
+        host: &str,
+        port: u16,
+        payload: &[u8],
+        rx_buf: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Self::Error> {
+        let address = self.resolve(host).await?;
+
+        let mut rx_meta = [PacketMetadata::EMPTY; 1];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut rx_storage = vec![0u8; rx_buf.len()];
+        let mut tx_storage = vec![0u8; payload.len()];
+        let mut socket = UdpSocket::new(
+            self.0,
+            &mut rx_meta,
+            &mut rx_storage,
+            &mut tx_meta,
+            &mut tx_storage,
+        );
+        socket.bind(0).map_err(EspWifiError::UdpBind)?;
+
+        socket
+            .send_to(payload, (address, port))
+            .await
+            .map_err(EspWifiError::UdpSend)?;
+        let (len, _) = with_timeout(UDP_TIMEOUT, socket.recv_from(rx_buf))
+            .await
+            .map_err(|_| EspWifiError::Timeout)?
+            .map_err(EspWifiError::UdpRecv)?;
+
+        Ok(&mut rx_buf[..len])
+    }
+
+    async fn connect<'a>(
+        &'a self,
+        remote: SocketAddr,
+        timeout: core::time::Duration,
+        rx_buf: &'a mut [u8],
+        tx_buf: &'a mut [u8],
+    ) -> Result<Self::Connection<'a>, TcpError> {
+        let socket = TcpSocket::new(self.0, rx_buf, tx_buf);
+        EspTcpConnection::connect(socket, remote, timeout).await
+    }
+
+    async fn accept<'a>(
+        &'a self,
+        port: u16,
+        rx_buf: &'a mut [u8],
+        tx_buf: &'a mut [u8],
+    ) -> Result<Self::Connection<'a>, TcpError> {
+        let socket = TcpSocket::new(self.0, rx_buf, tx_buf);
+        EspTcpConnection::accept(socket, port).await
+    }
+
+    /// At most [`udp::MAX_UDP_SOCKETS`] sockets can be bound at the same time,
+    /// which is enough for the DHT, LSD and uTP.
+    async fn bind_udp<'a>(
+        &'a self,
+        port: u16,
+        rx_buf: &'a mut [u8],
+        tx_buf: &'a mut [u8],
+    ) -> Result<Self::Socket<'a>, UdpError> {
+        EspUdpSocket::bind(self.0, port, rx_buf, tx_buf)
     }
 
     fn get_ipv4(&self) -> Ipv4Addr {
@@ -46,4 +196,30 @@ impl WifiStack for EspWifiStack {
             );
         }
     }
+
+    fn get_ipv6(&self) -> Option<Ipv6Addr> {
+        self.0.config_v6().map(|config| config.address.address())
+    }
+}
+
+impl EspWifiStack {
+    /// Looks up the address of `host`, preferring IPv4 and falling back to IPv6,
+    /// also when the IPv4 lookup fails.
+    async fn resolve(&self, host: &str) -> Result<IpAddress, EspWifiError> {
+        let dns = DnsSocket::new(self.0);
+        let v4 = dns.query(host, DnsQueryType::A).await;
+        if let Ok(addresses) = &v4
+            && let Some(address) = addresses.first()
+        {
+            return Ok(*address);
+        }
+        if self.0.config_v6().is_some()
+            && let Some(address) = dns.query(host, DnsQueryType::Aaaa).await?.first()
+        {
+            return Ok(*address);
+        }
+        Err(EspWifiError::Dns(
+            v4.err().unwrap_or(embassy_net::dns::Error::Failed),
+        ))
+    }
 }
@@ -1,14 +1,21 @@
+use core::net::Ipv6Addr;
+
 use defmt::{debug, info};
-use embassy_net::StackResources;
+use embassy_net::{ConfigV6, Ipv6Cidr, StackResources, StaticConfigV6};
 use embassy_time::Duration;
 use esp_hal::{peripherals, rng::Rng};
 use esp_radio::Controller;
 
 use crate::wifi::{
-    EspWifiStack,
+    EspWifiStack, IPV6_CONFIG, Ipv6Config, MAX_PEER_CONNECTIONS,
     network::{connection, net_task},
 };
 
+/// Sockets of the network stack: DHCP, DNS, one for tracker requests, one each for the
+/// DHT, LSD and uTP and one for every peer connection. The accept loop of a slot
+/// listens on the socket that becomes its connection, so listening needs no extra one.
+const SOCKET_COUNT: usize = 6 + MAX_PEER_CONNECTIONS;
+
 pub(crate) async fn wifi_setup(
     spawner: embassy_executor::Spawner,
     wifi_peripheral: peripherals::WIFI<'static>,
@@ -44,18 +51,25 @@ impl EspWifiStack {
             esp_radio::wifi::new(esp_radio_ctrl, wifi_peripheral, Default::default())
                 .expect("Failed to initialize Wi-Fi controller");
 
-        let config = embassy_net::Config::dhcpv4(Default::default());
+        let mut config = embassy_net::Config::dhcpv4(Default::default());
+        if let Some(ipv6) = IPV6_CONFIG {
+            let config_v6 = static_config_v6(ipv6, interfaces.sta.mac_address());
+            info!("IPv6 address: {}", config_v6.address);
+            config.ipv6 = ConfigV6::Static(config_v6);
+        } else {
+            info!("IPv6 is off, set IPV6_CONFIG to use it");
+        }
 
         let rng = Rng::new();
         let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
         // Init network stack
-        static STACK_RESOURCES_CELL: static_cell::StaticCell<StackResources<3>> =
+        static STACK_RESOURCES_CELL: static_cell::StaticCell<StackResources<SOCKET_COUNT>> =
             static_cell::StaticCell::new();
         let (stack, runner) = embassy_net::new(
             interfaces.sta,
             config,
-            STACK_RESOURCES_CELL.init(StackResources::<3>::new()),
+            STACK_RESOURCES_CELL.init(StackResources::<SOCKET_COUNT>::new()),
             seed,
         );
         spawner.spawn(connection(wifi_controller)).ok();
@@ -64,3 +78,33 @@ impl EspWifiStack {
         Self(stack)
     }
 }
+
+fn static_config_v6(config: Ipv6Config, mac: [u8; 6]) -> StaticConfigV6 {
+    let (address, prefix_len, gateway) = match config {
+        Ipv6Config::Static {
+            address,
+            prefix_len,
+            gateway,
+        } => (address, prefix_len, gateway),
+        Ipv6Config::Eui64 { prefix, gateway } => {
+            // the modified EUI-64 interface id of RFC 4291
+            let mut octets = prefix.octets();
+            octets[8..].copy_from_slice(&[
+                mac[0] ^ 0x02,
+                mac[1],
+                mac[2],
+                0xff,
+                0xfe,
+                mac[3],
+                mac[4],
+                mac[5],
+            ]);
+            (Ipv6Addr::from(octets), 64, gateway)
+        }
+    };
+    StaticConfigV6 {
+        address: Ipv6Cidr::new(address, prefix_len),
+        gateway,
+        dns_servers: Default::default(),
+    }
+}
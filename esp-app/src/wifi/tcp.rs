@@ -0,0 +1,126 @@
+use core::net::{IpAddr, SocketAddr};
+
+use core_logic::wifi::{TcpConnection, TcpError};
+use embassy_net::{
+    IpAddress, IpEndpoint,
+    tcp::{AcceptError, ConnectError, TcpSocket},
+};
+use embassy_time::with_timeout;
+
+/// A TCP connection on top of embassy-net, using buffers provided by the caller.
+pub struct EspTcpConnection<'a> {
+    socket: TcpSocket<'a>,
+    remote: SocketAddr,
+    timeout: Option<core::time::Duration>,
+}
+
+impl<'a> EspTcpConnection<'a> {
+    /// Connects the socket to `remote`, giving up after `timeout`.
+    pub(super) async fn connect(
+        mut socket: TcpSocket<'a>,
+        remote: SocketAddr,
+        timeout: core::time::Duration,
+    ) -> Result<Self, TcpError> {
+        with_timeout(to_embassy(timeout), socket.connect(to_endpoint(remote)))
+            .await
+            .map_err(|_| TcpError::TimedOut)?
+            .map_err(|err| match err {
+                ConnectError::InvalidState => TcpError::NoSocket,
+                ConnectError::ConnectionReset => TcpError::ConnectionReset,
+                ConnectError::TimedOut => TcpError::TimedOut,
+                ConnectError::NoRoute => TcpError::NoRoute,
+            })?;
+
+        Ok(Self {
+            socket,
+            remote,
+            timeout: None,
+        })
+    }
+
+    /// Waits for a remote host to connect to `port`.
+    pub(super) async fn accept(mut socket: TcpSocket<'a>, port: u16) -> Result<Self, TcpError> {
+        socket.accept(port).await.map_err(|err| match err {
+            AcceptError::InvalidState | AcceptError::InvalidPort => TcpError::NoSocket,
+            AcceptError::ConnectionReset => TcpError::ConnectionReset,
+        })?;
+        let remote = socket
+            .remote_endpoint()
+            .map(from_endpoint)
+            .ok_or(TcpError::ConnectionReset)?;
+
+        Ok(Self {
+            socket,
+            remote,
+            timeout: None,
+        })
+    }
+}
+
+impl TcpConnection for EspTcpConnection<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        let read = self.socket.read(buf);
+        match self.timeout {
+            Some(timeout) => with_timeout(to_embassy(timeout), read)
+                .await
+                .map_err(|_| TcpError::TimedOut)?,
+            None => read.await,
+        }
+        .map_err(|_| TcpError::ConnectionReset)
+    }
+
+    async fn write_all(&mut self, mut buf: &[u8]) -> Result<(), TcpError> {
+        while !buf.is_empty() {
+            let write = self.socket.write(buf);
+            let written = match self.timeout {
+                Some(timeout) => with_timeout(to_embassy(timeout), write)
+                    .await
+                    .map_err(|_| TcpError::TimedOut)?,
+                None => write.await,
+            }
+            .map_err(|_| TcpError::ConnectionReset)?;
+
+            if written == 0 {
+                return Err(TcpError::Closed);
+            }
+            buf = &buf[written..];
+        }
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Option<core::time::Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    async fn close(mut self) {
+        self.socket.close();
+        let _flush_result = self.socket.flush().await;
+    }
+}
+
+pub(super) fn to_embassy(duration: core::time::Duration) -> embassy_time::Duration {
+    embassy_time::Duration::from_micros(duration.as_micros() as u64)
+}
+
+pub(super) fn to_ip_address(ip: IpAddr) -> IpAddress {
+    match ip {
+        IpAddr::V4(ip) => IpAddress::Ipv4(ip),
+        IpAddr::V6(ip) => IpAddress::Ipv6(ip),
+    }
+}
+
+pub(super) fn to_endpoint(addr: SocketAddr) -> IpEndpoint {
+    IpEndpoint::new(to_ip_address(addr.ip()), addr.port())
+}
+
+pub(super) fn from_endpoint(endpoint: IpEndpoint) -> SocketAddr {
+    let ip = match endpoint.addr {
+        IpAddress::Ipv4(ip) => IpAddr::V4(ip),
+        IpAddress::Ipv6(ip) => IpAddr::V6(ip),
+    };
+    SocketAddr::new(ip, endpoint.port)
+}
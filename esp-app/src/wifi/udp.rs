@@ -0,0 +1,154 @@
+use core::{
+    cell::{Cell, UnsafeCell},
+    net::{IpAddr, SocketAddr},
+};
+
+use core_logic::wifi::{UdpError, UdpSocket};
+use critical_section::Mutex;
+use embassy_net::{
+    Stack,
+    udp::{self, BindError, PacketMetadata, RecvError, SendError},
+};
+use embassy_time::with_timeout;
+
+use super::tcp::{from_endpoint, to_embassy, to_endpoint, to_ip_address};
+
+/// UDP sockets that can be bound at the same time: one each for the DHT, LSD and
+/// the uTP connections of all peers. A socket gives its place back when dropped.
+pub(super) const MAX_UDP_SOCKETS: usize = 3;
+
+/// Datagrams a bound socket queues in each direction.
+pub(super) const UDP_PACKETS: usize = 8;
+
+/// The packet metadata of a bound socket, in each direction.
+type UdpMetadata = ([PacketMetadata; UDP_PACKETS], [PacketMetadata; UDP_PACKETS]);
+
+/// The packet metadata of the sockets that are bound at the same time. It has to
+/// outlive the network stack's borrow of it, so it lives in a static and each
+/// entry is lent to one socket at a time.
+static METADATA: MetadataPool = MetadataPool {
+    entries: [const {
+        UnsafeCell::new((
+            [PacketMetadata::EMPTY; UDP_PACKETS],
+            [PacketMetadata::EMPTY; UDP_PACKETS],
+        ))
+    }; MAX_UDP_SOCKETS],
+    taken: Mutex::new(Cell::new([false; MAX_UDP_SOCKETS])),
+};
+
+struct MetadataPool {
+    entries: [UnsafeCell<UdpMetadata>; MAX_UDP_SOCKETS],
+    taken: Mutex<Cell<[bool; MAX_UDP_SOCKETS]>>,
+}
+
+// SAFETY: an entry is only accessed through the `&mut` handed out by
+// `take_metadata`, and `taken` makes sure there is at most one per entry.
+unsafe impl Sync for MetadataPool {}
+
+/// Lends a free entry until the returned guard is dropped, `None` if all
+/// [`MAX_UDP_SOCKETS`] sockets are bound.
+fn take_metadata() -> Option<(MetadataGuard, &'static mut UdpMetadata)> {
+    let index = critical_section::with(|cs| {
+        let taken = METADATA.taken.borrow(cs);
+        let mut flags = taken.get();
+        let index = flags.iter().position(|taken| !taken)?;
+        flags[index] = true;
+        taken.set(flags);
+        Some(index)
+    })?;
+    // SAFETY: the entry was free, so nothing else refers to it until the guard
+    // gives it back
+    let metadata = unsafe { &mut *METADATA.entries[index].get() };
+    *metadata = (
+        [PacketMetadata::EMPTY; UDP_PACKETS],
+        [PacketMetadata::EMPTY; UDP_PACKETS],
+    );
+    Some((MetadataGuard(index), metadata))
+}
+
+/// Gives an entry of [`METADATA`] back when dropped.
+struct MetadataGuard(usize);
+
+impl Drop for MetadataGuard {
+    fn drop(&mut self) {
+        critical_section::with(|cs| {
+            let taken = METADATA.taken.borrow(cs);
+            let mut flags = taken.get();
+            flags[self.0] = false;
+            taken.set(flags);
+        });
+    }
+}
+
+/// A bound UDP socket on top of embassy-net, using buffers provided by the caller.
+pub struct EspUdpSocket<'a> {
+    socket: udp::UdpSocket<'a>,
+    stack: Stack<'a>,
+    timeout: Option<core::time::Duration>,
+    /// after `socket`, so the metadata is only given back once the socket is gone
+    _metadata: MetadataGuard,
+}
+
+impl<'a> EspUdpSocket<'a> {
+    /// Binds a socket with the given buffers to `port`. Fails with
+    /// [`UdpError::NoSocket`] while [`MAX_UDP_SOCKETS`] sockets are bound.
+    pub(super) fn bind(
+        stack: Stack<'a>,
+        port: u16,
+        rx_buf: &'a mut [u8],
+        tx_buf: &'a mut [u8],
+    ) -> Result<Self, UdpError> {
+        let (metadata, (rx_meta, tx_meta)) = take_metadata().ok_or(UdpError::NoSocket)?;
+        let mut socket = udp::UdpSocket::new(stack, rx_meta, rx_buf, tx_meta, tx_buf);
+        // on failure the socket is dropped before the guard, which was taken first
+        socket.bind(port).map_err(|err| match err {
+            BindError::InvalidState => UdpError::NoSocket,
+            BindError::NoRoute => UdpError::NoRoute,
+        })?;
+
+        Ok(Self {
+            socket,
+            stack,
+            timeout: None,
+            _metadata: metadata,
+        })
+    }
+}
+
+impl UdpSocket for EspUdpSocket<'_> {
+    async fn send_to(&mut self, buf: &[u8], remote: SocketAddr) -> Result<(), UdpError> {
+        self.socket
+            .send_to(buf, to_endpoint(remote))
+            .await
+            .map_err(|err| match err {
+                SendError::NoRoute => UdpError::NoRoute,
+                SendError::SocketNotBound => UdpError::NoSocket,
+                SendError::PacketTooLarge => UdpError::TooLarge,
+            })
+    }
+
+    async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), UdpError> {
+        let recv = self.socket.recv_from(buf);
+        let (len, meta) = match self.timeout {
+            Some(timeout) => with_timeout(to_embassy(timeout), recv)
+                .await
+                .map_err(|_| UdpError::TimedOut)?,
+            None => recv.await,
+        }
+        .map_err(|RecvError::Truncated| UdpError::Truncated)?;
+
+        Ok((len, from_endpoint(meta.endpoint)))
+    }
+
+    fn set_timeout(&mut self, timeout: Option<core::time::Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Multicast groups are joined by the whole interface, so every socket bound to
+    /// the group's port receives its datagrams.
+    fn join_multicast(&mut self, group: IpAddr) -> Result<(), UdpError> {
+        self.stack
+            .join_multicast_group(to_ip_address(group))
+            .map_err(|_| UdpError::Multicast)
+    }
+}